@@ -0,0 +1,598 @@
+//! Serve an easy-fs image through the FUSE device of the host
+//!
+//! The host kernel sends the requests on the mounted directory to
+//! `/dev/fuse`, and reads the replies back from it. Requests are served one
+//! by one through the inode layer of easy-fs, so after each of them the image
+//! is as consistent as after a system call of our own kernel. The node id of
+//! an inode is its inode id plus one, as FUSE gives node id 1 to the root.
+
+use easy_fs::block_cache::block_cache_sync_all;
+use easy_fs::{EasyFileSystem, Inode};
+use spin::Mutex;
+use std::collections::HashMap;
+use std::ffi::CString;
+use std::fs::{File, OpenOptions};
+use std::io::{Error, Read, Result, Write};
+use std::os::unix::io::AsRawFd;
+use std::sync::Arc;
+
+/// The version of the FUSE protocol spoken, the layouts below are of 7.19
+const FUSE_KERNEL_VERSION: u32 = 7;
+const FUSE_KERNEL_MINOR_VERSION: u32 = 19;
+/// The most bytes one write request carries
+const MAX_WRITE: usize = 128 * 1024;
+/// Seconds the host may cache entries and attributes
+const TTL: u64 = 1;
+
+const FUSE_LOOKUP: u32 = 1;
+const FUSE_FORGET: u32 = 2;
+const FUSE_GETATTR: u32 = 3;
+const FUSE_SETATTR: u32 = 4;
+const FUSE_MKNOD: u32 = 8;
+const FUSE_MKDIR: u32 = 9;
+const FUSE_UNLINK: u32 = 10;
+const FUSE_RMDIR: u32 = 11;
+const FUSE_RENAME: u32 = 12;
+const FUSE_LINK: u32 = 13;
+const FUSE_OPEN: u32 = 14;
+const FUSE_READ: u32 = 15;
+const FUSE_WRITE: u32 = 16;
+const FUSE_STATFS: u32 = 17;
+const FUSE_RELEASE: u32 = 18;
+const FUSE_FSYNC: u32 = 20;
+const FUSE_FLUSH: u32 = 25;
+const FUSE_INIT: u32 = 26;
+const FUSE_OPENDIR: u32 = 27;
+const FUSE_READDIR: u32 = 28;
+const FUSE_RELEASEDIR: u32 = 29;
+const FUSE_FSYNCDIR: u32 = 30;
+const FUSE_CREATE: u32 = 35;
+const FUSE_INTERRUPT: u32 = 36;
+const FUSE_DESTROY: u32 = 38;
+const FUSE_BATCH_FORGET: u32 = 42;
+
+/// INIT flag allowing writes larger than a page
+const FUSE_BIG_WRITES: u32 = 1 << 5;
+/// SETATTR flags telling which attributes to set
+const FATTR_MODE: u32 = 1 << 0;
+const FATTR_UID: u32 = 1 << 1;
+const FATTR_GID: u32 = 1 << 2;
+const FATTR_SIZE: u32 = 1 << 3;
+
+/// Size of the header of a request
+const IN_HEADER_SZ: usize = 40;
+/// Size of the header of a reply
+const OUT_HEADER_SZ: usize = 16;
+
+/// A reply payload, or the errno to fail the request with
+type Reply = core::result::Result<Vec<u8>, i32>;
+
+/// A request read from the FUSE device
+struct Request<'a> {
+    opcode: u32,
+    unique: u64,
+    nodeid: u64,
+    uid: u32,
+    gid: u32,
+    /// the arguments following the header
+    body: &'a [u8],
+}
+
+impl<'a> Request<'a> {
+    fn parse(bytes: &'a [u8]) -> Self {
+        Self {
+            opcode: u32_at(bytes, 4),
+            unique: u64_at(bytes, 8),
+            nodeid: u64_at(bytes, 16),
+            uid: u32_at(bytes, 24),
+            gid: u32_at(bytes, 28),
+            body: &bytes[IN_HEADER_SZ..],
+        }
+    }
+    /// the name starting at 'offset' of the body, ended by a zero byte
+    fn name_at(&self, offset: usize) -> core::result::Result<&'a str, i32> {
+        let bytes = &self.body[offset..];
+        let len = bytes
+            .iter()
+            .position(|&byte| byte == 0)
+            .unwrap_or(bytes.len());
+        core::str::from_utf8(&bytes[..len]).map_err(|_| libc::EINVAL)
+    }
+}
+
+fn u32_at(bytes: &[u8], offset: usize) -> u32 {
+    let mut value = [0u8; 4];
+    value.copy_from_slice(&bytes[offset..offset + 4]);
+    u32::from_ne_bytes(value)
+}
+
+fn u64_at(bytes: &[u8], offset: usize) -> u64 {
+    let mut value = [0u8; 8];
+    value.copy_from_slice(&bytes[offset..offset + 8]);
+    u64::from_ne_bytes(value)
+}
+
+fn put_u32(out: &mut Vec<u8>, value: u32) {
+    out.extend_from_slice(&value.to_ne_bytes());
+}
+
+fn put_u64(out: &mut Vec<u8>, value: u64) {
+    out.extend_from_slice(&value.to_ne_bytes());
+}
+
+/// Mount the image at 'mountpoint' and serve it until it is unmounted
+///
+/// Mounting needs the privileges of root.
+pub fn mount(efs: Arc<Mutex<EasyFileSystem>>, mountpoint: &str) -> Result<()> {
+    let mut device = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open("/dev/fuse")?;
+    let source = CString::new("easy-fs")?;
+    let target = CString::new(mountpoint)?;
+    let fstype = CString::new("fuse.easy-fs")?;
+    // the host checks permissions against the modes and owners we report
+    let options = CString::new(format!(
+        "fd={},rootmode=40000,user_id={},group_id={},default_permissions",
+        device.as_raw_fd(),
+        unsafe { libc::getuid() },
+        unsafe { libc::getgid() }
+    ))?;
+    if unsafe {
+        libc::mount(
+            source.as_ptr(),
+            target.as_ptr(),
+            fstype.as_ptr(),
+            libc::MS_NOSUID | libc::MS_NODEV,
+            options.as_ptr() as *const libc::c_void,
+        )
+    } != 0
+    {
+        return Err(Error::last_os_error());
+    }
+    let mut server = Server {
+        efs,
+        opens: HashMap::new(),
+        orphans: HashMap::new(),
+    };
+    let mut buffer = vec![0u8; IN_HEADER_SZ + MAX_WRITE + 4096];
+    loop {
+        let len = match device.read(&mut buffer) {
+            Ok(len) => len,
+            Err(error) => match error.raw_os_error() {
+                // the request was interrupted before we read it
+                Some(libc::ENOENT) | Some(libc::EINTR) | Some(libc::EAGAIN) => continue,
+                // unmounted
+                Some(libc::ENODEV) => break,
+                _ => return Err(error),
+            },
+        };
+        let request = Request::parse(&buffer[..len]);
+        if let Some(reply) = server.serve(&request) {
+            send(&mut device, request.unique, reply)?;
+        }
+        if request.opcode == FUSE_DESTROY {
+            break;
+        }
+    }
+    for (_, inode) in server.orphans.drain() {
+        inode.release();
+    }
+    Ok(())
+}
+
+/// Write the reply to the request 'unique' to the FUSE device
+fn send(device: &mut File, unique: u64, reply: Reply) -> Result<()> {
+    let (error, payload) = match reply {
+        Ok(payload) => (0, payload),
+        Err(errno) => (-errno, Vec::new()),
+    };
+    let mut out = Vec::with_capacity(OUT_HEADER_SZ + payload.len());
+    put_u32(&mut out, (OUT_HEADER_SZ + payload.len()) as u32);
+    put_u32(&mut out, error as u32);
+    put_u64(&mut out, unique);
+    out.extend_from_slice(&payload);
+    match device.write_all(&out) {
+        // the request was interrupted and nobody waits for the reply any more
+        Err(error) if error.raw_os_error() == Some(libc::ENOENT) => Ok(()),
+        result => result,
+    }
+}
+
+struct Server {
+    efs: Arc<Mutex<EasyFileSystem>>,
+    /// the number of open handles of each inode
+    opens: HashMap<u32, usize>,
+    /// files unlinked while open, released with their last handle
+    orphans: HashMap<u32, Arc<Inode>>,
+}
+
+impl Server {
+    /// Serve one request, return None if it takes no reply
+    fn serve(&mut self, request: &Request) -> Option<Reply> {
+        let reply = match request.opcode {
+            FUSE_FORGET | FUSE_BATCH_FORGET | FUSE_INTERRUPT => return None,
+            FUSE_INIT => Ok(Self::init(request)),
+            FUSE_DESTROY | FUSE_FLUSH | FUSE_RELEASEDIR => Ok(Vec::new()),
+            FUSE_FSYNC | FUSE_FSYNCDIR => {
+                block_cache_sync_all();
+                Ok(Vec::new())
+            }
+            FUSE_LOOKUP => self.lookup(request),
+            FUSE_GETATTR => self.inode(request.nodeid).map(|inode| attr_out(&inode)),
+            FUSE_SETATTR => self.setattr(request),
+            FUSE_MKNOD => self.mknod(request),
+            FUSE_MKDIR => self.mkdir(request),
+            FUSE_CREATE => self.create(request),
+            FUSE_UNLINK => self.unlink(request),
+            FUSE_RMDIR => self.rmdir(request),
+            FUSE_RENAME => self.rename(request),
+            FUSE_LINK => self.link(request),
+            FUSE_OPEN => self.open(request),
+            FUSE_RELEASE => self.release(request),
+            FUSE_READ => self.read(request),
+            FUSE_WRITE => self.write(request),
+            FUSE_OPENDIR => self.opendir(request),
+            FUSE_READDIR => self.readdir(request),
+            FUSE_STATFS => Ok(self.statfs()),
+            _ => Err(libc::ENOSYS),
+        };
+        Some(reply)
+    }
+    /// the inode with FUSE node id 'nodeid'
+    fn inode(&self, nodeid: u64) -> core::result::Result<Arc<Inode>, i32> {
+        let inode_id = (nodeid - 1) as u32;
+        let (block_id, block_offset, block_device) = {
+            let fs = self.efs.lock();
+            if inode_id >= fs.inode_count()
+                || !fs
+                    .inode_bitmap
+                    .is_allocated(&fs.block_device, inode_id as usize)
+            {
+                return Err(libc::ENOENT);
+            }
+            let (block_id, block_offset) = fs.get_disk_inode_pos(inode_id);
+            (block_id, block_offset, Arc::clone(&fs.block_device))
+        };
+        Ok(Arc::new(Inode::new(
+            block_id,
+            block_offset,
+            Arc::clone(&self.efs),
+            block_device,
+        )))
+    }
+    /// the directory with FUSE node id 'nodeid'
+    fn dir(&self, nodeid: u64) -> core::result::Result<Arc<Inode>, i32> {
+        let dir = self.inode(nodeid)?;
+        if dir.is_dir() {
+            Ok(dir)
+        } else {
+            Err(libc::ENOTDIR)
+        }
+    }
+    fn init(request: &Request) -> Vec<u8> {
+        let max_readahead = u32_at(request.body, 8);
+        let flags = u32_at(request.body, 12);
+        let mut out = Vec::new();
+        put_u32(&mut out, FUSE_KERNEL_VERSION);
+        put_u32(&mut out, FUSE_KERNEL_MINOR_VERSION);
+        put_u32(&mut out, max_readahead);
+        put_u32(&mut out, flags & FUSE_BIG_WRITES);
+        // max_background and congestion_threshold
+        put_u32(&mut out, 0);
+        put_u32(&mut out, MAX_WRITE as u32);
+        out
+    }
+    fn lookup(&self, request: &Request) -> Reply {
+        let dir = self.dir(request.nodeid)?;
+        let inode = dir.find(request.name_at(0)?).ok_or(libc::ENOENT)?;
+        Ok(entry_out(&inode))
+    }
+    fn setattr(&self, request: &Request) -> Reply {
+        let inode = self.inode(request.nodeid)?;
+        let valid = u32_at(request.body, 0);
+        if valid & FATTR_SIZE != 0 {
+            if inode.is_dir() {
+                return Err(libc::EISDIR);
+            }
+            let new_size = u64_at(request.body, 16);
+            if new_size > u32::MAX as u64 {
+                return Err(libc::EFBIG);
+            }
+            if !inode.truncate(new_size as u32) {
+                return Err(libc::ENOSPC);
+            }
+        }
+        if valid & FATTR_MODE != 0 {
+            inode.set_mode(u32_at(request.body, 68) as u16);
+        }
+        if valid & (FATTR_UID | FATTR_GID) != 0 {
+            let (mut uid, mut gid) = inode.owner();
+            if valid & FATTR_UID != 0 {
+                uid = u32_at(request.body, 76);
+            }
+            if valid & FATTR_GID != 0 {
+                gid = u32_at(request.body, 80);
+            }
+            inode.set_owner(uid, gid);
+        }
+        // times are kept by easy-fs itself and cannot be set
+        Ok(attr_out(&inode))
+    }
+    fn mknod(&self, request: &Request) -> Reply {
+        let mode = u32_at(request.body, 0);
+        if mode & libc::S_IFMT != libc::S_IFREG {
+            return Err(libc::EPERM);
+        }
+        let inode = self.new_inode(request, request.name_at(16)?, mode, false)?;
+        Ok(entry_out(&inode))
+    }
+    fn mkdir(&self, request: &Request) -> Reply {
+        let mode = u32_at(request.body, 0);
+        let inode = self.new_inode(request, request.name_at(8)?, mode, true)?;
+        Ok(entry_out(&inode))
+    }
+    fn create(&mut self, request: &Request) -> Reply {
+        let mode = u32_at(request.body, 4);
+        let inode = self.new_inode(request, request.name_at(16)?, mode, false)?;
+        *self.opens.entry(inode.inode_id()).or_insert(0) += 1;
+        let mut out = entry_out(&inode);
+        out.extend(open_out());
+        Ok(out)
+    }
+    /// create a file or directory for the user of 'request'
+    fn new_inode(
+        &self,
+        request: &Request,
+        name: &str,
+        mode: u32,
+        is_dir: bool,
+    ) -> core::result::Result<Arc<Inode>, i32> {
+        let dir = self.dir(request.nodeid)?;
+        let created = if is_dir {
+            dir.create_dir(name)
+        } else {
+            dir.create(name)
+        };
+        let inode = created.ok_or_else(|| {
+            if dir.find(name).is_some() {
+                libc::EEXIST
+            } else if name.len() > easy_fs::NAME_LENGTH_LIMIT {
+                libc::ENAMETOOLONG
+            } else {
+                libc::EINVAL
+            }
+        })?;
+        // the host has applied the umask already
+        inode.set_mode(mode as u16);
+        inode.set_owner(request.uid, request.gid);
+        Ok(inode)
+    }
+    fn unlink(&mut self, request: &Request) -> Reply {
+        let dir = self.dir(request.nodeid)?;
+        let name = request.name_at(0)?;
+        if dir.find(name).ok_or(libc::ENOENT)?.is_dir() {
+            return Err(libc::EISDIR);
+        }
+        let inode = dir.unlink(name).unwrap();
+        self.release_unlinked(inode);
+        Ok(Vec::new())
+    }
+    /// release a file with no links left, or keep it until its last handle is released
+    fn release_unlinked(&mut self, inode: Arc<Inode>) {
+        if inode.nlink() == 0 {
+            let inode_id = inode.inode_id();
+            if self.opens.contains_key(&inode_id) {
+                self.orphans.insert(inode_id, inode);
+            } else {
+                inode.release();
+            }
+        }
+    }
+    fn rmdir(&self, request: &Request) -> Reply {
+        let dir = self.dir(request.nodeid)?;
+        let name = request.name_at(0)?;
+        if !dir.find(name).ok_or(libc::ENOENT)?.is_dir() {
+            return Err(libc::ENOTDIR);
+        }
+        if dir.remove_dir(name) {
+            Ok(Vec::new())
+        } else if name == "." || name == ".." {
+            Err(libc::EINVAL)
+        } else {
+            Err(libc::ENOTEMPTY)
+        }
+    }
+    fn rename(&mut self, request: &Request) -> Reply {
+        let dir = self.dir(request.nodeid)?;
+        let new_dir = self.dir(u64_at(request.body, 0))?;
+        let old_name = request.name_at(8)?;
+        let new_name = request.name_at(8 + old_name.len() + 1)?;
+        let is_dir = dir.find(old_name).ok_or(libc::ENOENT)?.is_dir();
+        let replaced = new_dir.find(new_name);
+        if !dir.rename(old_name, &new_dir, new_name) {
+            return Err(match replaced {
+                Some(replaced) if replaced.is_dir() && !is_dir => libc::EISDIR,
+                Some(replaced) if !replaced.is_dir() && is_dir => libc::ENOTDIR,
+                Some(replaced) if replaced.is_dir() => libc::ENOTEMPTY,
+                _ => libc::EINVAL,
+            });
+        }
+        match replaced {
+            Some(replaced) if !is_dir => self.release_unlinked(replaced),
+            _ => {}
+        }
+        Ok(Vec::new())
+    }
+    fn link(&self, request: &Request) -> Reply {
+        let inode = self.inode(u64_at(request.body, 0))?;
+        let dir = self.dir(request.nodeid)?;
+        let name = request.name_at(8)?;
+        if inode.is_dir() {
+            return Err(libc::EPERM);
+        }
+        if dir.find(name).is_some() {
+            return Err(libc::EEXIST);
+        }
+        if !dir.link(name, &inode) {
+            return Err(libc::EINVAL);
+        }
+        Ok(entry_out(&inode))
+    }
+    fn open(&mut self, request: &Request) -> Reply {
+        let inode = self.inode(request.nodeid)?;
+        *self.opens.entry(inode.inode_id()).or_insert(0) += 1;
+        Ok(open_out())
+    }
+    fn release(&mut self, request: &Request) -> Reply {
+        let inode_id = (request.nodeid - 1) as u32;
+        if let Some(opens) = self.opens.get_mut(&inode_id) {
+            *opens -= 1;
+            if *opens == 0 {
+                self.opens.remove(&inode_id);
+                if let Some(inode) = self.orphans.remove(&inode_id) {
+                    inode.release();
+                }
+            }
+        }
+        Ok(Vec::new())
+    }
+    fn read(&self, request: &Request) -> Reply {
+        let inode = self.inode(request.nodeid)?;
+        let offset = u64_at(request.body, 8) as usize;
+        let mut buffer = vec![0u8; u32_at(request.body, 16) as usize];
+        let len = inode.read_at(offset, &mut buffer);
+        buffer.truncate(len);
+        Ok(buffer)
+    }
+    fn write(&self, request: &Request) -> Reply {
+        let inode = self.inode(request.nodeid)?;
+        let offset = u64_at(request.body, 8) as usize;
+        let size = u32_at(request.body, 16) as usize;
+        let len = inode.write_at(offset, &request.body[40..40 + size]);
+        if len == 0 && size > 0 {
+            return Err(libc::ENOSPC);
+        }
+        let mut out = Vec::new();
+        put_u32(&mut out, len as u32);
+        put_u32(&mut out, 0);
+        Ok(out)
+    }
+    fn opendir(&self, request: &Request) -> Reply {
+        self.dir(request.nodeid)?;
+        Ok(open_out())
+    }
+    /// the entries from the one at the offset on, as many as fit the size
+    fn readdir(&self, request: &Request) -> Reply {
+        let dir = self.dir(request.nodeid)?;
+        // the offset of an entry is the directory slot after it
+        let mut offset = u64_at(request.body, 8) as usize;
+        let size = u32_at(request.body, 16) as usize;
+        let mut out = Vec::new();
+        while let Some((name, inode, next)) = dir.read_dirent(offset) {
+            let dirent_size = (24 + name.len() + 7) / 8 * 8;
+            if out.len() + dirent_size > size {
+                break;
+            }
+            let kind = if inode.is_dir() {
+                libc::DT_DIR
+            } else {
+                libc::DT_REG
+            };
+            put_u64(&mut out, inode.inode_id() as u64 + 1);
+            put_u64(&mut out, next as u64);
+            put_u32(&mut out, name.len() as u32);
+            put_u32(&mut out, kind as u32);
+            out.extend_from_slice(name.as_bytes());
+            out.resize((out.len() + 7) / 8 * 8, 0);
+            offset = next;
+        }
+        Ok(out)
+    }
+    /// the block and inode usage, the block size and the name length limit
+    fn statfs(&self) -> Vec<u8> {
+        let fs = self.efs.lock();
+        let mut out = Vec::new();
+        // blocks, bfree, bavail, files and ffree
+        let free_blocks = fs.free_data_blocks();
+        for count in [
+            fs.data_blocks(),
+            free_blocks,
+            free_blocks,
+            fs.inode_count(),
+            fs.free_inodes(),
+        ]
+        .iter()
+        {
+            put_u64(&mut out, *count as u64);
+        }
+        put_u32(&mut out, easy_fs::BLOCK_SZ as u32);
+        put_u32(&mut out, easy_fs::NAME_LENGTH_LIMIT as u32);
+        put_u32(&mut out, easy_fs::BLOCK_SZ as u32);
+        // padding and spare
+        out.resize(80, 0);
+        out
+    }
+}
+
+/// The attributes of 'inode' as a fuse_attr
+fn attr(inode: &Inode) -> Vec<u8> {
+    let kind = if inode.is_dir() {
+        libc::S_IFDIR
+    } else {
+        libc::S_IFREG
+    };
+    let size = inode.size() as u64;
+    let (atime, mtime, ctime) = inode.times();
+    let (uid, gid) = inode.owner();
+    let mut out = Vec::new();
+    put_u64(&mut out, inode.inode_id() as u64 + 1);
+    put_u64(&mut out, size);
+    put_u64(&mut out, (size + 511) / 512);
+    put_u64(&mut out, atime);
+    put_u64(&mut out, mtime);
+    put_u64(&mut out, ctime);
+    // nanoseconds of the times
+    for _ in 0..3 {
+        put_u32(&mut out, 0);
+    }
+    put_u32(&mut out, kind | inode.mode() as u32);
+    put_u32(&mut out, inode.nlink());
+    put_u32(&mut out, uid);
+    put_u32(&mut out, gid);
+    // rdev
+    put_u32(&mut out, 0);
+    put_u32(&mut out, easy_fs::BLOCK_SZ as u32);
+    // flags
+    put_u32(&mut out, 0);
+    out
+}
+
+/// The reply to LOOKUP, a fuse_entry_out
+fn entry_out(inode: &Inode) -> Vec<u8> {
+    let mut out = Vec::new();
+    put_u64(&mut out, inode.inode_id() as u64 + 1);
+    // generation
+    put_u64(&mut out, 0);
+    put_u64(&mut out, TTL);
+    put_u64(&mut out, TTL);
+    put_u32(&mut out, 0);
+    put_u32(&mut out, 0);
+    out.extend(attr(inode));
+    out
+}
+
+/// The reply to GETATTR, a fuse_attr_out
+fn attr_out(inode: &Inode) -> Vec<u8> {
+    let mut out = Vec::new();
+    put_u64(&mut out, TTL);
+    put_u32(&mut out, 0);
+    put_u32(&mut out, 0);
+    out.extend(attr(inode));
+    out
+}
+
+/// The reply to OPEN, a fuse_open_out without a file handle
+fn open_out() -> Vec<u8> {
+    vec![0u8; 16]
+}
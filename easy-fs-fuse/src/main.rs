@@ -1,11 +1,25 @@
-use clap::{App, Arg};
-use easy_fs::{BlockDevice, EasyFileSystem};
+#[macro_use]
+extern crate clap;
+
+mod fuse;
+
+use clap::{App, Arg, ArgMatches, SubCommand};
+use easy_fs::block_cache::block_cache_sync_all;
+#[cfg(test)]
+use easy_fs::block_cache::{block_cache_drop_all, get_block_cache};
+use easy_fs::{fsck, BlockDevice, DiskInode, EasyFileSystem, Inode, JOURNAL_BLOCKS};
 use std::fs::{read_dir, File, OpenOptions};
-use std::io::{Read, Seek, SeekFrom, Write};
+use std::io::{Error, ErrorKind, Read, Result, Seek, SeekFrom, Write};
+use std::mem::size_of;
 use std::sync::Arc;
 use std::sync::Mutex;
+#[cfg(test)]
+use std::sync::MutexGuard;
+use std::time::{SystemTime, UNIX_EPOCH};
 
 const BLOCK_SZ: usize = 512;
+/// Bits in a bitmap block, the inodes one inode bitmap block allocates
+const BLOCK_BITS: usize = BLOCK_SZ * 8;
 
 struct BlockFile(Mutex<File>);
 
@@ -23,13 +37,189 @@ impl BlockDevice for BlockFile {
             .expect("Error when seeking!");
         assert_eq!(file.write(buf).unwrap(), BLOCK_SZ, "Not a complete block!");
     }
+
+    fn read_blocks(&self, block_id: usize, buf: &mut [u8]) {
+        let mut file = self.0.lock().unwrap();
+        file.seek(SeekFrom::Start((block_id * BLOCK_SZ) as u64))
+            .expect("Error when seeking!");
+        file.read_exact(buf).expect("Not complete blocks!");
+    }
 }
 
-fn main() {
-    easy_fs_pack().expect("Error when packing easy-fs!");
+/// A block file which drops all writes once its budget runs out, as if the
+/// machine lost power there
+#[cfg(test)]
+struct FaultyBlockFile {
+    file: BlockFile,
+    /// the number of writes still reaching the file, unlimited if None
+    budget: Mutex<Option<usize>>,
+    /// the number of read requests so far
+    reads: Mutex<usize>,
+}
+
+#[cfg(test)]
+impl BlockDevice for FaultyBlockFile {
+    fn read_block(&self, block_id: usize, buf: &mut [u8]) {
+        *self.reads.lock().unwrap() += 1;
+        self.file.read_block(block_id, buf);
+    }
+
+    fn read_blocks(&self, block_id: usize, buf: &mut [u8]) {
+        *self.reads.lock().unwrap() += 1;
+        self.file.read_blocks(block_id, buf);
+    }
+
+    fn write_block(&self, block_id: usize, buf: &[u8]) {
+        let mut budget = self.budget.lock().unwrap();
+        match budget.as_mut() {
+            Some(0) => return,
+            Some(budget) => *budget -= 1,
+            None => {}
+        }
+        self.file.write_block(block_id, buf);
+    }
 }
 
-fn easy_fs_pack() -> std::io::Result<()> {
+/// An image as made before versions, of 4096 blocks with one inode bitmap
+/// block. "/" holds "hello" with 'hello' in it, "big" with 'big' of 40
+/// blocks, and "bin" with an empty "sh", made before link counts.
+#[cfg(test)]
+fn v1_image(hello: &[u8], big: &[u8]) -> Vec<u8> {
+    fn put(image: &mut [u8], pos: usize, v: u32) {
+        image[pos..pos + 4].copy_from_slice(&v.to_le_bytes());
+    }
+    fn set_bit(image: &mut [u8], bitmap_block: usize, bit: usize) {
+        image[bitmap_block * BLOCK_SZ + bit / 8] |= 1 << (bit % 8);
+    }
+    // size, direct and indirect1 blocks and type of a 128-byte inode
+    fn inode(image: &mut [u8], inode_id: usize, size: usize, blocks: &[u32], indirect1: u32) {
+        let pos = 2 * BLOCK_SZ + inode_id * 128;
+        put(image, pos, size as u32);
+        for (i, block_id) in blocks.iter().enumerate() {
+            put(image, pos + 4 + i * 4, *block_id);
+        }
+        put(image, pos + 116, indirect1);
+        set_bit(image, 1, inode_id);
+    }
+    fn dirents(image: &mut [u8], block_id: u32, dirents: &[(&str, u32)]) {
+        for (i, (name, inode_id)) in dirents.iter().enumerate() {
+            let pos = block_id as usize * BLOCK_SZ + i * 32;
+            image[pos..pos + name.len()].copy_from_slice(name.as_bytes());
+            put(image, pos + 28, *inode_id);
+        }
+    }
+    let mut image = vec![0u8; 4096 * BLOCK_SZ];
+    // magic, total, inode bitmap, inode area, data bitmap and data area blocks
+    for (i, v) in [0x3b800001, 4096, 1, 1024, 1, 3069].iter().enumerate() {
+        put(&mut image, i * 4, *v);
+    }
+    // the data area starts at block 1027, its blocks are given out in order
+    let data_area = 1027;
+    (0..44).for_each(|bit| set_bit(&mut image, 1026, bit));
+    inode(&mut image, 0, 3 * 32, &[data_area], 0);
+    dirents(
+        &mut image,
+        data_area,
+        &[("hello", 1), ("big", 2), ("bin", 3)],
+    );
+    inode(&mut image, 1, hello.len(), &[data_area + 1], 0);
+    let pos = (data_area + 1) as usize * BLOCK_SZ;
+    image[pos..pos + hello.len()].copy_from_slice(hello);
+    // 28 direct blocks, then the indirect block and 12 blocks it lists
+    let big_blocks: Vec<u32> = (data_area + 2..data_area + 30)
+        .chain(data_area + 31..data_area + 43)
+        .collect();
+    inode(&mut image, 2, big.len(), &big_blocks[..28], data_area + 30);
+    for (i, block_id) in big_blocks[28..].iter().enumerate() {
+        put(
+            &mut image,
+            (data_area + 30) as usize * BLOCK_SZ + i * 4,
+            *block_id,
+        );
+    }
+    for (block_id, chunk) in big_blocks.iter().zip(big.chunks(BLOCK_SZ)) {
+        let pos = *block_id as usize * BLOCK_SZ;
+        image[pos..pos + chunk.len()].copy_from_slice(chunk);
+    }
+    inode(&mut image, 3, 3 * 32, &[data_area + 43], 0);
+    dirents(
+        &mut image,
+        data_area + 43,
+        &[(".", 3), ("..", 0), ("sh", 4)],
+    );
+    inode(&mut image, 4, 0, &[], 0);
+    // the type of a directory is 1
+    for inode_id in [0, 3] {
+        image[2 * BLOCK_SZ + inode_id * 128 + 124] = 1;
+    }
+    image
+}
+
+/// Taken by each test for as long as it uses the block caches, which are
+/// keyed by block id alone and so hold the blocks of one image at a time
+#[cfg(test)]
+static BLOCK_CACHES: Mutex<()> = Mutex::new(());
+
+/// An empty image made for one test
+#[cfg(test)]
+struct TestImage {
+    device: Arc<FaultyBlockFile>,
+    efs: Arc<spin::Mutex<EasyFileSystem>>,
+    /// the turn of the test with the block caches, given up last
+    _turn: MutexGuard<'static, ()>,
+}
+
+#[cfg(test)]
+impl TestImage {
+    /// Create an image of 'total_blocks' with one inode bitmap block at
+    /// target/'name'.img, once the tests before are done with the block caches
+    fn new(name: &str, total_blocks: u32) -> Self {
+        // a failed test gives its turn up all the same
+        let turn = BLOCK_CACHES
+            .lock()
+            .unwrap_or_else(|error| error.into_inner());
+        block_cache_drop_all();
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(format!("target/{}.img", name))
+            .unwrap();
+        file.set_len(total_blocks as u64 * BLOCK_SZ as u64).unwrap();
+        let device = Arc::new(FaultyBlockFile {
+            file: BlockFile(Mutex::new(file)),
+            budget: Mutex::new(None),
+            reads: Mutex::new(0),
+        });
+        let efs = EasyFileSystem::create(device.clone(), total_blocks, 1);
+        Self {
+            device,
+            efs,
+            _turn: turn,
+        }
+    }
+
+    fn root_inode(&self) -> Inode {
+        EasyFileSystem::root_inode(&self.efs)
+    }
+
+    /// Read or change the disk inode of 'inode_id' behind the back of the file system
+    fn disk_inode<V>(&self, inode_id: u32, f: impl FnOnce(&mut DiskInode) -> V) -> V {
+        let (block_id, block_offset) = self.efs.lock().get_disk_inode_pos(inode_id);
+        get_block_cache(block_id as usize, self.device.clone())
+            .lock()
+            .modify(block_offset, f)
+    }
+}
+
+fn main() {
+    let image = || {
+        Arg::with_name("image")
+            .required(true)
+            .help("The easy-fs image")
+    };
+    let path = |help| Arg::with_name("path").required(true).help(help);
     let matches = App::new("EasyFileSystem packer")
         .arg(
             Arg::with_name("source")
@@ -45,21 +235,341 @@ fn easy_fs_pack() -> std::io::Result<()> {
                 .takes_value(true)
                 .help("Executable target dir(with backslash)"),
         )
+        .subcommand(
+            SubCommand::with_name("mkfs")
+                .about("Create an empty easy-fs image")
+                .arg(image())
+                .arg(
+                    Arg::with_name("size")
+                        .short("s")
+                        .long("size")
+                        .takes_value(true)
+                        .default_value("16")
+                        .help("Size of the image in MiB"),
+                )
+                .arg(
+                    Arg::with_name("inodes")
+                        .short("i")
+                        .long("inodes")
+                        .takes_value(true)
+                        .default_value("4096")
+                        .help("Number of inodes, rounded up to a multiple of 4096"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("ls")
+                .about("List a directory of an easy-fs image")
+                .arg(image())
+                .arg(
+                    Arg::with_name("path")
+                        .default_value("/")
+                        .help("The directory to list"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("cat")
+                .about("Print a file of an easy-fs image")
+                .arg(image())
+                .arg(path("The file to print")),
+        )
+        .subcommand(
+            SubCommand::with_name("put")
+                .about("Copy a host file into an easy-fs image")
+                .arg(image())
+                .arg(
+                    Arg::with_name("host")
+                        .required(true)
+                        .help("The host file to copy"),
+                )
+                .arg(path("The file to create or overwrite")),
+        )
+        .subcommand(
+            SubCommand::with_name("get")
+                .about("Copy a file of an easy-fs image to the host")
+                .arg(image())
+                .arg(path("The file to copy"))
+                .arg(
+                    Arg::with_name("host")
+                        .required(true)
+                        .help("The host file to create or overwrite"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("rm")
+                .about("Remove a file or an empty directory of an easy-fs image")
+                .arg(image())
+                .arg(path("The file or directory to remove")),
+        )
+        .subcommand(
+            SubCommand::with_name("mount")
+                .about("Mount an easy-fs image with FUSE until it is unmounted")
+                .arg(image())
+                .arg(
+                    Arg::with_name("mountpoint")
+                        .required(true)
+                        .help("The directory to mount the image on"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("fsck")
+                .about("Check an easy-fs image, replaying its journal first")
+                .arg(image())
+                .arg(
+                    Arg::with_name("repair")
+                        .short("r")
+                        .long("repair")
+                        .help("Fix the problems that can be fixed"),
+                ),
+        )
         .get_matches();
-    let src_path = matches.value_of("source").unwrap();
-    let target_path = matches.value_of("target").unwrap();
-    println!("src_path = {}\ntarget_path = {}", src_path, target_path);
+    let result = match matches.subcommand() {
+        ("mkfs", Some(matches)) => easy_fs_mkfs(matches),
+        ("ls", Some(matches)) => easy_fs_ls(matches),
+        ("cat", Some(matches)) => easy_fs_cat(matches),
+        ("put", Some(matches)) => easy_fs_put(matches),
+        ("get", Some(matches)) => easy_fs_get(matches),
+        ("rm", Some(matches)) => easy_fs_rm(matches),
+        ("mount", Some(matches)) => open_image(matches.value_of("image").unwrap())
+            .and_then(|efs| fuse::mount(efs, matches.value_of("mountpoint").unwrap())),
+        ("fsck", Some(matches)) => easy_fs_check(matches).map(|sound| {
+            if !sound {
+                std::process::exit(1);
+            }
+        }),
+        _ => easy_fs_pack(&matches),
+    };
+    // the block caches are not dropped at exit
+    block_cache_sync_all();
+    if let Err(error) = result {
+        eprintln!("easy-fs-fuse: {}", error);
+        std::process::exit(1);
+    }
+}
+
+/// The host time in seconds, for inode timestamps
+fn host_clock() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |time| time.as_secs())
+}
+
+/// Create an empty image of 'total_blocks' at 'path', with 4096 inodes per inode bitmap block
+fn create_image(
+    path: &str,
+    total_blocks: u32,
+    inode_bitmap_blocks: u32,
+) -> Result<Arc<spin::Mutex<EasyFileSystem>>> {
+    let inode_area_blocks =
+        (inode_bitmap_blocks as usize * BLOCK_BITS * size_of::<DiskInode>() + BLOCK_SZ - 1)
+            / BLOCK_SZ;
+    // the superblock, the bitmaps and at least one data block
+    let least_blocks = 1 + inode_bitmap_blocks as usize + inode_area_blocks + 2;
+    if (total_blocks as usize) < least_blocks + JOURNAL_BLOCKS as usize {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!(
+                "{} blocks are too few for {} inodes",
+                total_blocks,
+                inode_bitmap_blocks as usize * BLOCK_BITS
+            ),
+        ));
+    }
     let block_file = Arc::new(BlockFile(Mutex::new({
         let f = OpenOptions::new()
             .read(true)
             .write(true)
             .create(true)
-            .open(format!("{}{}", target_path, "fs.img"))?;
-        f.set_len(16 * 2048 * 512).unwrap();
+            .open(path)?;
+        f.set_len(total_blocks as u64 * BLOCK_SZ as u64)?;
         f
     })));
-    // 16MiB, at most 4095 files
-    let efs = EasyFileSystem::create(block_file, 16 * 2048, 1);
+    let efs = EasyFileSystem::create(block_file, total_blocks, inode_bitmap_blocks);
+    efs.lock().set_clock(host_clock);
+    Ok(efs)
+}
+
+/// Open the image at 'path', replaying its journal
+fn open_image(path: &str) -> Result<Arc<spin::Mutex<EasyFileSystem>>> {
+    let block_file = Arc::new(BlockFile(Mutex::new(
+        OpenOptions::new().read(true).write(true).open(path)?,
+    )));
+    let efs = EasyFileSystem::open(block_file)
+        .map_err(|e| Error::new(ErrorKind::InvalidData, format!("{}: {}", path, e)))?;
+    efs.lock().set_clock(host_clock);
+    Ok(efs)
+}
+
+/// An error about the file at 'path' of an image
+fn path_error(kind: ErrorKind, path: &str, what: &str) -> Error {
+    Error::new(kind, format!("{}: {}", path, what))
+}
+
+/// Find the inode at 'path', starting from the root of the image
+fn find_path(efs: &Arc<spin::Mutex<EasyFileSystem>>, path: &str) -> Result<Arc<Inode>> {
+    path.split('/').filter(|name| !name.is_empty()).try_fold(
+        Arc::new(EasyFileSystem::root_inode(efs)),
+        |inode, name| {
+            if !inode.is_dir() {
+                return Err(path_error(ErrorKind::Other, path, "Not a directory"));
+            }
+            inode
+                .find(name)
+                .ok_or_else(|| path_error(ErrorKind::NotFound, path, "No such file or directory"))
+        },
+    )
+}
+
+/// Find the directory holding 'path', and the last name of 'path'
+fn find_parent<'a>(
+    efs: &Arc<spin::Mutex<EasyFileSystem>>,
+    path: &'a str,
+) -> Result<(Arc<Inode>, &'a str)> {
+    let path = path.trim_end_matches('/');
+    let (parent, name) = match path.rfind('/') {
+        Some(pos) => (&path[..pos], &path[pos + 1..]),
+        None => ("", path),
+    };
+    let parent = find_path(efs, parent)?;
+    if !parent.is_dir() {
+        return Err(path_error(ErrorKind::Other, path, "Not a directory"));
+    }
+    Ok((parent, name))
+}
+
+/// The whole content of the file 'inode'
+fn read_all(inode: &Inode) -> Vec<u8> {
+    let mut content = vec![0u8; inode.size() as usize];
+    let len = inode.read_at(0, &mut content);
+    content.truncate(len);
+    content
+}
+
+/// Permission string of 'inode' as printed by ls
+fn mode_string(inode: &Inode) -> String {
+    let mode = inode.mode();
+    let mut string = String::from(if inode.is_dir() { "d" } else { "-" });
+    for (i, c) in "rwxrwxrwx".chars().enumerate() {
+        string.push(if mode & (1 << (8 - i)) != 0 { c } else { '-' });
+    }
+    string
+}
+
+fn easy_fs_mkfs(matches: &ArgMatches) -> Result<()> {
+    let size = value_t!(matches, "size", u32).unwrap_or_else(|error| error.exit());
+    let inodes = value_t!(matches, "inodes", u32).unwrap_or_else(|error| error.exit());
+    create_image(
+        matches.value_of("image").unwrap(),
+        size.saturating_mul(2048),
+        ((inodes as usize + BLOCK_BITS - 1) / BLOCK_BITS).max(1) as u32,
+    )?;
+    Ok(())
+}
+
+fn easy_fs_ls(matches: &ArgMatches) -> Result<()> {
+    let efs = open_image(matches.value_of("image").unwrap())?;
+    let path = matches.value_of("path").unwrap();
+    let dir = find_path(&efs, path)?;
+    if !dir.is_dir() {
+        return Err(path_error(ErrorKind::Other, path, "Not a directory"));
+    }
+    for name in dir.ls() {
+        let inode = dir.find(&name).unwrap();
+        let (uid, gid) = inode.owner();
+        println!(
+            "{} {:>3} {:>5} {:>5} {:>10} {}",
+            mode_string(&inode),
+            inode.nlink(),
+            uid,
+            gid,
+            inode.size(),
+            name
+        );
+    }
+    Ok(())
+}
+
+fn easy_fs_cat(matches: &ArgMatches) -> Result<()> {
+    let efs = open_image(matches.value_of("image").unwrap())?;
+    let path = matches.value_of("path").unwrap();
+    let inode = find_path(&efs, path)?;
+    if inode.is_dir() {
+        return Err(path_error(ErrorKind::Other, path, "Is a directory"));
+    }
+    std::io::stdout().write_all(&read_all(&inode))
+}
+
+fn easy_fs_put(matches: &ArgMatches) -> Result<()> {
+    let efs = open_image(matches.value_of("image").unwrap())?;
+    let path = matches.value_of("path").unwrap();
+    let mut content = Vec::new();
+    File::open(matches.value_of("host").unwrap())?.read_to_end(&mut content)?;
+    let (dir, name) = find_parent(&efs, path)?;
+    let inode = match dir.find(name) {
+        Some(inode) if inode.is_dir() => {
+            return Err(path_error(ErrorKind::Other, path, "Is a directory"));
+        }
+        Some(inode) => {
+            inode.clear();
+            inode
+        }
+        None => dir
+            .create(name)
+            .ok_or_else(|| path_error(ErrorKind::InvalidInput, path, "Invalid file name"))?,
+    };
+    inode.write_at(0, &content);
+    Ok(())
+}
+
+fn easy_fs_get(matches: &ArgMatches) -> Result<()> {
+    let efs = open_image(matches.value_of("image").unwrap())?;
+    let path = matches.value_of("path").unwrap();
+    let inode = find_path(&efs, path)?;
+    if inode.is_dir() {
+        return Err(path_error(ErrorKind::Other, path, "Is a directory"));
+    }
+    File::create(matches.value_of("host").unwrap())?.write_all(&read_all(&inode))
+}
+
+fn easy_fs_rm(matches: &ArgMatches) -> Result<()> {
+    let efs = open_image(matches.value_of("image").unwrap())?;
+    let path = matches.value_of("path").unwrap();
+    let (dir, name) = find_parent(&efs, path)?;
+    let inode = dir
+        .find(name)
+        .ok_or_else(|| path_error(ErrorKind::NotFound, path, "No such file or directory"))?;
+    if !inode.is_dir() {
+        // the blocks stay in use while other links are left
+        dir.unlink(name).unwrap().release();
+    } else if !dir.remove_dir(name) {
+        return Err(path_error(ErrorKind::Other, path, "Directory not empty"));
+    }
+    Ok(())
+}
+
+/// Check the image, return whether it is sound or all its problems were repaired
+fn easy_fs_check(matches: &ArgMatches) -> Result<bool> {
+    let repair = matches.is_present("repair");
+    let efs = open_image(matches.value_of("image").unwrap())?;
+    let problems = fsck::check(&efs, repair);
+    for problem in problems.iter() {
+        if repair && problem.is_repairable() {
+            println!("{} (repaired)", problem);
+        } else {
+            println!("{}", problem);
+        }
+    }
+    println!("{} problems found", problems.len());
+    Ok(problems
+        .iter()
+        .all(|problem| repair && problem.is_repairable()))
+}
+
+fn easy_fs_pack(matches: &ArgMatches) -> Result<()> {
+    let src_path = matches.value_of("source").unwrap();
+    let target_path = matches.value_of("target").unwrap();
+    println!("src_path = {}\ntarget_path = {}", src_path, target_path);
+    // 64MiB with room for the kernel's swap file, at most 4095 files
+    let efs = create_image(&format!("{}{}", target_path, "fs.img"), 64 * 2048, 1)?;
     let root_inode = Arc::new(EasyFileSystem::root_inode(&efs));
     let apps: Vec<_> = read_dir(src_path)
         .unwrap()
@@ -88,19 +598,9 @@ fn easy_fs_pack() -> std::io::Result<()> {
 }
 
 #[test]
-fn efs_test() -> std::io::Result<()> {
-    let block_file = Arc::new(BlockFile(Mutex::new({
-        let f = OpenOptions::new()
-            .read(true)
-            .write(true)
-            .create(true)
-            .open("target/fs.img")?;
-        f.set_len(8192 * 512).unwrap();
-        f
-    })));
-    EasyFileSystem::create(block_file.clone(), 4096, 1);
-    let efs = EasyFileSystem::open(block_file.clone());
-    let root_inode = EasyFileSystem::root_inode(&efs);
+fn read_write() {
+    let image = TestImage::new("fs_rw", 8192);
+    let root_inode = image.root_inode();
     root_inode.create("filea");
     root_inode.create("fileb");
     for name in root_inode.ls() {
@@ -146,6 +646,690 @@ fn efs_test() -> std::io::Result<()> {
     random_str_test(400 * BLOCK_SZ);
     random_str_test(1000 * BLOCK_SZ);
     random_str_test(2000 * BLOCK_SZ);
+}
+
+#[test]
+fn block_cache() {
+    // block caches are written back lazily, and evicted least recently used first
+    use easy_fs::block_cache::{block_cache_set_capacity, block_cache_stats};
+    let image = TestImage::new("fs_cache", 8192);
+    let file = image.root_inode().create("file").unwrap();
+    file.write_at(0, &vec![1u8; 2000 * BLOCK_SZ]);
+    block_cache_sync_all();
+    let stats = block_cache_stats();
+    // overwrites need no transaction, their times go back with the content
+    file.write_at(BLOCK_SZ, b"lazy");
+    file.write_at(0, b"lazy");
+    assert_eq!(block_cache_stats().writebacks, stats.writebacks);
+    file.sync();
+    assert!(block_cache_stats().writebacks > stats.writebacks);
+    let stats = block_cache_stats();
+    block_cache_sync_all();
+    assert_eq!(block_cache_stats().writebacks, stats.writebacks);
+    block_cache_set_capacity(16);
+    let mut read_buffer = [0u8; BLOCK_SZ];
+    for i in 0..2000 {
+        assert_eq!(file.read_at(i * BLOCK_SZ, &mut read_buffer), BLOCK_SZ);
+    }
+    let stats = block_cache_stats();
+    assert!(stats.evictions > 1900);
+    file.read_at(BLOCK_SZ * 1999, &mut read_buffer);
+    assert_eq!(block_cache_stats().misses, stats.misses);
+    file.read_at(0, &mut read_buffer);
+    assert!(block_cache_stats().misses > stats.misses);
+    assert_eq!(&read_buffer[..4], b"lazy");
+    block_cache_set_capacity(256);
+}
+
+#[test]
+fn directories() {
+    let image = TestImage::new("fs_dirs", 4096);
+    let root_inode = image.root_inode();
+    root_inode.create("filea").unwrap();
+    root_inode.create("fileb").unwrap();
+    assert_eq!(root_inode.ls(), [".", "..", "filea", "fileb"]);
+    assert_eq!(root_inode.find("..").unwrap().inode_id(), 0);
+    let bin = root_inode.create_dir("bin").unwrap();
+    assert!(bin.is_dir());
+    assert!(root_inode.create_dir("bin").is_none());
+    assert!(root_inode.create("bin").is_none());
+    let sh = bin.create("sh").unwrap();
+    assert!(!sh.is_dir());
+    assert_eq!(bin.ls(), [".", "..", "sh"]);
+    assert_eq!(bin.find(".").unwrap().inode_id(), bin.inode_id());
+    assert_eq!(bin.find("..").unwrap().inode_id(), root_inode.inode_id());
+    assert_eq!(root_inode.find_name(bin.inode_id()).unwrap(), "bin");
+    assert!(root_inode.find("sh").is_none());
+    // only an empty directory can be removed
+    let lib = bin.create_dir("lib").unwrap();
+    lib.create("libc").unwrap();
+    assert!(!bin.remove_dir("lib"));
+    assert!(!bin.remove_dir("sh"));
+    assert!(!bin.remove_dir(".."));
+    let tmp_id = root_inode.create_dir("tmp").unwrap().inode_id();
+    assert!(root_inode.remove_dir("tmp"));
+    assert_eq!(root_inode.ls(), [".", "..", "filea", "fileb", "bin"]);
+    // the freed dirent slot and inode are used again
+    let var = root_inode.create_dir("var").unwrap();
+    assert_eq!(var.inode_id(), tmp_id);
+    assert_eq!(var.ls(), [".", ".."]);
+    assert_eq!(root_inode.ls(), [".", "..", "filea", "fileb", "bin", "var"]);
+    assert_eq!(root_inode.nlink(), 4);
+    // entries are read one at a time from a slot cursor
+    let (mut names, mut from) = (Vec::new(), 0);
+    while let Some((name, inode, next)) = root_inode.read_dirent(from) {
+        assert_eq!(inode.is_dir(), name != "filea" && name != "fileb");
+        names.push(name);
+        from = next;
+    }
+    assert_eq!(names, root_inode.ls());
+    assert!(root_inode.read_dirent(from).is_none());
+    assert_eq!(fsck::check(&image.efs, false), []);
+}
+
+#[test]
+fn hard_links() {
+    let image = TestImage::new("fs_links", 4096);
+    let root_inode = image.root_inode();
+    let bin = root_inode.create_dir("bin").unwrap();
+    let sh = bin.create("sh").unwrap();
+    let lib = bin.create_dir("lib").unwrap();
+    assert_eq!(sh.nlink(), 1);
+    assert!(root_inode.link("sh", &sh));
+    assert!(!root_inode.link("sh", &sh));
+    assert!(!root_inode.link("lib", &lib));
+    assert_eq!(sh.nlink(), 2);
+    assert_eq!(root_inode.find("sh").unwrap().inode_id(), sh.inode_id());
+    let unlinked = bin.unlink("sh").unwrap();
+    assert_eq!(unlinked.nlink(), 1);
+    assert!(!unlinked.release());
+    assert!(bin.find("sh").is_none());
+    assert!(bin.unlink("lib").is_none());
+    let sh_id = sh.inode_id();
+    let unlinked = root_inode.unlink("sh").unwrap();
+    assert_eq!(unlinked.nlink(), 0);
+    // the data stays readable until the file is released
+    let greet_str = "Hello, world!";
+    let mut buffer = [0u8; 233];
+    sh.write_at(0, greet_str.as_bytes());
+    let len = sh.read_at(0, &mut buffer);
+    assert_eq!(greet_str, core::str::from_utf8(&buffer[..len]).unwrap());
+    assert!(unlinked.release());
+    assert_eq!(root_inode.create("filec").unwrap().inode_id(), sh_id);
+    assert_eq!(fsck::check(&image.efs, false), []);
+}
+
+#[test]
+fn ownership_mode_and_times() {
+    let image = TestImage::new("fs_times", 4096);
+    let root_inode = image.root_inode();
+    let var = root_inode.create_dir("var").unwrap();
+    let greet_str = "Hello, world!";
+    let mut buffer = [0u8; 233];
+    let efs = &image.efs;
+    efs.lock().set_clock(|| 42);
+    let filed = root_inode.create("filed").unwrap();
+    assert_eq!(filed.mode(), 0o644);
+    assert_eq!(var.mode(), 0o755);
+    assert_eq!(filed.owner(), (0, 0));
+    assert_eq!(filed.times(), (42, 42, 42));
+    filed.set_mode(0o4600);
+    filed.set_owner(1000, 100);
+    assert_eq!(filed.mode(), 0o600);
+    assert_eq!(filed.owner(), (1000, 100));
+    efs.lock().set_clock(|| 43);
+    filed.read_at(0, &mut buffer);
+    assert_eq!(filed.times(), (43, 42, 42));
+    efs.lock().set_clock(|| 44);
+    filed.write_at(0, greet_str.as_bytes());
+    assert_eq!(filed.times(), (43, 44, 44));
+    assert_eq!(root_inode.times().1, 42);
+    // the access time is recorded by the first read since a change, or a day later
+    efs.lock().set_clock(|| 45);
+    filed.read_at(0, &mut buffer);
+    efs.lock().set_clock(|| 46);
+    filed.read_at(0, &mut buffer);
+    assert_eq!(filed.times(), (45, 44, 44));
+    efs.lock().set_clock(|| 45 + 24 * 60 * 60);
+    filed.read_at(0, &mut buffer);
+    assert_eq!(filed.times().0, 45 + 24 * 60 * 60);
+}
+
+#[test]
+fn statfs_usage() {
+    // inode and block usage, as reported by statfs
+    let image = TestImage::new("fs_usage", 4096);
+    let root_inode = image.root_inode();
+    let efs = &image.efs;
+    let usage = || {
+        let fs = efs.lock();
+        (fs.free_inodes(), fs.free_data_blocks())
+    };
+    let (free_inodes, free_blocks) = usage();
+    assert!(free_inodes < efs.lock().inode_count());
+    assert!(free_blocks < efs.lock().data_blocks());
+    let filee = root_inode.create("filee").unwrap();
+    filee.write_at(0, &[1u8; 3 * BLOCK_SZ]);
+    assert_eq!(usage().0, free_inodes - 1);
+    assert!(usage().1 <= free_blocks - 3);
+    assert!(root_inode.unlink("filee").unwrap().release());
+    assert_eq!(usage().0, free_inodes);
+}
+
+#[test]
+fn v1_upgrade() -> std::io::Result<()> {
+    // upgrade an image made before versions, cutting the writes off at some
+    // points of the upgrade, which goes on from there when opened again
+    use easy_fs::{OpenError, SuperBlock, EFS_VERSION};
+    let image = TestImage::new("fs_v1", 4096);
+    let v1_file = &image.device;
+    let greet_str = "Hello, world!";
+    let mut buffer = [0u8; 233];
+    let big: Vec<u8> = (0..40 * BLOCK_SZ).map(|i| (i % 241) as u8).collect();
+    let v1 = v1_image(greet_str.as_bytes(), &big);
+    let open_v1 = |budget: Option<usize>| {
+        block_cache_drop_all();
+        std::fs::write("target/fs_v1.img", &v1).unwrap();
+        *v1_file.budget.lock().unwrap() = budget;
+        EasyFileSystem::open(v1_file.clone()).unwrap();
+        let left = v1_file.budget.lock().unwrap().replace(0);
+        block_cache_drop_all();
+        *v1_file.budget.lock().unwrap() = None;
+        left.unwrap()
+    };
+    let writes = usize::MAX - open_v1(Some(usize::MAX));
+    let cuts = (0..writes)
+        .step_by(61)
+        .chain(writes.saturating_sub(8)..=writes);
+    for cut in cuts {
+        open_v1(Some(cut));
+        let efs = EasyFileSystem::open(v1_file.clone()).unwrap();
+        let root_inode = EasyFileSystem::root_inode(&efs);
+        assert_eq!(root_inode.ls(), ["hello", "big", "bin", ".", ".."]);
+        assert_eq!(root_inode.find("..").unwrap().inode_id(), 0);
+        let hello = root_inode.find("hello").unwrap();
+        let len = hello.read_at(0, &mut buffer);
+        assert_eq!(greet_str, core::str::from_utf8(&buffer[..len]).unwrap());
+        assert_eq!(
+            (hello.mode(), hello.owner(), hello.nlink()),
+            (0o644, (0, 0), 1)
+        );
+        let mut content = vec![0u8; big.len()];
+        assert_eq!(
+            root_inode.find("big").unwrap().read_at(0, &mut content),
+            big.len()
+        );
+        assert!(content == big);
+        let bin = root_inode.find("bin").unwrap();
+        assert_eq!(bin.ls(), [".", "..", "sh"]);
+        assert_eq!(bin.find("..").unwrap().inode_id(), 0);
+        assert_eq!((bin.mode(), bin.nlink(), root_inode.nlink()), (0o755, 2, 3));
+        assert_eq!(fsck::check(&efs, false), []);
+        get_block_cache(0, v1_file.clone())
+            .lock()
+            .read(0, |super_block: &SuperBlock| {
+                assert_eq!(super_block.version(), EFS_VERSION)
+            });
+    }
+    // an image using inodes the upgraded inode area has no room for is left alone
+    let mut crowded = v1.clone();
+    crowded[BLOCK_SZ + 2048 / 8] |= 1;
+    block_cache_drop_all();
+    std::fs::write("target/fs_v1.img", &crowded)?;
+    assert_eq!(
+        EasyFileSystem::open(v1_file.clone()).err(),
+        Some(OpenError::TooManyInodes)
+    );
+    block_cache_drop_all();
+    assert!(std::fs::read("target/fs_v1.img")? == crowded);
+    Ok(())
+}
+
+#[test]
+fn long_names() {
+    // long names take extension slots
+    let image = TestImage::new("fs_long_names", 4096);
+    let root_inode = image.root_inode();
+    let tmp = root_inode.create_dir("tmp").unwrap();
+    let long_name = "l".repeat(255);
+    let file_28 = "f".repeat(28);
+    assert!(tmp.create(&"l".repeat(256)).is_none());
+    let long_id = tmp.create(&long_name).unwrap().inode_id();
+    tmp.create(&file_28).unwrap();
+    tmp.create("short").unwrap();
+    assert!(tmp.create(&long_name).is_none());
+    assert_eq!(tmp.find(&long_name).unwrap().inode_id(), long_id);
+    assert!(tmp.find(&long_name[..254]).is_none());
+    assert!(tmp.find(&long_name[..27]).is_none());
+    assert_eq!(tmp.find_name(long_id).unwrap(), long_name);
+    assert_eq!(tmp.ls(), [".", "..", &long_name, &file_28, "short"]);
+    // the slots of a removed long name are reused
+    tmp.unlink(&long_name).unwrap().release();
+    tmp.unlink(&file_28).unwrap().release();
+    tmp.create("a").unwrap();
+    tmp.create(&"m".repeat(100)).unwrap();
+    assert_eq!(tmp.ls(), [".", "..", "a", &"m".repeat(100), "short"]);
+    assert!(!root_inode.remove_dir("tmp"));
+    for name in tmp.ls().iter().skip(2) {
+        tmp.unlink(name).unwrap().release();
+    }
+    assert!(root_inode.remove_dir("tmp"));
+    assert_eq!(fsck::check(&image.efs, false), []);
+}
+
+#[test]
+fn truncation() {
+    // truncation cuts a file anywhere, and it reads zeros where it grows again
+    let image = TestImage::new("fs_truncate", 8192);
+    let root_inode = image.root_inode();
+    let truncate_test = |file: &easy_fs::Inode, expected: &mut Vec<u8>, sizes: &[usize]| {
+        for &size in sizes {
+            file.truncate(size as u32);
+            expected.resize(size, 0);
+            let mut read_buffer = vec![0u8; size + BLOCK_SZ];
+            assert_eq!(file.read_at(0, &mut read_buffer), size);
+            assert!(read_buffer[..size] == expected[..]);
+            assert_eq!(fsck::check(&image.efs, false), []);
+        }
+    };
+    // blocks taken in turns with another file give one extent each
+    let (fragmented, other) = (
+        root_inode.create("fragmented").unwrap(),
+        root_inode.create("other").unwrap(),
+    );
+    let mut expected: Vec<u8> = (0..40 * BLOCK_SZ).map(|i| (i % 241) as u8).collect();
+    for (i, block) in expected.chunks(BLOCK_SZ).enumerate() {
+        fragmented.write_at(i * BLOCK_SZ, block);
+        other.write_at(i * BLOCK_SZ, block);
+    }
+    truncate_test(
+        &fragmented,
+        &mut expected,
+        &[
+            35 * BLOCK_SZ + 9,
+            20 * BLOCK_SZ,
+            30 * BLOCK_SZ + 1,
+            14 * BLOCK_SZ,
+            13 * BLOCK_SZ + 1,
+            0,
+            5 * BLOCK_SZ,
+        ],
+    );
+    // files made before extents keep their direct and indirect blocks
+    let legacy = root_inode.create("legacy").unwrap();
+    let (block_id, block_offset) = {
+        let legacy_id = legacy.inode_id();
+        image.efs.lock().get_disk_inode_pos(legacy_id)
+    };
+    get_block_cache(block_id as usize, image.device.clone())
+        .lock()
+        .modify(block_offset + 125, |flags: &mut u8| *flags = 0);
+    let mut expected: Vec<u8> = (0..200 * BLOCK_SZ).map(|i| (i % 239) as u8).collect();
+    legacy.write_at(0, &expected);
+    truncate_test(
+        &legacy,
+        &mut expected,
+        &[
+            180 * BLOCK_SZ + 7,
+            157 * BLOCK_SZ - 1,
+            100 * BLOCK_SZ + 3,
+            170 * BLOCK_SZ,
+            28 * BLOCK_SZ + 5,
+            28 * BLOCK_SZ,
+            10,
+            0,
+            30 * BLOCK_SZ + 1,
+        ],
+    );
+    // and grow no further than their doubly indirect blocks reach
+    assert_eq!(legacy.write_at(1 << 24, &[1]), 0);
+    assert_eq!(fsck::check(&image.efs, false), []);
+}
+
+#[test]
+fn size_limits() {
+    // files grow neither past their largest size nor past the free blocks
+    let image = TestImage::new("fs_full", 4096);
+    let root_inode = image.root_inode();
+    let efs = &image.efs;
+    let full = root_inode.create("full").unwrap();
+    assert!(!full.truncate(u32::MAX));
+    assert_eq!(full.write_at(u32::MAX as usize, &[1]), 0);
+    let free_blocks = efs.lock().free_data_blocks();
+    assert!(!full.truncate((free_blocks + 1) * BLOCK_SZ as u32));
+    assert_eq!(full.size(), 0);
+    assert_eq!(efs.lock().free_data_blocks(), free_blocks);
+    // so writes stop short when the data area fills up
+    let chunk = vec![7u8; 64 * BLOCK_SZ];
+    let mut written = 0;
+    loop {
+        let write_size = full.write_at(written, &chunk);
+        written += write_size;
+        if write_size < chunk.len() {
+            break;
+        }
+    }
+    assert_eq!(full.size() as usize, written);
+    assert!(efs.lock().free_data_blocks() < 64);
+    assert_eq!(fsck::check(efs, false), []);
+    root_inode.unlink("full").unwrap().release();
+    assert_eq!(efs.lock().free_data_blocks(), free_blocks);
+    assert_eq!(fsck::check(efs, false), []);
+}
+
+#[test]
+fn rename() {
+    // a file written aside is renamed over the old one
+    let image = TestImage::new("fs_rename", 4096);
+    let root_inode = image.root_inode();
+    let nlink = root_inode.nlink();
+    let etc = root_inode.create_dir("etc").unwrap();
+    let conf = etc.create("conf").unwrap();
+    conf.write_at(0, b"old");
+    let conf_tmp = etc.create("conf.tmp").unwrap();
+    conf_tmp.write_at(0, b"new");
+    assert!(etc.rename("conf.tmp", &etc, "conf"));
+    assert_eq!(etc.ls(), [".", "..", "conf"]);
+    assert_eq!(etc.find("conf").unwrap().inode_id(), conf_tmp.inode_id());
+    assert_eq!(conf.nlink(), 0);
+    assert!(conf.release());
+    assert!(!etc.rename("conf", &root_inode, "etc"));
+    assert!(!etc.rename("missing", &root_inode, "conf"));
+    // directories take their ".." along, but cannot move below themselves
+    let sub = etc.create_dir("sub").unwrap();
+    assert!(!root_inode.rename("etc", &sub, "etc"));
+    assert!(!root_inode.rename("etc", &etc, "etc"));
+    assert!(etc.rename("sub", &root_inode, "sub"));
+    assert_eq!(sub.find("..").unwrap().inode_id(), root_inode.inode_id());
+    assert_eq!((etc.nlink(), root_inode.nlink()), (2, nlink + 2));
+    // and replace only empty directories
+    sub.create("file").unwrap();
+    assert!(!root_inode.rename("etc", &root_inode, "sub"));
+    sub.unlink("file").unwrap().release();
+    assert!(root_inode.rename("etc", &root_inode, "sub"));
+    assert_eq!(root_inode.find("sub").unwrap().inode_id(), etc.inode_id());
+    assert_eq!(etc.ls(), [".", "..", "conf"]);
+    assert_eq!(root_inode.nlink(), nlink + 1);
+    assert_eq!(fsck::check(&image.efs, false), []);
+    etc.unlink("conf").unwrap().release();
+    assert!(root_inode.remove_dir("sub"));
+    assert_eq!(fsck::check(&image.efs, false), []);
+}
+
+#[test]
+fn journal_replay() -> std::io::Result<()> {
+    // cut the writes off at every point of some operations, and check the
+    // replayed image holds the result of a prefix of them, leaking nothing
+    let image = TestImage::new("fs_crash", 4096);
+    let faulty = &image.device;
+    let greet_str = "Hello, world!";
+    let mut buffer = [0u8; 233];
+    image
+        .root_inode()
+        .create("keep")
+        .unwrap()
+        .write_at(0, greet_str.as_bytes());
+    block_cache_drop_all();
+    let snapshot = std::fs::read("target/fs_crash.img")?;
+    let content: Vec<u8> = (0..3 * BLOCK_SZ).map(|i| (i % 251) as u8).collect();
+    let operations = |root_inode: &easy_fs::Inode| {
+        let dir = root_inode.create_dir("dir").unwrap();
+        let file = dir.create("file").unwrap();
+        file.write_at(0, &content);
+        file.sync();
+        root_inode.find("keep").unwrap().clear();
+    };
+    // the first free inode and data block
+    let first_free = |efs: &mut EasyFileSystem| {
+        let (inode_id, block_id) = (efs.alloc_inode(), efs.alloc_data().unwrap());
+        efs.dealloc_inode(inode_id);
+        efs.dealloc_data(block_id);
+        (inode_id, block_id)
+    };
+    // states after no operation, the directory, the empty file, the full file and the clear
+    let mut expected_free = Vec::new();
+    for cut in 0.. {
+        block_cache_drop_all();
+        std::fs::write("target/fs_crash.img", &snapshot)?;
+        let efs = EasyFileSystem::open(faulty.clone()).unwrap();
+        let root_inode = EasyFileSystem::root_inode(&efs);
+        *faulty.budget.lock().unwrap() = Some(cut);
+        operations(&root_inode);
+        let finished = *faulty.budget.lock().unwrap() != Some(0);
+        *faulty.budget.lock().unwrap() = Some(0);
+        block_cache_drop_all();
+        *faulty.budget.lock().unwrap() = None;
+        let efs = EasyFileSystem::open(faulty.clone()).unwrap();
+        let root_inode = EasyFileSystem::root_inode(&efs);
+        let state = match root_inode.find("dir") {
+            None => 0,
+            Some(dir) => match dir.find("file") {
+                None => 1,
+                Some(file) if root_inode.find("keep").unwrap().read_at(0, &mut buffer) > 0 => {
+                    let len = file.read_at(0, &mut vec![0u8; content.len()]);
+                    if len == 0 {
+                        2
+                    } else {
+                        assert_eq!(len, content.len());
+                        3
+                    }
+                }
+                Some(_) => 4,
+            },
+        };
+        assert_eq!(root_inode.nlink(), if state == 0 { 2 } else { 3 });
+        assert_eq!(fsck::check(&efs, false), []);
+        if cut == 0 {
+            // record the free inode and data block of each state with an uncut run
+            expected_free.push(first_free(&mut efs.lock()));
+            let dir = root_inode.create_dir("dir").unwrap();
+            expected_free.push(first_free(&mut efs.lock()));
+            let file = dir.create("file").unwrap();
+            expected_free.push(first_free(&mut efs.lock()));
+            file.write_at(0, &content);
+            file.sync();
+            expected_free.push(first_free(&mut efs.lock()));
+            root_inode.find("keep").unwrap().clear();
+            expected_free.push(first_free(&mut efs.lock()));
+            continue;
+        }
+        assert_eq!(first_free(&mut efs.lock()), expected_free[state]);
+        if finished {
+            assert_eq!(state, 4);
+            let mut buffer = vec![0u8; content.len()];
+            let dir = root_inode.find("dir").unwrap();
+            dir.find("file").unwrap().read_at(0, &mut buffer);
+            assert_eq!(buffer, content);
+            break;
+        }
+    }
+    Ok(())
+}
+
+#[test]
+fn fsck_repair() {
+    // damage the image behind the back of the file system, then repair it
+    use fsck::Problem;
+    let image = TestImage::new("fs_fsck", 4096);
+    let efs = &image.efs;
+    let root_inode = image.root_inode();
+    let device: Arc<dyn BlockDevice> = image.device.clone();
+    let content: Vec<u8> = (0..3 * BLOCK_SZ).map(|i| (i % 251) as u8).collect();
+    root_inode.create("keep").unwrap();
+    let file = root_inode
+        .create_dir("dir")
+        .unwrap()
+        .create("file")
+        .unwrap();
+    file.write_at(0, &content);
+    let ghost_id = root_inode.create("ghost").unwrap().inode_id();
+    let orphan = root_inode.create("orphan").unwrap();
+    orphan.write_at(0, &content);
+    let orphan_blocks: Vec<u32> = image.disk_inode(orphan.inode_id(), |disk_inode| {
+        disk_inode
+            .extents(&device)
+            .iter()
+            .flat_map(|extent| extent.start..extent.end())
+            .collect()
+    });
+    root_inode.unlink("orphan").unwrap();
+    efs.lock().dealloc_inode(ghost_id);
+    let file_id = file.inode_id();
+    let file_block = image.disk_inode(file_id, |disk_inode| {
+        disk_inode.nlink = 5;
+        disk_inode.direct[0]
+    });
+    efs.lock().dealloc_data(file_block);
+    let problems = fsck::check(efs, false);
+    let mut expected = vec![
+        Problem::DanglingEntry {
+            dir: 0,
+            name: String::from("ghost"),
+            inode_id: ghost_id,
+        },
+        Problem::WrongLinkCount {
+            inode_id: file_id,
+            nlink: 5,
+            entries: 1,
+        },
+        Problem::UnreachableInode {
+            inode_id: orphan.inode_id(),
+        },
+        Problem::FreeBlockInUse {
+            inode_id: file_id,
+            block_id: file_block,
+        },
+    ];
+    expected.extend(orphan_blocks.iter().map(|block_id| Problem::LeakedBlock {
+        block_id: *block_id,
+    }));
+    assert_eq!(problems.len(), expected.len());
+    assert!(expected.iter().all(|problem| problems.contains(problem)));
+    assert!(problems.iter().all(|problem| problem.is_repairable()));
+    assert_eq!(fsck::check(efs, true), problems);
+    assert_eq!(fsck::check(efs, false), []);
+    block_cache_drop_all();
+    let efs = EasyFileSystem::open(image.device.clone()).unwrap();
+    assert_eq!(fsck::check(&efs, false), []);
+    assert_eq!(
+        EasyFileSystem::root_inode(&efs).ls(),
+        [".", "..", "keep", "dir"]
+    );
+}
 
+#[test]
+fn bad_names() {
+    // a name damaged into something other than UTF-8 is reported, not read
+    use fsck::Problem;
+    let image = TestImage::new("fs_bad_names", 4096);
+    let root_inode = image.root_inode();
+    let device: Arc<dyn BlockDevice> = image.device.clone();
+    let bad_id = root_inode.create("bad").unwrap().inode_id();
+    let bad_slot = image.disk_inode(0, |disk_inode| {
+        let bad_slot = disk_inode
+            .find_dirent(&device, |index, name, _| (name == "bad").then_some(index))
+            .unwrap();
+        disk_inode.write_at(bad_slot * 32, &[0xff, 0xfe, 0], &device);
+        bad_slot
+    });
+    assert_eq!(
+        fsck::check(&image.efs, false),
+        [Problem::BadName {
+            dir: 0,
+            name: vec![0xff, 0xfe],
+        }]
+    );
+    assert_eq!(root_inode.ls(), [".", ".."]);
+    assert!(root_inode.find_name(bad_id).is_none());
+    image.disk_inode(0, |disk_inode| {
+        disk_inode.write_at(bad_slot * 32, b"bad", &device);
+    });
+    root_inode.unlink("bad").unwrap().release();
+    assert_eq!(fsck::check(&image.efs, false), []);
+}
+
+#[test]
+fn big_file_extents() {
+    // a file of tens of megabytes takes a few extents, read a run of blocks per request
+    let image = TestImage::new("fs_big", 131072);
+    let root_inode = image.root_inode();
+    let large = root_inode.create("large").unwrap();
+    let small = root_inode.create("small").unwrap();
+    let chunk: Vec<u8> = (0..64 * BLOCK_SZ).map(|i| (i % 253) as u8).collect();
+    let chunks = 24 * 1024 * 1024 / chunk.len();
+    for i in 0..chunks {
+        large.write_at(i * chunk.len(), &chunk);
+        // the small file takes the blocks right after the large one now and then
+        if i % 128 == 0 {
+            small.write_at(small.size() as usize, &chunk[..BLOCK_SZ]);
+        }
+    }
+    let device: Arc<dyn BlockDevice> = image.device.clone();
+    let extents = image.disk_inode(large.inode_id(), |disk_inode| disk_inode.extents(&device));
+    assert!(extents.len() <= 16);
+    assert_eq!(
+        extents
+            .iter()
+            .map(|extent| extent.len as usize)
+            .sum::<usize>(),
+        chunks * 64
+    );
+    block_cache_drop_all();
+    *image.device.reads.lock().unwrap() = 0;
+    let mut read_buffer = vec![0u8; 1024 * 1024];
+    for offset in (0..chunks * chunk.len()).step_by(read_buffer.len()) {
+        assert_eq!(large.read_at(offset, &mut read_buffer), read_buffer.len());
+        for piece in read_buffer.chunks(chunk.len()) {
+            assert!(piece == &chunk[..]);
+        }
+    }
+    assert!(*image.device.reads.lock().unwrap() < chunks * 64 / 16);
+    assert_eq!(fsck::check(&image.efs, false), []);
+    large.clear();
+    assert_eq!(fsck::check(&image.efs, false), []);
+}
+
+#[test]
+fn spread_file_freed() -> std::io::Result<()> {
+    // a file with a block under each of more data bitmap blocks than one
+    // transaction logs is freed a few blocks per transaction
+    use easy_fs::{SuperBlock, JOURNAL_CAPACITY};
+    let image = TestImage::new("fs_spread", 540_000);
+    let device: Arc<dyn BlockDevice> = image.device.clone();
+    let efs = &image.efs;
+    let (data_bitmap_start, data_bitmap_blocks) =
+        get_block_cache(0, device.clone())
+            .lock()
+            .read(0, |super_block: &SuperBlock| {
+                (
+                    1 + super_block.inode_bitmap_blocks + super_block.inode_area_blocks,
+                    super_block.data_bitmap_blocks,
+                )
+            });
+    // the file and its two extent blocks take one block under each
+    let spread_blocks = JOURNAL_CAPACITY + 1;
+    assert!(data_bitmap_blocks as usize >= spread_blocks + 2);
+    // leave only the second block under each data bitmap block free, the
+    // first one under the first is the block of "/"
+    for block_id in data_bitmap_start..data_bitmap_start + data_bitmap_blocks {
+        get_block_cache(block_id as usize, device.clone())
+            .lock()
+            .modify(0, |bitmap_block: &mut [u64; 64]| {
+                bitmap_block.fill(u64::MAX);
+                bitmap_block[0] = !2;
+            });
+    }
+    let spread = image.root_inode().create("spread").unwrap();
+    spread.write_at(0, &vec![1u8; spread_blocks * BLOCK_SZ]);
+    let free_under = |i: usize| {
+        !efs.lock()
+            .data_bitmap
+            .is_allocated(&device, i * BLOCK_BITS + 1)
+    };
+    assert_eq!((0..spread_blocks + 2).filter(|i| free_under(*i)).count(), 0);
+    spread.clear();
+    assert!((0..data_bitmap_blocks as usize).all(free_under));
+    block_cache_drop_all();
+    std::fs::remove_file("target/fs_spread.img")?;
     Ok(())
 }
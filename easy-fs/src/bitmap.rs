@@ -56,6 +56,74 @@ impl Bitmap {
         }
         None
     }
+    /// Allocate at most 'len' consecutive bits below 'bits', return the first one and how many
+    ///
+    /// The run starts at 'goal' if that bit is free, so a file keeps growing
+    /// where it ends, else at the first run of 'len' free bits, or at the
+    /// longest free run if there is none.
+    pub fn alloc_run(
+        &self,
+        block_device: &Arc<dyn BlockDevice>,
+        goal: Option<usize>,
+        len: usize,
+        bits: usize,
+    ) -> Option<(usize, usize)> {
+        let bits = bits.min(self.maximum());
+        let start =
+            match goal.filter(|goal| *goal < bits && !self.is_allocated(block_device, *goal)) {
+                Some(goal) => goal,
+                None => self.find_free_run(block_device, len, bits)?,
+            };
+        let mut run = 0;
+        while run < len && start + run < bits && !self.is_allocated(block_device, start + run) {
+            self.set(block_device, start + run);
+            run += 1;
+        }
+        Some((start, run))
+    }
+    /// The first bit of the first run of 'len' free bits below 'bits', or of the longest one
+    fn find_free_run(
+        &self,
+        block_device: &Arc<dyn BlockDevice>,
+        len: usize,
+        bits: usize,
+    ) -> Option<usize> {
+        let (mut longest, mut longest_len) = (None, 0);
+        let (mut run_start, mut run_len) = (0, 0);
+        for block_pos in 0..self.blocks {
+            let bitmap_block =
+                get_block_cache(block_pos + self.start_block_id, Arc::clone(block_device))
+                    .lock()
+                    .read(0, |bitmap_block: &BitmapBlock| *bitmap_block);
+            for (bits64_pos, bits64) in bitmap_block.iter().enumerate() {
+                let base = block_pos * BLOCK_BITS + bits64_pos * 64;
+                if *bits64 == u64::MAX {
+                    run_len = 0;
+                    continue;
+                }
+                for inner_pos in 0..64 {
+                    if base + inner_pos >= bits {
+                        return longest;
+                    }
+                    if *bits64 & (1u64 << inner_pos) != 0 {
+                        run_len = 0;
+                        continue;
+                    }
+                    if run_len == 0 {
+                        run_start = base + inner_pos;
+                    }
+                    run_len += 1;
+                    if run_len >= len {
+                        return Some(run_start);
+                    }
+                    if run_len > longest_len {
+                        (longest, longest_len) = (Some(run_start), run_len);
+                    }
+                }
+            }
+        }
+        longest
+    }
     /// Deallocate a block according to the bitmap info
     pub fn dealloc(&self, block_device: &Arc<dyn BlockDevice>, bit: usize) {
         let (block_pos, bits64_pos, inner_pos) = decomposition(bit);
@@ -66,6 +134,60 @@ impl Bitmap {
                 bitmap_block[bits64_pos] -= 1u64 << inner_pos;
             });
     }
+    /// Mark a free bit allocated
+    pub fn set(&self, block_device: &Arc<dyn BlockDevice>, bit: usize) {
+        let (block_pos, bits64_pos, inner_pos) = decomposition(bit);
+        get_block_cache(block_pos + self.start_block_id, Arc::clone(block_device))
+            .lock()
+            .modify(0, |bitmap_block: &mut BitmapBlock| {
+                assert!(bitmap_block[bits64_pos] & (1u64 << inner_pos) == 0);
+                bitmap_block[bits64_pos] |= 1u64 << inner_pos;
+            });
+    }
+    /// Is the bit allocated?
+    pub fn is_allocated(&self, block_device: &Arc<dyn BlockDevice>, bit: usize) -> bool {
+        let (block_pos, bits64_pos, inner_pos) = decomposition(bit);
+        get_block_cache(block_pos + self.start_block_id, Arc::clone(block_device))
+            .lock()
+            .read(0, |bitmap_block: &BitmapBlock| {
+                bitmap_block[bits64_pos] & (1u64 << inner_pos) > 0
+            })
+    }
+    /// Write the modified blocks of the bitmap back to disk
+    pub fn sync(&self, block_device: &Arc<dyn BlockDevice>) {
+        for block_id in self.start_block_id..self.start_block_id + self.blocks {
+            get_block_cache(block_id, Arc::clone(block_device))
+                .lock()
+                .sync();
+        }
+    }
+    /// The number of allocated bits below 'bits'
+    pub fn count_allocated(&self, block_device: &Arc<dyn BlockDevice>, bits: usize) -> usize {
+        let bits = bits.min(self.maximum());
+        let mut count = 0;
+        for block_pos in 0..self.blocks {
+            if block_pos * BLOCK_BITS >= bits {
+                break;
+            }
+            let bitmap_block =
+                get_block_cache(block_pos + self.start_block_id, Arc::clone(block_device))
+                    .lock()
+                    .read(0, |bitmap_block: &BitmapBlock| *bitmap_block);
+            for (bits64_pos, bits64) in bitmap_block.iter().enumerate() {
+                let base = block_pos * BLOCK_BITS + bits64_pos * 64;
+                if base >= bits {
+                    break;
+                }
+                let mask = if bits - base >= 64 {
+                    u64::MAX
+                } else {
+                    (1u64 << (bits - base)) - 1
+                };
+                count += (bits64 & mask).count_ones() as usize;
+            }
+        }
+        count
+    }
     /// bitmap max size in bits(the max number of blocks according to the bitmap size)
     pub fn maximum(&self) -> usize {
         self.blocks * BLOCK_BITS
@@ -1,10 +1,11 @@
 //! Block Cache Layer
 //! Implements about the disk block cache functionality
 use super::{BlockDevice, BLOCK_SZ};
-use alloc::collections::VecDeque;
+use alloc::collections::BTreeMap;
 use alloc::sync::Arc;
 use alloc::vec;
 use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use lazy_static::*;
 use spin::Mutex;
 /// BlockCache is a cache for a block in disk.
@@ -13,6 +14,8 @@ pub struct BlockCache {
     block_id: usize,
     block_device: Arc<dyn BlockDevice>,
     modified: bool,
+    /// modified inside a journal transaction, kept in memory until it commits
+    journaled: bool,
 }
 
 impl BlockCache {
@@ -21,11 +24,16 @@ impl BlockCache {
         // for alignment and move effciency
         let mut cache = vec![0u8; BLOCK_SZ];
         block_device.read_block(block_id, &mut cache);
+        Self::with_data(block_id, cache, block_device)
+    }
+    /// Create a BlockCache of the content of a block read from disk already.
+    fn with_data(block_id: usize, cache: Vec<u8>, block_device: Arc<dyn BlockDevice>) -> Self {
         Self {
             cache,
             block_id,
             block_device,
             modified: false,
+            journaled: false,
         }
     }
     /// Get the slice in the block cache according to the offset.
@@ -50,6 +58,7 @@ impl BlockCache {
         let type_size = core::mem::size_of::<T>();
         assert!(offset + type_size <= BLOCK_SZ);
         self.modified = true;
+        self.journaled |= JOURNALING.load(Ordering::Acquire);
         let addr = self.addr_of_offset(offset);
         unsafe { &mut *(addr as *mut T) }
     }
@@ -62,12 +71,20 @@ impl BlockCache {
         f(self.get_mut(offset))
     }
     /// Sync(write) the block cache to disk.
+    ///
+    /// A journaled block cache is only written back by the commit of its transaction.
     pub fn sync(&mut self) {
-        if self.modified {
+        if self.modified && !self.journaled {
             self.modified = false;
             self.block_device.write_block(self.block_id, &self.cache);
+            WRITEBACKS.fetch_add(1, Ordering::Relaxed);
         }
     }
+    /// Write back a journaled block cache, once its transaction is committed.
+    pub(crate) fn install(&mut self) {
+        self.journaled = false;
+        self.sync();
+    }
 }
 
 impl Drop for BlockCache {
@@ -76,55 +93,148 @@ impl Drop for BlockCache {
     }
 }
 
-const BLOCK_CACHE_SIZE: usize = 16;
+/// Default number of blocks cached
+const BLOCK_CACHE_SIZE: usize = 256;
+
+/// Hit and miss counts of the block caches
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct BlockCacheStats {
+    /// requests for a block already cached
+    pub hits: u64,
+    /// requests reading a block from the disk
+    pub misses: u64,
+    /// block caches dropped to make room for others
+    pub evictions: u64,
+    /// modified block caches written back to the disk
+    pub writebacks: u64,
+}
+
+/// The number of block caches written back, counted by [`BlockCache::sync`]
+static WRITEBACKS: AtomicU64 = AtomicU64::new(0);
 
 /// BlockCacheManager is a manager for BlockCache.
+///
+/// When it is full, the least recently used block cache nobody holds is
+/// written back if it was modified, and dropped.
+///
+/// Both indexes are B-trees rather than hash maps: `alloc` has no hash map
+/// for `no_std`, lookups stay logarithmic, the oldest use is the first key
+/// of `lru`, and syncing walks the blocks in ascending order.
 pub struct BlockCacheManager {
-    /// (block_id, block_cache)
-    queue: VecDeque<(usize, Arc<Mutex<BlockCache>>)>,
+    /// block_id -> (block_cache, time of its last use)
+    caches: BTreeMap<usize, (Arc<Mutex<BlockCache>>, u64)>,
+    /// time of the last use -> block_id, the least recently used first
+    lru: BTreeMap<u64, usize>,
+    /// time of the next use, counted in uses
+    clock: u64,
+    /// the number of block caches kept
+    capacity: usize,
+    stats: BlockCacheStats,
 }
 
 impl BlockCacheManager {
-    /// Create a new BlockCacheManager with an empty queue (block_id, block_cache)
+    /// Create a new BlockCacheManager without block caches
     pub fn new() -> Self {
         Self {
-            queue: VecDeque::new(),
+            caches: BTreeMap::new(),
+            lru: BTreeMap::new(),
+            clock: 0,
+            capacity: BLOCK_CACHE_SIZE,
+            stats: BlockCacheStats::default(),
         }
     }
-    /// Get a block cache from the queue. according to the block_id.
+    /// Get the block cache of 'block_id', read it from the disk if it is not cached.
     pub fn get_block_cache(
         &mut self,
         block_id: usize,
         block_device: Arc<dyn BlockDevice>,
     ) -> Arc<Mutex<BlockCache>> {
-        if let Some(pair) = self.queue.iter().find(|pair| pair.0 == block_id) {
-            Arc::clone(&pair.1)
-        } else {
-            // substitute
-            if self.queue.len() == BLOCK_CACHE_SIZE {
-                // from front to tail
-                if let Some((idx, _)) = self
-                    .queue
-                    .iter()
-                    .enumerate()
-                    .find(|(_, pair)| Arc::strong_count(&pair.1) == 1)
-                {
-                    self.queue.drain(idx..=idx);
-                } else {
-                    panic!("Run out of BlockCache!");
-                }
+        self.clock += 1;
+        if let Some((block_cache, last_use)) = self.caches.get_mut(&block_id) {
+            self.stats.hits += 1;
+            self.lru.remove(last_use);
+            self.lru.insert(self.clock, block_id);
+            *last_use = self.clock;
+            return Arc::clone(block_cache);
+        }
+        // load block into mem
+        self.insert(BlockCache::new(block_id, block_device))
+    }
+    /// Read the blocks from 'block_id' on which are not cached into block
+    /// caches, with one device request for each run of them.
+    pub fn prefetch(&mut self, block_id: usize, count: usize, block_device: Arc<dyn BlockDevice>) {
+        let mut i = 0;
+        while i < count {
+            let run = (i..count)
+                .take_while(|j| !self.caches.contains_key(&(block_id + j)))
+                .count();
+            if run == 0 {
+                i += 1;
+                continue;
+            }
+            let mut data = vec![0u8; run * BLOCK_SZ];
+            block_device.read_blocks(block_id + i, &mut data);
+            for (j, block) in data.chunks(BLOCK_SZ).enumerate() {
+                self.clock += 1;
+                self.insert(BlockCache::with_data(
+                    block_id + i + j,
+                    Vec::from(block),
+                    Arc::clone(&block_device),
+                ));
             }
-            // load block into mem and push back
-            let block_cache = Arc::new(Mutex::new(BlockCache::new(
-                block_id,
-                Arc::clone(&block_device),
-            )));
-            self.queue.push_back((block_id, Arc::clone(&block_cache)));
-            block_cache
+            i += run;
+        }
+    }
+    /// Cache a block read from disk, making room for it first.
+    fn insert(&mut self, block_cache: BlockCache) -> Arc<Mutex<BlockCache>> {
+        self.stats.misses += 1;
+        if self.caches.len() >= self.capacity && !self.evict() {
+            // only an open transaction may grow the block caches beyond the capacity
+            assert!(JOURNALING.load(Ordering::Acquire), "Run out of BlockCache!");
         }
+        let block_id = block_cache.block_id;
+        let block_cache = Arc::new(Mutex::new(block_cache));
+        self.caches
+            .insert(block_id, (Arc::clone(&block_cache), self.clock));
+        self.lru.insert(self.clock, block_id);
+        block_cache
+    }
+    /// Drop the least recently used block cache, return false if all are in use.
+    ///
+    /// Journaled block caches stay until their transaction commits.
+    fn evict(&mut self) -> bool {
+        let victim = self.lru.iter().find(|(_, block_id)| {
+            let block_cache = &self.caches[block_id].0;
+            Arc::strong_count(block_cache) == 1 && !block_cache.lock().journaled
+        });
+        let (last_use, block_id) = match victim {
+            Some((last_use, block_id)) => (*last_use, *block_id),
+            None => return false,
+        };
+        self.lru.remove(&last_use);
+        // written back when dropped
+        self.caches.remove(&block_id);
+        self.stats.evictions += 1;
+        true
+    }
+    /// Keep at most 'capacity' block caches from now on
+    pub fn set_capacity(&mut self, capacity: usize) {
+        self.capacity = capacity;
+        while self.caches.len() > capacity && self.evict() {}
     }
 }
 
+/// Set while a journal transaction is open
+///
+/// Like the block caches, which are keyed by block id alone, the flag is
+/// shared by all devices, so only one journaled image may be open at a time.
+static JOURNALING: AtomicBool = AtomicBool::new(false);
+
+/// Start or stop journaling the modified block caches, return whether it was on before
+pub(crate) fn set_journaling(on: bool) -> bool {
+    JOURNALING.swap(on, Ordering::AcqRel)
+}
+
 lazy_static! {
     /// BLOCK_CACHE_MANAGER: Glocal instance of BlockCacheManager.
     pub static ref BLOCK_CACHE_MANAGER: Mutex<BlockCacheManager> =
@@ -139,10 +249,46 @@ pub fn get_block_cache(
         .lock()
         .get_block_cache(block_id, block_device)
 }
+/// Read the uncached blocks of 'count' blocks from 'block_id' on into block
+/// caches, with as few device requests as possible.
+pub fn block_cache_prefetch(block_id: usize, count: usize, block_device: Arc<dyn BlockDevice>) {
+    BLOCK_CACHE_MANAGER
+        .lock()
+        .prefetch(block_id, count, block_device)
+}
 /// Sync(write) all the block cache to disk.
 pub fn block_cache_sync_all() {
     let manager = BLOCK_CACHE_MANAGER.lock();
-    for (_, cache) in manager.queue.iter() {
+    for (cache, _) in manager.caches.values() {
         cache.lock().sync();
     }
 }
+/// Keep at most 'capacity' block caches from now on
+pub fn block_cache_set_capacity(capacity: usize) {
+    BLOCK_CACHE_MANAGER.lock().set_capacity(capacity);
+}
+/// Hit and miss counts of the block caches so far
+pub fn block_cache_stats() -> BlockCacheStats {
+    BlockCacheStats {
+        writebacks: WRITEBACKS.load(Ordering::Relaxed),
+        ..BLOCK_CACHE_MANAGER.lock().stats
+    }
+}
+/// Get the journaled block caches with their block ids.
+pub(crate) fn journaled_block_caches() -> Vec<(usize, Arc<Mutex<BlockCache>>)> {
+    let manager = BLOCK_CACHE_MANAGER.lock();
+    manager
+        .caches
+        .iter()
+        .filter(|(_, (cache, _))| cache.lock().journaled)
+        .map(|(block_id, (cache, _))| (*block_id, Arc::clone(cache)))
+        .collect()
+}
+/// Write back and drop all block caches, later accesses read the disk again.
+///
+/// Journaled block caches are dropped without being written back, as a crash would.
+pub fn block_cache_drop_all() {
+    let mut manager = BLOCK_CACHE_MANAGER.lock();
+    manager.caches.clear();
+    manager.lru.clear();
+}
@@ -2,6 +2,7 @@
 //!
 //! Define the block read-write interface [BlockDevice] that the device driver needs to implement
 
+use super::BLOCK_SZ;
 use core::any::Any;
 
 pub trait BlockDevice: Send + Sync + Any {
@@ -9,4 +10,12 @@ pub trait BlockDevice: Send + Sync + Any {
     fn read_block(&self, block_id: usize, buf: &mut [u8]);
     /// Write a block to the block device.
     fn write_block(&self, block_id: usize, buf: &[u8]);
+    /// Read consecutive blocks from 'block_id' on, as many as fit in 'buf'.
+    ///
+    /// Devices serving several blocks in one request should override this.
+    fn read_blocks(&self, block_id: usize, buf: &mut [u8]) {
+        for (i, block) in buf.chunks_mut(BLOCK_SZ).enumerate() {
+            self.read_block(block_id + i, block);
+        }
+    }
 }
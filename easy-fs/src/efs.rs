@@ -4,11 +4,14 @@
 //!
 //! NOTICE: from this level, all data structures are in memory.
 use super::{
-    block_cache_sync_all, get_block_cache, Bitmap, BlockDevice, DiskInode, DiskInodeType, Inode,
-    SuperBlock,
+    block_cache::set_journaling, block_cache_sync_all, get_block_cache, Bitmap, BlockDevice,
+    DiskInode, DiskInodeType, Extent, Inode, Journal, SuperBlock, DISK_INODE_V1_SZ, EFS_VERSION,
+    JOURNAL_BLOCKS, UPGRADE_MOVED, UPGRADE_SAVED_SZ,
 };
 use crate::BLOCK_SZ;
 use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::fmt::{self, Display, Formatter};
 use spin::Mutex;
 
 /// EasyFileSystem struct
@@ -22,11 +25,42 @@ pub struct EasyFileSystem {
     /// The start block id of inode area
     inode_area_start_block: u32,
     /// The start block id of data area
-    data_area_start_block: u32,
+    pub(crate) data_area_start_block: u32,
+    /// The number of blocks in data area, the data bitmap may have more bits
+    pub(crate) data_area_blocks: u32,
+    /// The number of inodes the inode area holds
+    pub(crate) inode_count: u32,
+    /// The current time in seconds, for inode timestamps
+    clock: fn() -> u64,
+    /// The journal, images made before version 3 have none
+    journal: Option<Journal>,
 }
 
 type DataBlock = [u8; BLOCK_SZ];
 
+/// Why [`EasyFileSystem::open`] refuses an image
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OpenError {
+    /// the superblock has no easy-fs magic number
+    NotEasyFs,
+    /// a version 1 image uses more inodes than its inode area holds in the current format
+    TooManyInodes,
+}
+
+impl Display for OpenError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            OpenError::NotEasyFs => "not an easy-fs image",
+            OpenError::TooManyInodes => "too many inodes to upgrade the image",
+        })
+    }
+}
+
+/// A clock for file systems nobody gave one, all timestamps are zero
+fn no_clock() -> u64 {
+    0
+}
+
 impl EasyFileSystem {
     /// Create a new EasyFileSystem
     pub fn create(
@@ -40,7 +74,7 @@ impl EasyFileSystem {
         let inode_area_blocks =
             ((inode_num * core::mem::size_of::<DiskInode>() + BLOCK_SZ - 1) / BLOCK_SZ) as u32;
         let inode_total_blocks = inode_bitmap_blocks + inode_area_blocks;
-        let data_total_blocks = total_blocks - 1 - inode_total_blocks;
+        let data_total_blocks = total_blocks - 1 - inode_total_blocks - JOURNAL_BLOCKS;
         let data_bitmap_blocks = (data_total_blocks + 4096) / 4097;
         let data_area_blocks = data_total_blocks - data_bitmap_blocks;
         let data_bitmap = Bitmap::new(
@@ -54,6 +88,13 @@ impl EasyFileSystem {
             data_bitmap,
             inode_area_start_block: 1 + inode_bitmap_blocks,
             data_area_start_block: 1 + inode_total_blocks + data_bitmap_blocks,
+            data_area_blocks,
+            inode_count: inode_num as u32,
+            clock: no_clock,
+            journal: Some(Journal::new(
+                total_blocks - JOURNAL_BLOCKS,
+                Arc::clone(&block_device),
+            )),
         };
         // clear all blocks
         for i in 0..total_blocks {
@@ -75,6 +116,7 @@ impl EasyFileSystem {
                     inode_area_blocks,
                     data_bitmap_blocks,
                     data_area_blocks,
+                    JOURNAL_BLOCKS,
                 );
             },
         );
@@ -87,20 +129,25 @@ impl EasyFileSystem {
             .modify(root_inode_offset, |disk_inode: &mut DiskInode| {
                 disk_inode.initialize(DiskInodeType::Directory);
             });
+        let efs = Arc::new(Mutex::new(efs));
+        // "." and ".." of "/" both lead to itself
+        Self::root_inode(&efs).init_dir(0, &mut efs.lock());
         block_cache_sync_all();
-        Arc::new(Mutex::new(efs))
+        efs
     }
-    /// Open an existing EasyFileSystem
-    pub fn open(block_device: Arc<dyn BlockDevice>) -> Arc<Mutex<Self>> {
+    /// Open an existing EasyFileSystem, replaying its journal and upgrading images of older formats
+    pub fn open(block_device: Arc<dyn BlockDevice>) -> Result<Arc<Mutex<Self>>, OpenError> {
         // read SuperBlock
-        get_block_cache(0, Arc::clone(&block_device))
-            .lock()
-            .read(0, |super_block: &SuperBlock| {
-                assert!(super_block.is_valid(), "Error loading EFS!");
+        let (efs, version) = get_block_cache(0, Arc::clone(&block_device)).lock().read(
+            0,
+            |super_block: &SuperBlock| {
+                if !super_block.is_valid() {
+                    return Err(OpenError::NotEasyFs);
+                }
                 let inode_total_blocks =
                     super_block.inode_bitmap_blocks + super_block.inode_area_blocks;
                 let efs = Self {
-                    block_device,
+                    block_device: Arc::clone(&block_device),
                     inode_bitmap: Bitmap::new(1, super_block.inode_bitmap_blocks as usize),
                     data_bitmap: Bitmap::new(
                         (1 + inode_total_blocks) as usize,
@@ -108,9 +155,197 @@ impl EasyFileSystem {
                     ),
                     inode_area_start_block: 1 + super_block.inode_bitmap_blocks,
                     data_area_start_block: 1 + inode_total_blocks + super_block.data_bitmap_blocks,
+                    data_area_blocks: super_block.data_area_blocks,
+                    inode_count: (super_block.inode_area_blocks as usize * BLOCK_SZ
+                        / core::mem::size_of::<DiskInode>())
+                        as u32,
+                    clock: no_clock,
+                    journal: match super_block.journal_blocks {
+                        0 => None,
+                        journal_blocks => Some(Journal::new(
+                            super_block.total_blocks - journal_blocks,
+                            Arc::clone(&block_device),
+                        )),
+                    },
                 };
-                Arc::new(Mutex::new(efs))
+                Ok((efs, super_block.version()))
+            },
+        )?;
+        if let Some(journal) = &efs.journal {
+            journal.replay();
+        }
+        if version < 2 {
+            efs.upgrade_inodes_from_v1()?;
+            let efs = Arc::new(Mutex::new(efs));
+            Self::root_inode(&efs).upgrade_dirs_from_v1(&mut efs.lock());
+            efs.lock().finish_upgrade_from_v1();
+            return Ok(efs);
+        }
+        if version < EFS_VERSION {
+            // older inodes keep their block maps, new ones are mapped by extents
+            efs.set_current_version();
+        }
+        Ok(Arc::new(Mutex::new(efs)))
+    }
+    /// Rewrite the inode area of a version 1 image in the current format.
+    ///
+    /// Inodes are twice as large now, so the inode area holds half as many
+    /// of them, and every inode moves. Ownership defaults to root, with
+    /// mode 0o755 for directories and 0o644 for files, and the times to zero.
+    ///
+    /// The blocks are rewritten from the last one on, as an inode only moves
+    /// forward, into a block whose inodes moved already. Before a block is
+    /// rewritten, the superblock records it with a copy of the inodes moving
+    /// there, so an upgrade cut off by a crash goes on from that block.
+    fn upgrade_inodes_from_v1(&self) -> Result<(), OpenError> {
+        let inodes_per_block = (BLOCK_SZ / core::mem::size_of::<DiskInode>()) as u32;
+        let v1_inodes_per_block = (BLOCK_SZ / DISK_INODE_V1_SZ) as u32;
+        let (step, saved) = get_block_cache(0, Arc::clone(&self.block_device))
+            .lock()
+            .read(0, |super_block: &SuperBlock| {
+                (super_block.upgrade_step, super_block.upgrade_saved)
+            });
+        let rewritten = match step {
+            UPGRADE_MOVED => return Ok(()),
+            0 => {
+                let v1_inode_count = self.inode_bitmap.maximum() as u32;
+                if (self.inode_count..v1_inode_count).any(|inode_id| {
+                    self.inode_bitmap
+                        .is_allocated(&self.block_device, inode_id as usize)
+                }) {
+                    return Err(OpenError::TooManyInodes);
+                }
+                self.inode_count / inodes_per_block
+            }
+            step => {
+                self.rewrite_inode_block(step - 1, &saved);
+                step - 1
+            }
+        };
+        for block in (0..rewritten).rev() {
+            let inode_id = block * inodes_per_block;
+            let mut saved = [0u8; UPGRADE_SAVED_SZ];
+            get_block_cache(
+                (self.inode_area_start_block + inode_id / v1_inodes_per_block) as usize,
+                Arc::clone(&self.block_device),
+            )
+            .lock()
+            .read(
+                (inode_id % v1_inodes_per_block) as usize * DISK_INODE_V1_SZ,
+                |inodes: &[u8; UPGRADE_SAVED_SZ]| saved.copy_from_slice(inodes),
+            );
+            self.set_upgrade_step(block + 1, &saved);
+            self.rewrite_inode_block(block, &saved);
+        }
+        self.set_upgrade_step(UPGRADE_MOVED, &[0; UPGRADE_SAVED_SZ]);
+        Ok(())
+    }
+    /// Write block 'block' of the inode area in the current format, from the
+    /// version 1 inodes moving into it
+    fn rewrite_inode_block(&self, block: u32, saved: &[u8; UPGRADE_SAVED_SZ]) {
+        let inode_size = core::mem::size_of::<DiskInode>();
+        let first_inode_id = block as usize * BLOCK_SZ / inode_size;
+        let allocated: Vec<bool> = (0..BLOCK_SZ / inode_size)
+            .map(|i| {
+                self.inode_bitmap
+                    .is_allocated(&self.block_device, first_inode_id + i)
             })
+            .collect();
+        let block_cache = get_block_cache(
+            (self.inode_area_start_block + block) as usize,
+            Arc::clone(&self.block_device),
+        );
+        let mut block_cache = block_cache.lock();
+        block_cache.modify(0, |data_block: &mut DataBlock| {
+            data_block.fill(0);
+            // the version 1 inode is a prefix of the current one
+            for (i, old) in saved.chunks(DISK_INODE_V1_SZ).enumerate() {
+                data_block[i * inode_size..i * inode_size + DISK_INODE_V1_SZ].copy_from_slice(old);
+            }
+        });
+        for (i, allocated) in allocated.into_iter().enumerate() {
+            block_cache.modify(i * inode_size, |disk_inode: &mut DiskInode| {
+                disk_inode.mode = if disk_inode.is_dir() { 0o755 } else { 0o644 };
+                // images older than link counts have them all zero, those
+                // of directories are counted once all inodes moved
+                if allocated && disk_inode.nlink == 0 && !disk_inode.is_dir() {
+                    disk_inode.nlink = 1;
+                }
+            });
+        }
+        block_cache.sync();
+    }
+    /// Record the progress of an upgrade from version 1 in the superblock
+    fn set_upgrade_step(&self, step: u32, saved: &[u8; UPGRADE_SAVED_SZ]) {
+        let block_cache = get_block_cache(0, Arc::clone(&self.block_device));
+        let mut block_cache = block_cache.lock();
+        block_cache.modify(0, |super_block: &mut SuperBlock| {
+            super_block.upgrade_step = step;
+            super_block.upgrade_saved = *saved;
+        });
+        block_cache.sync();
+    }
+    /// Write the upgraded directories of a version 1 image back, then mark it as upgraded
+    fn finish_upgrade_from_v1(&self) {
+        // without a journal, a crash may leak the block given to "/" but
+        // must not leave "/" pointing to a free block
+        self.data_bitmap.sync(&self.block_device);
+        block_cache_sync_all();
+        self.set_current_version();
+    }
+    /// Mark the image as written in the current format
+    fn set_current_version(&self) {
+        get_block_cache(0, Arc::clone(&self.block_device))
+            .lock()
+            .modify(0, |super_block: &mut SuperBlock| {
+                super_block.set_current_version()
+            });
+        block_cache_sync_all();
+    }
+    /// Open a transaction, the block modifications until [`Self::commit`] reach the disk together
+    pub fn begin(&self) {
+        if let Some(journal) = &self.journal {
+            journal.begin();
+        }
+    }
+    /// Commit the open transaction, write the blocks it modified to disk,
+    /// or all modified blocks of an image without a journal
+    pub fn commit(&self) {
+        match &self.journal {
+            Some(journal) => journal.commit(),
+            None => block_cache_sync_all(),
+        }
+    }
+    /// Set the clock inode timestamps are taken from, in seconds
+    pub fn set_clock(&mut self, clock: fn() -> u64) {
+        self.clock = clock;
+    }
+    /// The current time in seconds
+    pub fn now(&self) -> u64 {
+        (self.clock)()
+    }
+    /// The number of inodes the inode area holds
+    pub fn inode_count(&self) -> u32 {
+        self.inode_count
+    }
+    /// The number of free inodes
+    pub fn free_inodes(&self) -> u32 {
+        self.inode_count
+            - self
+                .inode_bitmap
+                .count_allocated(&self.block_device, self.inode_count as usize) as u32
+    }
+    /// The number of blocks in data area
+    pub fn data_blocks(&self) -> u32 {
+        self.data_area_blocks
+    }
+    /// The number of free blocks in data area
+    pub fn free_data_blocks(&self) -> u32 {
+        self.data_area_blocks
+            - self
+                .data_bitmap
+                .count_allocated(&self.block_device, self.data_area_blocks as usize)
+                as u32
     }
     /// Get the root inode
     pub fn root_inode(efs: &Arc<Mutex<Self>>) -> Inode {
@@ -130,28 +365,77 @@ impl EasyFileSystem {
             (inode_id % inodes_per_block) as usize * inode_size,
         )
     }
+    /// Get the inode id according to its block position, the inverse of `get_disk_inode_pos`
+    pub fn get_inode_id(&self, block_id: u32, block_offset: usize) -> u32 {
+        let inode_size = core::mem::size_of::<DiskInode>();
+        let inodes_per_block = (BLOCK_SZ / inode_size) as u32;
+        (block_id - self.inode_area_start_block) * inodes_per_block
+            + (block_offset / inode_size) as u32
+    }
     /// Get data block position according to the data block id
     pub fn get_data_block_id(&self, data_block_id: u32) -> u32 {
         self.data_area_start_block + data_block_id
     }
     /// allocate a new inode, return its inode_id
     pub fn alloc_inode(&mut self) -> u32 {
-        self.inode_bitmap.alloc(&self.block_device).unwrap() as u32
+        let inode_id = self.inode_bitmap.alloc(&self.block_device).unwrap() as u32;
+        // the bitmap may cover more inodes than an upgraded inode area holds
+        assert!(inode_id < self.inode_count, "No free inode!");
+        inode_id
+    }
+    /// deallocate an inode according to its inode_id
+    pub fn dealloc_inode(&mut self, inode_id: u32) {
+        self.inode_bitmap
+            .dealloc(&self.block_device, inode_id as usize)
     }
 
-    /// allocate a new data block, return its block position (block_id)
-    pub fn alloc_data(&mut self) -> u32 {
-        self.data_bitmap.alloc(&self.block_device).unwrap() as u32 + self.data_area_start_block
+    /// allocate a new data block, return its block position (block_id),
+    /// `None` if the data area is full
+    pub fn alloc_data(&mut self) -> Option<u32> {
+        let data_block_id = self.data_bitmap.alloc(&self.block_device)? as u32;
+        // the bitmap may have more bits than the data area has blocks
+        if data_block_id >= self.data_area_blocks {
+            self.data_bitmap
+                .dealloc(&self.block_device, data_block_id as usize);
+            return None;
+        }
+        Some(data_block_id + self.data_area_start_block)
+    }
+    /// allocate at most 'len' consecutive data blocks, return the run, `None`
+    /// if the data area is full
+    ///
+    /// The run starts at block 'goal' if it is free, see [`Bitmap::alloc_run`].
+    pub fn alloc_data_run(&mut self, goal: Option<u32>, len: u32) -> Option<Extent> {
+        let data_area =
+            self.data_area_start_block..self.data_area_start_block + self.data_area_blocks;
+        let goal = goal
+            .filter(|goal| data_area.contains(goal))
+            .map(|goal| (goal - self.data_area_start_block) as usize);
+        let (start, len) = self.data_bitmap.alloc_run(
+            &self.block_device,
+            goal,
+            len as usize,
+            self.data_area_blocks as usize,
+        )?;
+        Some(Extent {
+            start: start as u32 + self.data_area_start_block,
+            len: len as u32,
+        })
     }
     /// deallocate a data block according to its block id
     pub fn dealloc_data(&mut self, block_id: u32) {
-        get_block_cache(block_id as usize, Arc::clone(&self.block_device))
-            .lock()
-            .modify(0, |data_block: &mut DataBlock| {
-                data_block.iter_mut().for_each(|p| {
-                    *p = 0;
-                })
-            });
+        // the content of a freed block is not journaled, but zeroed on disk
+        // right away, before the transaction freeing it commits
+        let journaling = set_journaling(false);
+        let block_cache = get_block_cache(block_id as usize, Arc::clone(&self.block_device));
+        let mut block_cache = block_cache.lock();
+        block_cache.modify(0, |data_block: &mut DataBlock| {
+            data_block.iter_mut().for_each(|p| {
+                *p = 0;
+            })
+        });
+        block_cache.sync();
+        set_journaling(journaling);
         self.data_bitmap.dealloc(
             &self.block_device,
             (block_id - self.data_area_start_block) as usize,
@@ -0,0 +1,540 @@
+//! File system checker
+//!
+//! [`check`] walks the directory tree from the root inode, collects the
+//! blocks of every inode it reaches, and cross-checks what it finds against
+//! the inode and data bitmaps and the link counts. It reads the disk only
+//! through block pointers it has checked, so a damaged image cannot make it
+//! read outside the device.
+
+use super::block_cache::{block_cache_sync_all, get_block_cache};
+use super::layout::{
+    ExtentBlock, IndirectBlock, EXTENT_BLOCK_COUNT, INDIRECT1_BOUND, INODE_DIRECT_COUNT,
+    INODE_EXTENT_COUNT, INODE_INDIRECT1_COUNT, INODE_INDIRECT2_COUNT,
+};
+use super::{DirEntry, DiskInode, EasyFileSystem, Extent, DIRENT_SZ};
+use alloc::collections::btree_map::Entry;
+use alloc::collections::{BTreeMap, BTreeSet};
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::fmt::{Display, Formatter, Result};
+use core::ops::Range;
+use spin::Mutex;
+
+/// A problem found by [`check`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Problem {
+    /// a directory entry leads to an inode which is not allocated
+    DanglingEntry {
+        /// inode id of the directory
+        dir: u32,
+        /// name of the entry
+        name: String,
+        /// inode id the entry leads to
+        inode_id: u32,
+    },
+    /// an allocated inode no directory entry leads to
+    UnreachableInode {
+        /// inode id
+        inode_id: u32,
+    },
+    /// the link count of an inode is not the number of entries leading to it
+    WrongLinkCount {
+        /// inode id
+        inode_id: u32,
+        /// link count on disk
+        nlink: u32,
+        /// number of entries found
+        entries: u32,
+    },
+    /// a directory is reached through more than one entry besides "." and ".."
+    LinkedDirectory {
+        /// inode id
+        inode_id: u32,
+    },
+    /// an inode points to a block outside the data area
+    BadBlock {
+        /// inode id
+        inode_id: u32,
+        /// block id
+        block_id: u32,
+    },
+    /// a block is used by more than one inode, or twice by one inode
+    DuplicateBlock {
+        /// inode id of the later user
+        inode_id: u32,
+        /// block id
+        block_id: u32,
+    },
+    /// a block in use is free in the data bitmap
+    FreeBlockInUse {
+        /// inode id
+        inode_id: u32,
+        /// block id
+        block_id: u32,
+    },
+    /// a block allocated in the data bitmap which no inode uses
+    LeakedBlock {
+        /// block id
+        block_id: u32,
+    },
+    /// the size of an inode does not match its block pointers
+    SizeMismatch {
+        /// inode id
+        inode_id: u32,
+        /// size on disk
+        size: u32,
+    },
+    /// a directory entry has a name which is not UTF-8 or holds '/' or '\0'
+    BadName {
+        /// inode id of the directory
+        dir: u32,
+        /// the bytes of the name
+        name: Vec<u8>,
+    },
+}
+
+impl Problem {
+    /// Can [`check`] repair the problem?
+    pub fn is_repairable(&self) -> bool {
+        matches!(
+            self,
+            Problem::DanglingEntry { .. }
+                | Problem::UnreachableInode { .. }
+                | Problem::WrongLinkCount { .. }
+                | Problem::FreeBlockInUse { .. }
+                | Problem::LeakedBlock { .. }
+        )
+    }
+}
+
+impl Display for Problem {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        match self {
+            Problem::DanglingEntry {
+                dir,
+                name,
+                inode_id,
+            } => write!(
+                f,
+                "entry {:?} of directory {} leads to free inode {}",
+                name, dir, inode_id
+            ),
+            Problem::UnreachableInode { inode_id } => {
+                write!(f, "inode {} is allocated but unreachable", inode_id)
+            }
+            Problem::WrongLinkCount {
+                inode_id,
+                nlink,
+                entries,
+            } => write!(
+                f,
+                "inode {} has link count {} but {} entries",
+                inode_id, nlink, entries
+            ),
+            Problem::LinkedDirectory { inode_id } => {
+                write!(f, "directory {} has more than one parent", inode_id)
+            }
+            Problem::BadBlock { inode_id, block_id } => write!(
+                f,
+                "inode {} points to block {} outside the data area",
+                inode_id, block_id
+            ),
+            Problem::DuplicateBlock { inode_id, block_id } => write!(
+                f,
+                "block {} of inode {} is already in use",
+                block_id, inode_id
+            ),
+            Problem::FreeBlockInUse { inode_id, block_id } => write!(
+                f,
+                "block {} of inode {} is free in the data bitmap",
+                block_id, inode_id
+            ),
+            Problem::LeakedBlock { block_id } => {
+                write!(f, "block {} is allocated but unused", block_id)
+            }
+            Problem::SizeMismatch { inode_id, size } => write!(
+                f,
+                "size {} of inode {} does not match its blocks",
+                size, inode_id
+            ),
+            Problem::BadName { dir, name } => write!(
+                f,
+                "entry {:?} of directory {} has a bad name",
+                String::from_utf8_lossy(name),
+                dir
+            ),
+        }
+    }
+}
+
+/// Check the file system, and repair what can be repaired if 'repair' is set.
+///
+/// Return all problems found, including the repaired ones.
+pub fn check(efs: &Arc<Mutex<EasyFileSystem>>, repair: bool) -> Vec<Problem> {
+    let fs = efs.lock();
+    let mut checker = Checker::new(&fs);
+    checker.walk();
+    checker.check_links();
+    checker.check_bitmaps();
+    if repair {
+        checker.repair();
+        block_cache_sync_all();
+    }
+    checker.problems
+}
+
+struct Checker<'a> {
+    fs: &'a EasyFileSystem,
+    /// block ids of the data area
+    data_area: Range<u32>,
+    /// inodes reached from the root
+    reached: Vec<bool>,
+    /// number of entries leading to each inode
+    entries: Vec<u32>,
+    /// the inode using each block
+    owners: BTreeMap<u32, u32>,
+    /// (directory, head slot, name length) of the dangling entries
+    dangling: Vec<(u32, usize, usize)>,
+    problems: Vec<Problem>,
+}
+
+/// read the disk inode with 'inode_id' with 'f' function
+fn read_disk_inode<V>(fs: &EasyFileSystem, inode_id: u32, f: impl FnOnce(&DiskInode) -> V) -> V {
+    let (block_id, block_offset) = fs.get_disk_inode_pos(inode_id);
+    get_block_cache(block_id as usize, Arc::clone(&fs.block_device))
+        .lock()
+        .read(block_offset, f)
+}
+
+/// modify the disk inode with 'inode_id' with 'f' function
+fn modify_disk_inode<V>(
+    fs: &EasyFileSystem,
+    inode_id: u32,
+    f: impl FnOnce(&mut DiskInode) -> V,
+) -> V {
+    let (block_id, block_offset) = fs.get_disk_inode_pos(inode_id);
+    get_block_cache(block_id as usize, Arc::clone(&fs.block_device))
+        .lock()
+        .modify(block_offset, f)
+}
+
+impl<'a> Checker<'a> {
+    fn new(fs: &'a EasyFileSystem) -> Self {
+        let inode_count = fs.inode_count as usize;
+        Self {
+            fs,
+            data_area: fs.data_area_start_block..fs.data_area_start_block + fs.data_area_blocks,
+            reached: vec![false; inode_count],
+            entries: vec![0; inode_count],
+            owners: BTreeMap::new(),
+            dangling: Vec::new(),
+            problems: Vec::new(),
+        }
+    }
+    /// walk the directory tree from the root
+    fn walk(&mut self) {
+        self.reached[0] = true;
+        let mut dirs = Vec::new();
+        if self.visit(0) {
+            dirs.push(0);
+        }
+        while let Some(dir) = dirs.pop() {
+            let mut dirents = Vec::new();
+            read_disk_inode(self.fs, dir, |disk_inode| {
+                disk_inode.find_raw_dirent_from(
+                    0,
+                    &self.fs.block_device,
+                    |index, name, inode_id| {
+                        dirents.push((index, Vec::from(name), inode_id));
+                        None::<()>
+                    },
+                )
+            });
+            for (index, raw_name, inode_id) in dirents {
+                let name = String::from(String::from_utf8_lossy(&raw_name));
+                if core::str::from_utf8(&raw_name).is_err() || name.contains(['/', '\0']) {
+                    self.problems.push(Problem::BadName {
+                        dir,
+                        name: raw_name.clone(),
+                    });
+                }
+                if inode_id >= self.fs.inode_count
+                    || !self
+                        .fs
+                        .inode_bitmap
+                        .is_allocated(&self.fs.block_device, inode_id as usize)
+                {
+                    self.dangling.push((dir, index, raw_name.len()));
+                    self.problems.push(Problem::DanglingEntry {
+                        dir,
+                        name,
+                        inode_id,
+                    });
+                    continue;
+                }
+                self.entries[inode_id as usize] += 1;
+                if name == "." || name == ".." {
+                    continue;
+                }
+                if self.reached[inode_id as usize] {
+                    if read_disk_inode(self.fs, inode_id, |disk_inode| disk_inode.is_dir()) {
+                        self.problems.push(Problem::LinkedDirectory { inode_id });
+                    }
+                    continue;
+                }
+                self.reached[inode_id as usize] = true;
+                if self.visit(inode_id) {
+                    dirs.push(inode_id);
+                }
+            }
+        }
+    }
+    /// take the blocks of a newly reached inode, return whether its entries can be read
+    fn visit(&mut self, inode_id: u32) -> bool {
+        let fs = self.fs;
+        let (blocks, intact, is_dir) = read_disk_inode(fs, inode_id, |disk_inode| {
+            let (blocks, intact) = self.inode_blocks(inode_id, disk_inode);
+            (blocks, intact, disk_inode.is_dir())
+        });
+        for block_id in blocks {
+            match self.owners.entry(block_id) {
+                Entry::Occupied(_) => self
+                    .problems
+                    .push(Problem::DuplicateBlock { inode_id, block_id }),
+                Entry::Vacant(entry) => {
+                    entry.insert(inode_id);
+                }
+            }
+        }
+        is_dir && intact
+    }
+    /// the blocks in the data area an inode points to, including its index
+    /// blocks, and whether all of its block pointers are sound
+    fn inode_blocks(&mut self, inode_id: u32, disk_inode: &DiskInode) -> (Vec<u32>, bool) {
+        if disk_inode.is_extent_mapped() {
+            return self.extent_blocks(inode_id, disk_inode);
+        }
+        let fs = self.fs;
+        let mut blocks = Vec::new();
+        let problems_before = self.problems.len();
+        let mut size_matches = !disk_inode.is_dir() || disk_inode.size as usize % DIRENT_SZ == 0;
+        let mut data_blocks = disk_inode.data_blocks() as usize;
+        if data_blocks > INDIRECT1_BOUND + INODE_INDIRECT2_COUNT {
+            size_matches = false;
+            data_blocks = INDIRECT1_BOUND + INODE_INDIRECT2_COUNT;
+        }
+        let (data_area, problems) = (self.data_area.clone(), &mut self.problems);
+        let mut take = |block_id: u32| {
+            if data_area.contains(&block_id) {
+                blocks.push(block_id);
+                true
+            } else {
+                problems.push(Problem::BadBlock { inode_id, block_id });
+                false
+            }
+        };
+        let read_index = |block_id: u32| {
+            get_block_cache(block_id as usize, Arc::clone(&fs.block_device))
+                .lock()
+                .read(0, |index_block: &IndirectBlock| *index_block)
+        };
+        // direct
+        for (i, block_id) in disk_inode.direct.iter().enumerate() {
+            if i < data_blocks {
+                take(*block_id);
+            } else if *block_id != 0 {
+                size_matches = false;
+            }
+        }
+        // indirect1
+        let indirect1_blocks = data_blocks
+            .saturating_sub(INODE_DIRECT_COUNT)
+            .min(INODE_INDIRECT1_COUNT);
+        if indirect1_blocks > 0 {
+            if take(disk_inode.indirect1) {
+                for block_id in read_index(disk_inode.indirect1)[..indirect1_blocks].iter() {
+                    take(*block_id);
+                }
+            }
+        } else if disk_inode.indirect1 != 0 {
+            size_matches = false;
+        }
+        // indirect2
+        let indirect2_blocks = data_blocks.saturating_sub(INDIRECT1_BOUND);
+        if indirect2_blocks > 0 {
+            if take(disk_inode.indirect2) {
+                let indirect2 = read_index(disk_inode.indirect2);
+                let count = (indirect2_blocks + INODE_INDIRECT1_COUNT - 1) / INODE_INDIRECT1_COUNT;
+                for (i, indirect1) in indirect2[..count].iter().enumerate() {
+                    if take(*indirect1) {
+                        let last = (indirect2_blocks - i * INODE_INDIRECT1_COUNT)
+                            .min(INODE_INDIRECT1_COUNT);
+                        for block_id in read_index(*indirect1)[..last].iter() {
+                            take(*block_id);
+                        }
+                    }
+                }
+            }
+        } else if disk_inode.indirect2 != 0 {
+            size_matches = false;
+        }
+        if !size_matches {
+            self.problems.push(Problem::SizeMismatch {
+                inode_id,
+                size: disk_inode.size,
+            });
+        }
+        (blocks, self.problems.len() == problems_before)
+    }
+    /// the blocks in the data area an extent-mapped inode points to,
+    /// including its extent blocks, and whether all of its extents are sound
+    fn extent_blocks(&mut self, inode_id: u32, disk_inode: &DiskInode) -> (Vec<u32>, bool) {
+        let fs = self.fs;
+        let mut blocks = Vec::new();
+        let problems_before = self.problems.len();
+        let mut size_matches = (!disk_inode.is_dir() || disk_inode.size as usize % DIRENT_SZ == 0)
+            && disk_inode.indirect2 == 0;
+        let (data_area, problems) = (self.data_area.clone(), &mut self.problems);
+        let mut take = |extent: Extent| {
+            let end = extent.start as u64 + extent.len as u64;
+            if data_area.contains(&extent.start) && end <= data_area.end as u64 {
+                blocks.extend(extent.start..extent.start + extent.len);
+                true
+            } else {
+                problems.push(Problem::BadBlock {
+                    inode_id,
+                    block_id: extent.start,
+                });
+                false
+            }
+        };
+        let mut data_blocks = 0u64;
+        // the extents in the inode, the used ones first
+        let mut used = true;
+        for pair in disk_inode.direct.chunks(2).take(INODE_EXTENT_COUNT) {
+            let extent = Extent {
+                start: pair[0],
+                len: pair[1],
+            };
+            if extent.len == 0 {
+                used = false;
+                size_matches &= extent.start == 0;
+            } else if used {
+                take(extent);
+                data_blocks += extent.len as u64;
+            } else {
+                size_matches = false;
+            }
+        }
+        // the chain of extent blocks, which must not run into itself
+        let mut extent_blocks = BTreeSet::new();
+        let mut next = disk_inode.indirect1;
+        while next != 0 {
+            if !take(Extent {
+                start: next,
+                len: 1,
+            }) || !extent_blocks.insert(next)
+            {
+                break;
+            }
+            let extent_block = get_block_cache(next as usize, Arc::clone(&fs.block_device))
+                .lock()
+                .read(0, |extent_block: &ExtentBlock| *extent_block);
+            if extent_block.count as usize > EXTENT_BLOCK_COUNT {
+                size_matches = false;
+                break;
+            }
+            for extent in extent_block.extents[..extent_block.count as usize].iter() {
+                take(*extent);
+                data_blocks += extent.len as u64;
+            }
+            next = extent_block.next;
+        }
+        if data_blocks != disk_inode.data_blocks() as u64 {
+            size_matches = false;
+        }
+        if !size_matches {
+            self.problems.push(Problem::SizeMismatch {
+                inode_id,
+                size: disk_inode.size,
+            });
+        }
+        (blocks, self.problems.len() == problems_before)
+    }
+    /// compare the link count of each reached inode with the entries leading to it
+    fn check_links(&mut self) {
+        for inode_id in 0..self.fs.inode_count {
+            if !self.reached[inode_id as usize] {
+                continue;
+            }
+            let nlink = read_disk_inode(self.fs, inode_id, |disk_inode| disk_inode.nlink as u32);
+            let entries = self.entries[inode_id as usize];
+            if nlink != entries {
+                self.problems.push(Problem::WrongLinkCount {
+                    inode_id,
+                    nlink,
+                    entries,
+                });
+            }
+        }
+    }
+    /// compare the bitmaps with the reached inodes and the blocks they use
+    fn check_bitmaps(&mut self) {
+        let fs = self.fs;
+        for inode_id in 0..fs.inode_bitmap.maximum() as u32 {
+            if fs
+                .inode_bitmap
+                .is_allocated(&fs.block_device, inode_id as usize)
+                && !self
+                    .reached
+                    .get(inode_id as usize)
+                    .copied()
+                    .unwrap_or(false)
+            {
+                self.problems.push(Problem::UnreachableInode { inode_id });
+            }
+        }
+        for bit in 0..fs.data_bitmap.maximum() as u32 {
+            let block_id = self.data_area.start + bit;
+            let allocated = fs.data_bitmap.is_allocated(&fs.block_device, bit as usize);
+            match (allocated, self.owners.get(&block_id)) {
+                (true, None) => self.problems.push(Problem::LeakedBlock { block_id }),
+                (false, Some(&inode_id)) => self
+                    .problems
+                    .push(Problem::FreeBlockInUse { inode_id, block_id }),
+                _ => {}
+            }
+        }
+    }
+    /// fix the repairable problems
+    fn repair(&self) {
+        let fs = self.fs;
+        for problem in self.problems.iter() {
+            match *problem {
+                Problem::UnreachableInode { inode_id } => {
+                    fs.inode_bitmap.dealloc(&fs.block_device, inode_id as usize)
+                }
+                Problem::WrongLinkCount {
+                    inode_id, entries, ..
+                } => modify_disk_inode(fs, inode_id, |disk_inode| {
+                    disk_inode.nlink = entries as u16;
+                }),
+                Problem::FreeBlockInUse { block_id, .. } => fs
+                    .data_bitmap
+                    .set(&fs.block_device, (block_id - self.data_area.start) as usize),
+                Problem::LeakedBlock { block_id } => fs
+                    .data_bitmap
+                    .dealloc(&fs.block_device, (block_id - self.data_area.start) as usize),
+                _ => {}
+            }
+        }
+        for (dir, index, name_len) in self.dangling.iter() {
+            let empty = vec![0u8; DirEntry::slots_for(*name_len) * DIRENT_SZ];
+            modify_disk_inode(fs, *dir, |disk_inode| {
+                disk_inode.write_at(index * DIRENT_SZ, &empty, &fs.block_device);
+            });
+        }
+    }
+}
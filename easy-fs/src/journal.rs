@@ -0,0 +1,107 @@
+//! Journal layer
+//!
+//! The journal makes the block modifications of one file system operation
+//! reach the disk all or not at all. While a transaction is open, modified
+//! block caches are journaled and stay in memory. The commit writes them to
+//! the journal region first, then the [`JournalHeader`] listing them, and only
+//! then to their own blocks. After a crash, [`Journal::replay`] writes the
+//! blocks of a committed transaction again.
+//!
+//! Only metadata is journaled: file content is written back lazily, when
+//! its block cache is evicted or synced. A crash may lose content written
+//! since, but never shows stale content of other files, as freed blocks are
+//! zeroed on disk before the commit freeing them completes.
+//!
+//! A file grows or shrinks by a few blocks per transaction, so that one
+//! transaction never logs more than [`JOURNAL_CAPACITY`] blocks. A crash
+//! while a file is resized by many blocks may leave it at a size in between.
+//!
+//! The block caches and the flag telling whether a transaction is open are
+//! shared by all devices, so only one journaled image may be open at a time.
+
+use super::block_cache::{block_cache_sync_all, journaled_block_caches, set_journaling};
+use super::{get_block_cache, BlockDevice, JournalHeader, BLOCK_SZ, JOURNAL_CAPACITY};
+use alloc::sync::Arc;
+
+type DataBlock = [u8; BLOCK_SZ];
+
+/// The journal region of an easy-fs image
+pub struct Journal {
+    /// The block id of the journal header, the logged blocks follow it
+    start_block: u32,
+    /// The block device
+    block_device: Arc<dyn BlockDevice>,
+}
+
+impl Journal {
+    /// Create the journal starting at 'start_block'
+    pub fn new(start_block: u32, block_device: Arc<dyn BlockDevice>) -> Self {
+        Self {
+            start_block,
+            block_device,
+        }
+    }
+    /// Open a transaction, block caches modified from now on are journaled
+    pub fn begin(&self) {
+        assert!(!set_journaling(true), "Nested transaction!");
+    }
+    /// Commit the open transaction and write its blocks back
+    ///
+    /// Block caches modified outside the transaction, like file content,
+    /// are left to be written back lazily.
+    pub fn commit(&self) {
+        set_journaling(false);
+        let caches = journaled_block_caches();
+        if caches.is_empty() {
+            return;
+        }
+        assert!(
+            caches.len() <= JOURNAL_CAPACITY,
+            "Transaction too large for the journal!"
+        );
+        let mut header = JournalHeader::empty();
+        for (i, (block_id, cache)) in caches.iter().enumerate() {
+            cache.lock().read(0, |data_block: &DataBlock| {
+                self.block_device
+                    .write_block(self.start_block as usize + 1 + i, data_block)
+            });
+            header.block_ids[i] = *block_id as u32;
+        }
+        header.count = caches.len() as u32;
+        self.write_header(&header);
+        for (_, cache) in caches.iter() {
+            cache.lock().install();
+        }
+        self.write_header(&JournalHeader::empty());
+    }
+    /// Write the blocks of a transaction committed before a crash back again
+    pub fn replay(&self) {
+        let mut header = JournalHeader::empty();
+        self.block_device
+            .read_block(self.start_block as usize, header.as_bytes_mut());
+        assert!(
+            header.count as usize <= JOURNAL_CAPACITY,
+            "Error loading EFS journal!"
+        );
+        if header.count == 0 {
+            return;
+        }
+        let mut logged = [0u8; BLOCK_SZ];
+        for (i, block_id) in header.block_ids[..header.count as usize].iter().enumerate() {
+            self.block_device
+                .read_block(self.start_block as usize + 1 + i, &mut logged);
+            get_block_cache(*block_id as usize, Arc::clone(&self.block_device))
+                .lock()
+                .modify(0, |data_block: &mut DataBlock| {
+                    data_block.copy_from_slice(&logged)
+                });
+        }
+        block_cache_sync_all();
+        self.write_header(&JournalHeader::empty());
+    }
+    /// Write the journal header to disk, bypassing the block cache
+    fn write_header(&self, header: &JournalHeader) {
+        self.block_device
+            .write_block(self.start_block as usize, header.as_bytes());
+    }
+}
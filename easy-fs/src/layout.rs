@@ -7,21 +7,52 @@
 //!  - inode area with [`DiskInode`]
 //!  - data bitmap
 //!  - data area with DataBlock
+//!  - journal with [`JournalHeader`], absent in images made before version 3
+//!
+//! Inodes made since version 4 map their blocks with [`Extent`]s, runs of
+//! blocks consecutive on disk, and older inodes keep their direct and
+//! indirect blocks.
 
+use super::block_cache::block_cache_prefetch;
 use super::{get_block_cache, BlockDevice, BLOCK_SZ};
 use alloc::sync::Arc;
 use alloc::vec::Vec;
 use core::fmt::{Debug, Formatter, Result};
 
 const EFS_MAGIC: u32 = 0x3b800001;
-const INODE_DIRECT_COUNT: usize = 28;
-const NAME_LENGTH_LIMIT: usize = 27;
-const INODE_INDIRECT1_COUNT: usize = BLOCK_SZ / 4;
-const INODE_INDIRECT2_COUNT: usize = INODE_INDIRECT1_COUNT * INODE_INDIRECT1_COUNT;
+/// the on-disk format written by this crate, images of older versions are upgraded on open
+pub const EFS_VERSION: u32 = 4;
+/// size of a [`DiskInode`] in version 1 images, which had no ownership, mode or times
+pub const DISK_INODE_V1_SZ: usize = 128;
+pub(crate) const INODE_DIRECT_COUNT: usize = 28;
+/// the most blocks one journal transaction may log
+pub const JOURNAL_CAPACITY: usize = BLOCK_SZ / 4 - 1;
+/// size of the journal of a new image, the header and a block for each logged block
+pub const JOURNAL_BLOCKS: u32 = 1 + JOURNAL_CAPACITY as u32;
+/// size of the version 1 inodes moving into one block of the inode area by an upgrade
+pub(crate) const UPGRADE_SAVED_SZ: usize =
+    BLOCK_SZ / core::mem::size_of::<DiskInode>() * DISK_INODE_V1_SZ;
+/// `upgrade_step` of a version 1 image whose inodes all moved, but whose directories are not upgraded yet
+pub(crate) const UPGRADE_MOVED: u32 = u32::MAX;
+/// the longest name a directory entry holds
+pub const NAME_LENGTH_LIMIT: usize = 255;
+/// the longest name that fits in the head slot of a directory entry
+pub const SHORT_NAME_LIMIT: usize = 27;
+pub(crate) const INODE_INDIRECT1_COUNT: usize = BLOCK_SZ / 4;
+pub(crate) const INODE_INDIRECT2_COUNT: usize = INODE_INDIRECT1_COUNT * INODE_INDIRECT1_COUNT;
 const DIRECT_BOUND: usize = INODE_DIRECT_COUNT;
-const INDIRECT1_BOUND: usize = DIRECT_BOUND + INODE_INDIRECT1_COUNT;
-#[allow(unused)]
+pub(crate) const INDIRECT1_BOUND: usize = DIRECT_BOUND + INODE_INDIRECT1_COUNT;
 const INDIRECT2_BOUND: usize = INDIRECT1_BOUND + INODE_INDIRECT2_COUNT;
+/// the most extents an extent-mapped inode keeps in itself
+pub(crate) const INODE_EXTENT_COUNT: usize = INODE_DIRECT_COUNT / 2;
+/// the most extents an [`ExtentBlock`] holds
+pub(crate) const EXTENT_BLOCK_COUNT: usize = BLOCK_SZ / 8 - 1;
+/// inode flag: the blocks are mapped by extents
+const INODE_EXTENTS: u8 = 1;
+/// the most blocks read from disk with one request by [`DiskInode::read_at`]
+const READ_RUN: u32 = 32;
+/// seconds after which a read records the access time again, see [`DiskInode::needs_access`]
+const RELATIME_INTERVAL: u64 = 24 * 60 * 60;
 
 /// superblock of easy-fs
 #[repr(C)]
@@ -38,6 +69,15 @@ pub struct SuperBlock {
     pub data_bitmap_blocks: u32,
     /// The number of blocks used for data area
     pub data_area_blocks: u32,
+    /// on-disk format version, zero in version 1 images
+    version: u32,
+    /// The number of blocks used for the journal at the end of the disk
+    pub journal_blocks: u32,
+    /// `n + 1` while an upgrade from version 1 rewrites block `n` of the
+    /// inode area, [`UPGRADE_MOVED`] once all are rewritten, zero otherwise
+    pub(crate) upgrade_step: u32,
+    /// the version 1 inodes moving into the block being rewritten
+    pub(crate) upgrade_saved: [u8; UPGRADE_SAVED_SZ],
 }
 
 impl Debug for SuperBlock {
@@ -48,6 +88,8 @@ impl Debug for SuperBlock {
             .field("inode_area_blocks", &self.inode_area_blocks)
             .field("data_bitmap_blocks", &self.data_bitmap_blocks)
             .field("data_area_blocks", &self.data_area_blocks)
+            .field("version", &self.version())
+            .field("journal_blocks", &self.journal_blocks)
             .finish()
     }
 }
@@ -61,6 +103,7 @@ impl SuperBlock {
         inode_area_blocks: u32,
         data_bitmap_blocks: u32,
         data_area_blocks: u32,
+        journal_blocks: u32,
     ) {
         *self = Self {
             magic: EFS_MAGIC,
@@ -69,12 +112,25 @@ impl SuperBlock {
             inode_area_blocks,
             data_bitmap_blocks,
             data_area_blocks,
+            version: EFS_VERSION,
+            journal_blocks,
+            upgrade_step: 0,
+            upgrade_saved: [0; UPGRADE_SAVED_SZ],
         }
     }
     /// Check if the superblock is valid according to the magic number
     pub fn is_valid(&self) -> bool {
         self.magic == EFS_MAGIC
     }
+    /// on-disk format version of the image
+    pub fn version(&self) -> u32 {
+        self.version.max(1)
+    }
+    /// Mark the image as written in the current format, with no upgrade going on
+    pub fn set_current_version(&mut self) {
+        self.version = EFS_VERSION;
+        self.upgrade_step = 0;
+    }
 }
 
 /// Inode Type of easy-fs
@@ -86,22 +142,71 @@ pub enum DiskInodeType {
     Directory,
 }
 
-type IndirectBlock = [u32; BLOCK_SZ / 4];
+pub(crate) type IndirectBlock = [u32; BLOCK_SZ / 4];
 type DataBlock = [u8; BLOCK_SZ];
 
+/// A run of blocks of a file, consecutive on disk
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Extent {
+    /// block id of the first block
+    pub start: u32,
+    /// number of blocks, zero for an unused extent
+    pub len: u32,
+}
+
+impl Extent {
+    /// block id right after the last block
+    pub fn end(&self) -> u32 {
+        self.start + self.len
+    }
+}
+
+/// Block holding the extents of a file which do not fit in its inode
+///
+/// The extent blocks of a file are chained from `indirect1` of its inode.
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub(crate) struct ExtentBlock {
+    /// block id of the next extent block, zero for the last one
+    pub next: u32,
+    /// number of extents used
+    pub count: u32,
+    /// the extents, in file order
+    pub extents: [Extent; EXTENT_BLOCK_COUNT],
+}
+
 /// Inode struct in disk
 #[repr(C)]
 pub struct DiskInode {
     /// file size
     pub size: u32,
-    /// array of direct block id
+    /// array of direct block id, or the first extents as (start, len) pairs if extent-mapped
     pub direct: [u32; INODE_DIRECT_COUNT],
-    /// one-level indirect block id
+    /// one-level indirect block id, or the first extent block if extent-mapped
     pub indirect1: u32,
-    /// two-level indirect block id
+    /// two-level indirect block id, unused if extent-mapped
     pub indirect2: u32,
     /// inode type
     type_: DiskInodeType,
+    /// [`INODE_EXTENTS`] or zero, in the padding of version 1 inodes
+    flags: u8,
+    /// number of directory entries linking to the inode
+    pub nlink: u16,
+    /// user id of the owner
+    pub uid: u32,
+    /// group id of the owner
+    pub gid: u32,
+    /// last access time, in seconds
+    pub atime: u64,
+    /// last modification time of the content, in seconds
+    pub mtime: u64,
+    /// last change time of the content or metadata, in seconds
+    pub ctime: u64,
+    /// permission bits, `0o777` at most
+    pub mode: u16,
+    /// pad the inode to 256 bytes for later use
+    reserved: [u16; 47],
 }
 
 impl DiskInode {
@@ -111,7 +216,32 @@ impl DiskInode {
         self.direct.iter_mut().for_each(|v| *v = 0);
         self.indirect1 = 0;
         self.indirect2 = 0;
+        // a directory is linked from its parent and its own "."
+        (self.nlink, self.mode) = match type_ {
+            DiskInodeType::File => (1, 0o644),
+            DiskInodeType::Directory => (2, 0o755),
+        };
         self.type_ = type_;
+        self.flags = INODE_EXTENTS;
+        self.uid = 0;
+        self.gid = 0;
+        self.atime = 0;
+        self.mtime = 0;
+        self.ctime = 0;
+        self.reserved.iter_mut().for_each(|v| *v = 0);
+    }
+    /// record a change of the content at `now`
+    pub fn touch(&mut self, now: u64) {
+        self.mtime = now;
+        self.ctime = now;
+    }
+    /// whether a read at `now` records the access time, like `relatime`:
+    /// only the first read since a change, or a day after the last one
+    pub fn needs_access(&self, now: u64) -> bool {
+        now != self.atime
+            && (self.atime <= self.mtime
+                || self.atime <= self.ctime
+                || now >= self.atime + RELATIME_INTERVAL)
     }
     /// inode is directory?
     pub fn is_dir(&self) -> bool {
@@ -122,6 +252,58 @@ impl DiskInode {
     pub fn is_file(&self) -> bool {
         self.type_ == DiskInodeType::File
     }
+    /// are the blocks mapped by extents rather than direct and indirect blocks?
+    pub fn is_extent_mapped(&self) -> bool {
+        self.flags & INODE_EXTENTS != 0
+    }
+    /// the extent kept in the inode at 'i'
+    fn inode_extent(&self, i: usize) -> Extent {
+        Extent {
+            start: self.direct[2 * i],
+            len: self.direct[2 * i + 1],
+        }
+    }
+    fn set_inode_extent(&mut self, i: usize, extent: Extent) {
+        self.direct[2 * i] = extent.start;
+        self.direct[2 * i + 1] = extent.len;
+    }
+    /// Visit the extents of an extent-mapped inode in file order until 'f' returns true
+    fn visit_extents(
+        &self,
+        block_device: &Arc<dyn BlockDevice>,
+        mut f: impl FnMut(Extent) -> bool,
+    ) {
+        for i in 0..INODE_EXTENT_COUNT {
+            let extent = self.inode_extent(i);
+            if extent.len == 0 {
+                break;
+            }
+            if f(extent) {
+                return;
+            }
+        }
+        let mut next = self.indirect1;
+        while next != 0 {
+            let extent_block = get_block_cache(next as usize, Arc::clone(block_device))
+                .lock()
+                .read(0, |extent_block: &ExtentBlock| *extent_block);
+            for extent in extent_block.extents[..extent_block.count as usize].iter() {
+                if f(*extent) {
+                    return;
+                }
+            }
+            next = extent_block.next;
+        }
+    }
+    /// All extents of an extent-mapped inode in file order
+    pub fn extents(&self, block_device: &Arc<dyn BlockDevice>) -> Vec<Extent> {
+        let mut v = Vec::new();
+        self.visit_extents(block_device, |extent| {
+            v.push(extent);
+            false
+        });
+        v
+    }
     /// Return block number correspond to size.
     pub fn data_blocks(&self) -> u32 {
         Self::_data_blocks(self.size)
@@ -151,6 +333,25 @@ impl DiskInode {
         assert!(new_size >= self.size);
         Self::total_blocks(new_size) - Self::total_blocks(self.size)
     }
+    /// The most blocks extending to 'new_size' allocates, data and index blocks.
+    ///
+    /// Each run of data blocks may take an extent of its own, so one extent
+    /// block is counted for every few new data blocks, and one more.
+    pub fn max_blocks_needed(&self, new_size: u32) -> u32 {
+        if !self.is_extent_mapped() {
+            return self.blocks_num_needed(new_size);
+        }
+        let data_blocks = Self::_data_blocks(new_size) - self.data_blocks();
+        data_blocks + data_blocks / EXTENT_BLOCK_COUNT as u32 + 1
+    }
+    /// The largest size the file can have, in bytes
+    pub fn max_size(&self) -> usize {
+        if self.is_extent_mapped() {
+            u32::MAX as usize / BLOCK_SZ * BLOCK_SZ
+        } else {
+            INDIRECT2_BOUND * BLOCK_SZ
+        }
+    }
     /// block id of the start block corresponding to the file offset for read_at/write_at.
     pub fn get_block_id(&self, inner_id: u32, block_device: &Arc<dyn BlockDevice>) -> u32 {
         let inner_id = inner_id as usize;
@@ -176,6 +377,129 @@ impl DiskInode {
                 })
         }
     }
+    /// The block holding block 'inner_id' of the file, and how many blocks
+    /// of the file follow it consecutively on disk, 'max' at most.
+    fn get_block_run(
+        &self,
+        inner_id: u32,
+        max: u32,
+        block_device: &Arc<dyn BlockDevice>,
+    ) -> (u32, u32) {
+        if !self.is_extent_mapped() {
+            let block_id = self.get_block_id(inner_id, block_device);
+            let mut len = 1;
+            while len < max && self.get_block_id(inner_id + len, block_device) == block_id + len {
+                len += 1;
+            }
+            return (block_id, len);
+        }
+        let (mut first, mut run) = (0, None);
+        self.visit_extents(block_device, |extent| {
+            if inner_id < first + extent.len {
+                let skip = inner_id - first;
+                run = Some((extent.start + skip, (extent.len - skip).min(max)));
+                true
+            } else {
+                first += extent.len;
+                false
+            }
+        });
+        run.unwrap()
+    }
+    /// increase file length to new_size, mapping the new blocks with extents.
+    ///
+    /// 'alloc' allocates a run of at most the given number of blocks,
+    /// starting at the given block if it can, and returns the run.
+    pub fn increase_size_extents(
+        &mut self,
+        new_size: u32,
+        alloc: &mut dyn FnMut(Option<u32>, u32) -> Extent,
+        block_device: &Arc<dyn BlockDevice>,
+    ) {
+        let mut needed = Self::_data_blocks(new_size) - self.data_blocks();
+        self.size = new_size;
+        let mut goal = None;
+        self.visit_extents(block_device, |extent| {
+            goal = Some(extent.end());
+            false
+        });
+        while needed > 0 {
+            let extent = alloc(goal, needed);
+            self.push_extent(extent, alloc, block_device);
+            goal = Some(extent.end());
+            needed -= extent.len;
+        }
+    }
+    /// append 'extent' to the extents, merged into the last one if it continues it
+    fn push_extent(
+        &mut self,
+        extent: Extent,
+        alloc: &mut dyn FnMut(Option<u32>, u32) -> Extent,
+        block_device: &Arc<dyn BlockDevice>,
+    ) {
+        if self.indirect1 == 0 {
+            let used = (0..INODE_EXTENT_COUNT)
+                .take_while(|i| self.inode_extent(*i).len > 0)
+                .count();
+            if used > 0 && self.inode_extent(used - 1).end() == extent.start {
+                let mut last = self.inode_extent(used - 1);
+                last.len += extent.len;
+                self.set_inode_extent(used - 1, last);
+            } else if used < INODE_EXTENT_COUNT {
+                self.set_inode_extent(used, extent);
+            } else {
+                self.indirect1 = Self::new_extent_block(extent, alloc, block_device);
+            }
+            return;
+        }
+        let mut block_id = self.indirect1;
+        loop {
+            let next = get_block_cache(block_id as usize, Arc::clone(block_device))
+                .lock()
+                .read(0, |extent_block: &ExtentBlock| extent_block.next);
+            if next == 0 {
+                break;
+            }
+            block_id = next;
+        }
+        let full = get_block_cache(block_id as usize, Arc::clone(block_device))
+            .lock()
+            .modify(0, |extent_block: &mut ExtentBlock| {
+                let count = extent_block.count as usize;
+                if count > 0 && extent_block.extents[count - 1].end() == extent.start {
+                    extent_block.extents[count - 1].len += extent.len;
+                } else if count < EXTENT_BLOCK_COUNT {
+                    extent_block.extents[count] = extent;
+                    extent_block.count += 1;
+                } else {
+                    return true;
+                }
+                false
+            });
+        if full {
+            let next = Self::new_extent_block(extent, alloc, block_device);
+            get_block_cache(block_id as usize, Arc::clone(block_device))
+                .lock()
+                .modify(0, |extent_block: &mut ExtentBlock| extent_block.next = next);
+        }
+    }
+    /// allocate an extent block holding nothing but 'extent'
+    fn new_extent_block(
+        extent: Extent,
+        alloc: &mut dyn FnMut(Option<u32>, u32) -> Extent,
+        block_device: &Arc<dyn BlockDevice>,
+    ) -> u32 {
+        let block_id = alloc(None, 1).start;
+        get_block_cache(block_id as usize, Arc::clone(block_device))
+            .lock()
+            .modify(0, |extent_block: &mut ExtentBlock| {
+                extent_block.next = 0;
+                extent_block.count = 1;
+                extent_block.extents = [Extent::default(); EXTENT_BLOCK_COUNT];
+                extent_block.extents[0] = extent;
+            });
+        block_id
+    }
     /// increase file length to new_size and allocate new blocks.
     pub fn increase_size(
         &mut self,
@@ -255,6 +579,24 @@ impl DiskInode {
     /// We will clear the block contents to zero later.
     pub fn clear_size(&mut self, block_device: &Arc<dyn BlockDevice>) -> Vec<u32> {
         let mut v: Vec<u32> = Vec::new();
+        if self.is_extent_mapped() {
+            self.visit_extents(block_device, |extent| {
+                v.extend(extent.start..extent.end());
+                false
+            });
+            // extent blocks
+            let mut next = self.indirect1;
+            while next != 0 {
+                v.push(next);
+                next = get_block_cache(next as usize, Arc::clone(block_device))
+                    .lock()
+                    .read(0, |extent_block: &ExtentBlock| extent_block.next);
+            }
+            self.size = 0;
+            self.direct.iter_mut().for_each(|v| *v = 0);
+            self.indirect1 = 0;
+            return v;
+        }
         let mut data_blocks = self.data_blocks() as usize;
         self.size = 0;
         let mut current_blocks = 0usize;
@@ -275,7 +617,7 @@ impl DiskInode {
         // indirect1
         get_block_cache(self.indirect1 as usize, Arc::clone(block_device))
             .lock()
-            .modify(0, |indirect1: &mut IndirectBlock| {
+            .read(0, |indirect1: &IndirectBlock| {
                 while current_blocks < data_blocks.min(INODE_INDIRECT1_COUNT) {
                     v.push(indirect1[current_blocks]);
                     //indirect1[current_blocks] = 0;
@@ -296,13 +638,13 @@ impl DiskInode {
         let b1 = data_blocks % INODE_INDIRECT1_COUNT;
         get_block_cache(self.indirect2 as usize, Arc::clone(block_device))
             .lock()
-            .modify(0, |indirect2: &mut IndirectBlock| {
+            .read(0, |indirect2: &IndirectBlock| {
                 // full indirect1 blocks
-                for entry in indirect2.iter_mut().take(a1) {
+                for entry in indirect2.iter().take(a1) {
                     v.push(*entry);
                     get_block_cache(*entry as usize, Arc::clone(block_device))
                         .lock()
-                        .modify(0, |indirect1: &mut IndirectBlock| {
+                        .read(0, |indirect1: &IndirectBlock| {
                             for entry in indirect1.iter() {
                                 v.push(*entry);
                             }
@@ -313,7 +655,7 @@ impl DiskInode {
                     v.push(indirect2[a1]);
                     get_block_cache(indirect2[a1] as usize, Arc::clone(block_device))
                         .lock()
-                        .modify(0, |indirect1: &mut IndirectBlock| {
+                        .read(0, |indirect1: &IndirectBlock| {
                             for entry in indirect1.iter().take(b1) {
                                 v.push(*entry);
                             }
@@ -324,7 +666,133 @@ impl DiskInode {
         self.indirect2 = 0;
         v
     }
+    /// Cut the file down to 'new_size' and return the blocks that should be deallocated.
+    ///
+    /// The entries of the blocks past the new end are cleared, the bytes of
+    /// the last block kept are left as they are.
+    pub fn decrease_size(
+        &mut self,
+        new_size: u32,
+        block_device: &Arc<dyn BlockDevice>,
+    ) -> Vec<u32> {
+        assert!(new_size <= self.size);
+        let keep = Self::_data_blocks(new_size);
+        let data_blocks = self.data_blocks();
+        self.size = new_size;
+        let mut v: Vec<u32> = Vec::new();
+        if self.is_extent_mapped() {
+            // the file block each extent starts at
+            let mut first = 0;
+            for i in 0..INODE_EXTENT_COUNT {
+                let mut extent = self.inode_extent(i);
+                let len = extent.len;
+                Self::cut_extent(&mut extent, first, keep, &mut v);
+                self.set_inode_extent(i, extent);
+                first += len;
+            }
+            let mut chain = Vec::new();
+            let mut next = self.indirect1;
+            while next != 0 {
+                chain.push(next);
+                next = get_block_cache(next as usize, Arc::clone(block_device))
+                    .lock()
+                    .read(0, |extent_block: &ExtentBlock| extent_block.next);
+            }
+            // extent blocks left empty are freed without being modified
+            let mut last_kept = None;
+            for block_id in chain {
+                let mut extent_block = get_block_cache(block_id as usize, Arc::clone(block_device))
+                    .lock()
+                    .read(0, |extent_block: &ExtentBlock| *extent_block);
+                let mut cut = false;
+                for extent in extent_block.extents[..extent_block.count as usize].iter_mut() {
+                    let len = extent.len;
+                    Self::cut_extent(extent, first, keep, &mut v);
+                    cut |= extent.len != len;
+                    first += len;
+                }
+                let count = extent_block.extents[..extent_block.count as usize]
+                    .iter()
+                    .take_while(|extent| extent.len > 0)
+                    .count();
+                if count == 0 {
+                    v.push(block_id);
+                    continue;
+                }
+                last_kept = Some(block_id);
+                if cut {
+                    extent_block.count = count as u32;
+                    get_block_cache(block_id as usize, Arc::clone(block_device))
+                        .lock()
+                        .modify(0, |on_disk: &mut ExtentBlock| *on_disk = extent_block);
+                }
+            }
+            match last_kept {
+                Some(block_id) => get_block_cache(block_id as usize, Arc::clone(block_device))
+                    .lock()
+                    .modify(0, |extent_block: &mut ExtentBlock| extent_block.next = 0),
+                None => self.indirect1 = 0,
+            }
+            return v;
+        }
+        for inner_id in keep..data_blocks {
+            v.push(self.get_block_id(inner_id, block_device));
+        }
+        let (keep, data_blocks) = (keep as usize, data_blocks as usize);
+        // the indirect1 blocks under indirect2 left empty
+        if data_blocks > INDIRECT1_BOUND {
+            let from = (keep.saturating_sub(INDIRECT1_BOUND) + INODE_INDIRECT1_COUNT - 1)
+                / INODE_INDIRECT1_COUNT;
+            let to =
+                (data_blocks - INDIRECT1_BOUND + INODE_INDIRECT1_COUNT - 1) / INODE_INDIRECT1_COUNT;
+            let last_kept = get_block_cache(self.indirect2 as usize, Arc::clone(block_device))
+                .lock()
+                .modify(0, |indirect2: &mut IndirectBlock| {
+                    v.extend_from_slice(&indirect2[from..to]);
+                    indirect2[from..to].fill(0);
+                    indirect2[from.saturating_sub(1)]
+                });
+            if keep <= INDIRECT1_BOUND {
+                v.push(self.indirect2);
+                self.indirect2 = 0;
+            } else if (keep - INDIRECT1_BOUND) % INODE_INDIRECT1_COUNT != 0 {
+                get_block_cache(last_kept as usize, Arc::clone(block_device))
+                    .lock()
+                    .modify(0, |indirect1: &mut IndirectBlock| {
+                        indirect1[(keep - INDIRECT1_BOUND) % INODE_INDIRECT1_COUNT..].fill(0)
+                    });
+            }
+        }
+        if data_blocks > INODE_DIRECT_COUNT {
+            if keep <= INODE_DIRECT_COUNT {
+                v.push(self.indirect1);
+                self.indirect1 = 0;
+            } else if keep < INDIRECT1_BOUND {
+                get_block_cache(self.indirect1 as usize, Arc::clone(block_device))
+                    .lock()
+                    .modify(0, |indirect1: &mut IndirectBlock| {
+                        indirect1[keep - INODE_DIRECT_COUNT..].fill(0)
+                    });
+            }
+        }
+        for block_id in self.direct.iter_mut().skip(keep) {
+            *block_id = 0;
+        }
+        v
+    }
+    /// Cut 'extent', which starts at block 'first' of the file, down to the
+    /// first 'keep' blocks of the file, and push the blocks cut off to 'v'.
+    fn cut_extent(extent: &mut Extent, first: u32, keep: u32, v: &mut Vec<u32>) {
+        let kept = keep.saturating_sub(first).min(extent.len);
+        v.extend(extent.start + kept..extent.end());
+        extent.len = kept;
+        if kept == 0 {
+            extent.start = 0;
+        }
+    }
     /// Read file data at offset position from inode into buf
+    ///
+    /// Blocks consecutive on disk are read with one request if they are not cached.
     pub fn read_at(
         &self,
         offset: usize,
@@ -336,33 +804,86 @@ impl DiskInode {
         if start >= end {
             return 0;
         }
-        let mut start_block = start / BLOCK_SZ;
-        let mut read_size = 0usize;
-        loop {
-            // calculate end of current block
-            let mut end_current_block = (start / BLOCK_SZ + 1) * BLOCK_SZ;
-            end_current_block = end_current_block.min(end);
-            // read and update read size
-            let block_read_size = end_current_block - start;
-            let dst = &mut buf[read_size..read_size + block_read_size];
-            get_block_cache(
-                self.get_block_id(start_block as u32, block_device) as usize,
-                Arc::clone(block_device),
-            )
-            .lock()
-            .read(0, |data_block: &DataBlock| {
-                let src = &data_block[start % BLOCK_SZ..start % BLOCK_SZ + block_read_size];
-                dst.copy_from_slice(src);
-            });
-            read_size += block_read_size;
-            // move to next block
-            if end_current_block == end {
-                break;
+        let end_block = ((end - 1) / BLOCK_SZ) as u32 + 1;
+        while start < end {
+            let start_block = (start / BLOCK_SZ) as u32;
+            let (block_id, run) = self.get_block_run(
+                start_block,
+                (end_block - start_block).min(READ_RUN),
+                block_device,
+            );
+            block_cache_prefetch(block_id as usize, run as usize, Arc::clone(block_device));
+            for block_id in block_id..block_id + run {
+                // calculate end of current block
+                let end_current_block = ((start / BLOCK_SZ + 1) * BLOCK_SZ).min(end);
+                let dst = &mut buf[start - offset..end_current_block - offset];
+                get_block_cache(block_id as usize, Arc::clone(block_device))
+                    .lock()
+                    .read(0, |data_block: &DataBlock| {
+                        let src = &data_block[start % BLOCK_SZ..start % BLOCK_SZ + dst.len()];
+                        dst.copy_from_slice(src);
+                    });
+                start = end_current_block;
             }
-            start_block += 1;
-            start = end_current_block;
         }
-        read_size
+        end - offset
+    }
+    /// Visit the directory entries of a directory inode until 'f' returns Some
+    ///
+    /// 'f' gets the index of the head slot, the full name and the inode id of each entry.
+    pub fn find_dirent<V>(
+        &self,
+        block_device: &Arc<dyn BlockDevice>,
+        f: impl FnMut(usize, &str, u32) -> Option<V>,
+    ) -> Option<V> {
+        self.find_dirent_from(0, block_device, f)
+    }
+    /// Visit the directory entries like [`DiskInode::find_dirent`], starting
+    /// at slot 'from', which is the head slot of an entry or a free slot
+    ///
+    /// Entries whose names are not UTF-8, found on damaged images only, are skipped.
+    pub fn find_dirent_from<V>(
+        &self,
+        from: usize,
+        block_device: &Arc<dyn BlockDevice>,
+        mut f: impl FnMut(usize, &str, u32) -> Option<V>,
+    ) -> Option<V> {
+        self.find_raw_dirent_from(from, block_device, |index, name, inode_id| {
+            core::str::from_utf8(name)
+                .ok()
+                .and_then(|name| f(index, name, inode_id))
+        })
+    }
+    /// Visit the directory entries like [`DiskInode::find_dirent_from`], with
+    /// the bytes of each name as they are on disk
+    pub fn find_raw_dirent_from<V>(
+        &self,
+        from: usize,
+        block_device: &Arc<dyn BlockDevice>,
+        mut f: impl FnMut(usize, &[u8], u32) -> Option<V>,
+    ) -> Option<V> {
+        // assert it is a directory
+        assert!(self.is_dir());
+        let slot_count = (self.size as usize) / DIRENT_SZ;
+        let mut dirent = DirEntry::empty();
+        let mut name = [0u8; NAME_LENGTH_LIMIT];
+        let mut index = from;
+        while index < slot_count {
+            self.read_at(DIRENT_SZ * index, dirent.as_bytes_mut(), block_device);
+            if dirent.is_empty() {
+                index += 1;
+                continue;
+            }
+            // a long name continues in the slots right after the head slot
+            let (head, len) = (dirent.name_head().len(), dirent.name_len());
+            name[..head].copy_from_slice(dirent.name_head());
+            self.read_at(DIRENT_SZ * (index + 1), &mut name[head..len], block_device);
+            if let Some(v) = f(index, &name[..len], dirent.inode_id()) {
+                return Some(v);
+            }
+            index += dirent.slots();
+        }
+        None
     }
     /// Write file data at offset position from buf into inode
     pub fn write_at(
@@ -374,41 +895,38 @@ impl DiskInode {
         let mut start = offset;
         let end = (offset + buf.len()).min(self.size as usize);
         assert!(start <= end);
-        let mut start_block = start / BLOCK_SZ;
-        let mut write_size = 0usize;
-        loop {
-            // calculate end of current block
-            let mut end_current_block = (start / BLOCK_SZ + 1) * BLOCK_SZ;
-            end_current_block = end_current_block.min(end);
-            // write and update write size
-            let block_write_size = end_current_block - start;
-            get_block_cache(
-                self.get_block_id(start_block as u32, block_device) as usize,
-                Arc::clone(block_device),
-            )
-            .lock()
-            .modify(0, |data_block: &mut DataBlock| {
-                let src = &buf[write_size..write_size + block_write_size];
-                let dst = &mut data_block[start % BLOCK_SZ..start % BLOCK_SZ + block_write_size];
-                dst.copy_from_slice(src);
-            });
-            write_size += block_write_size;
-            // move to next block
-            if end_current_block == end {
-                break;
+        while start < end {
+            let start_block = (start / BLOCK_SZ) as u32;
+            let end_block = ((end - 1) / BLOCK_SZ) as u32 + 1;
+            let (block_id, run) =
+                self.get_block_run(start_block, end_block - start_block, block_device);
+            for block_id in block_id..block_id + run {
+                // calculate end of current block
+                let end_current_block = ((start / BLOCK_SZ + 1) * BLOCK_SZ).min(end);
+                let src = &buf[start - offset..end_current_block - offset];
+                get_block_cache(block_id as usize, Arc::clone(block_device))
+                    .lock()
+                    .modify(0, |data_block: &mut DataBlock| {
+                        let dst = &mut data_block[start % BLOCK_SZ..start % BLOCK_SZ + src.len()];
+                        dst.copy_from_slice(src);
+                    });
+                start = end_current_block;
             }
-            start_block += 1;
-            start = end_current_block;
         }
-        write_size
+        end - offset
     }
 }
 
 #[repr(C)]
 /// Directory entry struct
+///
+/// An entry takes one [`DIRENT_SZ`] slot of the directory if its name has at
+/// most [`SHORT_NAME_LIMIT`] bytes. A longer name keeps its first bytes in the
+/// head slot, whose last name byte (always zero for short names) holds the
+/// full length, and continues in the extension slots right after it.
 pub struct DirEntry {
-    /// File name
-    name: [u8; NAME_LENGTH_LIMIT + 1],
+    /// File name, or the head of a long name followed by its length
+    name: [u8; SHORT_NAME_LIMIT + 1],
     /// Inode id
     inode_id: u32,
 }
@@ -419,19 +937,32 @@ impl DirEntry {
     /// Create an empty directory entry
     pub fn empty() -> Self {
         Self {
-            name: [0u8; NAME_LENGTH_LIMIT + 1],
+            name: [0u8; SHORT_NAME_LIMIT + 1],
             inode_id: 0,
         }
     }
-    /// Create a directory entry with name and inode number
+    /// Create the head slot of a directory entry with name and inode number
     pub fn new(name: &str, inode_id: u32) -> Self {
-        let mut bytes = [0u8; NAME_LENGTH_LIMIT + 1];
-        bytes[..name.len()].copy_from_slice(name.as_bytes());
+        let mut bytes = [0u8; SHORT_NAME_LIMIT + 1];
+        let head = name.len().min(SHORT_NAME_LIMIT);
+        bytes[..head].copy_from_slice(&name.as_bytes()[..head]);
+        if name.len() > SHORT_NAME_LIMIT {
+            bytes[SHORT_NAME_LIMIT] = name.len() as u8;
+        }
         Self {
             name: bytes,
             inode_id,
         }
     }
+    /// the slots of a directory entry with name and inode number, as they are written to the directory
+    pub fn encode(name: &str, inode_id: u32) -> Vec<u8> {
+        let mut bytes = Vec::from(Self::new(name, inode_id).as_bytes());
+        if name.len() > SHORT_NAME_LIMIT {
+            bytes.extend_from_slice(&name.as_bytes()[SHORT_NAME_LIMIT..]);
+        }
+        bytes.resize(Self::slots_for(name.len()) * DIRENT_SZ, 0);
+        bytes
+    }
     /// Convert directory entry into immutable bytes
     pub fn as_bytes(&self) -> &[u8] {
         unsafe { core::slice::from_raw_parts(self as *const _ as usize as *const u8, DIRENT_SZ) }
@@ -440,13 +971,62 @@ impl DirEntry {
     pub fn as_bytes_mut(&mut self) -> &mut [u8] {
         unsafe { core::slice::from_raw_parts_mut(self as *mut _ as usize as *mut u8, DIRENT_SZ) }
     }
-    /// get the name of the directory entry
-    pub fn name(&self) -> &str {
-        let len = (0usize..).find(|i| self.name[*i] == 0).unwrap();
-        core::str::from_utf8(&self.name[..len]).unwrap()
+    /// the length of the full name
+    pub fn name_len(&self) -> usize {
+        match self.name[SHORT_NAME_LIMIT] {
+            0 => (0usize..).find(|i| self.name[*i] == 0).unwrap(),
+            len => len as usize,
+        }
+    }
+    /// the bytes of the name kept in the head slot, the whole name unless it is long
+    pub fn name_head(&self) -> &[u8] {
+        &self.name[..self.name_len().min(SHORT_NAME_LIMIT)]
+    }
+    /// number of slots taken by an entry whose name has 'name_len' bytes
+    pub fn slots_for(name_len: usize) -> usize {
+        1 + (name_len.saturating_sub(SHORT_NAME_LIMIT) + DIRENT_SZ - 1) / DIRENT_SZ
+    }
+    /// number of slots taken by this entry, including its extension slots
+    pub fn slots(&self) -> usize {
+        Self::slots_for(self.name_len())
+    }
+    /// is this a free slot of the directory?
+    pub fn is_empty(&self) -> bool {
+        self.name[0] == 0
     }
     /// get the inode id of the directory entry
     pub fn inode_id(&self) -> u32 {
         self.inode_id
     }
 }
+
+/// Header block of the journal
+///
+/// A transaction is committed once a header with a nonzero count is on disk,
+/// the `i`th block after the header then holds the new content of block
+/// `block_ids[i]`.
+#[repr(C)]
+pub struct JournalHeader {
+    /// number of blocks logged by the committed transaction, zero if there is none
+    pub count: u32,
+    /// the block ids of the logged blocks
+    pub block_ids: [u32; JOURNAL_CAPACITY],
+}
+
+impl JournalHeader {
+    /// Create a header without a committed transaction
+    pub fn empty() -> Self {
+        Self {
+            count: 0,
+            block_ids: [0; JOURNAL_CAPACITY],
+        }
+    }
+    /// Convert the header into immutable bytes
+    pub fn as_bytes(&self) -> &[u8] {
+        unsafe { core::slice::from_raw_parts(self as *const _ as usize as *const u8, BLOCK_SZ) }
+    }
+    /// Convert the header into mutable bytes
+    pub fn as_bytes_mut(&mut self) -> &mut [u8] {
+        unsafe { core::slice::from_raw_parts_mut(self as *mut _ as usize as *mut u8, BLOCK_SZ) }
+    }
+}
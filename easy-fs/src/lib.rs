@@ -11,6 +11,7 @@
 //! - Disk block device interface layer
 //! - Block cache layer
 //! - Disk layout & data structure layer
+//! - Journal layer
 //! - Disk block manager layer
 //! - index node(inode, namely file control block) layer
 
@@ -22,6 +23,8 @@ pub mod bitmap;
 pub mod block_cache;
 pub mod block_dev;
 pub mod efs;
+pub mod fsck;
+pub mod journal;
 pub mod layout;
 pub mod vfs;
 
@@ -29,6 +32,7 @@ pub const BLOCK_SZ: usize = 512;
 use bitmap::Bitmap;
 use block_cache::{block_cache_sync_all, get_block_cache};
 pub use block_dev::BlockDevice;
-pub use efs::EasyFileSystem;
+pub use efs::{EasyFileSystem, OpenError};
+use journal::Journal;
 pub use layout::*;
 pub use vfs::Inode;
@@ -5,13 +5,22 @@
 //! NOTICE: The difference between [`Inode`] and [`DiskInode`]  can be seen from their names: DiskInode in a relatively fixed location within the disk block, while Inode Is a data structure placed in memory that records file inode information.
 use super::{
     block_cache_sync_all, get_block_cache, BlockDevice, DirEntry, DiskInode, DiskInodeType,
-    EasyFileSystem, DIRENT_SZ,
+    EasyFileSystem, BLOCK_SZ, DIRENT_SZ, NAME_LENGTH_LIMIT,
 };
 use alloc::string::String;
 use alloc::sync::Arc;
+use alloc::vec;
 use alloc::vec::Vec;
 use spin::{Mutex, MutexGuard};
 
+/// The most bytes a file grows or shrinks by in one transaction, so that
+/// the bitmap blocks and index blocks it changes fit in the journal
+const RESIZE_STEP: usize = 32 * BLOCK_SZ;
+/// The most data blocks inserting a directory entry allocates: the block of
+/// the entry, the index blocks mapping it, and the first block of a new
+/// directory. Operations inserting one check for them before they begin.
+const DIRENT_BLOCKS: u32 = 5;
+
 /// Inode struct in memory
 pub struct Inode {
     /// The block id of the inode
@@ -55,66 +64,209 @@ impl Inode {
     }
     /// find the disk inode id according to the file with 'name' by search the directory entries in the disk inode with Directory type
     fn find_inode_id(&self, name: &str, disk_inode: &DiskInode) -> Option<u32> {
-        // assert it is a directory
-        assert!(disk_inode.is_dir());
-        let file_count = (disk_inode.size as usize) / DIRENT_SZ;
-        let mut dirent = DirEntry::empty();
-        for i in 0..file_count {
-            assert_eq!(
-                disk_inode.read_at(DIRENT_SZ * i, dirent.as_bytes_mut(), &self.block_device,),
-                DIRENT_SZ,
-            );
-            if dirent.name() == name {
-                return Some(dirent.inode_id() as u32);
+        disk_inode.find_dirent(&self.block_device, |_, dirent_name, inode_id| {
+            if dirent_name == name {
+                Some(inode_id)
+            } else {
+                None
             }
-        }
-        None
+        })
     }
     /// find the disk inode of the file with 'name'
     pub fn find(&self, name: &str) -> Option<Arc<Inode>> {
         let fs = self.fs.lock();
         self.read_disk_inode(|disk_inode| {
-            self.find_inode_id(name, disk_inode).map(|inode_id| {
-                let (block_id, block_offset) = fs.get_disk_inode_pos(inode_id);
-                Arc::new(Self::new(
-                    block_id,
-                    block_offset,
-                    self.fs.clone(),
-                    self.block_device.clone(),
-                ))
+            self.find_inode_id(name, disk_inode)
+                .map(|inode_id| self.get_inode(&fs, inode_id))
+        })
+    }
+    /// the inode with 'inode_id' in the same file system
+    fn get_inode(&self, fs: &EasyFileSystem, inode_id: u32) -> Arc<Inode> {
+        let (block_id, block_offset) = fs.get_disk_inode_pos(inode_id);
+        Arc::new(Self::new(
+            block_id,
+            block_offset,
+            self.fs.clone(),
+            self.block_device.clone(),
+        ))
+    }
+    /// the inode id of this inode
+    pub fn inode_id(&self) -> u32 {
+        self.fs
+            .lock()
+            .get_inode_id(self.block_id as u32, self.block_offset)
+    }
+    /// is this inode a directory?
+    pub fn is_dir(&self) -> bool {
+        let _fs = self.fs.lock();
+        self.read_disk_inode(|disk_inode| disk_inode.is_dir())
+    }
+    /// find the name this directory gives to the inode with 'inode_id', "." and ".." excluded
+    pub fn find_name(&self, inode_id: u32) -> Option<String> {
+        let _fs = self.fs.lock();
+        self.read_disk_inode(|disk_inode| {
+            disk_inode.find_dirent(&self.block_device, |_, name, id| {
+                if id == inode_id && name != "." && name != ".." {
+                    Some(String::from(name))
+                } else {
+                    None
+                }
             })
         })
     }
-    /// increase the size of file( also known as 'disk inode')
+    /// increase the size of file( also known as 'disk inode'), return false
+    /// and change nothing if the data area has too few free blocks
     fn increase_size(
         &self,
         new_size: u32,
         disk_inode: &mut DiskInode,
         fs: &mut MutexGuard<EasyFileSystem>,
-    ) {
+    ) -> bool {
         if new_size < disk_inode.size {
-            return;
+            return true;
+        }
+        if fs.free_data_blocks() < disk_inode.max_blocks_needed(new_size) {
+            return false;
+        }
+        // the blocks were counted free above
+        if disk_inode.is_extent_mapped() {
+            disk_inode.increase_size_extents(
+                new_size,
+                &mut |goal, len| fs.alloc_data_run(goal, len).unwrap(),
+                &self.block_device,
+            );
+            return true;
         }
         let blocks_needed = disk_inode.blocks_num_needed(new_size);
         let mut v: Vec<u32> = Vec::new();
         for _ in 0..blocks_needed {
-            v.push(fs.alloc_data());
+            v.push(fs.alloc_data().unwrap());
         }
         disk_inode.increase_size(new_size, v, &self.block_device);
+        true
+    }
+    /// put the entry of 'name' into the first run of free slots long enough for it, or append it
+    fn insert_dirent(
+        &self,
+        name: &str,
+        inode_id: u32,
+        dir_inode: &mut DiskInode,
+        fs: &mut MutexGuard<EasyFileSystem>,
+    ) {
+        let bytes = DirEntry::encode(name, inode_id);
+        let slots = bytes.len() / DIRENT_SZ;
+        let slot_count = (dir_inode.size as usize) / DIRENT_SZ;
+        let mut slot = DirEntry::empty();
+        // the free run starts at 'index' and has 'free' slots
+        let (mut index, mut free) = (0, 0);
+        while index + free < slot_count && free < slots {
+            dir_inode.read_at(
+                DIRENT_SZ * (index + free),
+                slot.as_bytes_mut(),
+                &self.block_device,
+            );
+            if slot.is_empty() {
+                free += 1;
+            } else {
+                index += free + slot.slots();
+                free = 0;
+            }
+        }
+        // a free run at the end of the directory may be too short, grow it,
+        // the caller checked for DIRENT_BLOCKS free blocks
+        let grown = self.increase_size(((index + slots) * DIRENT_SZ) as u32, dir_inode, fs);
+        debug_assert!(grown);
+        dir_inode.write_at(index * DIRENT_SZ, &bytes, &self.block_device);
+    }
+    /// free the slots of the dirent with 'name', return its inode id
+    fn remove_dirent(&self, name: &str, dir_inode: &mut DiskInode) -> Option<u32> {
+        let (index, inode_id) =
+            dir_inode.find_dirent(&self.block_device, |index, dirent_name, inode_id| {
+                if dirent_name == name {
+                    Some((index, inode_id))
+                } else {
+                    None
+                }
+            })?;
+        let empty = vec![0u8; DirEntry::slots_for(name.len()) * DIRENT_SZ];
+        dir_inode.write_at(index * DIRENT_SZ, &empty, &self.block_device);
+        Some(inode_id)
+    }
+    /// can 'name' be the name of a directory entry?
+    fn is_valid_name(name: &str) -> bool {
+        !name.is_empty() && name.len() <= NAME_LENGTH_LIMIT && !name.contains(['/', '\0'])
+    }
+    /// write the "." and ".." entries of a new directory
+    pub(crate) fn init_dir(&self, parent_id: u32, fs: &mut MutexGuard<EasyFileSystem>) {
+        let self_id = fs.get_inode_id(self.block_id as u32, self.block_offset);
+        self.modify_disk_inode(|dir_inode| {
+            self.insert_dirent(".", self_id, dir_inode, fs);
+            self.insert_dirent("..", parent_id, dir_inode, fs);
+        });
+    }
+    /// finish the upgrade of a version 1 image at its root: give "/" the "."
+    /// and ".." it was made without, and each directory the link count its
+    /// entries make, which images older than link counts lack
+    pub(crate) fn upgrade_dirs_from_v1(&self, fs: &mut MutexGuard<EasyFileSystem>) {
+        self.modify_disk_inode(|root_inode| {
+            for name in [".", ".."] {
+                if self.find_inode_id(name, root_inode).is_none() {
+                    self.insert_dirent(name, 0, root_inode, fs);
+                }
+            }
+        });
+        let mut dirs = vec![0];
+        while let Some(dir_id) = dirs.pop() {
+            let dir = self.get_inode(fs, dir_id);
+            let mut entries = Vec::new();
+            dir.read_disk_inode(|dir_inode| {
+                dir_inode.find_dirent(&self.block_device, |_, name, inode_id| {
+                    if name != "." && name != ".." {
+                        entries.push(inode_id);
+                    }
+                    None::<()>
+                })
+            });
+            let subdirs: Vec<u32> = entries
+                .into_iter()
+                .filter(|inode_id| {
+                    self.get_inode(fs, *inode_id)
+                        .read_disk_inode(|disk_inode| disk_inode.is_dir())
+                })
+                .collect();
+            dir.modify_disk_inode(|dir_inode| dir_inode.nlink = 2 + subdirs.len() as u16);
+            dirs.extend(subdirs);
+        }
     }
-    /// create a file with 'name' in the root directory
+    /// create a file with 'name' in this directory
     pub fn create(&self, name: &str) -> Option<Arc<Inode>> {
+        self.create_inode(name, DiskInodeType::File)
+    }
+    /// create a directory with 'name' in this directory
+    pub fn create_dir(&self, name: &str) -> Option<Arc<Inode>> {
+        self.create_inode(name, DiskInodeType::Directory)
+    }
+    /// create an inode of 'type_' with 'name' in this directory
+    fn create_inode(&self, name: &str, type_: DiskInodeType) -> Option<Arc<Inode>> {
+        if !Self::is_valid_name(name) {
+            return None;
+        }
         let mut fs = self.fs.lock();
-        let op = |root_inode: &mut DiskInode| {
+        let op = |dir_inode: &DiskInode| {
             // assert it is a directory
-            assert!(root_inode.is_dir());
+            assert!(dir_inode.is_dir());
             // has the file been created?
-            self.find_inode_id(name, root_inode)
+            self.find_inode_id(name, dir_inode)
         };
-        if self.modify_disk_inode(op).is_some() {
+        if self.read_disk_inode(op).is_some()
+            || fs.free_inodes() == 0
+            || fs.free_data_blocks() < DIRENT_BLOCKS
+        {
             return None;
         }
-        // create a new file
+        let is_dir = type_ == DiskInodeType::Directory;
+        fs.begin();
+        // create a new inode
         // alloc a inode with an indirect block
         let new_inode_id = fs.alloc_inode();
         // initialize inode
@@ -122,79 +274,440 @@ impl Inode {
         get_block_cache(new_inode_block_id as usize, Arc::clone(&self.block_device))
             .lock()
             .modify(new_inode_block_offset, |new_inode: &mut DiskInode| {
-                new_inode.initialize(DiskInodeType::File);
+                new_inode.initialize(type_);
+                new_inode.atime = fs.now();
+                new_inode.touch(fs.now());
             });
-        self.modify_disk_inode(|root_inode| {
-            // append file in the dirent
-            let file_count = (root_inode.size as usize) / DIRENT_SZ;
-            let new_size = (file_count + 1) * DIRENT_SZ;
-            // increase size
-            self.increase_size(new_size as u32, root_inode, &mut fs);
-            // write dirent
-            let dirent = DirEntry::new(name, new_inode_id);
-            root_inode.write_at(
-                file_count * DIRENT_SZ,
-                dirent.as_bytes(),
-                &self.block_device,
-            );
+        let new_inode = self.get_inode(&fs, new_inode_id);
+        if is_dir {
+            let parent_id = fs.get_inode_id(self.block_id as u32, self.block_offset);
+            new_inode.init_dir(parent_id, &mut fs);
+        }
+        self.modify_disk_inode(|dir_inode| {
+            self.insert_dirent(name, new_inode_id, dir_inode, &mut fs);
+            // the ".." of the new directory
+            if is_dir {
+                dir_inode.nlink += 1;
+            }
+            dir_inode.touch(fs.now());
         });
-
-        let (block_id, block_offset) = fs.get_disk_inode_pos(new_inode_id);
-        block_cache_sync_all();
+        fs.commit();
         // return inode
-        Some(Arc::new(Self::new(
-            block_id,
-            block_offset,
-            self.fs.clone(),
-            self.block_device.clone(),
-        )))
+        Some(new_inode)
         // release efs lock automatically by compiler
     }
-    /// create a directory with 'name' in the root directory
+    /// remove the empty directory with 'name' from this directory
+    ///
+    /// The entry goes in one transaction, and the blocks of the directory
+    /// are freed after it commits.
+    pub fn remove_dir(&self, name: &str) -> bool {
+        if name == "." || name == ".." {
+            return false;
+        }
+        let mut fs = self.fs.lock();
+        let dir = match self.read_disk_inode(|disk_inode| self.find_inode_id(name, disk_inode)) {
+            Some(inode_id) => self.get_inode(&fs, inode_id),
+            None => return false,
+        };
+        if !dir.read_disk_inode(|disk_inode| dir.is_empty_dir(disk_inode)) {
+            return false;
+        }
+        fs.begin();
+        self.modify_disk_inode(|dir_inode| {
+            dir_inode.nlink -= 1;
+            dir_inode.touch(fs.now());
+            self.remove_dirent(name, dir_inode)
+        });
+        dir.modify_disk_inode(|disk_inode| disk_inode.nlink = 0);
+        fs.commit();
+        dir.free(&mut fs);
+        true
+    }
+    /// is 'disk_inode' a directory with no entries but "." and ".."?
+    fn is_empty_dir(&self, disk_inode: &DiskInode) -> bool {
+        disk_inode.is_dir()
+            && disk_inode
+                .find_dirent(&self.block_device, |_, name, _| {
+                    if name != "." && name != ".." {
+                        Some(())
+                    } else {
+                        None
+                    }
+                })
+                .is_none()
+    }
+    /// move the entry with 'old_name' in this directory to 'new_name' in 'new_dir'
     ///
-    /// list the file names in the root directory
+    /// An entry already at 'new_name' is replaced in the same transaction: a
+    /// file only by a file, which keeps its blocks until [`Inode::release`]
+    /// is called on it, and an empty directory only by a directory, which is
+    /// freed after the transaction. A directory cannot move below itself.
+    pub fn rename(&self, old_name: &str, new_dir: &Inode, new_name: &str) -> bool {
+        if !Self::is_valid_name(new_name)
+            || [old_name, new_name]
+                .iter()
+                .any(|name| *name == "." || *name == "..")
+        {
+            return false;
+        }
+        let mut fs = self.fs.lock();
+        let inode_id =
+            match self.read_disk_inode(|dir_inode| self.find_inode_id(old_name, dir_inode)) {
+                Some(inode_id) => inode_id,
+                None => return false,
+            };
+        if !new_dir.read_disk_inode(|disk_inode| disk_inode.is_dir()) {
+            return false;
+        }
+        let inode = self.get_inode(&fs, inode_id);
+        let is_dir = inode.read_disk_inode(|disk_inode| disk_inode.is_dir());
+        let self_id = fs.get_inode_id(self.block_id as u32, self.block_offset);
+        let new_dir_id = fs.get_inode_id(new_dir.block_id as u32, new_dir.block_offset);
+        if is_dir {
+            // walk up from the new directory to the root
+            let mut dir_id = new_dir_id;
+            while dir_id != inode_id {
+                let dir = self.get_inode(&fs, dir_id);
+                match dir.read_disk_inode(|dir_inode| dir.find_inode_id("..", dir_inode)) {
+                    Some(parent_id) if parent_id != dir_id => dir_id = parent_id,
+                    _ => break,
+                }
+            }
+            if dir_id == inode_id {
+                return false;
+            }
+        }
+        let replaced_id =
+            new_dir.read_disk_inode(|dir_inode| new_dir.find_inode_id(new_name, dir_inode));
+        if replaced_id == Some(inode_id) {
+            // both names link to the same file already
+            return true;
+        }
+        if fs.free_data_blocks() < DIRENT_BLOCKS {
+            return false;
+        }
+        let replaced = replaced_id.map(|replaced_id| self.get_inode(&fs, replaced_id));
+        if let Some(replaced) = &replaced {
+            let replaceable = replaced.read_disk_inode(|disk_inode| {
+                if is_dir {
+                    replaced.is_empty_dir(disk_inode)
+                } else {
+                    !disk_inode.is_dir()
+                }
+            });
+            if !replaceable {
+                return false;
+            }
+        }
+        fs.begin();
+        if let Some(replaced) = &replaced {
+            new_dir.modify_disk_inode(|dir_inode| {
+                new_dir.remove_dirent(new_name, dir_inode);
+                // the ".." of the replaced directory
+                if is_dir {
+                    dir_inode.nlink -= 1;
+                }
+            });
+            replaced.modify_disk_inode(|disk_inode| {
+                if is_dir {
+                    disk_inode.nlink = 0;
+                } else {
+                    disk_inode.nlink = disk_inode.nlink.saturating_sub(1);
+                    disk_inode.ctime = fs.now();
+                }
+            });
+        }
+        self.modify_disk_inode(|dir_inode| {
+            self.remove_dirent(old_name, dir_inode);
+            if is_dir {
+                dir_inode.nlink -= 1;
+            }
+            dir_inode.touch(fs.now());
+        });
+        new_dir.modify_disk_inode(|dir_inode| {
+            new_dir.insert_dirent(new_name, inode_id, dir_inode, &mut fs);
+            if is_dir {
+                dir_inode.nlink += 1;
+            }
+            dir_inode.touch(fs.now());
+        });
+        inode.modify_disk_inode(|disk_inode| {
+            if is_dir && new_dir_id != self_id {
+                inode.remove_dirent("..", disk_inode);
+                inode.insert_dirent("..", new_dir_id, disk_inode, &mut fs);
+            }
+            disk_inode.ctime = fs.now();
+        });
+        fs.commit();
+        // the replaced directory is unlinked now, its blocks are freed after
+        if let Some(replaced) = replaced.filter(|_| is_dir) {
+            replaced.free(&mut fs);
+        }
+        true
+    }
+    /// the number of directory entries linking to this inode
+    pub fn nlink(&self) -> u32 {
+        let _fs = self.fs.lock();
+        self.read_disk_inode(|disk_inode| disk_inode.nlink as u32)
+    }
+    /// add an entry with 'name' for the file 'inode' to this directory
+    pub fn link(&self, name: &str, inode: &Inode) -> bool {
+        if !Self::is_valid_name(name) {
+            return false;
+        }
+        let mut fs = self.fs.lock();
+        // directories cannot be linked, and names are unique
+        if inode.read_disk_inode(|disk_inode| disk_inode.is_dir())
+            || self
+                .read_disk_inode(|dir_inode| self.find_inode_id(name, dir_inode))
+                .is_some()
+        {
+            return false;
+        }
+        if fs.free_data_blocks() < DIRENT_BLOCKS {
+            return false;
+        }
+        let inode_id = fs.get_inode_id(inode.block_id as u32, inode.block_offset);
+        fs.begin();
+        self.modify_disk_inode(|dir_inode| {
+            self.insert_dirent(name, inode_id, dir_inode, &mut fs);
+            dir_inode.touch(fs.now());
+        });
+        inode.modify_disk_inode(|disk_inode| {
+            disk_inode.nlink += 1;
+            disk_inode.ctime = fs.now();
+        });
+        fs.commit();
+        true
+    }
+    /// remove the entry with 'name' of a file from this directory and return the file
+    ///
+    /// The file keeps its blocks until [`Inode::release`] is called on it.
+    pub fn unlink(&self, name: &str) -> Option<Arc<Inode>> {
+        let fs = self.fs.lock();
+        let inode_id = self.read_disk_inode(|dir_inode| self.find_inode_id(name, dir_inode))?;
+        let inode = self.get_inode(&fs, inode_id);
+        if inode.read_disk_inode(|disk_inode| disk_inode.is_dir()) {
+            return None;
+        }
+        fs.begin();
+        self.modify_disk_inode(|dir_inode| {
+            dir_inode.touch(fs.now());
+            self.remove_dirent(name, dir_inode)
+        });
+        inode.modify_disk_inode(|disk_inode| {
+            disk_inode.nlink = disk_inode.nlink.saturating_sub(1);
+            disk_inode.ctime = fs.now();
+        });
+        fs.commit();
+        Some(inode)
+    }
+    /// free the data blocks and the inode of a file no directory entry links to any more
+    ///
+    /// Return false if the file still has links.
+    pub fn release(&self) -> bool {
+        let mut fs = self.fs.lock();
+        if self.read_disk_inode(|disk_inode| disk_inode.nlink) > 0 {
+            return false;
+        }
+        self.free(&mut fs);
+        true
+    }
+    /// free the blocks of this unlinked inode a few per transaction, then the inode
+    ///
+    /// An inode left half freed by a crash is unreachable, fsck reclaims it.
+    fn free(&self, fs: &mut MutexGuard<EasyFileSystem>) {
+        self.shrink(0, fs);
+        fs.begin();
+        let inode_id = fs.get_inode_id(self.block_id as u32, self.block_offset);
+        fs.dealloc_inode(inode_id);
+        fs.commit();
+    }
+    /// list the file names in this directory
     pub fn ls(&self) -> Vec<String> {
         let _fs = self.fs.lock();
         self.read_disk_inode(|disk_inode| {
-            let file_count = (disk_inode.size as usize) / DIRENT_SZ;
             let mut v: Vec<String> = Vec::new();
-            for i in 0..file_count {
-                let mut dirent = DirEntry::empty();
-                assert_eq!(
-                    disk_inode.read_at(i * DIRENT_SZ, dirent.as_bytes_mut(), &self.block_device,),
-                    DIRENT_SZ,
-                );
-                v.push(String::from(dirent.name()));
-            }
+            disk_inode.find_dirent(&self.block_device, |_, name, _| -> Option<()> {
+                v.push(String::from(name));
+                None
+            });
             v
         })
     }
+    /// the first entry of this directory at slot 'from' or after it, with
+    /// the slot to read the next entry from
+    pub fn read_dirent(&self, from: usize) -> Option<(String, Arc<Inode>, usize)> {
+        let fs = self.fs.lock();
+        self.read_disk_inode(|disk_inode| {
+            disk_inode.find_dirent_from(from, &self.block_device, |index, name, inode_id| {
+                let slots = DirEntry::slots_for(name.len());
+                Some((
+                    String::from(name),
+                    self.get_inode(&fs, inode_id),
+                    index + slots,
+                ))
+            })
+        })
+    }
     /// Read the content in offset position of the file into 'buf'
+    ///
+    /// The access time is only recorded as [`DiskInode::needs_access`]
+    /// says, so most reads leave the inode block clean.
     pub fn read_at(&self, offset: usize, buf: &mut [u8]) -> usize {
-        let _fs = self.fs.lock();
+        let fs = self.fs.lock();
+        let now = fs.now();
+        if self.read_disk_inode(|disk_inode| disk_inode.needs_access(now)) {
+            self.modify_disk_inode(|disk_inode| disk_inode.atime = now);
+        }
         self.read_disk_inode(|disk_inode| disk_inode.read_at(offset, buf, &self.block_device))
     }
     /// Write the content in 'buf' into offset position of the file
+    ///
+    /// The file grows by a few blocks per transaction, so that the metadata
+    /// each one changes fits in the journal. The content is not journaled,
+    /// it stays in the block cache until it is evicted or synced. So do the
+    /// times of a write within the file, which needs no transaction.
+    ///
+    /// The write is short if the data area fills up, and writes nothing
+    /// past [`DiskInode::max_size`].
     pub fn write_at(&self, offset: usize, buf: &[u8]) -> usize {
         let mut fs = self.fs.lock();
-        let size = self.modify_disk_inode(|disk_inode| {
-            self.increase_size((offset + buf.len()) as u32, disk_inode, &mut fs);
+        let (size, max_size) =
+            self.read_disk_inode(|disk_inode| (disk_inode.size as usize, disk_inode.max_size()));
+        let end = match offset.checked_add(buf.len()) {
+            Some(end) if end <= max_size => end,
+            _ => return 0,
+        };
+        if end > size && !self.grow(end, &mut fs) && offset > size {
+            // the file grew without reaching 'offset', leave it as it was
+            self.shrink(size as u32, &mut fs);
+            return 0;
+        }
+        self.modify_disk_inode(|disk_inode| {
+            disk_inode.touch(fs.now());
             disk_inode.write_at(offset, buf, &self.block_device)
+        })
+    }
+    /// grow the file to at least 'size' bytes a few blocks per transaction, and record the change
+    ///
+    /// Return false if the data area fills up first, the file keeps the steps it grew by.
+    fn grow(&self, size: usize, fs: &mut MutexGuard<EasyFileSystem>) -> bool {
+        loop {
+            fs.begin();
+            let grown = self.modify_disk_inode(|disk_inode| {
+                let new_size = size.min(disk_inode.size as usize + RESIZE_STEP);
+                if !self.increase_size(new_size as u32, disk_inode, fs) {
+                    return None;
+                }
+                disk_inode.touch(fs.now());
+                Some(new_size >= size)
+            });
+            fs.commit();
+            match grown {
+                Some(true) => return true,
+                Some(false) => {}
+                None => return false,
+            }
+        }
+    }
+    /// Set the size of the file to 'new_size', cutting off its end or extending it with zeros
+    ///
+    /// Return false and leave the file as it was if it cannot grow to 'new_size'.
+    pub fn truncate(&self, new_size: u32) -> bool {
+        let mut fs = self.fs.lock();
+        let (size, max_size) =
+            self.read_disk_inode(|disk_inode| (disk_inode.size, disk_inode.max_size()));
+        if new_size as usize > max_size {
+            return false;
+        }
+        if new_size >= size {
+            if !self.grow(new_size as usize, &mut fs) {
+                self.shrink(size, &mut fs);
+                return false;
+            }
+            return true;
+        }
+        // blocks are zero when allocated, so only the rest of the last block
+        // kept needs zeroing for the file to read zeros if it grows again
+        let cut_end = ((new_size as usize + BLOCK_SZ - 1) / BLOCK_SZ * BLOCK_SZ).min(size as usize);
+        self.modify_disk_inode(|disk_inode| {
+            let zeros = vec![0u8; cut_end - new_size as usize];
+            disk_inode.write_at(new_size as usize, &zeros, &self.block_device)
         });
+        self.shrink(new_size, &mut fs);
+        true
+    }
+    /// cut the file down to 'size' bytes a few blocks per transaction, and record the change
+    fn shrink(&self, size: u32, fs: &mut MutexGuard<EasyFileSystem>) {
+        loop {
+            fs.begin();
+            let shrunk = self.modify_disk_inode(|disk_inode| {
+                let new_size = size.max(disk_inode.size.saturating_sub(RESIZE_STEP as u32));
+                for block_id in disk_inode.decrease_size(new_size, &self.block_device) {
+                    fs.dealloc_data(block_id);
+                }
+                disk_inode.touch(fs.now());
+                new_size <= size
+            });
+            fs.commit();
+            if shrunk {
+                break;
+            }
+        }
+    }
+    /// Write the content of the file back to the disk
+    ///
+    /// Block caches do not know the file they belong to, so all of the
+    /// modified ones are written back.
+    pub fn sync(&self) {
+        let _fs = self.fs.lock();
         block_cache_sync_all();
-        size
     }
     /// Set the file(disk inode) length to zero, delloc all data blocks of the file.
     pub fn clear(&self) {
         let mut fs = self.fs.lock();
+        self.shrink(0, &mut fs);
+    }
+    /// size of the file in bytes
+    pub fn size(&self) -> u32 {
+        let _fs = self.fs.lock();
+        self.read_disk_inode(|disk_inode| disk_inode.size)
+    }
+    /// permission bits of the inode
+    pub fn mode(&self) -> u16 {
+        let _fs = self.fs.lock();
+        self.read_disk_inode(|disk_inode| disk_inode.mode)
+    }
+    /// user id and group id of the owner
+    pub fn owner(&self) -> (u32, u32) {
+        let _fs = self.fs.lock();
+        self.read_disk_inode(|disk_inode| (disk_inode.uid, disk_inode.gid))
+    }
+    /// access, modification and change time, in seconds
+    pub fn times(&self) -> (u64, u64, u64) {
+        let _fs = self.fs.lock();
+        self.read_disk_inode(|disk_inode| (disk_inode.atime, disk_inode.mtime, disk_inode.ctime))
+    }
+    /// set the permission bits, only the lowest nine are kept
+    pub fn set_mode(&self, mode: u16) {
+        let fs = self.fs.lock();
+        fs.begin();
         self.modify_disk_inode(|disk_inode| {
-            let size = disk_inode.size;
-            let data_blocks_dealloc = disk_inode.clear_size(&self.block_device);
-            assert!(data_blocks_dealloc.len() == DiskInode::total_blocks(size) as usize);
-            for data_block in data_blocks_dealloc.into_iter() {
-                fs.dealloc_data(data_block);
-            }
+            disk_inode.mode = mode & 0o777;
+            disk_inode.ctime = fs.now();
         });
-        block_cache_sync_all();
+        fs.commit();
+    }
+    /// give the inode to user 'uid' and group 'gid'
+    pub fn set_owner(&self, uid: u32, gid: u32) {
+        let fs = self.fs.lock();
+        fs.begin();
+        self.modify_disk_inode(|disk_inode| {
+            disk_inode.uid = uid;
+            disk_inode.gid = gid;
+            disk_inode.ctime = fs.now();
+        });
+        fs.commit();
     }
 }
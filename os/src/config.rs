@@ -16,6 +16,27 @@ pub const PAGE_SIZE: usize = 0x1000;
 pub const PAGE_SIZE_BITS: usize = 0xc;
 /// the max number of syscall
 pub const MAX_SYSCALL_NUM: usize = 500;
+/// free frames kept in reserve for the kernel by swapping user pages out
+pub const FRAME_RESERVE: usize = 256;
+/// name the swap file is created under in the root directory, it is unlinked at once
+pub const SWAP_FILE: &str = ".swap";
+/// the most pages the swap file holds, 32 MiB, it must fit in the file system image
+pub const SWAP_SLOTS: usize = 8192;
+/// the most disk blocks cached in the kernel heap
+pub const BLOCK_CACHE_CAPACITY: usize = 1024;
+/// fds a spawned child may have, see `sys_spawn`
+pub const MAX_FD: usize = 1024;
+/// the most file actions one `sys_spawn` takes
+pub const MAX_SPAWN_ACTIONS: usize = 64;
+/// the pass every task advances by is `BIG_STRIDE / priority`
+pub const BIG_STRIDE: usize = 0x10_0000;
+/// priority of the initial process
+pub const DEFAULT_PRIORITY: usize = 16;
+/// the highest priority a thread may set, far below `BIG_STRIDE` so that the
+/// pass of a thread still advances by a few thousand each time
+pub const MAX_PRIORITY: usize = 256;
+/// end of the user part of the address space, the lower half of SV39
+pub const USER_SPACE_END: usize = 1 << 38;
 /// the virtual addr of trapoline
 pub const TRAMPOLINE: usize = usize::MAX - PAGE_SIZE + 1;
 /// the virtual addr of trap context
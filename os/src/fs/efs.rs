@@ -0,0 +1,149 @@
+//! easy-fs behind the [`VfsInode`] and [`SuperBlock`] traits
+
+use super::vfs::{SuperBlock, VfsInode};
+use crate::timer::get_time_ms;
+use alloc::string::String;
+use alloc::sync::Arc;
+use core::any::Any;
+use easy_fs::block_cache::block_cache_sync_all;
+use easy_fs::{BlockDevice, EasyFileSystem, Inode};
+
+/// An easy-fs image on a block device
+pub struct EfsSuperBlock {
+    dev: u64,
+    root: Arc<Inode>,
+}
+
+impl EfsSuperBlock {
+    /// open the image on `block_device`, with device number `dev`
+    pub fn open(block_device: Arc<dyn BlockDevice>, dev: u64) -> Self {
+        let efs = EasyFileSystem::open(block_device).expect("Error loading EFS!");
+        // there is no real-time clock, times are counted from boot
+        efs.lock().set_clock(|| (get_time_ms() / 1000) as u64);
+        let root = Arc::new(EasyFileSystem::root_inode(&efs));
+        Self { dev, root }
+    }
+}
+
+impl SuperBlock for EfsSuperBlock {
+    fn root(&self) -> Arc<dyn VfsInode> {
+        Arc::new(EfsInode {
+            dev: self.dev,
+            inode: self.root.clone(),
+        })
+    }
+    fn sync(&self) {
+        // block caches do not know their device, there is only one
+        block_cache_sync_all();
+    }
+}
+
+/// An inode of an easy-fs image
+pub struct EfsInode {
+    dev: u64,
+    inode: Arc<Inode>,
+}
+
+impl EfsInode {
+    /// another inode of the same image
+    fn wrap(&self, inode: Arc<Inode>) -> Arc<dyn VfsInode> {
+        Arc::new(Self {
+            dev: self.dev,
+            inode,
+        })
+    }
+    /// `inode` if it is an inode of the same image
+    fn same_fs<'a>(&self, inode: &'a dyn VfsInode) -> Option<&'a Inode> {
+        inode
+            .as_any()
+            .downcast_ref::<Self>()
+            .filter(|inode| inode.dev == self.dev)
+            .map(|inode| &*inode.inode)
+    }
+}
+
+impl VfsInode for EfsInode {
+    fn dev(&self) -> u64 {
+        self.dev
+    }
+    fn ino(&self) -> u64 {
+        self.inode.inode_id() as u64
+    }
+    fn is_dir(&self) -> bool {
+        self.inode.is_dir()
+    }
+    fn size(&self) -> usize {
+        self.inode.size() as usize
+    }
+    fn nlink(&self) -> u32 {
+        self.inode.nlink()
+    }
+    fn mode(&self) -> u16 {
+        self.inode.mode()
+    }
+    fn owner(&self) -> (u32, u32) {
+        self.inode.owner()
+    }
+    fn set_owner(&self, uid: u32, gid: u32) {
+        self.inode.set_owner(uid, gid)
+    }
+    fn times(&self) -> (u64, u64, u64) {
+        self.inode.times()
+    }
+    fn read_at(&self, offset: usize, buf: &mut [u8]) -> usize {
+        self.inode.read_at(offset, buf)
+    }
+    fn write_at(&self, offset: usize, buf: &[u8]) -> usize {
+        self.inode.write_at(offset, buf)
+    }
+    fn truncate(&self, size: usize) -> bool {
+        if self.inode.is_dir() || size > u32::MAX as usize {
+            return false;
+        }
+        self.inode.truncate(size as u32)
+    }
+    fn sync(&self) {
+        self.inode.sync()
+    }
+    fn find(&self, name: &str) -> Option<Arc<dyn VfsInode>> {
+        self.inode.find(name).map(|inode| self.wrap(inode))
+    }
+    fn find_name(&self, ino: u64) -> Option<String> {
+        self.inode.find_name(ino as u32)
+    }
+    fn read_dirent(&self, from: usize) -> Option<(String, Arc<dyn VfsInode>, usize)> {
+        self.inode
+            .read_dirent(from)
+            .map(|(name, inode, next)| (name, self.wrap(inode), next))
+    }
+    fn create(&self, name: &str) -> Option<Arc<dyn VfsInode>> {
+        self.inode.create(name).map(|inode| self.wrap(inode))
+    }
+    fn create_dir(&self, name: &str) -> Option<Arc<dyn VfsInode>> {
+        self.inode.create_dir(name).map(|inode| self.wrap(inode))
+    }
+    fn link(&self, name: &str, inode: &dyn VfsInode) -> bool {
+        match self.same_fs(inode) {
+            Some(inode) => self.inode.link(name, inode),
+            None => false,
+        }
+    }
+    fn unlink(&self, name: &str) -> Option<Arc<dyn VfsInode>> {
+        self.inode.unlink(name).map(|inode| self.wrap(inode))
+    }
+    fn release(&self) -> bool {
+        self.inode.release()
+    }
+    fn remove_dir(&self, name: &str) -> bool {
+        self.inode.remove_dir(name)
+    }
+    fn rename(&self, old_name: &str, new_dir: &dyn VfsInode, new_name: &str) -> bool {
+        match self.same_fs(new_dir) {
+            Some(new_dir) => self.inode.rename(old_name, new_dir, new_name),
+            None => false,
+        }
+    }
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
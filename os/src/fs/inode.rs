@@ -1,32 +1,56 @@
-use super::File;
+use super::efs::EfsSuperBlock;
+use super::ramfs::RamFs;
+use super::vfs::{
+    alloc_dev, cross_down, cross_up, mount, mount_root, same_inode, umount, SuperBlock, VfsInode,
+};
+use super::{File, Stat, StatMode, SEEK_CUR, SEEK_END, SEEK_SET};
+use crate::config::BLOCK_CACHE_CAPACITY;
 use crate::drivers::BLOCK_DEVICE;
 use crate::mm::UserBuffer;
 use crate::sync::UPSafeCell;
+use crate::task::cwd_on_dev;
+use alloc::collections::BTreeMap;
+use alloc::string::String;
 use alloc::sync::Arc;
 use alloc::vec::Vec;
 use bitflags::*;
-use easy_fs::{EasyFileSystem, Inode};
+use easy_fs::block_cache::block_cache_set_capacity;
 use lazy_static::*;
 
+/// `d_type` of a directory in a `linux_dirent64` record
+const DT_DIR: u8 = 4;
+/// `d_type` of a regular file in a `linux_dirent64` record
+const DT_REG: u8 = 8;
+/// size of `d_ino`, `d_off`, `d_reclen` and `d_type` of a `linux_dirent64` record
+const DIRENT64_HEADER_SZ: usize = 19;
+
 /// inode in memory
 pub struct OSInode {
     readable: bool,
     writable: bool,
+    /// every write goes to the end of the file
+    append: bool,
     inner: UPSafeCell<OSInodeInner>,
 }
 /// inner of inode in memory
 pub struct OSInodeInner {
+    /// the byte offset of a file, the slot offset of a directory
     offset: usize,
-    inode: Arc<Inode>,
+    inode: Arc<dyn VfsInode>,
 }
 
 impl OSInode {
     /// create a new inode in memory
-    pub fn new(readable: bool, writable: bool, inode: Arc<Inode>) -> Self {
+    pub fn new(readable: bool, writable: bool, append: bool, inode: Arc<dyn VfsInode>) -> Self {
         trace!("kernel: OSInode::new");
+        *OPEN_INODES
+            .exclusive_access()
+            .entry((inode.dev(), inode.ino()))
+            .or_insert(0) += 1;
         Self {
             readable,
             writable,
+            append,
             inner: unsafe { UPSafeCell::new(OSInodeInner { offset: 0, inode }) },
         }
     }
@@ -48,18 +72,42 @@ impl OSInode {
     }
 }
 
+impl Drop for OSInode {
+    /// an unlinked file is released with its last open handle
+    fn drop(&mut self) {
+        let inode = &self.inner.exclusive_access().inode;
+        let key = (inode.dev(), inode.ino());
+        let mut open_inodes = OPEN_INODES.exclusive_access();
+        let count = open_inodes.get_mut(&key).unwrap();
+        *count -= 1;
+        if *count == 0 {
+            open_inodes.remove(&key);
+            drop(open_inodes);
+            inode.release();
+        }
+    }
+}
+
 lazy_static! {
-    pub static ref ROOT_INODE: Arc<Inode> = {
-        let efs = EasyFileSystem::open(BLOCK_DEVICE.clone());
-        Arc::new(EasyFileSystem::root_inode(&efs))
+    /// the number of `OSInode`s open on each inode, by device and inode number
+    static ref OPEN_INODES: UPSafeCell<BTreeMap<(u64, u64), usize>> =
+        unsafe { UPSafeCell::new(BTreeMap::new()) };
+    /// root directory of the easy-fs image mounted at "/"
+    pub static ref ROOT_INODE: Arc<dyn VfsInode> = {
+        block_cache_set_capacity(BLOCK_CACHE_CAPACITY);
+        mount_root(Arc::new(EfsSuperBlock::open(BLOCK_DEVICE.clone(), alloc_dev())))
     };
 }
 
 /// List all apps in the root directory
 pub fn list_apps() {
     println!("/**** APPS ****");
-    for app in ROOT_INODE.ls() {
-        println!("{}", app);
+    let mut from = 0;
+    while let Some((app, _, next)) = ROOT_INODE.read_dirent(from) {
+        if app != "." && app != ".." {
+            println!("{}", app);
+        }
+        from = next;
     }
     println!("**************/");
 }
@@ -77,6 +125,54 @@ bitflags! {
         const CREATE = 1 << 9;
         /// truncate file size to 0
         const TRUNC = 1 << 10;
+        /// fail if CREATE is set and the file exists
+        const EXCL = 1 << 7;
+        /// write at the end of the file every time
+        const APPEND = 1 << 11;
+        /// fail if the file is not a directory
+        const DIRECTORY = 1 << 16;
+    }
+}
+
+bitflags! {
+    /// Ways of accessing an inode, in the bit positions of its mode
+    pub struct Access: u16 {
+        /// read a file or list a directory
+        const READ = 0o4;
+        /// write a file or change the entries of a directory
+        const WRITE = 0o2;
+        /// execute a file or search a directory
+        const EXEC = 0o1;
+    }
+}
+
+/// Who a process acts as when it accesses files
+#[derive(Clone, Copy, Debug)]
+pub struct Credential {
+    /// user id
+    pub uid: u32,
+    /// group id
+    pub gid: u32,
+}
+
+impl Credential {
+    /// the superuser, who may access everything
+    pub const ROOT: Self = Self { uid: 0, gid: 0 };
+    /// May `self` access `inode` in all the ways of `access`?
+    pub fn permits(&self, inode: &dyn VfsInode, access: Access) -> bool {
+        if self.uid == 0 {
+            return true;
+        }
+        let mode = inode.mode();
+        let (uid, gid) = inode.owner();
+        let granted = if uid == self.uid {
+            mode >> 6
+        } else if gid == self.gid {
+            mode >> 3
+        } else {
+            mode
+        };
+        Access::from_bits_truncate(granted).contains(access)
     }
 }
 
@@ -94,31 +190,276 @@ impl OpenFlags {
     }
 }
 
-/// Open a file
+/// Find the inode at `path`, relative to `dir` unless it starts with '/'.
+/// `cred` must be allowed to search every directory on the way. The walk
+/// enters file systems mounted on the way and leaves them at "..".
+pub fn find_path(
+    dir: &Arc<dyn VfsInode>,
+    path: &str,
+    cred: &Credential,
+) -> Option<Arc<dyn VfsInode>> {
+    if path.is_empty() {
+        return None;
+    }
+    let mut inode = cross_down(if path.starts_with('/') {
+        ROOT_INODE.clone()
+    } else {
+        dir.clone()
+    });
+    for name in path.split('/') {
+        if name.is_empty() || name == "." {
+            continue;
+        }
+        if !inode.is_dir() || !cred.permits(&*inode, Access::EXEC) {
+            return None;
+        }
+        if name == ".." {
+            inode = cross_up(inode);
+        }
+        inode = cross_down(inode.find(name)?);
+    }
+    Some(inode)
+}
+
+/// Split `path` into the directory holding its last component and that
+/// component's name, which is neither "." nor "..". `cred` must be allowed
+/// to change the entries of the directory.
+pub fn find_parent<'a>(
+    dir: &Arc<dyn VfsInode>,
+    path: &'a str,
+    cred: &Credential,
+) -> Option<(Arc<dyn VfsInode>, &'a str)> {
+    let path = path.trim_end_matches('/');
+    let (parent, name) = match path.rfind('/') {
+        Some(pos) => (&path[..=pos], &path[pos + 1..]),
+        None => ("", path),
+    };
+    if name.is_empty() || name == "." || name == ".." {
+        return None;
+    }
+    let parent = if parent.is_empty() {
+        dir.clone()
+    } else {
+        find_path(dir, parent, cred)?
+    };
+    if !parent.is_dir() || !cred.permits(&*parent, Access::WRITE | Access::EXEC) {
+        return None;
+    }
+    Some((parent, name))
+}
+
+/// The absolute path of the directory `dir`, `None` if it was removed or
+/// its file system was unmounted
+pub fn path_of(dir: &Arc<dyn VfsInode>) -> Option<String> {
+    let root = cross_down(ROOT_INODE.clone());
+    let mut names = Vec::new();
+    let mut inode = dir.clone();
+    while !same_inode(&*cross_down(inode.clone()), &*root) {
+        if !inode.is_dir() {
+            return None;
+        }
+        let inode_here = cross_up(inode);
+        let parent = inode_here.find("..")?;
+        if same_inode(&*parent, &*inode_here) {
+            return None;
+        }
+        names.push(parent.find_name(inode_here.ino())?);
+        inode = parent;
+    }
+    let mut path = String::new();
+    for name in names.iter().rev() {
+        path.push('/');
+        path.push_str(name);
+    }
+    if path.is_empty() {
+        path.push('/');
+    }
+    Some(path)
+}
+
+/// Open a file in the root directory as the superuser
 pub fn open_file(name: &str, flags: OpenFlags) -> Option<Arc<OSInode>> {
-    trace!("kernel: open_file: name = {}, flags = {:?}", name, flags);
+    open_file_at(&ROOT_INODE, name, flags, &Credential::ROOT)
+}
+
+/// Open a file at `path` relative to `dir` on behalf of `cred`, a new file
+/// is owned by `cred`. Directories can only be opened read-only.
+pub fn open_file_at(
+    dir: &Arc<dyn VfsInode>,
+    path: &str,
+    flags: OpenFlags,
+    cred: &Credential,
+) -> Option<Arc<OSInode>> {
+    trace!("kernel: open_file: path = {}, flags = {:?}", path, flags);
     let (readable, writable) = flags.read_write();
-    if flags.contains(OpenFlags::CREATE) {
-        if let Some(inode) = ROOT_INODE.find(name) {
+    let writes = writable || flags.contains(OpenFlags::TRUNC);
+    let inode = if let Some(inode) = find_path(dir, path, cred) {
+        if flags.contains(OpenFlags::CREATE | OpenFlags::EXCL)
+            || (inode.is_dir() && writes)
+            || (!inode.is_dir() && flags.contains(OpenFlags::DIRECTORY))
+        {
+            return None;
+        }
+        let mut access = Access::empty();
+        access.set(Access::READ, readable);
+        access.set(Access::WRITE, writes);
+        if !cred.permits(&*inode, access) {
+            return None;
+        }
+        if flags.contains(OpenFlags::TRUNC) {
             // clear size
-            inode.clear();
-            Some(Arc::new(OSInode::new(readable, writable, inode)))
-        } else {
-            // create file
-            ROOT_INODE
-                .create(name)
-                .map(|inode| Arc::new(OSInode::new(readable, writable, inode)))
+            inode.truncate(0);
         }
+        inode
+    } else if flags.contains(OpenFlags::CREATE) && !flags.contains(OpenFlags::DIRECTORY) {
+        // create file
+        let (parent, name) = find_parent(dir, path, cred)?;
+        let inode = parent.create(name)?;
+        inode.set_owner(cred.uid, cred.gid);
+        inode
     } else {
-        ROOT_INODE.find(name).map(|inode| {
-            if flags.contains(OpenFlags::TRUNC) {
-                inode.clear();
-            }
-            Arc::new(OSInode::new(readable, writable, inode))
-        })
+        return None;
+    };
+    let append = flags.contains(OpenFlags::APPEND);
+    Some(Arc::new(OSInode::new(readable, writable, append, inode)))
+}
+
+/// Add a link at `new_path` to the file at `old_path`, both relative to `dir`
+pub fn link_at(dir: &Arc<dyn VfsInode>, old_path: &str, new_path: &str, cred: &Credential) -> bool {
+    match (
+        find_path(dir, old_path, cred),
+        find_parent(dir, new_path, cred),
+    ) {
+        (Some(inode), Some((parent, name))) => parent.link(name, &*inode),
+        _ => false,
     }
 }
 
+/// Move the entry at `old_path` to `new_path`, both relative to `dir`,
+/// replacing what is at `new_path` unless `replace` is false. A replaced
+/// file is released now if this was its last link and it is not open.
+pub fn rename_at(
+    dir: &Arc<dyn VfsInode>,
+    old_path: &str,
+    new_path: &str,
+    replace: bool,
+    cred: &Credential,
+) -> bool {
+    let ((old_parent, old_name), (new_parent, new_name)) = match (
+        find_parent(dir, old_path, cred),
+        find_parent(dir, new_path, cred),
+    ) {
+        (Some(old), Some(new)) => (old, new),
+        _ => return false,
+    };
+    let replaced = new_parent.find(new_name);
+    if replaced.is_some() && !replace {
+        return false;
+    }
+    // a replaced directory is freed by the rename itself
+    let replaced = replaced.filter(|inode| !inode.is_dir());
+    if !old_parent.rename(old_name, &*new_parent, new_name) {
+        return false;
+    }
+    if let Some(inode) = replaced {
+        if inode.nlink() == 0
+            && !OPEN_INODES
+                .exclusive_access()
+                .contains_key(&(inode.dev(), inode.ino()))
+        {
+            inode.release();
+        }
+    }
+    true
+}
+
+/// Remove the link at `path` relative to `dir` to a file. The file is
+/// released now if this was its last link and it is not open.
+pub fn unlink_at(dir: &Arc<dyn VfsInode>, path: &str, cred: &Credential) -> bool {
+    let inode = match find_parent(dir, path, cred).and_then(|(parent, name)| parent.unlink(name)) {
+        Some(inode) => inode,
+        None => return false,
+    };
+    if !OPEN_INODES
+        .exclusive_access()
+        .contains_key(&(inode.dev(), inode.ino()))
+    {
+        inode.release();
+    }
+    true
+}
+
+/// Mount a new file system of type `fstype` on the directory at `path`
+/// relative to `dir`. Only the superuser may mount, and only "ramfs" can be
+/// made for now.
+pub fn mount_at(dir: &Arc<dyn VfsInode>, path: &str, fstype: &str, cred: &Credential) -> bool {
+    if cred.uid != 0 {
+        return false;
+    }
+    let point = match find_path(dir, path, cred) {
+        Some(point) => point,
+        None => return false,
+    };
+    let fs: Arc<dyn SuperBlock> = match fstype {
+        "ramfs" => Arc::new(RamFs::new(alloc_dev())),
+        _ => return false,
+    };
+    mount(&point, fs)
+}
+
+/// Unmount the file system whose root is at `path` relative to `dir`. Only
+/// the superuser may unmount, and not while it is busy: a file of it is
+/// open, or the working directory of a process is on it.
+pub fn umount_at(dir: &Arc<dyn VfsInode>, path: &str, cred: &Credential) -> bool {
+    if cred.uid != 0 {
+        return false;
+    }
+    let root = match find_path(dir, path, cred) {
+        Some(root) => root,
+        None => return false,
+    };
+    if OPEN_INODES
+        .exclusive_access()
+        .keys()
+        .any(|&(dev, _)| dev == root.dev())
+        || cwd_on_dev(root.dev())
+    {
+        return false;
+    }
+    umount(&root)
+}
+
+/// read the data of 'inode' from 'offset' into buffer, return the number of bytes read
+fn read_inode(inode: &dyn VfsInode, mut offset: usize, mut buf: UserBuffer) -> usize {
+    let mut total_read_size = 0usize;
+    for slice in buf.buffers.iter_mut() {
+        let read_size = inode.read_at(offset, *slice);
+        if read_size == 0 {
+            break;
+        }
+        offset += read_size;
+        total_read_size += read_size;
+    }
+    total_read_size
+}
+
+/// write buffer data into 'inode' from 'offset', return the number of bytes written
+///
+/// The write stops short when the file system is full or the file at its
+/// largest size.
+fn write_inode(inode: &dyn VfsInode, mut offset: usize, buf: UserBuffer) -> usize {
+    let mut total_write_size = 0usize;
+    for slice in buf.buffers.iter() {
+        let write_size = inode.write_at(offset, *slice);
+        offset += write_size;
+        total_write_size += write_size;
+        if write_size < slice.len() {
+            break;
+        }
+    }
+    total_write_size
+}
+
 impl File for OSInode {
     /// file readable?
     fn readable(&self) -> bool {
@@ -129,31 +470,108 @@ impl File for OSInode {
         self.writable
     }
     /// read file data into buffer
-    fn read(&self, mut buf: UserBuffer) -> usize {
+    fn read(&self, buf: UserBuffer) -> usize {
         trace!("kernel: OSInode::read");
         let mut inner = self.inner.exclusive_access();
-        let mut total_read_size = 0usize;
-        for slice in buf.buffers.iter_mut() {
-            let read_size = inner.inode.read_at(inner.offset, *slice);
-            if read_size == 0 {
-                break;
-            }
-            inner.offset += read_size;
-            total_read_size += read_size;
-        }
+        let total_read_size = read_inode(&*inner.inode, inner.offset, buf);
+        inner.offset += total_read_size;
         total_read_size
     }
     /// write buffer data into file
     fn write(&self, buf: UserBuffer) -> usize {
         trace!("kernel: OSInode::write");
         let mut inner = self.inner.exclusive_access();
-        let mut total_write_size = 0usize;
-        for slice in buf.buffers.iter() {
-            let write_size = inner.inode.write_at(inner.offset, *slice);
-            assert_eq!(write_size, slice.len());
-            inner.offset += write_size;
-            total_write_size += write_size;
+        if self.append {
+            inner.offset = inner.inode.size();
         }
+        let total_write_size = write_inode(&*inner.inode, inner.offset, buf);
+        inner.offset += total_write_size;
         total_write_size
     }
+    fn seek(&self, offset: isize, whence: usize) -> Option<usize> {
+        let mut inner = self.inner.exclusive_access();
+        let base = match whence {
+            SEEK_SET => 0,
+            SEEK_CUR => inner.offset,
+            SEEK_END => inner.inode.size(),
+            _ => return None,
+        };
+        // a file can be seeked past its end, but not before its start
+        let offset = base.checked_add_signed(offset)?;
+        inner.offset = offset;
+        Some(offset)
+    }
+    fn read_at(&self, offset: usize, buf: UserBuffer) -> Option<usize> {
+        let inner = self.inner.exclusive_access();
+        Some(read_inode(&*inner.inode, offset, buf))
+    }
+    fn write_at(&self, offset: usize, buf: UserBuffer) -> Option<usize> {
+        let inner = self.inner.exclusive_access();
+        let len = buf.len();
+        match write_inode(&*inner.inode, offset, buf) {
+            0 if len > 0 => None,
+            write_size => Some(write_size),
+        }
+    }
+    fn truncate(&self, size: usize) -> bool {
+        self.inner.exclusive_access().inode.truncate(size)
+    }
+    fn read_dir(&self, buf: UserBuffer) -> Option<usize> {
+        let mut inner = self.inner.exclusive_access();
+        if !inner.inode.is_dir() {
+            return None;
+        }
+        let mut records: Vec<u8> = Vec::new();
+        while let Some((name, inode, next)) = inner.inode.read_dirent(inner.offset) {
+            // the name ends with a nul, and records are aligned to 8 bytes
+            let reclen = (DIRENT64_HEADER_SZ + name.len() + 1 + 7) / 8 * 8;
+            if records.len() + reclen > buf.len() {
+                if records.is_empty() {
+                    return None;
+                }
+                break;
+            }
+            let start = records.len();
+            records.extend_from_slice(&inode.ino().to_le_bytes());
+            records.extend_from_slice(&(next as u64).to_le_bytes());
+            records.extend_from_slice(&(reclen as u16).to_le_bytes());
+            records.push(if inode.is_dir() { DT_DIR } else { DT_REG });
+            records.extend_from_slice(name.as_bytes());
+            records.resize(start + reclen, 0);
+            inner.offset = next;
+        }
+        for (byte_ref, byte) in buf.into_iter().zip(records.iter()) {
+            unsafe {
+                *byte_ref = *byte;
+            }
+        }
+        Some(records.len())
+    }
+    fn sync(&self) -> bool {
+        self.inner.exclusive_access().inode.sync();
+        true
+    }
+    fn stat(&self) -> Option<Stat> {
+        let inner = self.inner.exclusive_access();
+        let inode = &inner.inode;
+        let kind = if inode.is_dir() {
+            StatMode::DIR
+        } else {
+            StatMode::FILE
+        };
+        let (uid, gid) = inode.owner();
+        let (atime, mtime, ctime) = inode.times();
+        Some(Stat {
+            dev: inode.dev(),
+            ino: inode.ino(),
+            mode: kind | StatMode::from_bits_truncate(inode.mode() as u32),
+            nlink: inode.nlink(),
+            uid,
+            gid,
+            atime,
+            mtime,
+            ctime,
+            pad: [0; 3],
+        })
+    }
 }
@@ -1,10 +1,26 @@
 //! File trait & inode(dir, file, pipe, stdin, stdout)
 
+mod efs;
 mod inode;
 mod pipe;
+mod ramfs;
 mod stdio;
+mod vfs;
 
 use crate::mm::UserBuffer;
+use crate::task::TaskControlBlock;
+use crate::timer::sleep_ms;
+use alloc::sync::Arc;
+
+/// how often mounted file systems are written back, in milliseconds
+const WRITEBACK_INTERVAL_MS: usize = 1000;
+
+/// `whence` of `File::seek`, the offset is from the start of the file
+pub const SEEK_SET: usize = 0;
+/// `whence` of `File::seek`, the offset is from the current offset
+pub const SEEK_CUR: usize = 1;
+/// `whence` of `File::seek`, the offset is from the end of the file
+pub const SEEK_END: usize = 2;
 
 /// trait File for all file types
 pub trait File: Send + Sync {
@@ -16,6 +32,54 @@ pub trait File: Send + Sync {
     fn read(&self, buf: UserBuffer) -> usize;
     /// write to the file from buf, return the number of bytes written
     fn write(&self, buf: UserBuffer) -> usize;
+    /// read without blocking, return `None` if the read would block
+    fn try_read(&self, buf: UserBuffer) -> Option<usize> {
+        Some(self.read(buf))
+    }
+    /// write without blocking, return `None` if the write would block
+    fn try_write(&self, buf: UserBuffer) -> Option<usize> {
+        Some(self.write(buf))
+    }
+    /// wake the blocked `task` once a blocked `try_read` or `try_write` may
+    /// go on, return false if the file cannot tell
+    fn add_waiter(&self, _task: Arc<TaskControlBlock>) -> bool {
+        false
+    }
+    /// forget `task` added by `add_waiter`
+    fn remove_waiter(&self, _task: &Arc<TaskControlBlock>) {}
+    /// the stat of the file, `None` if it is not backed by an inode
+    fn stat(&self) -> Option<Stat> {
+        None
+    }
+    /// write the file back to the disk, return false if it is not backed by one
+    fn sync(&self) -> bool {
+        false
+    }
+    /// move the offset of the file by `offset` from where `whence` says,
+    /// return the new offset, `None` if the file cannot seek
+    fn seek(&self, _offset: isize, _whence: usize) -> Option<usize> {
+        None
+    }
+    /// read from `offset` of the file to buf without moving the offset of
+    /// the file, `None` if the file cannot seek
+    fn read_at(&self, _offset: usize, _buf: UserBuffer) -> Option<usize> {
+        None
+    }
+    /// write to `offset` of the file from buf without moving the offset of
+    /// the file, `None` if the file cannot seek
+    fn write_at(&self, _offset: usize, _buf: UserBuffer) -> Option<usize> {
+        None
+    }
+    /// set the size of the file, return false if it is not a regular file
+    fn truncate(&self, _size: usize) -> bool {
+        false
+    }
+    /// read the next entries of a directory to buf as `linux_dirent64`
+    /// records, return the number of bytes read, `None` if the file is not
+    /// a directory or buf cannot hold the next entry
+    fn read_dir(&self, _buf: UserBuffer) -> Option<usize> {
+        None
+    }
 }
 
 /// The stat of a inode
@@ -30,8 +94,18 @@ pub struct Stat {
     pub mode: StatMode,
     /// number of hard links
     pub nlink: u32,
+    /// user id of the owner
+    pub uid: u32,
+    /// group id of the owner
+    pub gid: u32,
+    /// last access time, in seconds
+    pub atime: u64,
+    /// last modification time, in seconds
+    pub mtime: u64,
+    /// last status change time, in seconds
+    pub ctime: u64,
     /// unused pad
-    pad: [u64; 7],
+    pad: [u64; 3],
 }
 
 bitflags! {
@@ -44,9 +118,23 @@ bitflags! {
         const DIR   = 0o040000;
         /// ordinary regular file
         const FILE  = 0o100000;
+        /// permission bits of owner, group and others
+        const PERM  = 0o777;
     }
 }
 
-pub use inode::{list_apps, open_file, OSInode, OpenFlags};
+pub use inode::{
+    find_parent, find_path, link_at, list_apps, mount_at, open_file, open_file_at, path_of,
+    rename_at, umount_at, unlink_at, Access, Credential, OSInode, OpenFlags, ROOT_INODE,
+};
 pub use pipe::{make_pipe, Pipe};
 pub use stdio::{Stdin, Stdout};
+pub use vfs::{sync_all, SuperBlock, VfsInode};
+
+/// Kernel task writing mounted file systems back to their devices periodically
+pub async fn writeback() {
+    loop {
+        sleep_ms(WRITEBACK_INTERVAL_MS).await;
+        sync_all();
+    }
+}
@@ -2,8 +2,9 @@ use super::File;
 use crate::mm::UserBuffer;
 use crate::sync::UPSafeCell;
 use alloc::sync::{Arc, Weak};
+use alloc::vec::Vec;
 
-use crate::task::suspend_current_and_run_next;
+use crate::task::{suspend_current_and_run_next, wakeup_task, TaskControlBlock};
 
 /// IPC pipe
 pub struct Pipe {
@@ -46,6 +47,8 @@ pub struct PipeRingBuffer {
     tail: usize,
     status: RingBufferStatus,
     write_end: Option<Weak<Pipe>>,
+    /// tasks blocked until the pipe can be read or written again
+    waiters: Vec<Arc<TaskControlBlock>>,
 }
 
 impl PipeRingBuffer {
@@ -56,6 +59,7 @@ impl PipeRingBuffer {
             tail: 0,
             status: RingBufferStatus::Empty,
             write_end: None,
+            waiters: Vec::new(),
         }
     }
     pub fn set_write_end(&mut self, write_end: &Arc<Pipe>) {
@@ -97,6 +101,12 @@ impl PipeRingBuffer {
     pub fn all_write_ends_closed(&self) -> bool {
         self.write_end.as_ref().unwrap().upgrade().is_none()
     }
+    /// wake the tasks waiting for the pipe
+    fn wake_waiters(&mut self) {
+        for task in self.waiters.drain(..) {
+            wakeup_task(task);
+        }
+    }
 }
 
 /// Return (read_end, write_end)
@@ -133,6 +143,8 @@ impl File for Pipe {
                 suspend_current_and_run_next();
                 continue;
             }
+            // the waiters run once this task gives up the CPU, after the copy
+            ring_buffer.wake_waiters();
             for _ in 0..loop_read {
                 if let Some(byte_ref) = buf_iter.next() {
                     unsafe {
@@ -162,6 +174,7 @@ impl File for Pipe {
                 suspend_current_and_run_next();
                 continue;
             }
+            ring_buffer.wake_waiters();
             // write at most loop_write bytes
             for _ in 0..loop_write {
                 if let Some(byte_ref) = buf_iter.next() {
@@ -176,4 +189,62 @@ impl File for Pipe {
             }
         }
     }
+    fn try_read(&self, buf: UserBuffer) -> Option<usize> {
+        trace!("kernel: Pipe::try_read");
+        assert!(self.readable());
+        let mut ring_buffer = self.buffer.exclusive_access();
+        let loop_read = ring_buffer.available_read();
+        if loop_read == 0 {
+            // end of file once every write end is gone
+            return if ring_buffer.all_write_ends_closed() {
+                Some(0)
+            } else {
+                None
+            };
+        }
+        let mut already_read = 0usize;
+        for byte_ref in buf.into_iter().take(loop_read) {
+            unsafe {
+                *byte_ref = ring_buffer.read_byte();
+            }
+            already_read += 1;
+        }
+        ring_buffer.wake_waiters();
+        Some(already_read)
+    }
+    fn try_write(&self, buf: UserBuffer) -> Option<usize> {
+        trace!("kernel: Pipe::try_write");
+        assert!(self.writable());
+        let mut ring_buffer = self.buffer.exclusive_access();
+        let loop_write = ring_buffer.available_write();
+        if loop_write == 0 {
+            return None;
+        }
+        let mut already_write = 0usize;
+        for byte_ref in buf.into_iter().take(loop_write) {
+            ring_buffer.write_byte(unsafe { *byte_ref });
+            already_write += 1;
+        }
+        ring_buffer.wake_waiters();
+        Some(already_write)
+    }
+    fn add_waiter(&self, task: Arc<TaskControlBlock>) -> bool {
+        self.buffer.exclusive_access().waiters.push(task);
+        true
+    }
+    fn remove_waiter(&self, task: &Arc<TaskControlBlock>) {
+        self.buffer
+            .exclusive_access()
+            .waiters
+            .retain(|waiter| !Arc::ptr_eq(waiter, task));
+    }
+}
+
+impl Drop for Pipe {
+    fn drop(&mut self) {
+        // readers waiting for the pipe see its end once the write end is gone
+        if self.writable {
+            self.buffer.exclusive_access().wake_waiters();
+        }
+    }
 }
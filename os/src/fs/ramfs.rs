@@ -0,0 +1,503 @@
+//! An in-memory file system, its content is gone once it is unmounted
+
+use super::vfs::{SuperBlock, VfsInode};
+use crate::sync::UPSafeCell;
+use crate::timer::get_time_ms;
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::sync::{Arc, Weak};
+use alloc::vec::Vec;
+use core::any::Any;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+/// the longest name of a directory entry, as in easy-fs
+const NAME_LENGTH_LIMIT: usize = 255;
+/// the largest size of a file, its data is on the kernel heap
+const MAX_FILE_SIZE: usize = 4 * 1024 * 1024;
+/// seconds after which a read records the access time again, as in easy-fs
+const RELATIME_INTERVAL: u64 = 24 * 60 * 60;
+
+/// seconds since boot
+fn now() -> u64 {
+    (get_time_ms() / 1000) as u64
+}
+
+/// An in-memory file system
+pub struct RamFs {
+    root: Arc<RamInode>,
+}
+
+impl RamFs {
+    /// a file system with an empty root directory, with device number `dev`
+    pub fn new(dev: u64) -> Self {
+        Self {
+            root: RamInode::new_dir(dev, Arc::new(AtomicU64::new(0)), None),
+        }
+    }
+}
+
+impl SuperBlock for RamFs {
+    fn root(&self) -> Arc<dyn VfsInode> {
+        self.root.clone()
+    }
+    fn sync(&self) {}
+}
+
+/// the data of a file, or the entries of a directory but "." and ".."
+enum Content {
+    File(Vec<u8>),
+    Dir(Entries),
+}
+
+/// the entries of a directory, each in a slot that stays the same while
+/// others come and go, so reading the directory goes on from a slot
+struct Entries {
+    /// slot -> name and inode
+    slots: BTreeMap<usize, (String, Arc<RamInode>)>,
+    /// name -> slot
+    names: BTreeMap<String, usize>,
+    /// the slot of the next entry, the first two are "." and ".."
+    next_slot: usize,
+}
+
+impl Entries {
+    fn new() -> Self {
+        Self {
+            slots: BTreeMap::new(),
+            names: BTreeMap::new(),
+            next_slot: 2,
+        }
+    }
+    fn is_empty(&self) -> bool {
+        self.names.is_empty()
+    }
+    fn get(&self, name: &str) -> Option<&Arc<RamInode>> {
+        self.names.get(name).map(|slot| &self.slots[slot].1)
+    }
+    /// put `inode` in a new slot, in place of an entry with `name`
+    fn insert(&mut self, name: String, inode: Arc<RamInode>) {
+        self.remove(&name);
+        self.names.insert(name.clone(), self.next_slot);
+        self.slots.insert(self.next_slot, (name, inode));
+        self.next_slot += 1;
+    }
+    fn remove(&mut self, name: &str) -> Option<Arc<RamInode>> {
+        let slot = self.names.remove(name)?;
+        self.slots.remove(&slot).map(|(_, inode)| inode)
+    }
+    fn iter(&self) -> impl Iterator<Item = &(String, Arc<RamInode>)> {
+        self.slots.values()
+    }
+    /// the first entry at slot `from` or after it, with its slot
+    fn from(&self, from: usize) -> Option<(usize, &(String, Arc<RamInode>))> {
+        self.slots
+            .range(from..)
+            .next()
+            .map(|(slot, entry)| (*slot, entry))
+    }
+}
+
+/// inner of an in-memory inode
+struct RamInodeInner {
+    content: Content,
+    /// the directory holding a directory, `None` for the root
+    parent: Option<Weak<RamInode>>,
+    nlink: u32,
+    mode: u16,
+    uid: u32,
+    gid: u32,
+    atime: u64,
+    mtime: u64,
+    ctime: u64,
+}
+
+impl RamInodeInner {
+    /// the entries of a directory, `None` for a file
+    fn entries(&mut self) -> Option<&mut Entries> {
+        match &mut self.content {
+            Content::Dir(entries) => Some(entries),
+            Content::File(_) => None,
+        }
+    }
+    /// set the modification and change time to now
+    fn touch(&mut self) {
+        self.mtime = now();
+        self.ctime = self.mtime;
+    }
+    /// record a read, only if the access time is not after the last change
+    /// or is a day old, like `DiskInode::needs_access` of easy-fs
+    fn access(&mut self) {
+        let now = now();
+        if now != self.atime
+            && (self.atime <= self.mtime
+                || self.atime <= self.ctime
+                || now >= self.atime + RELATIME_INTERVAL)
+        {
+            self.atime = now;
+        }
+    }
+}
+
+/// An inode of an in-memory file system
+pub struct RamInode {
+    dev: u64,
+    ino: u64,
+    /// the inode number of the next inode of the file system
+    next_ino: Arc<AtomicU64>,
+    /// this inode, to put it into directories
+    this: Weak<RamInode>,
+    inner: UPSafeCell<RamInodeInner>,
+}
+
+impl RamInode {
+    /// a new empty file
+    fn new_file(dev: u64, next_ino: Arc<AtomicU64>) -> Arc<Self> {
+        Self::new(dev, next_ino, Content::File(Vec::new()), None, 1, 0o644)
+    }
+    /// a new empty directory in `parent`, the root if there is none
+    fn new_dir(dev: u64, next_ino: Arc<AtomicU64>, parent: Option<Weak<Self>>) -> Arc<Self> {
+        Self::new(
+            dev,
+            next_ino,
+            Content::Dir(Entries::new()),
+            parent,
+            2,
+            0o755,
+        )
+    }
+    /// a new inode holding `content`
+    fn new(
+        dev: u64,
+        next_ino: Arc<AtomicU64>,
+        content: Content,
+        parent: Option<Weak<Self>>,
+        nlink: u32,
+        mode: u16,
+    ) -> Arc<Self> {
+        let time = now();
+        Arc::new_cyclic(|this| Self {
+            dev,
+            ino: next_ino.fetch_add(1, Ordering::Relaxed),
+            next_ino,
+            this: this.clone(),
+            inner: unsafe {
+                UPSafeCell::new(RamInodeInner {
+                    content,
+                    parent,
+                    nlink,
+                    mode,
+                    uid: 0,
+                    gid: 0,
+                    atime: time,
+                    mtime: time,
+                    ctime: time,
+                })
+            },
+        })
+    }
+    /// `inode` if it is an inode of the same file system
+    fn same_fs<'a>(&self, inode: &'a dyn VfsInode) -> Option<&'a Self> {
+        inode
+            .as_any()
+            .downcast_ref::<Self>()
+            .filter(|inode| inode.dev == self.dev)
+    }
+    /// the directory holding this directory, or this one for the root,
+    /// `None` if this one was removed and its directory is gone too
+    fn parent(&self) -> Option<Arc<Self>> {
+        match &self.inner.exclusive_access().parent {
+            Some(parent) => parent.upgrade(),
+            None => self.this.upgrade(),
+        }
+    }
+    /// the entry with `name`, "." and ".." excluded
+    fn entry(&self, name: &str) -> Option<Arc<Self>> {
+        self.inner.exclusive_access().entries()?.get(name).cloned()
+    }
+    /// can `name` be the name of a directory entry?
+    fn is_valid_name(name: &str) -> bool {
+        !name.is_empty()
+            && name.len() <= NAME_LENGTH_LIMIT
+            && !name.contains(['/', '\0'])
+            && name != "."
+            && name != ".."
+    }
+    /// is this a directory with no entries?
+    fn is_empty_dir(&self) -> bool {
+        matches!(self.inner.exclusive_access().entries(), Some(entries) if entries.is_empty())
+    }
+    /// put a new file or directory in this directory
+    fn create_inode(&self, name: &str, is_dir: bool) -> Option<Arc<dyn VfsInode>> {
+        if !Self::is_valid_name(name) || !self.is_dir() || self.entry(name).is_some() {
+            return None;
+        }
+        let inode = if is_dir {
+            Self::new_dir(self.dev, self.next_ino.clone(), Some(self.this.clone()))
+        } else {
+            Self::new_file(self.dev, self.next_ino.clone())
+        };
+        let mut inner = self.inner.exclusive_access();
+        inner
+            .entries()
+            .unwrap()
+            .insert(String::from(name), inode.clone());
+        if is_dir {
+            inner.nlink += 1;
+        }
+        inner.touch();
+        Some(inode)
+    }
+}
+
+impl VfsInode for RamInode {
+    fn dev(&self) -> u64 {
+        self.dev
+    }
+    fn ino(&self) -> u64 {
+        self.ino
+    }
+    fn is_dir(&self) -> bool {
+        matches!(self.inner.exclusive_access().content, Content::Dir(_))
+    }
+    fn size(&self) -> usize {
+        match &self.inner.exclusive_access().content {
+            Content::File(data) => data.len(),
+            Content::Dir(_) => 0,
+        }
+    }
+    fn nlink(&self) -> u32 {
+        self.inner.exclusive_access().nlink
+    }
+    fn mode(&self) -> u16 {
+        self.inner.exclusive_access().mode
+    }
+    fn owner(&self) -> (u32, u32) {
+        let inner = self.inner.exclusive_access();
+        (inner.uid, inner.gid)
+    }
+    fn set_owner(&self, uid: u32, gid: u32) {
+        let mut inner = self.inner.exclusive_access();
+        inner.uid = uid;
+        inner.gid = gid;
+        inner.ctime = now();
+    }
+    fn times(&self) -> (u64, u64, u64) {
+        let inner = self.inner.exclusive_access();
+        (inner.atime, inner.mtime, inner.ctime)
+    }
+    fn read_at(&self, offset: usize, buf: &mut [u8]) -> usize {
+        let mut inner = self.inner.exclusive_access();
+        inner.access();
+        let data = match &inner.content {
+            Content::File(data) => data,
+            Content::Dir(_) => return 0,
+        };
+        let start = offset.min(data.len());
+        let len = buf.len().min(data.len() - start);
+        buf[..len].copy_from_slice(&data[start..start + len]);
+        len
+    }
+    fn write_at(&self, offset: usize, buf: &[u8]) -> usize {
+        let mut inner = self.inner.exclusive_access();
+        let data = match &mut inner.content {
+            Content::File(data) => data,
+            Content::Dir(_) => return 0,
+        };
+        // the write stops short at the largest size, or when the heap is full
+        let end = offset.saturating_add(buf.len()).min(MAX_FILE_SIZE);
+        if offset >= end {
+            return 0;
+        }
+        if data.len() < end {
+            if data.try_reserve(end - data.len()).is_err() {
+                return 0;
+            }
+            data.resize(end, 0);
+        }
+        data[offset..end].copy_from_slice(&buf[..end - offset]);
+        inner.touch();
+        end - offset
+    }
+    fn truncate(&self, size: usize) -> bool {
+        let mut inner = self.inner.exclusive_access();
+        match &mut inner.content {
+            Content::File(data) => {
+                if size > MAX_FILE_SIZE
+                    || (size > data.len() && data.try_reserve(size - data.len()).is_err())
+                {
+                    return false;
+                }
+                data.resize(size, 0);
+            }
+            Content::Dir(_) => return false,
+        }
+        inner.touch();
+        true
+    }
+    fn sync(&self) {}
+    fn find(&self, name: &str) -> Option<Arc<dyn VfsInode>> {
+        if !self.is_dir() {
+            return None;
+        }
+        match name {
+            "." => Some(self.this.upgrade().unwrap()),
+            ".." => self.parent().map(|parent| parent as Arc<dyn VfsInode>),
+            _ => self.entry(name).map(|inode| inode as Arc<dyn VfsInode>),
+        }
+    }
+    fn find_name(&self, ino: u64) -> Option<String> {
+        self.inner
+            .exclusive_access()
+            .entries()?
+            .iter()
+            .find(|(_, inode)| inode.ino == ino)
+            .map(|(name, _)| name.clone())
+    }
+    fn read_dirent(&self, from: usize) -> Option<(String, Arc<dyn VfsInode>, usize)> {
+        if !self.is_dir() {
+            return None;
+        }
+        // "." and ".." come first, then the entries in their slots
+        let (name, inode, slot): (String, Arc<dyn VfsInode>, usize) = match from {
+            0 => (String::from("."), self.this.upgrade().unwrap(), 0),
+            1 => (String::from(".."), self.parent()?, 1),
+            _ => {
+                let mut inner = self.inner.exclusive_access();
+                let (slot, (name, inode)) = inner.entries().unwrap().from(from)?;
+                (name.clone(), inode.clone(), slot)
+            }
+        };
+        Some((name, inode, slot + 1))
+    }
+    fn create(&self, name: &str) -> Option<Arc<dyn VfsInode>> {
+        self.create_inode(name, false)
+    }
+    fn create_dir(&self, name: &str) -> Option<Arc<dyn VfsInode>> {
+        self.create_inode(name, true)
+    }
+    fn link(&self, name: &str, inode: &dyn VfsInode) -> bool {
+        let inode = match self.same_fs(inode) {
+            Some(inode) if !inode.is_dir() => inode.this.upgrade().unwrap(),
+            _ => return false,
+        };
+        if !Self::is_valid_name(name) || !self.is_dir() || self.entry(name).is_some() {
+            return false;
+        }
+        let mut inner = self.inner.exclusive_access();
+        inner
+            .entries()
+            .unwrap()
+            .insert(String::from(name), inode.clone());
+        inner.touch();
+        let mut inode_inner = inode.inner.exclusive_access();
+        inode_inner.nlink += 1;
+        inode_inner.ctime = now();
+        true
+    }
+    fn unlink(&self, name: &str) -> Option<Arc<dyn VfsInode>> {
+        let inode = self.entry(name)?;
+        if inode.is_dir() {
+            return None;
+        }
+        let mut inner = self.inner.exclusive_access();
+        inner.entries().unwrap().remove(name);
+        inner.touch();
+        let mut inode_inner = inode.inner.exclusive_access();
+        inode_inner.nlink = inode_inner.nlink.saturating_sub(1);
+        inode_inner.ctime = now();
+        drop(inode_inner);
+        Some(inode)
+    }
+    fn release(&self) -> bool {
+        let mut inner = self.inner.exclusive_access();
+        if inner.nlink > 0 {
+            return false;
+        }
+        if let Content::File(data) = &mut inner.content {
+            *data = Vec::new();
+        }
+        true
+    }
+    fn remove_dir(&self, name: &str) -> bool {
+        match self.entry(name) {
+            Some(dir) if dir.is_empty_dir() => {
+                let mut inner = self.inner.exclusive_access();
+                inner.entries().unwrap().remove(name);
+                inner.nlink -= 1;
+                inner.touch();
+                dir.inner.exclusive_access().nlink = 0;
+                true
+            }
+            _ => false,
+        }
+    }
+    fn rename(&self, old_name: &str, new_dir: &dyn VfsInode, new_name: &str) -> bool {
+        let new_dir = match self.same_fs(new_dir) {
+            Some(new_dir) if new_dir.is_dir() => new_dir,
+            _ => return false,
+        };
+        let inode = match self.entry(old_name) {
+            Some(inode) if Self::is_valid_name(new_name) => inode,
+            _ => return false,
+        };
+        let is_dir = inode.is_dir();
+        if is_dir {
+            // walk up from the new directory to the root
+            let mut dir = new_dir.this.upgrade().unwrap();
+            while dir.ino != inode.ino {
+                match dir.parent() {
+                    Some(parent) if parent.ino != dir.ino => dir = parent,
+                    _ => break,
+                }
+            }
+            if dir.ino == inode.ino {
+                return false;
+            }
+        }
+        let replaced = new_dir.entry(new_name);
+        match &replaced {
+            // both names link to the same file already
+            Some(replaced) if replaced.ino == inode.ino => return true,
+            Some(replaced) if is_dir && !replaced.is_empty_dir() => return false,
+            Some(replaced) if !is_dir && replaced.is_dir() => return false,
+            _ => {}
+        }
+        let mut inner = self.inner.exclusive_access();
+        inner.entries().unwrap().remove(old_name);
+        if is_dir {
+            inner.nlink -= 1;
+        }
+        inner.touch();
+        drop(inner);
+        let mut new_inner = new_dir.inner.exclusive_access();
+        new_inner
+            .entries()
+            .unwrap()
+            .insert(String::from(new_name), inode.clone());
+        if is_dir {
+            new_inner.nlink += 1;
+        }
+        new_inner.touch();
+        drop(new_inner);
+        if let Some(replaced) = replaced {
+            let mut replaced_inner = replaced.inner.exclusive_access();
+            if is_dir {
+                // the ".." of the replaced directory
+                new_dir.inner.exclusive_access().nlink -= 1;
+                replaced_inner.nlink = 0;
+            } else {
+                replaced_inner.nlink = replaced_inner.nlink.saturating_sub(1);
+            }
+            replaced_inner.ctime = now();
+        }
+        let mut inode_inner = inode.inner.exclusive_access();
+        if is_dir {
+            inode_inner.parent = Some(new_dir.this.clone());
+        }
+        inode_inner.ctime = now();
+        true
+    }
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
@@ -16,8 +16,12 @@ impl File for Stdin {
     fn writable(&self) -> bool {
         false
     }
+    /// read one character, or nothing into an empty buffer
     fn read(&self, mut user_buf: UserBuffer) -> usize {
-        assert_eq!(user_buf.len(), 1);
+        let dst = match user_buf.buffers.first_mut() {
+            Some(dst) => dst,
+            None => return 0,
+        };
         // busy loop
         let mut c: usize;
         loop {
@@ -31,13 +35,29 @@ impl File for Stdin {
         }
         let ch = c as u8;
         unsafe {
-            user_buf.buffers[0].as_mut_ptr().write_volatile(ch);
+            dst.as_mut_ptr().write_volatile(ch);
         }
         1
     }
     fn write(&self, _user_buf: UserBuffer) -> usize {
         panic!("Cannot write to stdin!");
     }
+    /// read one character if one is there, like `read`
+    fn try_read(&self, mut user_buf: UserBuffer) -> Option<usize> {
+        let dst = match user_buf.buffers.first_mut() {
+            Some(dst) => dst,
+            None => return Some(0),
+        };
+        match console_getchar() {
+            0 => None,
+            c => {
+                unsafe {
+                    dst.as_mut_ptr().write_volatile(c as u8);
+                }
+                Some(1)
+            }
+        }
+    }
 }
 
 impl File for Stdout {
@@ -0,0 +1,207 @@
+//! Virtual file system
+//!
+//! File systems implement [`SuperBlock`] and [`VfsInode`], and the mount
+//! table joins them into one tree: the root file system is mounted at "/",
+//! others cover directories of file systems mounted before them. An inode
+//! is known by its device number and inode number together.
+
+use crate::sync::UPSafeCell;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::any::Any;
+use core::sync::atomic::{AtomicU64, Ordering};
+use lazy_static::*;
+
+/// A file or directory of a mounted file system
+///
+/// The methods taking another inode fail when it belongs to another file
+/// system.
+pub trait VfsInode: Send + Sync {
+    /// the device number of the file system
+    fn dev(&self) -> u64;
+    /// the inode number, unique in the file system
+    fn ino(&self) -> u64;
+    /// is this inode a directory?
+    fn is_dir(&self) -> bool;
+    /// size of the file in bytes
+    fn size(&self) -> usize;
+    /// the number of directory entries linking to this inode
+    fn nlink(&self) -> u32;
+    /// permission bits of the inode
+    fn mode(&self) -> u16;
+    /// user id and group id of the owner
+    fn owner(&self) -> (u32, u32);
+    /// give the inode to user `uid` and group `gid`
+    fn set_owner(&self, uid: u32, gid: u32);
+    /// access, modification and change time, in seconds
+    fn times(&self) -> (u64, u64, u64);
+    /// read from `offset` of the file into buf, return the number of bytes read
+    fn read_at(&self, offset: usize, buf: &mut [u8]) -> usize;
+    /// write buf to `offset` of the file, growing it if needed, return the
+    /// number of bytes written, short if the file cannot grow that far
+    fn write_at(&self, offset: usize, buf: &[u8]) -> usize;
+    /// set the size of the file, cutting off its end or extending it with
+    /// zeros, return false if it is not a regular file or cannot grow
+    fn truncate(&self, size: usize) -> bool;
+    /// write the file back to the device
+    fn sync(&self);
+    /// the inode of the entry with `name` in this directory
+    fn find(&self, name: &str) -> Option<Arc<dyn VfsInode>>;
+    /// the name this directory gives to the inode `ino`, "." and ".." excluded
+    fn find_name(&self, ino: u64) -> Option<String>;
+    /// the first entry of this directory at cursor `from` or after it, with
+    /// the cursor of the entry after it; the first cursor is 0
+    fn read_dirent(&self, from: usize) -> Option<(String, Arc<dyn VfsInode>, usize)>;
+    /// create a file with `name` in this directory
+    fn create(&self, name: &str) -> Option<Arc<dyn VfsInode>>;
+    /// create a directory with `name` in this directory
+    fn create_dir(&self, name: &str) -> Option<Arc<dyn VfsInode>>;
+    /// add an entry with `name` for the file `inode` to this directory
+    fn link(&self, name: &str, inode: &dyn VfsInode) -> bool;
+    /// remove the entry with `name` of a file from this directory and return
+    /// the file, which keeps its data until [`VfsInode::release`]
+    fn unlink(&self, name: &str) -> Option<Arc<dyn VfsInode>>;
+    /// free the data of a file no entry links to any more, return false if
+    /// it still has links
+    fn release(&self) -> bool;
+    /// remove the empty directory with `name` from this directory
+    fn remove_dir(&self, name: &str) -> bool;
+    /// move the entry with `old_name` in this directory to `new_name` in
+    /// `new_dir`, replacing a file or an empty directory there in one step
+    fn rename(&self, old_name: &str, new_dir: &dyn VfsInode, new_name: &str) -> bool;
+    /// the inode as `Any`, for a file system to recognize its own inodes
+    fn as_any(&self) -> &dyn Any;
+}
+
+/// A file system that can be mounted
+pub trait SuperBlock: Send + Sync {
+    /// the root directory of the file system
+    fn root(&self) -> Arc<dyn VfsInode>;
+    /// write all modified data of the file system back to the device
+    fn sync(&self);
+}
+
+/// Are `a` and `b` the same inode?
+pub fn same_inode(a: &dyn VfsInode, b: &dyn VfsInode) -> bool {
+    a.dev() == b.dev() && a.ino() == b.ino()
+}
+
+/// the device number of the next file system made
+static NEXT_DEV: AtomicU64 = AtomicU64::new(0);
+
+/// A new device number for a file system
+pub fn alloc_dev() -> u64 {
+    NEXT_DEV.fetch_add(1, Ordering::Relaxed)
+}
+
+/// A file system in the mount table
+struct Mount {
+    /// the directory the file system covers, `None` for "/"
+    point: Option<Arc<dyn VfsInode>>,
+    /// the root directory of the file system
+    root: Arc<dyn VfsInode>,
+    fs: Arc<dyn SuperBlock>,
+}
+
+lazy_static! {
+    /// the mounted file systems, in the order they were mounted
+    static ref MOUNTS: UPSafeCell<Vec<Mount>> = unsafe { UPSafeCell::new(Vec::new()) };
+}
+
+/// Mount `fs` at "/", return its root directory
+pub fn mount_root(fs: Arc<dyn SuperBlock>) -> Arc<dyn VfsInode> {
+    let root = fs.root();
+    let mut mounts = MOUNTS.exclusive_access();
+    assert!(mounts.iter().all(|mount| mount.point.is_some()));
+    mounts.push(Mount {
+        point: None,
+        root: root.clone(),
+        fs,
+    });
+    root
+}
+
+/// Mount `fs` on the directory `point`, which nothing covers yet
+pub fn mount(point: &Arc<dyn VfsInode>, fs: Arc<dyn SuperBlock>) -> bool {
+    if !point.is_dir() || covering(point).is_some() {
+        return false;
+    }
+    let root = fs.root();
+    MOUNTS.exclusive_access().push(Mount {
+        point: Some(point.clone()),
+        root,
+        fs,
+    });
+    true
+}
+
+/// Unmount the file system whose root directory is `root`, after writing it
+/// back. It fails for "/", for a directory that is no root and for a file
+/// system with others mounted in it.
+pub fn umount(root: &Arc<dyn VfsInode>) -> bool {
+    let mut mounts = MOUNTS.exclusive_access();
+    let index = match mounts
+        .iter()
+        .position(|mount| mount.point.is_some() && same_inode(&*mount.root, &**root))
+    {
+        Some(index) => index,
+        None => return false,
+    };
+    if mounts
+        .iter()
+        .any(|mount| matches!(&mount.point, Some(point) if point.dev() == root.dev()))
+    {
+        return false;
+    }
+    let mount = mounts.remove(index);
+    drop(mounts);
+    mount.fs.sync();
+    true
+}
+
+/// the root of the file system mounted on `dir`, if any
+fn covering(dir: &Arc<dyn VfsInode>) -> Option<Arc<dyn VfsInode>> {
+    MOUNTS
+        .exclusive_access()
+        .iter()
+        .find(|mount| matches!(&mount.point, Some(point) if same_inode(&**point, &**dir)))
+        .map(|mount| mount.root.clone())
+}
+
+/// What the path walk sees at `dir`: the root of the file system mounted
+/// on it last, or `dir` itself
+pub fn cross_down(mut dir: Arc<dyn VfsInode>) -> Arc<dyn VfsInode> {
+    while let Some(root) = covering(&dir) {
+        dir = root;
+    }
+    dir
+}
+
+/// The directory whose ".." the path walk takes at `dir`: the directory a
+/// mounted file system's root covers, or `dir` itself
+pub fn cross_up(mut dir: Arc<dyn VfsInode>) -> Arc<dyn VfsInode> {
+    loop {
+        let point = MOUNTS
+            .exclusive_access()
+            .iter()
+            .find(|mount| same_inode(&*mount.root, &*dir))
+            .and_then(|mount| mount.point.clone());
+        match point {
+            Some(point) => dir = point,
+            None => return dir,
+        }
+    }
+}
+
+/// Write all mounted file systems back to their devices
+pub fn sync_all() {
+    let fs: Vec<Arc<dyn SuperBlock>> = MOUNTS
+        .exclusive_access()
+        .iter()
+        .map(|mount| mount.fs.clone())
+        .collect();
+    for fs in fs {
+        fs.sync();
+    }
+}
@@ -79,6 +79,7 @@ pub fn rust_main() -> ! {
     timer::set_next_trigger();
     fs::list_apps();
     task::add_initproc();
+    task::kernel_spawn(fs::writeback());
     task::run_tasks();
     panic!("Unreachable in rust_main!");
 }
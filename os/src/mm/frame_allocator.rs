@@ -3,6 +3,7 @@
 use super::{PhysAddr, PhysPageNum};
 use crate::config::MEMORY_END;
 use crate::sync::UPSafeCell;
+use crate::task::reclaim_frames;
 use alloc::vec::Vec;
 use core::fmt::{self, Debug, Formatter};
 use lazy_static::*;
@@ -41,6 +42,7 @@ trait FrameAllocator {
     fn new() -> Self;
     fn alloc(&mut self) -> Option<PhysPageNum>;
     fn dealloc(&mut self, ppn: PhysPageNum);
+    fn free_count(&self) -> usize;
 }
 
 pub struct StackFrameAllocator {
@@ -83,6 +85,9 @@ impl FrameAllocator for StackFrameAllocator {
         // recycle
         self.recycled.push(ppn);
     }
+    fn free_count(&self) -> usize {
+        self.end - self.current + self.recycled.len()
+    }
 }
 
 type FrameAllocatorImpl = StackFrameAllocator;
@@ -102,12 +107,18 @@ pub fn init_frame_allocator() {
     );
 }
 
-/// Allocate a physical page frame in FrameTracker style
+/// Allocate a physical page frame in FrameTracker style. When none is free,
+/// user pages are written to swap first, `None` if that frees nothing.
 pub fn frame_alloc() -> Option<FrameTracker> {
-    FRAME_ALLOCATOR
-        .exclusive_access()
-        .alloc()
-        .map(FrameTracker::new)
+    let ppn = FRAME_ALLOCATOR.exclusive_access().alloc();
+    let ppn = match ppn {
+        Some(ppn) => ppn,
+        None => {
+            reclaim_frames();
+            FRAME_ALLOCATOR.exclusive_access().alloc()?
+        }
+    };
+    Some(FrameTracker::new(ppn))
 }
 
 /// Deallocate a physical page frame with a given ppn
@@ -115,6 +126,11 @@ pub fn frame_dealloc(ppn: PhysPageNum) {
     FRAME_ALLOCATOR.exclusive_access().dealloc(ppn);
 }
 
+/// The number of physical page frames left
+pub fn frame_free_count() -> usize {
+    FRAME_ALLOCATOR.exclusive_access().free_count()
+}
+
 #[allow(unused)]
 pub fn frame_allocator_test() {
     let mut v: Vec<FrameTracker> = Vec::new();
@@ -1,5 +1,6 @@
 //! Address Space [`MemorySet`] management of Process
 
+use super::swap::{swap_out, SwapSlot};
 use super::{frame_alloc, FrameTracker};
 use super::{PTEFlags, PageTable, PageTableEntry};
 use super::{PhysAddr, PhysPageNum, VirtAddr, VirtPageNum};
@@ -57,17 +58,159 @@ impl MemorySet {
     pub fn token(&self) -> usize {
         self.page_table.token()
     }
-    /// Assume that no conflicts.
+    /// Assume that no conflicts. Return false and map nothing if no frame is
+    /// left for the area.
     pub fn insert_framed_area(
         &mut self,
         start_va: VirtAddr,
         end_va: VirtAddr,
         permission: MapPermission,
-    ) {
+    ) -> bool {
         self.push(
             MapArea::new(start_va, end_va, MapType::Framed, permission),
             None,
-        );
+        )
+    }
+    /// Insert an anonymous area whose frames are allocated on first touch.
+    /// Assume that no conflicts.
+    pub fn insert_lazy_area(
+        &mut self,
+        start_va: VirtAddr,
+        end_va: VirtAddr,
+        permission: MapPermission,
+    ) {
+        let mut map_area = MapArea::new(start_va, end_va, MapType::Framed, permission);
+        map_area.lazy = true;
+        self.push(map_area, None);
+    }
+    /// Whether any area intersects `[start_vpn, end_vpn)`
+    pub fn overlaps(&self, start_vpn: VirtPageNum, end_vpn: VirtPageNum) -> bool {
+        self.areas.iter().any(|area| {
+            area.vpn_range.get_start() < end_vpn && start_vpn < area.vpn_range.get_end()
+        })
+    }
+    /// Unmap `[start_vpn, end_vpn)`, splitting the lazy areas that stick out of it.
+    /// Return false and change nothing unless lazy areas cover the whole range.
+    pub fn remove_lazy_range(&mut self, start_vpn: VirtPageNum, end_vpn: VirtPageNum) -> bool {
+        // areas never overlap, so the covered pages can simply be summed up
+        let covered: usize = self
+            .areas
+            .iter()
+            .filter(|area| area.lazy)
+            .map(|area| {
+                let start = area.vpn_range.get_start().max(start_vpn);
+                let end = area.vpn_range.get_end().min(end_vpn);
+                end.0.saturating_sub(start.0)
+            })
+            .sum();
+        if covered != end_vpn.0 - start_vpn.0 {
+            return false;
+        }
+        let mut idx = 0;
+        while idx < self.areas.len() {
+            let area = &self.areas[idx];
+            let (start, end) = (area.vpn_range.get_start(), area.vpn_range.get_end());
+            if !area.lazy || end <= start_vpn || end_vpn <= start {
+                idx += 1;
+                continue;
+            }
+            if start < start_vpn {
+                // keep the head, the tail is visited later
+                let tail = self.areas[idx].split_off(start_vpn);
+                self.areas.push(tail);
+                idx += 1;
+                continue;
+            }
+            if end_vpn < end {
+                let tail = self.areas[idx].split_off(end_vpn);
+                self.areas.push(tail);
+            }
+            let mut area = self.areas.remove(idx);
+            area.unmap(&mut self.page_table);
+        }
+        unsafe {
+            asm!("sfence.vma");
+        }
+        true
+    }
+    /// Resolve a page fault at `va`, `access` is the permission the faulting
+    /// access needs. Pages in swap are read back, lazy pages get their frame
+    /// on first touch and writes to copy-on-write pages get a private copy.
+    /// Return false for real faults.
+    pub fn handle_page_fault(&mut self, va: VirtAddr, access: MapPermission) -> bool {
+        let vpn = va.floor();
+        let area = match self
+            .areas
+            .iter_mut()
+            .find(|area| area.vpn_range.get_start() <= vpn && vpn < area.vpn_range.get_end())
+        {
+            Some(area) => area,
+            None => return false,
+        };
+        if !area.map_perm.contains(access) {
+            return false;
+        }
+        match self.page_table.translate(vpn) {
+            Some(pte) if pte.is_valid() => {
+                if pte.writable()
+                    || !area.map_perm.contains(MapPermission::W)
+                    || !area.copy_on_write(&mut self.page_table, vpn)
+                {
+                    return false;
+                }
+            }
+            // out of frames and swap, or the page is lost, the fault cannot be resolved
+            _ if area.swapped.contains_key(&vpn) => {
+                if !area.swap_in(&mut self.page_table, vpn) {
+                    return false;
+                }
+            }
+            _ if area.lazy => {
+                if !area.map_one(&mut self.page_table, vpn) {
+                    return false;
+                }
+            }
+            _ => return false,
+        }
+        unsafe {
+            asm!("sfence.vma");
+        }
+        true
+    }
+    /// Run the clock hand over the resident user pages from `hand` on,
+    /// skipping frames shared copy-on-write. Pages used since the hand last
+    /// passed get their accessed bit cleared, the first unused page is written
+    /// to swap. Return the page after the evicted one, or `None` if the hand
+    /// reached the end of the address space or swap is full.
+    pub fn evict_one(&mut self, hand: VirtPageNum) -> Option<VirtPageNum> {
+        let mut areas: Vec<&mut MapArea> = self
+            .areas
+            .iter_mut()
+            .filter(|area| area.map_perm.contains(MapPermission::U))
+            .collect();
+        areas.sort_by_key(|area| area.vpn_range.get_start());
+        let page_table = &mut self.page_table;
+        let mut next = None;
+        for area in areas {
+            let victim = area
+                .data_frames
+                .range(hand..)
+                .filter(|(_, frame)| Arc::strong_count(frame) == 1)
+                .map(|(&vpn, _)| vpn)
+                .find(|&vpn| !page_table.take_accessed(vpn));
+            if let Some(mut vpn) = victim {
+                if area.evict(page_table, vpn) {
+                    vpn.step();
+                    next = Some(vpn);
+                }
+                break;
+            }
+        }
+        // the TLB may still cache entries whose accessed bit was cleared
+        unsafe {
+            asm!("sfence.vma");
+        }
+        next
     }
     /// remove a area
     pub fn remove_area_with_start_vpn(&mut self, start_vpn: VirtPageNum) {
@@ -86,13 +229,16 @@ impl MemorySet {
     }
     /// Add a new MapArea into this MemorySet.
     /// Assuming that there are no conflicts in the virtual address
-    /// space.
-    fn push(&mut self, mut map_area: MapArea, data: Option<&[u8]>) {
-        map_area.map(&mut self.page_table);
+    /// space. Return false and add nothing if no frame is left for the area.
+    fn push(&mut self, mut map_area: MapArea, data: Option<&[u8]>) -> bool {
+        if !map_area.map(&mut self.page_table) {
+            return false;
+        }
         if let Some(data) = data {
             map_area.copy_data(&mut self.page_table, data);
         }
         self.areas.push(map_area);
+        true
     }
     /// Mention that trampoline is not collected by areas.
     fn map_trampoline(&mut self) {
@@ -180,16 +326,19 @@ impl MemorySet {
         memory_set
     }
     /// Include sections in elf and trampoline and TrapContext and user stack,
-    /// also returns user_sp_base and entry point.
-    pub fn from_elf(elf_data: &[u8]) -> (Self, usize, usize) {
+    /// also returns user_sp_base and entry point. `None` if the elf is
+    /// invalid or no frame is left for its sections.
+    pub fn from_elf(elf_data: &[u8]) -> Option<(Self, usize, usize)> {
         let mut memory_set = Self::new_bare();
         // map trampoline
         memory_set.map_trampoline();
         // map program headers of elf, with U flag
-        let elf = xmas_elf::ElfFile::new(elf_data).unwrap();
+        let elf = xmas_elf::ElfFile::new(elf_data).ok()?;
         let elf_header = elf.header;
         let magic = elf_header.pt1.magic;
-        assert_eq!(magic, [0x7f, 0x45, 0x4c, 0x46], "invalid elf!");
+        if magic != [0x7f, 0x45, 0x4c, 0x46] {
+            return None;
+        }
         let ph_count = elf_header.pt2.ph_count();
         let mut max_end_vpn = VirtPageNum(0);
         for i in 0..ph_count {
@@ -210,41 +359,74 @@ impl MemorySet {
                 }
                 let map_area = MapArea::new(start_va, end_va, MapType::Framed, map_perm);
                 max_end_vpn = map_area.vpn_range.get_end();
-                memory_set.push(
+                if !memory_set.push(
                     map_area,
                     Some(&elf.input[ph.offset() as usize..(ph.offset() + ph.file_size()) as usize]),
-                );
+                ) {
+                    return None;
+                }
             }
         }
         // map user stack with U flags
         let max_end_va: VirtAddr = max_end_vpn.into();
         let mut user_stack_base: usize = max_end_va.into();
         user_stack_base += PAGE_SIZE;
-        (
+        Some((
             memory_set,
             user_stack_base,
             elf.header.pt2.entry_point() as usize,
-        )
+        ))
     }
-    /// Create a new address space by copy code&data from a exited process's address space.
-    pub fn from_existed_user(user_space: &Self) -> Self {
+    /// Create a new address space from a parent's, for fork. Frames of user
+    /// areas are shared, and writable ones become copy-on-write in both spaces.
+    /// Trap contexts are copied at once, as the kernel writes them through
+    /// their physical addresses. `None` if no frame is left for the pages the
+    /// parent has in swap, or one of them cannot be read back.
+    pub fn from_existed_user(user_space: &mut Self) -> Option<Self> {
         let mut memory_set = Self::new_bare();
         // map trampoline
         memory_set.map_trampoline();
-        // copy data sections/trap_context/user_stack
         for area in user_space.areas.iter() {
-            let new_area = MapArea::from_another(area);
-            memory_set.push(new_area, None);
-            // copy data from another space
-            for vpn in area.vpn_range {
-                let src_ppn = user_space.translate(vpn).unwrap().ppn();
-                let dst_ppn = memory_set.translate(vpn).unwrap().ppn();
-                dst_ppn
-                    .get_bytes_array()
-                    .copy_from_slice(src_ppn.get_bytes_array());
+            let mut new_area = MapArea::from_another(area);
+            if !area.map_perm.contains(MapPermission::U) {
+                memory_set.push(new_area, None);
+                // copy data from another space
+                for vpn in area.vpn_range {
+                    let src_ppn = user_space.translate(vpn).unwrap().ppn();
+                    let dst_ppn = memory_set.translate(vpn).unwrap().ppn();
+                    dst_ppn
+                        .get_bytes_array()
+                        .copy_from_slice(src_ppn.get_bytes_array());
+                }
+                continue;
+            }
+            // pages never touched by the parent stay lazy in the child
+            let flags = PTEFlags::from_bits(area.map_perm.bits).unwrap() - PTEFlags::W;
+            for (&vpn, frame) in area.data_frames.iter() {
+                if area.map_perm.contains(MapPermission::W) {
+                    user_space.page_table.unmap(vpn);
+                    user_space.page_table.map(vpn, frame.ppn, flags);
+                }
+                memory_set.page_table.map(vpn, frame.ppn, flags);
+                new_area.data_frames.insert(vpn, Arc::clone(frame));
+            }
+            // pages in swap are read back into private frames of the child
+            for (&vpn, slot) in area.swapped.iter() {
+                let frame = frame_alloc()?;
+                if !slot.read(frame.ppn) {
+                    return None;
+                }
+                let flags = PTEFlags::from_bits(area.map_perm.bits).unwrap();
+                memory_set.page_table.map(vpn, frame.ppn, flags);
+                new_area.data_frames.insert(vpn, Arc::new(frame));
             }
+            memory_set.areas.push(new_area);
         }
-        memory_set
+        // the parent may still have writable entries in its TLB
+        unsafe {
+            asm!("sfence.vma");
+        }
+        Some(memory_set)
     }
     /// Change page table by writing satp CSR Register.
     pub fn activate(&self) {
@@ -279,7 +461,8 @@ impl MemorySet {
         }
     }
 
-    /// append the area to new_end
+    /// append the area to new_end, false if there is no such area or no
+    /// frame is left for the new pages
     #[allow(unused)]
     pub fn append_to(&mut self, start: VirtAddr, new_end: VirtAddr) -> bool {
         if let Some(area) = self
@@ -287,8 +470,7 @@ impl MemorySet {
             .iter_mut()
             .find(|area| area.vpn_range.get_start() == start.floor())
         {
-            area.append_to(&mut self.page_table, new_end.ceil());
-            true
+            area.append_to(&mut self.page_table, new_end.ceil())
         } else {
             false
         }
@@ -297,9 +479,14 @@ impl MemorySet {
 
 pub struct MapArea {
     pub vpn_range: VPNRange,
-    pub data_frames: BTreeMap<VirtPageNum, FrameTracker>,
+    /// frames shared with other address spaces are copied on write
+    pub data_frames: BTreeMap<VirtPageNum, Arc<FrameTracker>>,
     pub map_type: MapType,
     pub map_perm: MapPermission,
+    /// frames are allocated by page faults instead of when mapping
+    pub lazy: bool,
+    /// evicted pages, read back by page faults
+    pub swapped: BTreeMap<VirtPageNum, SwapSlot>,
 }
 
 impl MapArea {
@@ -316,6 +503,8 @@ impl MapArea {
             data_frames: BTreeMap::new(),
             map_type,
             map_perm,
+            lazy: false,
+            swapped: BTreeMap::new(),
         }
     }
     pub fn from_another(another: &Self) -> Self {
@@ -324,33 +513,115 @@ impl MapArea {
             data_frames: BTreeMap::new(),
             map_type: another.map_type,
             map_perm: another.map_perm,
+            lazy: another.lazy,
+            swapped: BTreeMap::new(),
         }
     }
-    pub fn map_one(&mut self, page_table: &mut PageTable, vpn: VirtPageNum) {
+    /// Split the area at `vpn`, keep `[start, vpn)` and return `[vpn, end)`
+    pub fn split_off(&mut self, vpn: VirtPageNum) -> Self {
+        let tail = Self {
+            vpn_range: VPNRange::new(vpn, self.vpn_range.get_end()),
+            data_frames: self.data_frames.split_off(&vpn),
+            map_type: self.map_type,
+            map_perm: self.map_perm,
+            lazy: self.lazy,
+            swapped: self.swapped.split_off(&vpn),
+        };
+        self.vpn_range = VPNRange::new(self.vpn_range.get_start(), vpn);
+        tail
+    }
+    /// Map `vpn`, return false if no frame is left for it
+    pub fn map_one(&mut self, page_table: &mut PageTable, vpn: VirtPageNum) -> bool {
         let ppn: PhysPageNum;
         match self.map_type {
             MapType::Identical => {
                 ppn = PhysPageNum(vpn.0);
             }
             MapType::Framed => {
-                let frame = frame_alloc().unwrap();
+                let frame = match frame_alloc() {
+                    Some(frame) => frame,
+                    None => return false,
+                };
                 ppn = frame.ppn;
-                self.data_frames.insert(vpn, frame);
+                self.data_frames.insert(vpn, Arc::new(frame));
             }
         }
         let pte_flags = PTEFlags::from_bits(self.map_perm.bits).unwrap();
         page_table.map(vpn, ppn, pte_flags);
+        true
+    }
+    /// Give a copy-on-write page its own frame, or keep the frame if nobody
+    /// shares it any more, and map the page writable again. Return false if
+    /// no frame is left for the copy.
+    pub fn copy_on_write(&mut self, page_table: &mut PageTable, vpn: VirtPageNum) -> bool {
+        let frame = self.data_frames.get_mut(&vpn).unwrap();
+        if Arc::strong_count(frame) > 1 {
+            let copy = match frame_alloc() {
+                Some(copy) => copy,
+                None => return false,
+            };
+            copy.ppn
+                .get_bytes_array()
+                .copy_from_slice(frame.ppn.get_bytes_array());
+            *frame = Arc::new(copy);
+        }
+        let ppn = frame.ppn;
+        page_table.unmap(vpn);
+        page_table.map(vpn, ppn, PTEFlags::from_bits(self.map_perm.bits).unwrap());
+        true
+    }
+    /// Read a page back from swap into a new frame, return false and keep
+    /// the page in swap if no frame is left. A page whose slot cannot be read
+    /// is lost, and false is returned too.
+    pub fn swap_in(&mut self, page_table: &mut PageTable, vpn: VirtPageNum) -> bool {
+        let frame = match frame_alloc() {
+            Some(frame) => frame,
+            None => return false,
+        };
+        let slot = self.swapped.remove(&vpn).unwrap();
+        if !slot.read(frame.ppn) {
+            return false;
+        }
+        page_table.map(
+            vpn,
+            frame.ppn,
+            PTEFlags::from_bits(self.map_perm.bits).unwrap(),
+        );
+        self.data_frames.insert(vpn, Arc::new(frame));
+        true
+    }
+    /// Write a resident page to swap and free its frame, false if swap is full
+    pub fn evict(&mut self, page_table: &mut PageTable, vpn: VirtPageNum) -> bool {
+        let slot = match swap_out(self.data_frames[&vpn].ppn) {
+            Some(slot) => slot,
+            None => return false,
+        };
+        self.data_frames.remove(&vpn);
+        page_table.unmap(vpn);
+        self.swapped.insert(vpn, slot);
+        true
     }
     pub fn unmap_one(&mut self, page_table: &mut PageTable, vpn: VirtPageNum) {
-        if self.map_type == MapType::Framed {
-            self.data_frames.remove(&vpn);
+        if self.map_type == MapType::Framed && self.data_frames.remove(&vpn).is_none() {
+            // a lazy page that was never touched, or a page in swap
+            self.swapped.remove(&vpn);
+            return;
         }
         page_table.unmap(vpn);
     }
-    pub fn map(&mut self, page_table: &mut PageTable) {
+    /// Map the pages of the area, return false and map none of them if no
+    /// frame is left
+    pub fn map(&mut self, page_table: &mut PageTable) -> bool {
+        if self.lazy {
+            return true;
+        }
         for vpn in self.vpn_range {
-            self.map_one(page_table, vpn);
+            if !self.map_one(page_table, vpn) {
+                self.unmap(page_table);
+                return false;
+            }
         }
+        true
     }
     pub fn unmap(&mut self, page_table: &mut PageTable) {
         for vpn in self.vpn_range {
@@ -364,12 +635,21 @@ impl MapArea {
         }
         self.vpn_range = VPNRange::new(self.vpn_range.get_start(), new_end);
     }
+    /// Extend the area to `new_end`, return false and keep it as it was if
+    /// no frame is left for the new pages
     #[allow(unused)]
-    pub fn append_to(&mut self, page_table: &mut PageTable, new_end: VirtPageNum) {
-        for vpn in VPNRange::new(self.vpn_range.get_end(), new_end) {
-            self.map_one(page_table, vpn)
+    pub fn append_to(&mut self, page_table: &mut PageTable, new_end: VirtPageNum) -> bool {
+        let old_end = self.vpn_range.get_end();
+        for vpn in VPNRange::new(old_end, new_end) {
+            if !self.map_one(page_table, vpn) {
+                for vpn in VPNRange::new(old_end, vpn) {
+                    self.unmap_one(page_table, vpn);
+                }
+                return false;
+            }
         }
         self.vpn_range = VPNRange::new(self.vpn_range.get_start(), new_end);
+        true
     }
     /// data: start-aligned but maybe with shorter length
     /// assume that all frames were cleared before
@@ -1,5 +1,10 @@
 //! Implementation of [`PageTableEntry`] and [`PageTable`].
-use super::{frame_alloc, FrameTracker, PhysAddr, PhysPageNum, StepByOne, VirtAddr, VirtPageNum};
+use super::{
+    frame_alloc, FrameTracker, MapPermission, PhysAddr, PhysPageNum, StepByOne, VirtAddr,
+    VirtPageNum,
+};
+use crate::config::PAGE_SIZE;
+use crate::task::current_handle_page_fault;
 use alloc::string::String;
 use alloc::vec;
 use alloc::vec::Vec;
@@ -137,6 +142,13 @@ impl PageTable {
         assert!(pte.is_valid(), "vpn {:?} is invalid before unmapping", vpn);
         *pte = PageTableEntry::empty();
     }
+    /// Clear the accessed bit of a mapped page, return whether it was set
+    pub fn take_accessed(&mut self, vpn: VirtPageNum) -> bool {
+        let pte = self.find_pte(vpn).unwrap();
+        let accessed = pte.flags().contains(PTEFlags::A);
+        pte.bits &= !(PTEFlags::A.bits() as usize);
+        accessed
+    }
     /// get the page table entry from the virtual page number
     pub fn translate(&self, vpn: VirtPageNum) -> Option<PageTableEntry> {
         self.find_pte(vpn).map(|pte| *pte)
@@ -156,8 +168,44 @@ impl PageTable {
     }
 }
 
-/// Create mutable `Vec<u8>` slice in kernel space from ptr in other address space. NOTICE: the content pointed to by the pointer `ptr` can cross physical pages.
-pub fn translated_byte_buffer(token: usize, ptr: *const u8, len: usize) -> Vec<&'static mut [u8]> {
+/// Get the entry of a user page for the kernel to read, or to write if `write`.
+/// Lazily allocated and copy-on-write pages of the current process are faulted
+/// in. `None` if the page is not mapped, or `write` is set and the user may
+/// not write it: a read-only page may share its frame with other processes.
+fn translate_user(page_table: &PageTable, vpn: VirtPageNum, write: bool) -> Option<PageTableEntry> {
+    match page_table.translate(vpn) {
+        Some(pte) if pte.is_valid() && (pte.writable() || !write) => return Some(pte),
+        _ => {}
+    }
+    let access = if write {
+        MapPermission::W
+    } else {
+        MapPermission::empty()
+    };
+    if !current_handle_page_fault(page_table.token(), vpn.into(), access) && write {
+        return None;
+    }
+    page_table.translate(vpn).filter(PageTableEntry::is_valid)
+}
+
+/// Get the physical address of a user virtual address, see `translate_user`
+fn translate_user_va(page_table: &PageTable, va: VirtAddr, write: bool) -> Option<PhysAddr> {
+    let aligned_pa: PhysAddr = translate_user(page_table, va.floor(), write)?.ppn().into();
+    Some((aligned_pa.0 + va.page_offset()).into())
+}
+
+/// Get the physical address of a user virtual address the kernel only reads
+fn translate_user_va_ro(page_table: &PageTable, va: VirtAddr) -> PhysAddr {
+    translate_user_va(page_table, va, false).unwrap_or_else(|| panic!("bad user page {:?}", va))
+}
+
+/// Create mutable `Vec<u8>` slice in kernel space from ptr in other address space, for the kernel to write if `write`, `None` if some page cannot be. NOTICE: the content pointed to by the pointer `ptr` can cross physical pages.
+pub fn translated_byte_buffer(
+    token: usize,
+    ptr: *const u8,
+    len: usize,
+    write: bool,
+) -> Option<Vec<&'static mut [u8]>> {
     let page_table = PageTable::from_token(token);
     let mut start = ptr as usize;
     let end = start + len;
@@ -165,7 +213,7 @@ pub fn translated_byte_buffer(token: usize, ptr: *const u8, len: usize) -> Vec<&
     while start < end {
         let start_va = VirtAddr::from(start);
         let mut vpn = start_va.floor();
-        let ppn = page_table.translate(vpn).unwrap().ppn();
+        let ppn = translate_user(&page_table, vpn, write)?.ppn();
         vpn.step();
         let mut end_va: VirtAddr = vpn.into();
         end_va = end_va.min(VirtAddr::from(end));
@@ -176,7 +224,43 @@ pub fn translated_byte_buffer(token: usize, ptr: *const u8, len: usize) -> Vec<&
         }
         start = end_va.into();
     }
-    v
+    Some(v)
+}
+
+/// Copy `value` to `ptr` in other address space, false if the user may not write there. NOTICE: unlike `translated_refmut`, the destination can cross physical pages.
+pub fn copy_to_user<T>(token: usize, ptr: *mut T, value: &T) -> bool {
+    let src = unsafe {
+        core::slice::from_raw_parts(value as *const T as *const u8, core::mem::size_of::<T>())
+    };
+    let buffers = match translated_byte_buffer(token, ptr as *const u8, src.len(), true) {
+        Some(buffers) => buffers,
+        None => return false,
+    };
+    let mut copied = 0;
+    for dst in buffers {
+        dst.copy_from_slice(&src[copied..copied + dst.len()]);
+        copied += dst.len();
+    }
+    true
+}
+
+/// Copy a `T` from `ptr` in other address space. NOTICE: unlike `translated_ref`, the source can cross physical pages.
+pub fn copy_from_user<T: Copy>(token: usize, ptr: *const T) -> T {
+    let page_table = PageTable::from_token(token);
+    let mut value = core::mem::MaybeUninit::<T>::uninit();
+    let dst = unsafe {
+        core::slice::from_raw_parts_mut(value.as_mut_ptr() as *mut u8, core::mem::size_of::<T>())
+    };
+    let mut copied = 0;
+    while copied < dst.len() {
+        let va = VirtAddr::from(ptr as usize + copied);
+        let len = (PAGE_SIZE - va.page_offset()).min(dst.len() - copied);
+        let pa = translate_user_va_ro(&page_table, va);
+        let src = unsafe { core::slice::from_raw_parts(pa.0 as *const u8, len) };
+        dst[copied..copied + len].copy_from_slice(src);
+        copied += len;
+    }
+    unsafe { value.assume_init() }
 }
 
 /// Create String in kernel address space from u8 Array(end with 0) in other address space
@@ -185,10 +269,7 @@ pub fn translated_str(token: usize, ptr: *const u8) -> String {
     let mut string = String::new();
     let mut va = ptr as usize;
     loop {
-        let ch: u8 = *(page_table
-            .translate_va(VirtAddr::from(va))
-            .unwrap()
-            .get_mut());
+        let ch: u8 = *(translate_user_va_ro(&page_table, VirtAddr::from(va)).get_mut());
         if ch == 0 {
             break;
         }
@@ -201,20 +282,14 @@ pub fn translated_str(token: usize, ptr: *const u8) -> String {
 /// translate a pointer `ptr` in other address space to a immutable u8 slice in kernel address space. NOTICE: the content pointed to by the pointer `ptr` cannot cross physical pages, otherwise translated_byte_buffer should be used.
 pub fn translated_ref<T>(token: usize, ptr: *const T) -> &'static T {
     let page_table = PageTable::from_token(token);
-    page_table
-        .translate_va(VirtAddr::from(ptr as usize))
-        .unwrap()
-        .get_ref()
+    translate_user_va_ro(&page_table, VirtAddr::from(ptr as usize)).get_ref()
 }
 
-/// translate a pointer `ptr` in other address space to a mutable u8 slice in kernel address space. NOTICE: the content pointed to by the pointer `ptr` cannot cross physical pages, otherwise translated_byte_buffer should be used.
-pub fn translated_refmut<T>(token: usize, ptr: *mut T) -> &'static mut T {
+/// translate a pointer `ptr` in other address space to a mutable u8 slice in kernel address space, `None` if the user may not write there. NOTICE: the content pointed to by the pointer `ptr` cannot cross physical pages, otherwise translated_byte_buffer should be used.
+pub fn translated_refmut<T>(token: usize, ptr: *mut T) -> Option<&'static mut T> {
     let page_table = PageTable::from_token(token);
     let va = ptr as usize;
-    page_table
-        .translate_va(VirtAddr::from(va))
-        .unwrap()
-        .get_mut()
+    Some(translate_user_va(&page_table, VirtAddr::from(va), true)?.get_mut())
 }
 
 /// An abstraction over a buffer passed from user space to kernel space
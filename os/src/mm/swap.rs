@@ -0,0 +1,91 @@
+//! Swap space for evicted user pages
+//!
+//! Evicted pages are written to a swap file on the easy-fs image, one page
+//! per slot. The file is created as [`SWAP_FILE`] in the root directory on
+//! first use after boot and unlinked at once, so no process can reach it,
+//! and the kernel holds it open from then on. It grows as slots are handed
+//! out, up to [`SWAP_SLOTS`]; freed slots are reused first. The blocks of
+//! the swap file of an earlier boot are left for fsck to reclaim.
+
+use super::PhysPageNum;
+use crate::config::{PAGE_SIZE, SWAP_FILE, SWAP_SLOTS};
+use crate::fs::{unlink_at, Credential, VfsInode, ROOT_INODE};
+use crate::sync::UPSafeCell;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use lazy_static::*;
+
+struct SwapSpace {
+    file: Option<Arc<dyn VfsInode>>,
+    /// slots below `next` have been written at least once
+    next: usize,
+    recycled: Vec<usize>,
+}
+
+impl SwapSpace {
+    /// the swap file, `None` if it cannot be created
+    fn file(&mut self) -> Option<&Arc<dyn VfsInode>> {
+        if self.file.is_none() {
+            // a swap file left linked by an older kernel is stale
+            unlink_at(&ROOT_INODE, SWAP_FILE, &Credential::ROOT);
+            let file = ROOT_INODE.create(SWAP_FILE)?;
+            ROOT_INODE.unlink(SWAP_FILE);
+            self.file = Some(file);
+        }
+        self.file.as_ref()
+    }
+}
+
+lazy_static! {
+    static ref SWAP_SPACE: UPSafeCell<SwapSpace> = unsafe {
+        UPSafeCell::new(SwapSpace {
+            file: None,
+            next: 0,
+            recycled: Vec::new(),
+        })
+    };
+}
+
+/// A page in the swap file, the slot is freed on drop
+pub struct SwapSlot(usize);
+
+impl SwapSlot {
+    /// Read the page back into the frame `ppn`, false if the slot cannot be
+    /// read in full
+    pub fn read(&self, ppn: PhysPageNum) -> bool {
+        let mut swap = SWAP_SPACE.exclusive_access();
+        match swap.file() {
+            Some(file) => file.read_at(self.0 * PAGE_SIZE, ppn.get_bytes_array()) == PAGE_SIZE,
+            None => false,
+        }
+    }
+}
+
+impl Drop for SwapSlot {
+    fn drop(&mut self) {
+        SWAP_SPACE.exclusive_access().recycled.push(self.0);
+    }
+}
+
+/// Write the frame `ppn` to a free slot, `None` if all slots are in use, the
+/// file system is full or the swap space is busy, as when a swap-in runs
+/// out of frames
+pub fn swap_out(ppn: PhysPageNum) -> Option<SwapSlot> {
+    let mut swap = SWAP_SPACE.try_exclusive_access()?;
+    let slot = match swap.recycled.pop() {
+        Some(slot) => slot,
+        None if swap.next < SWAP_SLOTS => {
+            swap.next += 1;
+            swap.next - 1
+        }
+        None => return None,
+    };
+    let written = swap.file().map_or(0, |file| {
+        file.write_at(slot * PAGE_SIZE, ppn.get_bytes_array())
+    });
+    if written < PAGE_SIZE {
+        swap.recycled.push(slot);
+        return None;
+    }
+    Some(SwapSlot(slot))
+}
@@ -0,0 +1,415 @@
+//! Asynchronous syscalls through a shared submission/completion ring
+//!
+//! A process registers one page-aligned [`AioRing`] in its own address space
+//! with `sys_aio_setup`. Userspace fills submission entries and calls
+//! `sys_aio_enter`, which takes them, completes what it can right away and
+//! keeps the rest pending in the process. Pending operations are polled again
+//! on every `sys_aio_enter` and on every timer interrupt of the process, and
+//! each completion is posted to the completion queue together with the
+//! `user_data` of its submission. A waiting `sys_aio_enter` blocks until a
+//! sleep expires or a pipe of a pending operation moves, and polls files
+//! that cannot wake it, like the console, every [`AIO_POLL_MS`].
+
+use super::process::try_waitpid;
+use crate::config::PAGE_SIZE;
+use crate::fs::File;
+use crate::mm::{
+    translated_byte_buffer, translated_refmut, MapPermission, PageTable, UserBuffer, VirtAddr,
+};
+use crate::task::{
+    block_current_and_run_next, current_handle_page_fault, current_process, current_task,
+    current_user_token, ProcessControlBlock,
+};
+use crate::timer::{add_timer, get_time_ms, remove_timer};
+use alloc::collections::VecDeque;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+/// number of entries in the submission queue
+pub const AIO_SQ_ENTRIES: usize = 32;
+/// number of entries in the completion queue
+pub const AIO_CQ_ENTRIES: usize = 64;
+
+/// do nothing, completes with 0
+pub const AIO_OP_NOP: u32 = 0;
+/// read `len` bytes from `fd` into `addr`
+pub const AIO_OP_READ: u32 = 1;
+/// write `len` bytes at `addr` to `fd`
+pub const AIO_OP_WRITE: u32 = 2;
+/// complete after `len` milliseconds
+pub const AIO_OP_SLEEP: u32 = 3;
+/// wait for child `fd` (-1 for any) to exit, exit code is stored at `addr`
+pub const AIO_OP_WAITPID: u32 = 4;
+/// cancel the pending operation whose `user_data` equals `addr`
+pub const AIO_OP_CANCEL: u32 = 5;
+
+/// result of an operation removed by [`AIO_OP_CANCEL`]
+pub const AIO_ECANCELED: isize = -125;
+
+/// how often a waiting `sys_aio_enter` polls operations nothing wakes it for,
+/// in milliseconds
+const AIO_POLL_MS: usize = 10;
+
+/// Submission queue entry
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct AioSqe {
+    /// one of the `AIO_OP_*` constants
+    pub opcode: u32,
+    /// reserved, must be 0
+    pub flags: u32,
+    /// file descriptor, or pid for [`AIO_OP_WAITPID`]
+    pub fd: isize,
+    /// user buffer address
+    pub addr: usize,
+    /// buffer length, or milliseconds for [`AIO_OP_SLEEP`]
+    pub len: usize,
+    /// copied to the completion entry untouched
+    pub user_data: u64,
+}
+
+/// Completion queue entry
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct AioCqe {
+    /// `user_data` of the completed submission
+    pub user_data: u64,
+    /// what the synchronous syscall would have returned
+    pub result: isize,
+}
+
+/// The ring shared between a process and the kernel.
+///
+/// The kernel consumes `sqes` and advances `sq_head`; userspace consumes
+/// `cqes` and advances `cq_head`. Indexes grow without bound and wrap.
+#[repr(C)]
+pub struct AioRing {
+    /// next submission the kernel will take
+    pub sq_head: u32,
+    /// next free submission slot
+    pub sq_tail: u32,
+    /// next completion userspace will take
+    pub cq_head: u32,
+    /// next free completion slot
+    pub cq_tail: u32,
+    /// submission queue
+    pub sqes: [AioSqe; AIO_SQ_ENTRIES],
+    /// completion queue
+    pub cqes: [AioCqe; AIO_CQ_ENTRIES],
+}
+
+/// An operation that could not complete on submission
+struct AioOp {
+    sqe: AioSqe,
+    /// expire time of [`AIO_OP_SLEEP`] in milliseconds, 0 for other operations
+    expire_ms: usize,
+}
+
+/// Async I/O state of a process
+pub struct AioContext {
+    /// user virtual address of the [`AioRing`]
+    ring: usize,
+    /// submitted but not yet completed operations
+    pending: Vec<AioOp>,
+    /// completions that did not fit in the completion queue
+    overflow: VecDeque<AioCqe>,
+}
+
+impl AioContext {
+    fn new(ring: usize) -> Self {
+        Self {
+            ring,
+            pending: Vec::new(),
+            overflow: VecDeque::new(),
+        }
+    }
+
+    /// move completions into the completion queue, keep the rest in `overflow`
+    fn post(&mut self, ring: &mut AioRing, cqes: Vec<AioCqe>) {
+        self.overflow.extend(cqes);
+        while let Some(cqe) = self.overflow.front() {
+            if ring.cq_tail.wrapping_sub(ring.cq_head) as usize >= AIO_CQ_ENTRIES {
+                break;
+            }
+            ring.cqes[ring.cq_tail as usize % AIO_CQ_ENTRIES] = *cqe;
+            ring.cq_tail = ring.cq_tail.wrapping_add(1);
+            self.overflow.pop_front();
+        }
+    }
+}
+
+impl AioRing {
+    /// the number of completions userspace has not taken yet
+    fn ready(&self) -> usize {
+        self.cq_tail.wrapping_sub(self.cq_head) as usize
+    }
+}
+
+/// the token and the ring of `process`. Touching the ring may copy its page
+/// on write, which needs the PCB, so the PCB must not be borrowed here.
+fn ring_of(process: &Arc<ProcessControlBlock>) -> Option<(usize, &'static mut AioRing)> {
+    let inner = process.inner_exclusive_access();
+    let token = inner.memory_set.token();
+    let ring = inner.aio.as_ref()?.ring;
+    drop(inner);
+    Some((token, translated_refmut(token, ring as *mut AioRing)?))
+}
+
+fn get_file(process: &Arc<ProcessControlBlock>, fd: isize) -> Option<Arc<dyn File + Send + Sync>> {
+    let inner = process.inner_exclusive_access();
+    if fd < 0 || fd as usize >= inner.fd_table.len() {
+        return None;
+    }
+    inner.fd_table[fd as usize].clone()
+}
+
+/// try to complete `op`, `None` if it would block
+fn execute(process: &Arc<ProcessControlBlock>, token: usize, op: &AioOp) -> Option<isize> {
+    let sqe = &op.sqe;
+    match sqe.opcode {
+        AIO_OP_NOP => Some(0),
+        AIO_OP_READ => match get_file(process, sqe.fd) {
+            Some(file) if file.readable() => {
+                match translated_byte_buffer(token, sqe.addr as *const u8, sqe.len, true) {
+                    Some(buffers) => file.try_read(UserBuffer::new(buffers)).map(|n| n as isize),
+                    None => Some(-1),
+                }
+            }
+            _ => Some(-1),
+        },
+        AIO_OP_WRITE => match get_file(process, sqe.fd) {
+            Some(file) if file.writable() => {
+                match translated_byte_buffer(token, sqe.addr as *const u8, sqe.len, false) {
+                    Some(buffers) => file.try_write(UserBuffer::new(buffers)).map(|n| n as isize),
+                    None => Some(-1),
+                }
+            }
+            _ => Some(-1),
+        },
+        AIO_OP_SLEEP => {
+            if get_time_ms() >= op.expire_ms {
+                Some(0)
+            } else {
+                None
+            }
+        }
+        AIO_OP_WAITPID => match try_waitpid(process, sqe.fd, sqe.addr as *mut i32) {
+            -2 => None,
+            result => Some(result),
+        },
+        _ => Some(-1),
+    }
+}
+
+/// poll every pending operation of `process` once and post the completions
+fn poll_pending(process: &Arc<ProcessControlBlock>) {
+    let (token, ring) = match ring_of(process) {
+        Some(ring) => ring,
+        None => return,
+    };
+    let mut inner = process.inner_exclusive_access();
+    let mut pending = match inner.aio.as_mut() {
+        Some(aio) => core::mem::take(&mut aio.pending),
+        None => return,
+    };
+    // operations may access the PCB themselves, so release it while polling
+    drop(inner);
+    let mut completed = Vec::new();
+    pending.retain(|op| match execute(process, token, op) {
+        Some(result) => {
+            completed.push(AioCqe {
+                user_data: op.sqe.user_data,
+                result,
+            });
+            false
+        }
+        None => true,
+    });
+    let mut inner = process.inner_exclusive_access();
+    if let Some(aio) = inner.aio.as_mut() {
+        pending.append(&mut aio.pending);
+        aio.pending = pending;
+        aio.post(ring, completed);
+    }
+}
+
+/// take up to `to_submit` entries from the submission queue,
+/// return the number taken or -1 if there is no ring
+fn submit(process: &Arc<ProcessControlBlock>, to_submit: usize) -> isize {
+    let (token, ring) = match ring_of(process) {
+        Some(ring) => ring,
+        None => return -1,
+    };
+    let mut sqes = Vec::new();
+    while sqes.len() < to_submit && ring.sq_head != ring.sq_tail {
+        sqes.push(ring.sqes[ring.sq_head as usize % AIO_SQ_ENTRIES]);
+        ring.sq_head = ring.sq_head.wrapping_add(1);
+    }
+    let submitted = sqes.len() as isize;
+    for sqe in sqes {
+        let mut completed = Vec::new();
+        if sqe.opcode == AIO_OP_CANCEL {
+            let mut inner = process.inner_exclusive_access();
+            let aio = inner.aio.as_mut().unwrap();
+            let target = aio
+                .pending
+                .iter()
+                .position(|op| op.sqe.user_data == sqe.addr as u64);
+            let result = if let Some(idx) = target {
+                completed.push(AioCqe {
+                    user_data: aio.pending.remove(idx).sqe.user_data,
+                    result: AIO_ECANCELED,
+                });
+                0
+            } else {
+                -1
+            };
+            completed.push(AioCqe {
+                user_data: sqe.user_data,
+                result,
+            });
+            aio.post(ring, completed);
+            continue;
+        }
+        let expire_ms = if sqe.opcode == AIO_OP_SLEEP {
+            get_time_ms().saturating_add(sqe.len)
+        } else {
+            0
+        };
+        let op = AioOp { sqe, expire_ms };
+        let result = execute(process, token, &op);
+        let mut inner = process.inner_exclusive_access();
+        let aio = inner.aio.as_mut().unwrap();
+        match result {
+            Some(result) => {
+                completed.push(AioCqe {
+                    user_data: sqe.user_data,
+                    result,
+                });
+                aio.post(ring, completed);
+            }
+            None => aio.pending.push(op),
+        }
+    }
+    submitted
+}
+
+/// Poll the async operations of the current process, called on timer interrupts
+pub fn aio_poll_current() {
+    let process = current_process();
+    if process.inner_exclusive_access().aio.is_some() {
+        poll_pending(&process);
+    }
+}
+
+/// aio_setup syscall
+///
+/// Register the ring at `ring`, which must be page-aligned and writable.
+/// Pass 0 to unregister; pending operations are dropped either way.
+pub fn sys_aio_setup(ring: usize) -> isize {
+    trace!(
+        "kernel:pid[{}] sys_aio_setup",
+        current_task().unwrap().process.upgrade().unwrap().getpid()
+    );
+    let process = current_process();
+    if ring == 0 {
+        process.inner_exclusive_access().aio = None;
+        return 0;
+    }
+    if ring % PAGE_SIZE != 0 {
+        return -1;
+    }
+    let token = current_user_token();
+    let va = VirtAddr::from(ring);
+    // make a lazy or copy-on-write page present and writable right away
+    match PageTable::from_token(token).translate(va.floor()) {
+        Some(pte) if pte.is_valid() && pte.readable() && pte.writable() => {}
+        _ if current_handle_page_fault(token, va, MapPermission::R | MapPermission::W) => {}
+        _ => return -1,
+    }
+    let ring_ref = match translated_refmut(token, ring as *mut AioRing) {
+        Some(ring_ref) => ring_ref,
+        None => return -1,
+    };
+    ring_ref.sq_head = 0;
+    ring_ref.sq_tail = 0;
+    ring_ref.cq_head = 0;
+    ring_ref.cq_tail = 0;
+    process.inner_exclusive_access().aio = Some(AioContext::new(ring));
+    0
+}
+
+/// aio_enter syscall
+///
+/// Submit up to `to_submit` entries, then wait until at least `min_complete`
+/// completions are in the completion queue or `timeout_ms` elapsed
+/// (negative waits forever). Return the number of completions ready.
+pub fn sys_aio_enter(to_submit: usize, min_complete: usize, timeout_ms: isize) -> isize {
+    trace!(
+        "kernel:pid[{}] sys_aio_enter",
+        current_task().unwrap().process.upgrade().unwrap().getpid()
+    );
+    let process = current_process();
+    if submit(&process, to_submit) < 0 {
+        return -1;
+    }
+    let deadline = if timeout_ms < 0 {
+        None
+    } else {
+        Some(get_time_ms() + timeout_ms as usize)
+    };
+    loop {
+        poll_pending(&process);
+        let ready = match ring_of(&process) {
+            Some((_, ring)) => ring.ready(),
+            None => return -1,
+        };
+        let inner = process.inner_exclusive_access();
+        let aio = inner.aio.as_ref().unwrap();
+        if ready >= min_complete || deadline.map_or(false, |d| get_time_ms() >= d) {
+            return ready as isize;
+        }
+        let sqes: Vec<AioSqe> = aio.pending.iter().map(|op| op.sqe).collect();
+        let next_expire = aio
+            .pending
+            .iter()
+            .filter(|op| op.sqe.opcode == AIO_OP_SLEEP)
+            .map(|op| op.expire_ms)
+            .min();
+        drop(inner);
+        // wait on the files of pending reads and writes, and poll the others
+        let task = current_task().unwrap();
+        let mut waited = Vec::new();
+        let mut must_poll = false;
+        for sqe in sqes.iter().filter(|sqe| sqe.opcode != AIO_OP_SLEEP) {
+            match get_file(&process, sqe.fd) {
+                Some(file)
+                    if (sqe.opcode == AIO_OP_READ || sqe.opcode == AIO_OP_WRITE)
+                        && file.add_waiter(task.clone()) =>
+                {
+                    waited.push(file)
+                }
+                _ => must_poll = true,
+            }
+        }
+        let poll = must_poll.then(|| get_time_ms() + AIO_POLL_MS);
+        let wake_ms = [next_expire, deadline, poll]
+            .iter()
+            .flatten()
+            .min()
+            .copied();
+        if wake_ms.is_none() && waited.is_empty() {
+            // nothing pending can ever complete
+            return ready as isize;
+        }
+        if let Some(wake_ms) = wake_ms {
+            add_timer(wake_ms, task.clone());
+        }
+        block_current_and_run_next();
+        // whatever woke this task, the other sources must not wake it later
+        remove_timer(task.clone());
+        for file in waited {
+            file.remove_waiter(&task);
+        }
+    }
+}
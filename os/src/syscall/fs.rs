@@ -1,7 +1,17 @@
-use crate::fs::{make_pipe, open_file, OpenFlags, Stat};
-use crate::mm::{translated_byte_buffer, translated_refmut, translated_str, UserBuffer};
+use crate::fs::{
+    find_parent, find_path, link_at, make_pipe, mount_at, open_file_at, path_of, rename_at,
+    sync_all, umount_at, unlink_at, Access, OpenFlags, Stat,
+};
+use crate::mm::{
+    copy_to_user, translated_byte_buffer, translated_refmut, translated_str, UserBuffer,
+};
 use crate::task::{current_process, current_task, current_user_token};
 use alloc::sync::Arc;
+
+/// `flags` of `sys_unlinkat` removing a directory
+const AT_REMOVEDIR: u32 = 0x200;
+/// `flags` of `sys_renameat2` failing if the new path exists
+const RENAME_NOREPLACE: u32 = 1;
 /// write syscall
 pub fn sys_write(fd: usize, buf: *const u8, len: usize) -> isize {
     trace!(
@@ -21,7 +31,14 @@ pub fn sys_write(fd: usize, buf: *const u8, len: usize) -> isize {
         let file = file.clone();
         // release current task TCB manually to avoid multi-borrow
         drop(inner);
-        file.write(UserBuffer::new(translated_byte_buffer(token, buf, len))) as isize
+        match translated_byte_buffer(token, buf, len, false) {
+            // nothing written of a non-empty buffer, the file system is full
+            Some(buffers) => match file.write(UserBuffer::new(buffers)) {
+                0 if len > 0 => -1,
+                write_size => write_size as isize,
+            },
+            None => -1,
+        }
     } else {
         -1
     }
@@ -46,7 +63,127 @@ pub fn sys_read(fd: usize, buf: *const u8, len: usize) -> isize {
         // release current task TCB manually to avoid multi-borrow
         drop(inner);
         trace!("kernel: sys_read .. file.read");
-        file.read(UserBuffer::new(translated_byte_buffer(token, buf, len))) as isize
+        match translated_byte_buffer(token, buf, len, true) {
+            Some(buffers) => file.read(UserBuffer::new(buffers)) as isize,
+            None => -1,
+        }
+    } else {
+        -1
+    }
+}
+/// getdents64 syscall
+///
+/// Read the next entries of the directory of `fd` to `buf` as
+/// `linux_dirent64` records, return the number of bytes read, 0 at the end.
+pub fn sys_getdents64(fd: usize, buf: *const u8, len: usize) -> isize {
+    trace!(
+        "kernel:pid[{}] sys_getdents64",
+        current_task().unwrap().process.upgrade().unwrap().getpid()
+    );
+    let token = current_user_token();
+    let process = current_process();
+    let inner = process.inner_exclusive_access();
+    let file = match inner.fd_table.get(fd) {
+        Some(Some(file)) if file.readable() => file.clone(),
+        _ => return -1,
+    };
+    drop(inner);
+    let buf = match translated_byte_buffer(token, buf, len, true) {
+        Some(buffers) => UserBuffer::new(buffers),
+        None => return -1,
+    };
+    match file.read_dir(buf) {
+        Some(read_size) => read_size as isize,
+        None => -1,
+    }
+}
+/// lseek syscall
+///
+/// Move the offset of the file of `fd`, return the new offset.
+pub fn sys_lseek(fd: usize, offset: isize, whence: usize) -> isize {
+    trace!(
+        "kernel:pid[{}] sys_lseek",
+        current_task().unwrap().process.upgrade().unwrap().getpid()
+    );
+    let process = current_process();
+    let inner = process.inner_exclusive_access();
+    let file = match inner.fd_table.get(fd) {
+        Some(Some(file)) => file.clone(),
+        _ => return -1,
+    };
+    drop(inner);
+    match file.seek(offset, whence) {
+        Some(offset) => offset as isize,
+        None => -1,
+    }
+}
+/// pread64 syscall
+///
+/// Read from `offset` of the file of `fd` without moving its offset.
+pub fn sys_pread(fd: usize, buf: *const u8, len: usize, offset: usize) -> isize {
+    trace!(
+        "kernel:pid[{}] sys_pread",
+        current_task().unwrap().process.upgrade().unwrap().getpid()
+    );
+    let token = current_user_token();
+    let process = current_process();
+    let inner = process.inner_exclusive_access();
+    let file = match inner.fd_table.get(fd) {
+        Some(Some(file)) if file.readable() => file.clone(),
+        _ => return -1,
+    };
+    drop(inner);
+    let buf = match translated_byte_buffer(token, buf, len, true) {
+        Some(buffers) => UserBuffer::new(buffers),
+        None => return -1,
+    };
+    match file.read_at(offset, buf) {
+        Some(read_size) => read_size as isize,
+        None => -1,
+    }
+}
+/// pwrite64 syscall
+///
+/// Write to `offset` of the file of `fd` without moving its offset.
+pub fn sys_pwrite(fd: usize, buf: *const u8, len: usize, offset: usize) -> isize {
+    trace!(
+        "kernel:pid[{}] sys_pwrite",
+        current_task().unwrap().process.upgrade().unwrap().getpid()
+    );
+    let token = current_user_token();
+    let process = current_process();
+    let inner = process.inner_exclusive_access();
+    let file = match inner.fd_table.get(fd) {
+        Some(Some(file)) if file.writable() => file.clone(),
+        _ => return -1,
+    };
+    drop(inner);
+    let buf = match translated_byte_buffer(token, buf, len, false) {
+        Some(buffers) => UserBuffer::new(buffers),
+        None => return -1,
+    };
+    match file.write_at(offset, buf) {
+        Some(write_size) => write_size as isize,
+        None => -1,
+    }
+}
+/// ftruncate syscall
+///
+/// Cut the file of `fd` down to `len` bytes, or extend it with zeros.
+pub fn sys_ftruncate(fd: usize, len: usize) -> isize {
+    trace!(
+        "kernel:pid[{}] sys_ftruncate",
+        current_task().unwrap().process.upgrade().unwrap().getpid()
+    );
+    let process = current_process();
+    let inner = process.inner_exclusive_access();
+    let file = match inner.fd_table.get(fd) {
+        Some(Some(file)) if file.writable() => file.clone(),
+        _ => return -1,
+    };
+    drop(inner);
+    if file.truncate(len) {
+        0
     } else {
         -1
     }
@@ -60,7 +197,14 @@ pub fn sys_open(path: *const u8, flags: u32) -> isize {
     let process = current_process();
     let token = current_user_token();
     let path = translated_str(token, path);
-    if let Some(inode) = open_file(path.as_str(), OpenFlags::from_bits(flags).unwrap()) {
+    let inner = process.inner_exclusive_access();
+    let (cwd, cred) = (inner.cwd.clone(), inner.cred);
+    drop(inner);
+    let flags = match OpenFlags::from_bits(flags) {
+        Some(flags) => flags,
+        None => return -1,
+    };
+    if let Some(inode) = open_file_at(&cwd, path.as_str(), flags, &cred) {
         let mut inner = process.inner_exclusive_access();
         let fd = inner.alloc_fd();
         inner.fd_table[fd] = Some(inode);
@@ -94,14 +238,23 @@ pub fn sys_pipe(pipe: *mut usize) -> isize {
     );
     let process = current_process();
     let token = current_user_token();
+    // writing to user memory may fault in a lazy page of this process, so
+    // translate before borrowing it
+    let (read_fd_ref, write_fd_ref) = match (
+        translated_refmut(token, pipe),
+        translated_refmut(token, unsafe { pipe.add(1) }),
+    ) {
+        (Some(read_fd_ref), Some(write_fd_ref)) => (read_fd_ref, write_fd_ref),
+        _ => return -1,
+    };
     let mut inner = process.inner_exclusive_access();
     let (pipe_read, pipe_write) = make_pipe();
     let read_fd = inner.alloc_fd();
     inner.fd_table[read_fd] = Some(pipe_read);
     let write_fd = inner.alloc_fd();
     inner.fd_table[write_fd] = Some(pipe_write);
-    *translated_refmut(token, pipe) = read_fd;
-    *translated_refmut(token, unsafe { pipe.add(1) }) = write_fd;
+    *read_fd_ref = read_fd;
+    *write_fd_ref = write_fd;
     0
 }
 /// dup syscall
@@ -123,29 +276,261 @@ pub fn sys_dup(fd: usize) -> isize {
     new_fd as isize
 }
 
-/// YOUR JOB: Implement fstat.
-pub fn sys_fstat(_fd: usize, _st: *mut Stat) -> isize {
+/// fstat syscall
+pub fn sys_fstat(fd: usize, st: *mut Stat) -> isize {
     trace!(
-        "kernel:pid[{}] sys_fstat NOT IMPLEMENTED",
+        "kernel:pid[{}] sys_fstat",
         current_task().unwrap().process.upgrade().unwrap().getpid()
     );
-    -1
+    let token = current_user_token();
+    let process = current_process();
+    let inner = process.inner_exclusive_access();
+    let file = match inner.fd_table.get(fd) {
+        Some(Some(file)) => file.clone(),
+        _ => return -1,
+    };
+    // writing to user memory may fault in a lazy page of this process
+    drop(inner);
+    match file.stat() {
+        Some(stat) if copy_to_user(token, st, &stat) => 0,
+        _ => -1,
+    }
 }
 
-/// YOUR JOB: Implement linkat.
-pub fn sys_linkat(_old_name: *const u8, _new_name: *const u8) -> isize {
+/// sync syscall
+///
+/// Write all mounted file systems back to their devices.
+pub fn sys_sync() -> isize {
     trace!(
-        "kernel:pid[{}] sys_linkat NOT IMPLEMENTED",
+        "kernel:pid[{}] sys_sync",
         current_task().unwrap().process.upgrade().unwrap().getpid()
     );
-    -1
+    sync_all();
+    0
 }
 
-/// YOUR JOB: Implement unlinkat.
-pub fn sys_unlinkat(_name: *const u8) -> isize {
+/// fsync syscall
+///
+/// Write the file of `fd` back to the disk, fail if it is not backed by one.
+pub fn sys_fsync(fd: usize) -> isize {
     trace!(
-        "kernel:pid[{}] sys_unlinkat NOT IMPLEMENTED",
+        "kernel:pid[{}] sys_fsync",
         current_task().unwrap().process.upgrade().unwrap().getpid()
     );
-    -1
+    let process = current_process();
+    let inner = process.inner_exclusive_access();
+    let file = match inner.fd_table.get(fd) {
+        Some(Some(file)) => file.clone(),
+        _ => return -1,
+    };
+    drop(inner);
+    if file.sync() {
+        0
+    } else {
+        -1
+    }
+}
+
+/// linkat syscall
+///
+/// Like `sys_open`, the dirfds are ignored and relative paths start from the cwd.
+pub fn sys_linkat(old_name: *const u8, new_name: *const u8) -> isize {
+    trace!(
+        "kernel:pid[{}] sys_linkat",
+        current_task().unwrap().process.upgrade().unwrap().getpid()
+    );
+    let token = current_user_token();
+    let old_path = translated_str(token, old_name);
+    let new_path = translated_str(token, new_name);
+    let process = current_process();
+    let inner = process.inner_exclusive_access();
+    let (cwd, cred) = (inner.cwd.clone(), inner.cred);
+    drop(inner);
+    if link_at(&cwd, old_path.as_str(), new_path.as_str(), &cred) {
+        0
+    } else {
+        -1
+    }
+}
+
+/// renameat2 syscall
+///
+/// Move a file or directory to a new path, replacing a file or an empty
+/// directory there unless `flags` has `RENAME_NOREPLACE`.
+pub fn sys_renameat2(old_name: *const u8, new_name: *const u8, flags: u32) -> isize {
+    trace!(
+        "kernel:pid[{}] sys_renameat2",
+        current_task().unwrap().process.upgrade().unwrap().getpid()
+    );
+    if flags & !RENAME_NOREPLACE != 0 {
+        return -1;
+    }
+    let token = current_user_token();
+    let old_path = translated_str(token, old_name);
+    let new_path = translated_str(token, new_name);
+    let process = current_process();
+    let inner = process.inner_exclusive_access();
+    let (cwd, cred) = (inner.cwd.clone(), inner.cred);
+    drop(inner);
+    let replace = flags & RENAME_NOREPLACE == 0;
+    if rename_at(&cwd, old_path.as_str(), new_path.as_str(), replace, &cred) {
+        0
+    } else {
+        -1
+    }
+}
+
+/// mount syscall
+///
+/// Mount a new file system of type `fstype` on the directory `target`. The
+/// source is ignored since only "ramfs", which has no device, can be made,
+/// and no `flags` are supported.
+pub fn sys_mount(target: *const u8, fstype: *const u8, flags: u32) -> isize {
+    trace!(
+        "kernel:pid[{}] sys_mount",
+        current_task().unwrap().process.upgrade().unwrap().getpid()
+    );
+    if flags != 0 {
+        return -1;
+    }
+    let token = current_user_token();
+    let target = translated_str(token, target);
+    let fstype = translated_str(token, fstype);
+    let process = current_process();
+    let inner = process.inner_exclusive_access();
+    let (cwd, cred) = (inner.cwd.clone(), inner.cred);
+    drop(inner);
+    if mount_at(&cwd, target.as_str(), fstype.as_str(), &cred) {
+        0
+    } else {
+        -1
+    }
+}
+
+/// umount2 syscall
+///
+/// Unmount the file system mounted on `target`, no `flags` are supported.
+pub fn sys_umount2(target: *const u8, flags: u32) -> isize {
+    trace!(
+        "kernel:pid[{}] sys_umount2",
+        current_task().unwrap().process.upgrade().unwrap().getpid()
+    );
+    if flags != 0 {
+        return -1;
+    }
+    let token = current_user_token();
+    let target = translated_str(token, target);
+    let process = current_process();
+    let inner = process.inner_exclusive_access();
+    let (cwd, cred) = (inner.cwd.clone(), inner.cred);
+    drop(inner);
+    if umount_at(&cwd, target.as_str(), &cred) {
+        0
+    } else {
+        -1
+    }
+}
+
+/// unlinkat syscall
+///
+/// Remove a link to a file, or an empty directory if `flags` has `AT_REMOVEDIR`.
+pub fn sys_unlinkat(name: *const u8, flags: u32) -> isize {
+    trace!(
+        "kernel:pid[{}] sys_unlinkat",
+        current_task().unwrap().process.upgrade().unwrap().getpid()
+    );
+    let token = current_user_token();
+    let path = translated_str(token, name);
+    let process = current_process();
+    let inner = process.inner_exclusive_access();
+    let (cwd, cred) = (inner.cwd.clone(), inner.cred);
+    drop(inner);
+    let removed = if flags & AT_REMOVEDIR == 0 {
+        unlink_at(&cwd, path.as_str(), &cred)
+    } else {
+        matches!(find_parent(&cwd, path.as_str(), &cred), Some((parent, name)) if parent.remove_dir(name))
+    };
+    if removed {
+        0
+    } else {
+        -1
+    }
+}
+
+/// mkdirat syscall
+///
+/// Like `sys_open`, `dirfd` is ignored and a relative `path` starts from the cwd.
+pub fn sys_mkdirat(_dirfd: isize, path: *const u8) -> isize {
+    trace!(
+        "kernel:pid[{}] sys_mkdirat",
+        current_task().unwrap().process.upgrade().unwrap().getpid()
+    );
+    let token = current_user_token();
+    let path = translated_str(token, path);
+    let process = current_process();
+    let inner = process.inner_exclusive_access();
+    let (cwd, cred) = (inner.cwd.clone(), inner.cred);
+    drop(inner);
+    let dir =
+        find_parent(&cwd, path.as_str(), &cred).and_then(|(parent, name)| parent.create_dir(name));
+    match dir {
+        Some(dir) => {
+            dir.set_owner(cred.uid, cred.gid);
+            0
+        }
+        None => -1,
+    }
+}
+
+/// chdir syscall
+pub fn sys_chdir(path: *const u8) -> isize {
+    trace!(
+        "kernel:pid[{}] sys_chdir",
+        current_task().unwrap().process.upgrade().unwrap().getpid()
+    );
+    let token = current_user_token();
+    let path = translated_str(token, path);
+    let process = current_process();
+    let inner = process.inner_exclusive_access();
+    let (cwd, cred) = (inner.cwd.clone(), inner.cred);
+    drop(inner);
+    match find_path(&cwd, path.as_str(), &cred) {
+        Some(dir) if dir.is_dir() && cred.permits(&*dir, Access::EXEC) => {
+            process.inner_exclusive_access().cwd = dir;
+            0
+        }
+        _ => -1,
+    }
+}
+
+/// getcwd syscall
+///
+/// Copy the null-terminated path of the cwd into `buf`, return its length
+/// with the terminator, or -1 if it does not fit in `len` bytes or the cwd
+/// was removed.
+pub fn sys_getcwd(buf: *mut u8, len: usize) -> isize {
+    trace!(
+        "kernel:pid[{}] sys_getcwd",
+        current_task().unwrap().process.upgrade().unwrap().getpid()
+    );
+    let token = current_user_token();
+    let cwd = current_process().inner_exclusive_access().cwd.clone();
+    let mut path = match path_of(&cwd) {
+        Some(path) => path,
+        None => return -1,
+    };
+    path.push('\0');
+    if path.len() > len {
+        return -1;
+    }
+    let buffers = match translated_byte_buffer(token, buf, path.len(), true) {
+        Some(buffers) => buffers,
+        None => return -1,
+    };
+    let mut src = path.as_bytes();
+    for dst in buffers {
+        dst.copy_from_slice(&src[..dst.len()]);
+        src = &src[dst.len()..];
+    }
+    path.len() as isize
 }
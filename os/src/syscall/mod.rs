@@ -10,6 +10,8 @@
 //! `sys_` then the name of the syscall. You can find functions like this in
 //! submodules, and you should also implement syscalls this way.
 
+/// chdir syscall
+pub const SYSCALL_CHDIR: usize = 49;
 /// openat syscall
 pub const SYSCALL_OPENAT: usize = 56;
 /// close syscall
@@ -18,12 +20,34 @@ pub const SYSCALL_CLOSE: usize = 57;
 pub const SYSCALL_READ: usize = 63;
 /// write syscall
 pub const SYSCALL_WRITE: usize = 64;
+/// lseek syscall
+pub const SYSCALL_LSEEK: usize = 62;
+/// getdents64 syscall
+pub const SYSCALL_GETDENTS64: usize = 61;
+/// pread64 syscall
+pub const SYSCALL_PREAD: usize = 67;
+/// pwrite64 syscall
+pub const SYSCALL_PWRITE: usize = 68;
+/// ftruncate syscall
+pub const SYSCALL_FTRUNCATE: usize = 46;
 /// unlinkat syscall
 pub const SYSCALL_UNLINKAT: usize = 35;
+/// mkdirat syscall
+pub const SYSCALL_MKDIRAT: usize = 34;
 /// linkat syscall
 pub const SYSCALL_LINKAT: usize = 37;
+/// renameat2 syscall
+pub const SYSCALL_RENAMEAT2: usize = 276;
+/// umount2 syscall
+pub const SYSCALL_UMOUNT2: usize = 39;
+/// mount syscall
+pub const SYSCALL_MOUNT: usize = 40;
 /// fstat syscall
 pub const SYSCALL_FSTAT: usize = 80;
+/// sync syscall
+pub const SYSCALL_SYNC: usize = 81;
+/// fsync syscall
+pub const SYSCALL_FSYNC: usize = 82;
 /// exit syscall
 pub const SYSCALL_EXIT: usize = 93;
 /// sleep syscall
@@ -44,6 +68,14 @@ pub const SYSCALL_SIGRETURN: usize = 139;
 pub const SYSCALL_GETTIMEOFDAY: usize = 169;
 /// getpid syscall
 pub const SYSCALL_GETPID: usize = 172;
+/// setgid syscall
+pub const SYSCALL_SETGID: usize = 144;
+/// setuid syscall
+pub const SYSCALL_SETUID: usize = 146;
+/// getuid syscall
+pub const SYSCALL_GETUID: usize = 174;
+/// getgid syscall
+pub const SYSCALL_GETGID: usize = 176;
 /// gettid syscall
 pub const SYSCALL_GETTID: usize = 178;
 /// fork syscall
@@ -70,6 +102,8 @@ pub const SYSCALL_MAIL_READ: usize = 401;
 /// mail write syscall
 pub const SYSCALL_MAIL_WRITE: usize = 402;
 */
+/// getcwd syscall
+pub const SYSCALL_GETCWD: usize = 17;
 /// dup syscall
 pub const SYSCALL_DUP: usize = 24;
 /// pipe syscall
@@ -100,36 +134,67 @@ pub const SYSCALL_CONDVAR_CREATE: usize = 471;
 pub const SYSCALL_CONDVAR_SIGNAL: usize = 472;
 /// condvar_wait syscallca
 pub const SYSCALL_CONDVAR_WAIT: usize = 473;
+/// aio_setup syscall
+pub const SYSCALL_AIO_SETUP: usize = 490;
+/// aio_enter syscall
+pub const SYSCALL_AIO_ENTER: usize = 491;
 
+mod aio;
 mod fs;
 mod process;
 mod sync;
 mod thread;
 
+use aio::*;
+pub use aio::{aio_poll_current, AioContext};
 use fs::*;
 use process::*;
 use sync::*;
 use thread::*;
 
+use crate::config::MAX_SYSCALL_NUM;
 use crate::fs::Stat;
+use crate::task::current_process;
 
 /// handle syscall exception with `syscall_id` and other arguments
-pub fn syscall(syscall_id: usize, args: [usize; 4]) -> isize {
+pub fn syscall(syscall_id: usize, args: [usize; 6]) -> isize {
+    if syscall_id < MAX_SYSCALL_NUM {
+        current_process().inner_exclusive_access().syscall_times[syscall_id] += 1;
+    }
     match syscall_id {
         SYSCALL_DUP => sys_dup(args[0]),
         SYSCALL_LINKAT => sys_linkat(args[1] as *const u8, args[3] as *const u8),
-        SYSCALL_UNLINKAT => sys_unlinkat(args[1] as *const u8),
+        SYSCALL_RENAMEAT2 => {
+            sys_renameat2(args[1] as *const u8, args[3] as *const u8, args[4] as u32)
+        }
+        SYSCALL_UMOUNT2 => sys_umount2(args[0] as *const u8, args[1] as u32),
+        SYSCALL_MOUNT => sys_mount(args[1] as *const u8, args[2] as *const u8, args[3] as u32),
+        SYSCALL_UNLINKAT => sys_unlinkat(args[1] as *const u8, args[2] as u32),
+        SYSCALL_MKDIRAT => sys_mkdirat(args[0] as isize, args[1] as *const u8),
+        SYSCALL_CHDIR => sys_chdir(args[0] as *const u8),
+        SYSCALL_GETCWD => sys_getcwd(args[0] as *mut u8, args[1]),
         SYSCALL_OPENAT => sys_open(args[1] as *const u8, args[2] as u32),
         SYSCALL_CLOSE => sys_close(args[0]),
         SYSCALL_PIPE => sys_pipe(args[0] as *mut usize),
         SYSCALL_READ => sys_read(args[0], args[1] as *const u8, args[2]),
         SYSCALL_WRITE => sys_write(args[0], args[1] as *const u8, args[2]),
+        SYSCALL_LSEEK => sys_lseek(args[0], args[1] as isize, args[2]),
+        SYSCALL_GETDENTS64 => sys_getdents64(args[0], args[1] as *const u8, args[2]),
+        SYSCALL_PREAD => sys_pread(args[0], args[1] as *const u8, args[2], args[3]),
+        SYSCALL_PWRITE => sys_pwrite(args[0], args[1] as *const u8, args[2], args[3]),
+        SYSCALL_FTRUNCATE => sys_ftruncate(args[0], args[1]),
         SYSCALL_FSTAT => sys_fstat(args[0], args[1] as *mut Stat),
+        SYSCALL_SYNC => sys_sync(),
+        SYSCALL_FSYNC => sys_fsync(args[0]),
         SYSCALL_EXIT => sys_exit(args[0] as i32),
         SYSCALL_SLEEP => sys_sleep(args[0]),
         SYSCALL_YIELD => sys_yield(),
         SYSCALL_GETPID => sys_getpid(),
         SYSCALL_GETTID => sys_gettid(),
+        SYSCALL_GETUID => sys_getuid(),
+        SYSCALL_GETGID => sys_getgid(),
+        SYSCALL_SETUID => sys_setuid(args[0] as u32),
+        SYSCALL_SETGID => sys_setgid(args[0] as u32),
         SYSCALL_FORK => sys_fork(),
         SYSCALL_EXEC => sys_exec(args[0] as *const u8, args[1] as *const usize),
         SYSCALL_WAITPID => sys_waitpid(args[0] as isize, args[1] as *mut i32),
@@ -138,7 +203,12 @@ pub fn syscall(syscall_id: usize, args: [usize; 4]) -> isize {
         SYSCALL_MUNMAP => sys_munmap(args[0], args[1]),
         SYSCALL_SET_PRIORITY => sys_set_priority(args[0] as isize),
         SYSCALL_TASK_INFO => sys_task_info(args[0] as *mut TaskInfo),
-        SYSCALL_SPAWN => sys_spawn(args[0] as *const u8),
+        SYSCALL_SPAWN => sys_spawn(
+            args[0] as *const u8,
+            args[1] as *const usize,
+            args[2] as *const _,
+            args[3],
+        ),
         SYSCALL_THREAD_CREATE => sys_thread_create(args[0], args[1]),
         SYSCALL_WAITTID => sys_waittid(args[0]) as isize,
         SYSCALL_MUTEX_CREATE => sys_mutex_create(args[0] == 1),
@@ -152,6 +222,8 @@ pub fn syscall(syscall_id: usize, args: [usize; 4]) -> isize {
         SYSCALL_CONDVAR_SIGNAL => sys_condvar_signal(args[0]),
         SYSCALL_CONDVAR_WAIT => sys_condvar_wait(args[0], args[1]),
         SYSCALL_KILL => sys_kill(args[0], args[1] as u32),
+        SYSCALL_AIO_SETUP => sys_aio_setup(args[0]),
+        SYSCALL_AIO_ENTER => sys_aio_enter(args[0], args[1], args[2] as isize),
         _ => panic!("Unsupported syscall_id: {}", syscall_id),
     }
 }
@@ -1,14 +1,18 @@
 use crate::{
-    config::MAX_SYSCALL_NUM,
-    fs::{open_file, OpenFlags},
-    mm::{translated_ref, translated_refmut, translated_str},
+    config::{MAX_FD, MAX_PRIORITY, MAX_SPAWN_ACTIONS, MAX_SYSCALL_NUM, PAGE_SIZE, USER_SPACE_END},
+    fs::{open_file_at, OpenFlags},
+    mm::{
+        copy_from_user, copy_to_user, translated_ref, translated_refmut, translated_str,
+        MapPermission, VirtAddr,
+    },
     task::{
         current_process, current_task, current_user_token, exit_current_and_run_next, pid2process,
-        suspend_current_and_run_next, SignalFlags, TaskStatus,
+        suspend_current_and_run_next, ProcessControlBlock, SignalFlags, TaskStatus,
     },
-    timer::get_time_us,
+    timer::{get_time_ms, get_time_us},
 };
 use alloc::{string::String, sync::Arc, vec::Vec};
+use core::{mem::MaybeUninit, ptr::addr_of_mut};
 
 #[repr(C)]
 #[derive(Debug)]
@@ -18,14 +22,19 @@ pub struct TimeVal {
 }
 
 /// Task information
+#[repr(C)]
 #[allow(dead_code)]
 pub struct TaskInfo {
     /// Task status in it's life cycle
     status: TaskStatus,
     /// The numbers of syscall called by task
     syscall_times: [u32; MAX_SYSCALL_NUM],
-    /// Total running time of task
+    /// Milliseconds since the task was first scheduled
     time: usize,
+    /// Time spent in user mode, in microseconds
+    user_time: usize,
+    /// Time spent in kernel mode, in microseconds
+    kernel_time: usize,
 }
 /// exit syscall
 ///
@@ -52,6 +61,54 @@ pub fn sys_getpid() -> isize {
     );
     current_task().unwrap().process.upgrade().unwrap().getpid() as isize
 }
+/// getuid syscall
+pub fn sys_getuid() -> isize {
+    trace!(
+        "kernel:pid[{}] sys_getuid",
+        current_task().unwrap().process.upgrade().unwrap().getpid()
+    );
+    current_process().inner_exclusive_access().cred.uid as isize
+}
+/// getgid syscall
+pub fn sys_getgid() -> isize {
+    trace!(
+        "kernel:pid[{}] sys_getgid",
+        current_task().unwrap().process.upgrade().unwrap().getpid()
+    );
+    current_process().inner_exclusive_access().cred.gid as isize
+}
+/// setuid syscall
+///
+/// Only the superuser may become another user.
+pub fn sys_setuid(uid: u32) -> isize {
+    trace!(
+        "kernel:pid[{}] sys_setuid",
+        current_task().unwrap().process.upgrade().unwrap().getpid()
+    );
+    let process = current_process();
+    let mut inner = process.inner_exclusive_access();
+    if inner.cred.uid != 0 && inner.cred.uid != uid {
+        return -1;
+    }
+    inner.cred.uid = uid;
+    0
+}
+/// setgid syscall
+///
+/// Only the superuser may join another group.
+pub fn sys_setgid(gid: u32) -> isize {
+    trace!(
+        "kernel:pid[{}] sys_setgid",
+        current_task().unwrap().process.upgrade().unwrap().getpid()
+    );
+    let process = current_process();
+    let mut inner = process.inner_exclusive_access();
+    if inner.cred.uid != 0 && inner.cred.gid != gid {
+        return -1;
+    }
+    inner.cred.gid = gid;
+    0
+}
 /// fork child process syscall
 pub fn sys_fork() -> isize {
     trace!(
@@ -59,7 +116,10 @@ pub fn sys_fork() -> isize {
         current_task().unwrap().process.upgrade().unwrap().getpid()
     );
     let current_process = current_process();
-    let new_process = current_process.fork();
+    let new_process = match current_process.fork() {
+        Some(new_process) => new_process,
+        None => return -1,
+    };
     let new_pid = new_process.getpid();
     // modify trap context of new_task, because it returns immediately after switching
     let new_process_inner = new_process.inner_exclusive_access();
@@ -89,11 +149,16 @@ pub fn sys_exec(path: *const u8, mut args: *const usize) -> isize {
             args = args.add(1);
         }
     }
-    if let Some(app_inode) = open_file(path.as_str(), OpenFlags::RDONLY) {
+    let process = current_process();
+    let inner = process.inner_exclusive_access();
+    let (cwd, cred) = (inner.cwd.clone(), inner.cred);
+    drop(inner);
+    if let Some(app_inode) = open_file_at(&cwd, path.as_str(), OpenFlags::RDONLY, &cred) {
         let all_data = app_inode.read_all();
-        let process = current_process();
         let argc = args_vec.len();
-        process.exec(all_data.as_slice(), args_vec);
+        if !process.exec(all_data.as_slice(), args_vec) {
+            return -1;
+        }
         // return argc because cx.x[10] will be covered with it later
         argc as isize
     } else {
@@ -107,7 +172,22 @@ pub fn sys_exec(path: *const u8, mut args: *const usize) -> isize {
 /// Else if there is a child process but it is still running, return -2.
 pub fn sys_waitpid(pid: isize, exit_code_ptr: *mut i32) -> isize {
     //trace!("kernel: sys_waitpid");
-    let process = current_process();
+    try_waitpid(&current_process(), pid, exit_code_ptr)
+}
+
+/// reap a zombie child of `process` without blocking, see [`sys_waitpid`]
+pub(super) fn try_waitpid(
+    process: &Arc<ProcessControlBlock>,
+    pid: isize,
+    exit_code_ptr: *mut i32,
+) -> isize {
+    // writing to user memory may fault in a lazy page of this process, so
+    // translate before borrowing it, and fail before reaping a child
+    let token = process.inner_exclusive_access().memory_set.token();
+    let exit_code_ref = match translated_refmut(token, exit_code_ptr) {
+        Some(exit_code_ref) => exit_code_ref,
+        None => return -1,
+    };
     // find a child process
 
     let mut inner = process.inner_exclusive_access();
@@ -132,7 +212,7 @@ pub fn sys_waitpid(pid: isize, exit_code_ptr: *mut i32) -> isize {
         // ++++ temporarily access child PCB exclusively
         let exit_code = child.inner_exclusive_access().exit_code;
         // ++++ release child PCB
-        *translated_refmut(inner.memory_set.token(), exit_code_ptr) = exit_code;
+        *exit_code_ref = exit_code;
         found_pid as isize
     } else {
         -2
@@ -160,53 +240,109 @@ pub fn sys_kill(pid: usize, signal: u32) -> isize {
 
 /// get_time syscall
 ///
-/// YOUR JOB: get time with second and microsecond
-/// HINT: You might reimplement it with virtual memory management.
-/// HINT: What if [`TimeVal`] is splitted by two pages ?
+/// get time with second and microsecond, [`TimeVal`] may span two pages
 pub fn sys_get_time(ts: *mut TimeVal, _tz: usize) -> isize {
     let token = current_user_token();
     let time = get_time_us();
-    // TODO: split TimeVal into two pages
-    *translated_refmut(token, ts) = TimeVal {
+    let time_val = TimeVal {
         sec: time / 1_000_000,
         usec: time % 1_000_000,
     };
-    0
+    if copy_to_user(token, ts, &time_val) {
+        0
+    } else {
+        -1
+    }
 }
 
 /// task_info syscall
 ///
-/// YOUR JOB: Finish sys_task_info to pass testcases
-/// HINT: You might reimplement it with virtual memory management.
-/// HINT: What if [`TaskInfo`] is splitted by two pages ?
-pub fn sys_task_info(_ti: *mut TaskInfo) -> isize {
+/// Report syscall counts and CPU time of the current process, [`TaskInfo`] may span two pages
+pub fn sys_task_info(ti: *mut TaskInfo) -> isize {
     trace!(
-        "kernel:pid[{}] sys_task_info NOT IMPLEMENTED",
+        "kernel:pid[{}] sys_task_info",
         current_task().unwrap().process.upgrade().unwrap().getpid()
     );
-    -1
+    let process = current_process();
+    let inner = process.inner_exclusive_access();
+    // zeroed and filled in place, so the padding between the fields copies
+    // out zeros rather than whatever the kernel stack held
+    let mut info = MaybeUninit::<TaskInfo>::zeroed();
+    let fields = info.as_mut_ptr();
+    unsafe {
+        addr_of_mut!((*fields).status).write(TaskStatus::Running);
+        addr_of_mut!((*fields).syscall_times).write(inner.syscall_times);
+        addr_of_mut!((*fields).time)
+            .write(inner.start_time.map_or(0, |start| get_time_ms() - start));
+        addr_of_mut!((*fields).user_time).write(inner.user_time);
+        addr_of_mut!((*fields).kernel_time).write(inner.kernel_time);
+    }
+    drop(inner);
+    if copy_to_user(current_user_token(), ti, unsafe { info.assume_init_ref() }) {
+        0
+    } else {
+        -1
+    }
 }
 
 /// mmap syscall
 ///
-/// YOUR JOB: Implement mmap.
-pub fn sys_mmap(_start: usize, _len: usize, _port: usize) -> isize {
+/// Map `len` bytes of anonymous memory at the page-aligned `start`. Bits 0, 1
+/// and 2 of `port` allow read, write and execute. Frames are allocated when a
+/// page is first touched.
+pub fn sys_mmap(start: usize, len: usize, port: usize) -> isize {
     trace!(
-        "kernel:pid[{}] sys_mmap NOT IMPLEMENTED",
+        "kernel:pid[{}] sys_mmap",
         current_task().unwrap().process.upgrade().unwrap().getpid()
     );
-    -1
+    if start % PAGE_SIZE != 0
+        || len == 0
+        || len > USER_SPACE_END.saturating_sub(start)
+        || port & !0x7 != 0
+        || port & 0x7 == 0
+    {
+        return -1;
+    }
+    let start_va = VirtAddr::from(start);
+    let end_va = VirtAddr::from(start + len);
+    let process = current_process();
+    let mut inner = process.inner_exclusive_access();
+    if inner.memory_set.overlaps(start_va.floor(), end_va.ceil()) {
+        return -1;
+    }
+    // `port` is `MapPermission` shifted down by one, and a writable page must
+    // also be readable in the page table
+    let mut permission = MapPermission::from_bits_truncate((port << 1) as u8) | MapPermission::U;
+    if permission.contains(MapPermission::W) {
+        permission |= MapPermission::R;
+    }
+    inner
+        .memory_set
+        .insert_lazy_area(start_va, end_va, permission);
+    0
 }
 
 /// munmap syscall
 ///
-/// YOUR JOB: Implement munmap.
-pub fn sys_munmap(_start: usize, _len: usize) -> isize {
+/// Unmap `[start, start + len)`, which must be wholly mapped by `sys_mmap`.
+/// Mappings only partly inside the range are split.
+pub fn sys_munmap(start: usize, len: usize) -> isize {
     trace!(
-        "kernel:pid[{}] sys_munmap NOT IMPLEMENTED",
+        "kernel:pid[{}] sys_munmap",
         current_task().unwrap().process.upgrade().unwrap().getpid()
     );
-    -1
+    if start % PAGE_SIZE != 0 || len == 0 || len > USER_SPACE_END.saturating_sub(start) {
+        return -1;
+    }
+    let start_vpn = VirtAddr::from(start).floor();
+    let end_vpn = VirtAddr::from(start + len).ceil();
+    let process = current_process();
+    let mut inner = process.inner_exclusive_access();
+    if inner.memory_set.remove_lazy_range(start_vpn, end_vpn) {
+        0
+    } else {
+        -1
+    }
 }
 
 /// change data segment size
@@ -218,24 +354,107 @@ pub fn sys_munmap(_start: usize, _len: usize) -> isize {
 //     -1
 // }
 
+/// `SpawnAction::op` making `new_fd` of the child a copy of `fd`
+const SPAWN_DUP2: usize = 1;
+/// `SpawnAction::op` closing `fd` in the child
+const SPAWN_CLOSE: usize = 2;
+
+/// A posix_spawn-like file action, applied in order to the child's fd table
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct SpawnAction {
+    op: usize,
+    fd: usize,
+    new_fd: usize,
+}
+
 /// spawn syscall
-/// YOUR JOB: Implement spawn.
-/// HINT: fork + exec =/= spawn
-pub fn sys_spawn(_path: *const u8) -> isize {
+///
+/// Create a child running the program at `path` with the null-terminated
+/// `args` (none if null), without copying the caller's address space. The
+/// child inherits the fd table after `action_count` file actions at
+/// `actions` are applied. Return the child's pid, or -1 if the program
+/// cannot be opened or loaded, or an action names a closed fd.
+pub fn sys_spawn(
+    path: *const u8,
+    mut args: *const usize,
+    actions: *const SpawnAction,
+    action_count: usize,
+) -> isize {
     trace!(
-        "kernel:pid[{}] sys_spawn NOT IMPLEMENTED",
+        "kernel:pid[{}] sys_spawn",
         current_task().unwrap().process.upgrade().unwrap().getpid()
     );
-    -1
+    let token = current_user_token();
+    let path = translated_str(token, path);
+    let mut args_vec: Vec<String> = Vec::new();
+    while !args.is_null() {
+        let arg_str_ptr = *translated_ref(token, args);
+        if arg_str_ptr == 0 {
+            break;
+        }
+        args_vec.push(translated_str(token, arg_str_ptr as *const u8));
+        unsafe {
+            args = args.add(1);
+        }
+    }
+    if action_count > MAX_SPAWN_ACTIONS {
+        return -1;
+    }
+    // an action may straddle two pages
+    let actions: Vec<SpawnAction> = (0..action_count)
+        .map(|i| copy_from_user(token, unsafe { actions.add(i) }))
+        .collect();
+    let process = current_process();
+    let inner = process.inner_exclusive_access();
+    let mut fd_table = inner.fd_table.clone();
+    let (cwd, cred) = (inner.cwd.clone(), inner.cred);
+    drop(inner);
+    for action in actions {
+        if action.fd >= fd_table.len() || fd_table[action.fd].is_none() {
+            // closing a closed fd is harmless
+            if action.op == SPAWN_CLOSE {
+                continue;
+            }
+            return -1;
+        }
+        match action.op {
+            SPAWN_DUP2 => {
+                if action.new_fd >= MAX_FD {
+                    return -1;
+                }
+                if action.new_fd >= fd_table.len() {
+                    fd_table.resize(action.new_fd + 1, None);
+                }
+                fd_table[action.new_fd] = fd_table[action.fd].clone();
+            }
+            SPAWN_CLOSE => fd_table[action.fd] = None,
+            _ => return -1,
+        }
+    }
+    if let Some(app_inode) = open_file_at(&cwd, path.as_str(), OpenFlags::RDONLY, &cred) {
+        let all_data = app_inode.read_all();
+        match process.spawn(all_data.as_slice(), args_vec, fd_table) {
+            Some(child) => child.getpid() as isize,
+            None => -1,
+        }
+    } else {
+        -1
+    }
 }
 
 /// set priority syscall
 ///
-/// YOUR JOB: Set task priority
-pub fn sys_set_priority(_prio: isize) -> isize {
+/// Set the stride scheduling priority of the current thread, return it or -1
+/// if it is not in `2..=MAX_PRIORITY`
+pub fn sys_set_priority(prio: isize) -> isize {
     trace!(
-        "kernel:pid[{}] sys_set_priority NOT IMPLEMENTED",
+        "kernel:pid[{}] sys_set_priority",
         current_task().unwrap().process.upgrade().unwrap().getpid()
     );
-    -1
+    if !(2..=MAX_PRIORITY as isize).contains(&prio) {
+        return -1;
+    }
+    current_task().unwrap().inner_exclusive_access().priority = prio as usize;
+    prio
 }
@@ -20,7 +20,7 @@ pub fn sys_thread_create(entry: usize, arg: usize) -> isize {
     let task = current_task().unwrap();
     let process = task.process.upgrade().unwrap();
     // create a new thread
-    let new_task = Arc::new(TaskControlBlock::new(
+    let new_task = match TaskControlBlock::new(
         Arc::clone(&process),
         task.inner_exclusive_access()
             .res
@@ -28,7 +28,17 @@ pub fn sys_thread_create(entry: usize, arg: usize) -> isize {
             .unwrap()
             .ustack_base,
         true,
-    ));
+    ) {
+        Some(new_task) => Arc::new(new_task),
+        None => return -1,
+    };
+    // the new thread inherits priority and pass, so it neither starves nor is starved
+    {
+        let task_inner = task.inner_exclusive_access();
+        let mut new_task_inner = new_task.inner_exclusive_access();
+        new_task_inner.priority = task_inner.priority;
+        new_task_inner.stride = task_inner.stride;
+    }
     // add new task to scheduler
     add_task(Arc::clone(&new_task));
     let new_task_inner = new_task.inner_exclusive_access();
@@ -0,0 +1,97 @@
+//! Executor of stackless kernel tasks
+//!
+//! Kernel-internal jobs are written as `Future`s and polled on the idle
+//! control flow in [`run_tasks`](super::run_tasks), so they share the boot
+//! stack instead of each owning a [`KernelStack`](super::KernelStack) and a
+//! [`TaskContext`](super::TaskContext). `run_tasks` polls at most one ready
+//! kernel task before each switch to a user task, so neither side can starve
+//! the other.
+
+use crate::sync::UPSafeCell;
+use alloc::boxed::Box;
+use alloc::collections::{BTreeMap, VecDeque};
+use alloc::sync::Arc;
+use alloc::task::Wake;
+use core::future::Future;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::task::{Context, Poll, Waker};
+use lazy_static::*;
+
+type KernelFuture = Pin<Box<dyn Future<Output = ()> + Send + 'static>>;
+
+/// A stackless kernel task
+struct KernelTask {
+    future: KernelFuture,
+    waker: Arc<KernelTaskWaker>,
+}
+
+/// Waker of a kernel task, puts its id back into the ready queue
+struct KernelTaskWaker {
+    id: usize,
+    /// the task is already in the ready queue
+    queued: AtomicBool,
+}
+
+impl Wake for KernelTaskWaker {
+    fn wake(self: Arc<Self>) {
+        self.wake_by_ref();
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        if !self.queued.swap(true, Ordering::AcqRel) {
+            KERNEL_READY_QUEUE.exclusive_access().push_back(self.id);
+        }
+    }
+}
+
+lazy_static! {
+    /// kernel tasks which are not being polled, keyed by id
+    static ref KERNEL_TASKS: UPSafeCell<BTreeMap<usize, KernelTask>> =
+        unsafe { UPSafeCell::new(BTreeMap::new()) };
+    /// ids of kernel tasks that have been woken
+    static ref KERNEL_READY_QUEUE: UPSafeCell<VecDeque<usize>> =
+        unsafe { UPSafeCell::new(VecDeque::new()) };
+    static ref KERNEL_TASK_ID: UPSafeCell<usize> = unsafe { UPSafeCell::new(0) };
+}
+
+/// Spawn a kernel task, it is first polled by the next round of `run_tasks`
+pub fn kernel_spawn(future: impl Future<Output = ()> + Send + 'static) {
+    let id = {
+        let mut next_id = KERNEL_TASK_ID.exclusive_access();
+        *next_id += 1;
+        *next_id
+    };
+    let waker = Arc::new(KernelTaskWaker {
+        id,
+        queued: AtomicBool::new(false),
+    });
+    KERNEL_TASKS.exclusive_access().insert(
+        id,
+        KernelTask {
+            future: Box::pin(future),
+            waker: Arc::clone(&waker),
+        },
+    );
+    waker.wake();
+}
+
+/// Poll one ready kernel task, return false if none was ready
+pub fn run_kernel_task() -> bool {
+    let id = match KERNEL_READY_QUEUE.exclusive_access().pop_front() {
+        Some(id) => id,
+        None => return false,
+    };
+    // the task may spawn or wake kernel tasks, so do not keep the map borrowed
+    let mut task = match KERNEL_TASKS.exclusive_access().remove(&id) {
+        Some(task) => task,
+        None => return false,
+    };
+    task.waker.queued.store(false, Ordering::Release);
+    let waker = Waker::from(Arc::clone(&task.waker));
+    let mut cx = Context::from_waker(&waker);
+    if let Poll::Pending = task.future.as_mut().poll(&mut cx) {
+        KERNEL_TASKS.exclusive_access().insert(id, task);
+    }
+    true
+}
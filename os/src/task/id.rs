@@ -2,7 +2,7 @@
 
 use super::ProcessControlBlock;
 use crate::config::{KERNEL_STACK_SIZE, PAGE_SIZE, TRAMPOLINE, TRAP_CONTEXT_BASE, USER_STACK_SIZE};
-use crate::mm::{MapPermission, PhysPageNum, VirtAddr, KERNEL_SPACE};
+use crate::mm::{MapPermission, MemorySet, PhysPageNum, VirtAddr, KERNEL_SPACE};
 use crate::sync::UPSafeCell;
 use alloc::{
     sync::{Arc, Weak},
@@ -86,10 +86,13 @@ pub struct KernelStack(pub usize);
 pub fn kstack_alloc() -> KernelStack {
     let kstack_id = KSTACK_ALLOCATOR.exclusive_access().alloc();
     let (kstack_bottom, kstack_top) = kernel_stack_position(kstack_id);
-    KERNEL_SPACE.exclusive_access().insert_framed_area(
-        kstack_bottom.into(),
-        kstack_top.into(),
-        MapPermission::R | MapPermission::W,
+    assert!(
+        KERNEL_SPACE.exclusive_access().insert_framed_area(
+            kstack_bottom.into(),
+            kstack_top.into(),
+            MapPermission::R | MapPermission::W,
+        ),
+        "Run out of frames!"
     );
     KernelStack(kstack_id)
 }
@@ -145,43 +148,56 @@ fn ustack_bottom_from_tid(ustack_base: usize, tid: usize) -> usize {
 }
 
 impl TaskUserRes {
-    /// Create a new TaskUserRes (Task User Resource)
+    /// Create a new TaskUserRes (Task User Resource), `None` if no frame is
+    /// left for its user stack and trap context
     pub fn new(
         process: Arc<ProcessControlBlock>,
         ustack_base: usize,
         alloc_user_res: bool,
-    ) -> Self {
+    ) -> Option<Self> {
         let tid = process.inner_exclusive_access().alloc_tid();
         let task_user_res = Self {
             tid,
             ustack_base,
             process: Arc::downgrade(&process),
         };
-        if alloc_user_res {
-            task_user_res.alloc_user_res();
+        // the tid is freed again on drop
+        if alloc_user_res && !task_user_res.alloc_user_res() {
+            return None;
         }
-        task_user_res
+        Some(task_user_res)
     }
-    /// Allocate user resource for a task
-    pub fn alloc_user_res(&self) {
+    /// Allocate user resource for a task, return false if no frame is left
+    pub fn alloc_user_res(&self) -> bool {
         let process = self.process.upgrade().unwrap();
         let mut process_inner = process.inner_exclusive_access();
+        self.map_user_res(&mut process_inner.memory_set, self.ustack_base)
+    }
+    /// Map the user stack of the task above `ustack_base` and its trap context
+    /// into `memory_set`, return false and map neither if no frame is left
+    pub fn map_user_res(&self, memory_set: &mut MemorySet, ustack_base: usize) -> bool {
         // alloc user stack
-        let ustack_bottom = ustack_bottom_from_tid(self.ustack_base, self.tid);
+        let ustack_bottom = ustack_bottom_from_tid(ustack_base, self.tid);
         let ustack_top = ustack_bottom + USER_STACK_SIZE;
-        process_inner.memory_set.insert_framed_area(
+        if !memory_set.insert_framed_area(
             ustack_bottom.into(),
             ustack_top.into(),
             MapPermission::R | MapPermission::W | MapPermission::U,
-        );
+        ) {
+            return false;
+        }
         // alloc trap_cx
         let trap_cx_bottom = trap_cx_bottom_from_tid(self.tid);
         let trap_cx_top = trap_cx_bottom + PAGE_SIZE;
-        process_inner.memory_set.insert_framed_area(
+        if !memory_set.insert_framed_area(
             trap_cx_bottom.into(),
             trap_cx_top.into(),
             MapPermission::R | MapPermission::W,
-        );
+        ) {
+            memory_set.remove_area_with_start_vpn(VirtAddr::from(ustack_bottom).into());
+            return false;
+        }
+        true
     }
     /// Deallocate user resource for a task
     fn dealloc_user_res(&self) {
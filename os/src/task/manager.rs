@@ -3,65 +3,60 @@
 //! It is only used to manage processes and schedule process based on ready queue.
 //! Other CPU process monitoring functions are in Processor.
 
+use super::current_task;
+use super::scheduler::{Scheduler, SelectedScheduler};
 use super::{ProcessControlBlock, TaskControlBlock, TaskStatus};
 use crate::sync::UPSafeCell;
-use alloc::collections::{BTreeMap, VecDeque};
+use alloc::collections::BTreeMap;
 use alloc::sync::Arc;
 use lazy_static::*;
+
 ///A array of `TaskControlBlock` that is thread-safe
 pub struct TaskManager {
-    ready_queue: VecDeque<Arc<TaskControlBlock>>,
+    /// The ready queue, ordered by the policy selected at build time
+    scheduler: SelectedScheduler,
 
     /// The stopping task, leave a reference so that the kernel stack will not be recycled when switching tasks
     stop_task: Option<Arc<TaskControlBlock>>,
 }
 
-/// A simple FIFO scheduler.
 impl TaskManager {
     ///Creat an empty TaskManager
     pub fn new() -> Self {
         Self {
-            ready_queue: VecDeque::new(),
+            scheduler: SelectedScheduler::new(),
             stop_task: None,
         }
     }
     /// Add process back to ready queue
     pub fn add(&mut self, task: Arc<TaskControlBlock>) {
-        self.ready_queue.push_back(task);
+        self.scheduler.add(task);
     }
     /// Fetch a task from ready queue
     pub fn fetch(&mut self) -> Option<Arc<TaskControlBlock>> {
-        for (index, task) in self.ready_queue.iter().enumerate() {
-            let tid = task.inner_exclusive_access().res.as_ref().unwrap().tid;
-            let process = task.process.upgrade().unwrap();
-            let mut process_inner = process.inner_exclusive_access();
-
-            if !process_inner.locker.finish[tid] {
-                process_inner.locker.alloc(tid);
-
-                self.ready_queue.remove(index);
-                return Some(self.ready_queue[index].clone());
-            }
+        let task = self.scheduler.fetch()?;
+        let tid = task.inner_exclusive_access().res.as_ref().unwrap().tid;
+        let process = task.process.upgrade().unwrap();
+        let mut process_inner = process.inner_exclusive_access();
+        if !process_inner.locker.finish[tid] {
+            process_inner.locker.alloc(tid);
         }
-
-        self.ready_queue.pop_front()
+        drop(process_inner);
+        Some(task)
     }
     /// Remove a task
     pub fn remove(&mut self, task: Arc<TaskControlBlock>) {
-        if let Some((id, f_task)) = self
-            .ready_queue
-            .iter()
-            .enumerate()
-            .find(|(_, t)| Arc::as_ptr(t) == Arc::as_ptr(&task))
-        {
-            let tid = f_task.inner_exclusive_access().res.as_ref().unwrap().tid;
+        if self.scheduler.remove(&task) {
+            let tid = task.inner_exclusive_access().res.as_ref().unwrap().tid;
             let process = task.process.upgrade().unwrap();
             let mut process_inner = process.inner_exclusive_access();
             process_inner.locker.finish(tid);
-
-            self.ready_queue.remove(id);
         }
     }
+    /// A timer tick hit the running task, return whether to preempt it
+    pub fn on_tick(&mut self, current: &Arc<TaskControlBlock>) -> bool {
+        self.scheduler.on_tick(current)
+    }
     /// Add a task to stopping task
     pub fn add_stop(&mut self, task: Arc<TaskControlBlock>) {
         // NOTE: as the last stopping task has completely stopped (not
@@ -87,15 +82,26 @@ pub fn add_task(task: Arc<TaskControlBlock>) {
     TASK_MANAGER.exclusive_access().add(task);
 }
 
-/// Wake up a task
+/// Wake up a task, unless it is not blocked: a task waiting on several
+/// sources at once is woken by the first one only
 pub fn wakeup_task(task: Arc<TaskControlBlock>) {
     trace!("kernel: TaskManager::wakeup_task");
     let mut task_inner = task.inner_exclusive_access();
+    if task_inner.task_status != TaskStatus::Blocked {
+        return;
+    }
     task_inner.task_status = TaskStatus::Ready;
     drop(task_inner);
     add_task(task);
 }
 
+/// Let the scheduler account a timer tick of the running task,
+/// return whether it should be preempted
+pub fn scheduler_tick() -> bool {
+    let task = current_task().unwrap();
+    TASK_MANAGER.exclusive_access().on_tick(&task)
+}
+
 /// Remove a task from the ready queue
 pub fn remove_task(task: Arc<TaskControlBlock>) {
     //trace!("kernel: TaskManager::remove_task");
@@ -119,6 +125,14 @@ pub fn pid2process(pid: usize) -> Option<Arc<ProcessControlBlock>> {
     map.get(&pid).map(Arc::clone)
 }
 
+/// Is the working directory of any process on the file system with device number `dev`?
+pub fn cwd_on_dev(dev: u64) -> bool {
+    PID2PCB
+        .exclusive_access()
+        .values()
+        .any(|process| process.inner_exclusive_access().cwd.dev() == dev)
+}
+
 /// Insert item(pid, pcb) into PID2PCB map (called by do_fork AND ProcessControlBlock::new)
 pub fn insert_into_pid2process(pid: usize, process: Arc<ProcessControlBlock>) {
     PID2PCB.exclusive_access().insert(pid, process);
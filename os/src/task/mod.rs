@@ -10,28 +10,37 @@
 //! might not be what you expect.
 
 mod context;
+mod executor;
 mod id;
 mod manager;
 mod process;
 mod processor;
+mod scheduler;
 mod signal;
 mod switch;
 #[allow(clippy::module_inception)]
 mod task;
 
 use self::id::TaskUserRes;
+use crate::config::FRAME_RESERVE;
 use crate::fs::{open_file, OpenFlags};
-use crate::task::manager::add_stopping_task;
+use crate::mm::{frame_free_count, MapPermission, VirtAddr, VirtPageNum};
+use crate::sync::UPSafeCell;
+use crate::task::manager::{add_stopping_task, PID2PCB};
 use crate::timer::remove_timer;
 use alloc::{sync::Arc, vec::Vec};
 use lazy_static::*;
 use manager::fetch_task;
-use process::ProcessControlBlock;
 use switch::__switch;
 
 pub use context::TaskContext;
+pub use executor::{kernel_spawn, run_kernel_task};
+pub use process::ProcessControlBlock;
 pub use id::{kstack_alloc, pid_alloc, KernelStack, PidHandle, IDLE_PID};
-pub use manager::{add_task, pid2process, remove_from_pid2process, remove_task, wakeup_task};
+pub use manager::{
+    add_task, cwd_on_dev, pid2process, remove_from_pid2process, remove_task, scheduler_tick,
+    wakeup_task,
+};
 pub use processor::{
     current_kstack_top, current_process, current_task, current_trap_cx, current_trap_cx_user_va,
     current_user_token, run_tasks, schedule, take_current_task,
@@ -198,6 +207,82 @@ pub fn current_add_signal(signal: SignalFlags) {
     process_inner.signals |= signal;
 }
 
+/// Resolve a page fault at `va` in the address space `token` of the current
+/// process, return false if it is a real fault. The fault fails too if the
+/// process is borrowed already, by a caller that touches user memory while
+/// holding it.
+pub fn current_handle_page_fault(token: usize, va: VirtAddr, access: MapPermission) -> bool {
+    let process = current_process();
+    let mut process_inner = match process.try_inner_exclusive_access() {
+        Some(process_inner) => process_inner,
+        None => return false,
+    };
+    process_inner.memory_set.token() == token
+        && process_inner.memory_set.handle_page_fault(va, access)
+}
+
+lazy_static! {
+    /// clock hand of page replacement: a pid and a page of its address space
+    static ref RECLAIM_HAND: UPSafeCell<(usize, VirtPageNum)> =
+        unsafe { UPSafeCell::new((0, VirtPageNum(0))) };
+}
+
+/// Write user pages to swap until [`FRAME_RESERVE`] frames are free, so that
+/// the kernel finds frames in the middle of a syscall. Called on trap entry,
+/// where no process is borrowed, and by `frame_alloc` when no frame is left,
+/// where the processes borrowed are skipped. The clock hand sweeps processes
+/// in pid order.
+pub fn reclaim_frames() {
+    if frame_free_count() >= FRAME_RESERVE {
+        return;
+    }
+    // a reclaim already running needs no help
+    let mut hand = match RECLAIM_HAND.try_exclusive_access() {
+        Some(hand) => hand,
+        None => return,
+    };
+    // the first sweep may only clear accessed bits
+    let mut sweeps = 0;
+    while frame_free_count() < FRAME_RESERVE && sweeps < 3 {
+        let next = match PID2PCB.try_exclusive_access() {
+            Some(pid2pcb) => pid2pcb
+                .range(hand.0..)
+                .next()
+                .map(|(&pid, process)| (pid, Arc::clone(process))),
+            None => return,
+        };
+        let (pid, process) = match next {
+            Some(next) => next,
+            None => {
+                sweeps += 1;
+                *hand = (0, VirtPageNum(0));
+                continue;
+            }
+        };
+        if pid != hand.0 {
+            *hand = (pid, VirtPageNum(0));
+        }
+        *hand = match evict_from(&process, hand.1) {
+            Some(vpn) => (pid, vpn),
+            None => (pid + 1, VirtPageNum(0)),
+        };
+    }
+}
+
+/// Evict one page of `process` from `hand` on. Processes with a thread inside
+/// a syscall are skipped, as the kernel may hold pointers to their frames,
+/// and so are the processes or threads borrowed now.
+fn evict_from(process: &Arc<ProcessControlBlock>, hand: VirtPageNum) -> Option<VirtPageNum> {
+    let mut inner = process.try_inner_exclusive_access()?;
+    if inner.tasks.iter().flatten().any(|task| {
+        task.try_inner_exclusive_access()
+            .map_or(true, |task_inner| task_inner.in_syscall)
+    }) {
+        return None;
+    }
+    inner.memory_set.evict_one(hand)
+}
+
 /// the inactive(blocked) tasks are removed when the PCB is deallocated.(called by exit_current_and_run_next)
 pub fn remove_inactive_task(task: Arc<TaskControlBlock>) {
     remove_task(Arc::clone(&task));
@@ -3,11 +3,13 @@
 use super::id::RecycleAllocator;
 use super::manager::insert_into_pid2process;
 use super::TaskControlBlock;
-use super::{add_task, SignalFlags};
+use super::{add_task, current_task, SignalFlags};
 use super::{pid_alloc, PidHandle};
-use crate::fs::{File, Stdin, Stdout};
+use crate::config::MAX_SYSCALL_NUM;
+use crate::fs::{Credential, File, Stdin, Stdout, VfsInode, ROOT_INODE};
 use crate::mm::{translated_refmut, MemorySet, KERNEL_SPACE};
 use crate::sync::{Condvar, Mutex, Semaphore, UPSafeCell};
+use crate::syscall::AioContext;
 use crate::trap::{trap_handler, TrapContext};
 use alloc::string::String;
 use alloc::sync::{Arc, Weak};
@@ -37,6 +39,10 @@ pub struct ProcessControlBlockInner {
     pub exit_code: i32,
     /// file descriptor table
     pub fd_table: Vec<Option<Arc<dyn File + Send + Sync>>>,
+    /// current working directory
+    pub cwd: Arc<dyn VfsInode>,
+    /// who the process acts as when it accesses files
+    pub cred: Credential,
     /// signal flags
     pub signals: SignalFlags,
     /// tasks(also known as threads)
@@ -53,6 +59,16 @@ pub struct ProcessControlBlockInner {
     pub deadlock_detect: bool,
     /// mutex and semaphore locker
     pub locker: ProcessLocker,
+    /// async syscall ring and pending operations
+    pub aio: Option<AioContext>,
+    /// the number of times each syscall was called
+    pub syscall_times: [u32; MAX_SYSCALL_NUM],
+    /// when a thread of the process was first scheduled, in milliseconds
+    pub start_time: Option<usize>,
+    /// time spent in user mode by all threads, in microseconds
+    pub user_time: usize,
+    /// time spent in kernel mode by all threads, in microseconds
+    pub kernel_time: usize,
 }
 
 /// Locker of Process Control Block
@@ -105,11 +121,15 @@ impl ProcessControlBlock {
     pub fn inner_exclusive_access(&self) -> RefMut<'_, ProcessControlBlockInner> {
         self.inner.exclusive_access()
     }
+    /// inner_exclusive_access, or `None` if the inner is borrowed already
+    pub fn try_inner_exclusive_access(&self) -> Option<RefMut<'_, ProcessControlBlockInner>> {
+        self.inner.try_exclusive_access()
+    }
     /// new process from elf file
     pub fn new(elf_data: &[u8]) -> Arc<Self> {
         trace!("kernel: ProcessControlBlock::new");
         // memory_set with elf program headers/trampoline/trap context/user stack
-        let (memory_set, ustack_base, entry_point) = MemorySet::from_elf(elf_data);
+        let (memory_set, ustack_base, entry_point) = MemorySet::from_elf(elf_data).unwrap();
         // allocate a pid
         let pid_handle = pid_alloc();
         let process = Arc::new(Self {
@@ -129,6 +149,8 @@ impl ProcessControlBlock {
                         // 2 -> stderr
                         Some(Arc::new(Stdout)),
                     ],
+                    cwd: ROOT_INODE.clone(),
+                    cred: Credential::ROOT,
                     signals: SignalFlags::empty(),
                     tasks: Vec::new(),
                     task_res_allocator: RecycleAllocator::new(),
@@ -137,15 +159,17 @@ impl ProcessControlBlock {
                     condvar_list: Vec::new(),
                     deadlock_detect: false,
                     locker: ProcessLocker::new(),
+                    aio: None,
+                    syscall_times: [0; MAX_SYSCALL_NUM],
+                    start_time: None,
+                    user_time: 0,
+                    kernel_time: 0,
                 })
             },
         });
         // create a main thread, we should allocate ustack and trap_cx here
-        let task = Arc::new(TaskControlBlock::new(
-            Arc::clone(&process),
-            ustack_base,
-            true,
-        ));
+        let task =
+            Arc::new(TaskControlBlock::new(Arc::clone(&process), ustack_base, true).unwrap());
         // prepare trap_cx of main thread
         let task_inner = task.inner_exclusive_access();
         let trap_cx = task_inner.get_trap_cx();
@@ -170,51 +194,43 @@ impl ProcessControlBlock {
         process
     }
 
-    /// Only support processes with a single thread.
-    pub fn exec(self: &Arc<Self>, elf_data: &[u8], args: Vec<String>) {
+    /// Only support processes with a single thread. Return false and keep
+    /// the old image if the elf is invalid or no frame is left for the new one.
+    pub fn exec(self: &Arc<Self>, elf_data: &[u8], args: Vec<String>) -> bool {
         trace!("kernel: exec");
         assert_eq!(self.inner_exclusive_access().thread_count(), 1);
         // memory_set with elf program headers/trampoline/trap context/user stack
         trace!("kernel: exec .. MemorySet::from_elf");
-        let (memory_set, ustack_base, entry_point) = MemorySet::from_elf(elf_data);
+        let (mut memory_set, ustack_base, entry_point) = match MemorySet::from_elf(elf_data) {
+            Some(elf) => elf,
+            None => return false,
+        };
         let new_token = memory_set.token();
-        // substitute memory_set
-        trace!("kernel: exec .. substitute memory_set");
-        self.inner_exclusive_access().memory_set = memory_set;
-        // then we alloc user resource for main thread again
-        // since memory_set has been changed
+        // alloc user resource for main thread again in the new memory_set,
+        // before the old one is gone
         trace!("kernel: exec .. alloc user resource for main thread again");
         let task = self.inner_exclusive_access().get_task(0);
         let mut task_inner = task.inner_exclusive_access();
+        if !task_inner
+            .res
+            .as_ref()
+            .unwrap()
+            .map_user_res(&mut memory_set, ustack_base)
+        {
+            return false;
+        }
         task_inner.res.as_mut().unwrap().ustack_base = ustack_base;
-        task_inner.res.as_mut().unwrap().alloc_user_res();
+        // substitute memory_set, the old aio ring is gone with it
+        trace!("kernel: exec .. substitute memory_set");
+        let mut inner = self.inner_exclusive_access();
+        inner.memory_set = memory_set;
+        inner.aio = None;
+        drop(inner);
         task_inner.trap_cx_ppn = task_inner.res.as_mut().unwrap().trap_cx_ppn();
         // push arguments on user stack
         trace!("kernel: exec .. push arguments on user stack");
-        let mut user_sp = task_inner.res.as_mut().unwrap().ustack_top();
-        user_sp -= (args.len() + 1) * core::mem::size_of::<usize>();
-        let argv_base = user_sp;
-        let mut argv: Vec<_> = (0..=args.len())
-            .map(|arg| {
-                translated_refmut(
-                    new_token,
-                    (argv_base + arg * core::mem::size_of::<usize>()) as *mut usize,
-                )
-            })
-            .collect();
-        *argv[args.len()] = 0;
-        for i in 0..args.len() {
-            user_sp -= args[i].len() + 1;
-            *argv[i] = user_sp;
-            let mut p = user_sp;
-            for c in args[i].as_bytes() {
-                *translated_refmut(new_token, p as *mut u8) = *c;
-                p += 1;
-            }
-            *translated_refmut(new_token, p as *mut u8) = 0;
-        }
-        // make the user_sp aligned to 8B for k210 platform
-        user_sp -= user_sp % core::mem::size_of::<usize>();
+        let ustack_top = task_inner.res.as_ref().unwrap().ustack_top();
+        let (user_sp, argv_base) = push_args(new_token, ustack_top, &args);
         // initialize trap_cx
         trace!("kernel: exec .. initialize trap_cx");
         let mut trap_cx = TrapContext::app_init_context(
@@ -227,15 +243,17 @@ impl ProcessControlBlock {
         trap_cx.x[10] = args.len();
         trap_cx.x[11] = argv_base;
         *task_inner.get_trap_cx() = trap_cx;
+        true
     }
 
-    /// Only support processes with a single thread.
-    pub fn fork(self: &Arc<Self>) -> Arc<Self> {
+    /// Only support processes with a single thread. `None` if the frames of
+    /// the child cannot be allocated.
+    pub fn fork(self: &Arc<Self>) -> Option<Arc<Self>> {
         trace!("kernel: fork");
         let mut parent = self.inner_exclusive_access();
         assert_eq!(parent.thread_count(), 1);
-        // clone parent's memory_set completely including trampoline/ustacks/trap_cxs
-        let memory_set = MemorySet::from_existed_user(&parent.memory_set);
+        // share parent's memory_set copy-on-write, trap_cxs are copied at once
+        let memory_set = MemorySet::from_existed_user(&mut parent.memory_set)?;
         // alloc a pid
         let pid = pid_alloc();
         // copy fd table
@@ -258,6 +276,8 @@ impl ProcessControlBlock {
                     children: Vec::new(),
                     exit_code: 0,
                     fd_table: new_fd_table,
+                    cwd: parent.cwd.clone(),
+                    cred: parent.cred,
                     signals: SignalFlags::empty(),
                     tasks: Vec::new(),
                     task_res_allocator: RecycleAllocator::new(),
@@ -266,39 +286,132 @@ impl ProcessControlBlock {
                     condvar_list: Vec::new(),
                     deadlock_detect: false,
                     locker: ProcessLocker::new(),
+                    aio: None,
+                    syscall_times: [0; MAX_SYSCALL_NUM],
+                    start_time: None,
+                    user_time: 0,
+                    kernel_time: 0,
                 })
             },
         });
         // add child
         parent.children.push(Arc::clone(&child));
         // create main thread of child process
-        let task = Arc::new(TaskControlBlock::new(
-            Arc::clone(&child),
-            parent
-                .get_task(0)
-                .inner_exclusive_access()
-                .res
-                .as_ref()
-                .unwrap()
-                .ustack_base(),
-            // here we do not allocate trap_cx or ustack again
-            // but mention that we allocate a new kstack here
-            false,
-        ));
+        let ustack_base = parent
+            .get_task(0)
+            .inner_exclusive_access()
+            .res
+            .as_ref()
+            .unwrap()
+            .ustack_base();
+        // here we do not allocate trap_cx or ustack again, so this cannot fail,
+        // but mention that we allocate a new kstack here
+        let task = Arc::new(TaskControlBlock::new(Arc::clone(&child), ustack_base, false).unwrap());
         // attach task to child process
         let mut child_inner = child.inner_exclusive_access();
         child_inner.locker.init();
         child_inner.tasks.push(Some(Arc::clone(&task)));
         drop(child_inner);
         // modify kstack_top in trap_cx of this thread
-        let task_inner = task.inner_exclusive_access();
+        let mut task_inner = task.inner_exclusive_access();
         let trap_cx = task_inner.get_trap_cx();
         trap_cx.kernel_sp = task.kstack.get_top();
+        // inherit priority and pass of the forking thread
+        let current = current_task().unwrap();
+        let current_inner = current.inner_exclusive_access();
+        task_inner.priority = current_inner.priority;
+        task_inner.stride = current_inner.stride;
+        drop(current_inner);
         drop(task_inner);
         insert_into_pid2process(child.getpid(), Arc::clone(&child));
         // add this thread to scheduler
         add_task(task);
-        child
+        Some(child)
+    }
+    /// Create a child running `elf_data` with `args` in a fresh address space,
+    /// without copying the address space of `self` first. The child starts
+    /// with `fd_table` and its main thread inherits the calling thread's
+    /// priority and pass. `None` if the elf is invalid or no frame is left
+    /// for the child.
+    pub fn spawn(
+        self: &Arc<Self>,
+        elf_data: &[u8],
+        args: Vec<String>,
+        fd_table: Vec<Option<Arc<dyn File + Send + Sync>>>,
+    ) -> Option<Arc<Self>> {
+        trace!("kernel: spawn");
+        let parent = self.inner_exclusive_access();
+        let (cwd, cred) = (parent.cwd.clone(), parent.cred);
+        drop(parent);
+        let (memory_set, ustack_base, entry_point) = MemorySet::from_elf(elf_data)?;
+        let token = memory_set.token();
+        let pid = pid_alloc();
+        let child = Arc::new(Self {
+            pid,
+            inner: unsafe {
+                UPSafeCell::new(ProcessControlBlockInner {
+                    is_zombie: false,
+                    memory_set,
+                    parent: Some(Arc::downgrade(self)),
+                    children: Vec::new(),
+                    exit_code: 0,
+                    fd_table,
+                    cwd,
+                    cred,
+                    signals: SignalFlags::empty(),
+                    tasks: Vec::new(),
+                    task_res_allocator: RecycleAllocator::new(),
+                    mutex_list: Vec::new(),
+                    semaphore_list: Vec::new(),
+                    condvar_list: Vec::new(),
+                    deadlock_detect: false,
+                    locker: ProcessLocker::new(),
+                    aio: None,
+                    syscall_times: [0; MAX_SYSCALL_NUM],
+                    start_time: None,
+                    user_time: 0,
+                    kernel_time: 0,
+                })
+            },
+        });
+        // create a main thread with its own ustack and trap_cx
+        let task = Arc::new(TaskControlBlock::new(
+            Arc::clone(&child),
+            ustack_base,
+            true,
+        )?);
+        let mut task_inner = task.inner_exclusive_access();
+        let ustack_top = task_inner.res.as_ref().unwrap().ustack_top();
+        let (user_sp, argv_base) = push_args(token, ustack_top, &args);
+        let mut trap_cx = TrapContext::app_init_context(
+            entry_point,
+            user_sp,
+            KERNEL_SPACE.exclusive_access().token(),
+            task.kstack.get_top(),
+            trap_handler as usize,
+        );
+        trap_cx.x[10] = args.len();
+        trap_cx.x[11] = argv_base;
+        *task_inner.get_trap_cx() = trap_cx;
+        // inherit priority and pass of the spawning thread
+        let current = current_task().unwrap();
+        let current_inner = current.inner_exclusive_access();
+        task_inner.priority = current_inner.priority;
+        task_inner.stride = current_inner.stride;
+        drop(current_inner);
+        drop(task_inner);
+        // attach task to child process
+        let mut child_inner = child.inner_exclusive_access();
+        child_inner.locker.init();
+        child_inner.tasks.push(Some(Arc::clone(&task)));
+        drop(child_inner);
+        self.inner_exclusive_access()
+            .children
+            .push(Arc::clone(&child));
+        insert_into_pid2process(child.getpid(), Arc::clone(&child));
+        // add main thread to scheduler
+        add_task(task);
+        Some(child)
     }
     /// get pid
     pub fn getpid(&self) -> usize {
@@ -306,6 +419,39 @@ impl ProcessControlBlock {
     }
 }
 
+/// Push `args` as a null-terminated argv below `ustack_top` in the address
+/// space `token`, return the new user sp (aligned to 8B) and the argv base
+fn push_args(token: usize, ustack_top: usize, args: &[String]) -> (usize, usize) {
+    // the user stack is mapped writable
+    let user_byte = |p: usize| translated_refmut(token, p as *mut u8).unwrap();
+    let mut user_sp = ustack_top;
+    user_sp -= (args.len() + 1) * core::mem::size_of::<usize>();
+    let argv_base = user_sp;
+    let mut argv: Vec<_> = (0..=args.len())
+        .map(|arg| {
+            translated_refmut(
+                token,
+                (argv_base + arg * core::mem::size_of::<usize>()) as *mut usize,
+            )
+            .unwrap()
+        })
+        .collect();
+    *argv[args.len()] = 0;
+    for i in 0..args.len() {
+        user_sp -= args[i].len() + 1;
+        *argv[i] = user_sp;
+        let mut p = user_sp;
+        for c in args[i].as_bytes() {
+            *user_byte(p) = *c;
+            p += 1;
+        }
+        *user_byte(p) = 0;
+    }
+    // make the user_sp aligned to 8B for k210 platform
+    user_sp -= user_sp % core::mem::size_of::<usize>();
+    (user_sp, argv_base)
+}
+
 impl ProcessLocker {
     pub fn new() -> Self {
         Self {
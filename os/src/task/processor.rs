@@ -5,9 +5,10 @@
 //! and the replacement and transfer of control flow of different applications are executed.
 
 use super::__switch;
-use super::{fetch_task, TaskStatus};
+use super::{fetch_task, run_kernel_task, TaskStatus};
 use super::{ProcessControlBlock, TaskContext, TaskControlBlock};
 use crate::sync::UPSafeCell;
+use crate::timer::{check_timer, get_time_ms, get_time_us};
 use crate::trap::TrapContext;
 use alloc::sync::Arc;
 use lazy_static::*;
@@ -50,8 +51,13 @@ lazy_static! {
 
 ///The main part of process execution and scheduling
 ///Loop `fetch_task` to get the process that needs to run, and switch the process through `__switch`
+///
+///One ready kernel task is polled before each user task, on the idle control flow.
+///Timer interrupts are only taken from user tasks, so timers are checked here
+///while nothing is ready to run.
 pub fn run_tasks() {
     loop {
+        let ran_kernel_task = run_kernel_task();
         let mut processor = PROCESSOR.exclusive_access();
         if let Some(task) = fetch_task() {
             let idle_task_cx_ptr = processor.get_idle_task_cx_ptr();
@@ -59,8 +65,17 @@ pub fn run_tasks() {
             let mut task_inner = task.inner_exclusive_access();
             let next_task_cx_ptr = &task_inner.task_cx as *const TaskContext;
             task_inner.task_status = TaskStatus::Running;
+            task_inner.last_time = get_time_us();
             // release coming task_inner manually
             drop(task_inner);
+            let process = task.process.upgrade().unwrap();
+            process
+                .inner_exclusive_access()
+                .start_time
+                .get_or_insert_with(get_time_ms);
+            drop(process);
+            // keep the task to charge its kernel time once it switches back here
+            let running = Arc::clone(&task);
             // release coming task TCB manually
             processor.current = Some(task);
             // release processor manually
@@ -68,8 +83,10 @@ pub fn run_tasks() {
             unsafe {
                 __switch(idle_task_cx_ptr, next_task_cx_ptr);
             }
-        } else {
-            warn!("no tasks available in run_tasks");
+            running.account_time(false);
+        } else if !ran_kernel_task {
+            drop(processor);
+            check_timer();
         }
     }
 }
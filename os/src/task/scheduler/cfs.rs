@@ -0,0 +1,61 @@
+//! Fair scheduling: the ready task with the smallest virtual runtime runs
+//! next. Every tick charges the running task `BIG_STRIDE / priority` virtual
+//! runtime, and it is preempted once it is more than one default-priority
+//! tick ahead of the leftmost task.
+
+use super::Scheduler;
+use crate::config::{BIG_STRIDE, DEFAULT_PRIORITY};
+use crate::task::TaskControlBlock;
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+
+/// A vruntime-ordered fair scheduler
+pub struct CfsScheduler {
+    /// ready tasks keyed by (vruntime, arrival), the leftmost runs next
+    ready_queue: BTreeMap<(usize, usize), Arc<TaskControlBlock>>,
+    /// monotonic lower bound of vruntime among runnable tasks
+    min_vruntime: usize,
+    arrival: usize,
+}
+
+impl Scheduler for CfsScheduler {
+    fn new() -> Self {
+        Self {
+            ready_queue: BTreeMap::new(),
+            min_vruntime: 0,
+            arrival: 0,
+        }
+    }
+    fn add(&mut self, task: Arc<TaskControlBlock>) {
+        let mut task_inner = task.inner_exclusive_access();
+        // a task that slept must not bank its idle time against the others
+        task_inner.vruntime = task_inner.vruntime.max(self.min_vruntime);
+        let key = (task_inner.vruntime, self.arrival);
+        drop(task_inner);
+        self.arrival += 1;
+        self.ready_queue.insert(key, task);
+    }
+    fn fetch(&mut self) -> Option<Arc<TaskControlBlock>> {
+        let key = *self.ready_queue.keys().next()?;
+        self.min_vruntime = self.min_vruntime.max(key.0);
+        self.ready_queue.remove(&key)
+    }
+    fn remove(&mut self, task: &Arc<TaskControlBlock>) -> bool {
+        let key = self
+            .ready_queue
+            .iter()
+            .find(|(_, t)| Arc::as_ptr(t) == Arc::as_ptr(task))
+            .map(|(key, _)| *key);
+        key.and_then(|key| self.ready_queue.remove(&key)).is_some()
+    }
+    fn on_tick(&mut self, current: &Arc<TaskControlBlock>) -> bool {
+        let mut current_inner = current.inner_exclusive_access();
+        current_inner.vruntime += BIG_STRIDE / current_inner.priority;
+        match self.ready_queue.keys().next() {
+            Some(&(leftmost, _)) => {
+                current_inner.vruntime > leftmost + BIG_STRIDE / DEFAULT_PRIORITY
+            }
+            None => false,
+        }
+    }
+}
@@ -0,0 +1,40 @@
+//! Round robin: every task runs one tick in arrival order
+
+use super::Scheduler;
+use crate::task::TaskControlBlock;
+use alloc::collections::VecDeque;
+use alloc::sync::Arc;
+
+/// A simple FIFO scheduler
+pub struct FifoScheduler {
+    ready_queue: VecDeque<Arc<TaskControlBlock>>,
+}
+
+impl Scheduler for FifoScheduler {
+    fn new() -> Self {
+        Self {
+            ready_queue: VecDeque::new(),
+        }
+    }
+    fn add(&mut self, task: Arc<TaskControlBlock>) {
+        self.ready_queue.push_back(task);
+    }
+    fn fetch(&mut self) -> Option<Arc<TaskControlBlock>> {
+        self.ready_queue.pop_front()
+    }
+    fn remove(&mut self, task: &Arc<TaskControlBlock>) -> bool {
+        if let Some(idx) = self
+            .ready_queue
+            .iter()
+            .position(|t| Arc::as_ptr(t) == Arc::as_ptr(task))
+        {
+            self.ready_queue.remove(idx);
+            true
+        } else {
+            false
+        }
+    }
+    fn on_tick(&mut self, _current: &Arc<TaskControlBlock>) -> bool {
+        true
+    }
+}
@@ -0,0 +1,86 @@
+//! Multilevel feedback queue: a task that uses up the time slice of its level
+//! moves one level down, and every task is boosted back to the top level
+//! periodically so that none starves
+
+use super::Scheduler;
+use crate::task::TaskControlBlock;
+use alloc::collections::VecDeque;
+use alloc::sync::Arc;
+
+/// number of priority levels, 0 is the highest
+const MLFQ_LEVELS: usize = 3;
+/// every task goes back to level 0 after this many ticks
+const MLFQ_BOOST_TICKS: usize = 100;
+
+/// time slice of `level` in ticks
+fn time_slice(level: usize) -> usize {
+    1 << level
+}
+
+/// A multilevel feedback queue scheduler
+pub struct MlfqScheduler {
+    ready_queues: [VecDeque<Arc<TaskControlBlock>>; MLFQ_LEVELS],
+    ticks_since_boost: usize,
+}
+
+impl MlfqScheduler {
+    fn boost(&mut self, current: &Arc<TaskControlBlock>) {
+        for level in 1..MLFQ_LEVELS {
+            while let Some(task) = self.ready_queues[level].pop_front() {
+                self.ready_queues[0].push_back(task);
+            }
+        }
+        for task in self.ready_queues[0].iter().chain(Some(current)) {
+            let mut task_inner = task.inner_exclusive_access();
+            task_inner.level = 0;
+            task_inner.ticks = 0;
+        }
+    }
+}
+
+impl Scheduler for MlfqScheduler {
+    fn new() -> Self {
+        Self {
+            ready_queues: Default::default(),
+            ticks_since_boost: 0,
+        }
+    }
+    fn add(&mut self, task: Arc<TaskControlBlock>) {
+        let level = task.inner_exclusive_access().level;
+        self.ready_queues[level].push_back(task);
+    }
+    fn fetch(&mut self) -> Option<Arc<TaskControlBlock>> {
+        self.ready_queues
+            .iter_mut()
+            .find_map(|queue| queue.pop_front())
+    }
+    fn remove(&mut self, task: &Arc<TaskControlBlock>) -> bool {
+        for queue in self.ready_queues.iter_mut() {
+            if let Some(idx) = queue
+                .iter()
+                .position(|t| Arc::as_ptr(t) == Arc::as_ptr(task))
+            {
+                queue.remove(idx);
+                return true;
+            }
+        }
+        false
+    }
+    fn on_tick(&mut self, current: &Arc<TaskControlBlock>) -> bool {
+        self.ticks_since_boost += 1;
+        if self.ticks_since_boost >= MLFQ_BOOST_TICKS {
+            self.ticks_since_boost = 0;
+            self.boost(current);
+            return true;
+        }
+        let mut current_inner = current.inner_exclusive_access();
+        // ticks are kept across yields, so yielding early does not keep a task on top
+        current_inner.ticks += 1;
+        if current_inner.ticks < time_slice(current_inner.level) {
+            return false;
+        }
+        current_inner.ticks = 0;
+        current_inner.level = (current_inner.level + 1).min(MLFQ_LEVELS - 1);
+        true
+    }
+}
@@ -0,0 +1,68 @@
+//! Pluggable scheduling policies
+//!
+//! [`TaskManager`](super::manager::TaskManager) talks to the ready queue only
+//! through the [`Scheduler`] trait. The policy is chosen at build time with a
+//! cargo feature of the `os` crate:
+//!
+//! - `sched_stride` (default): stride scheduling by `priority`
+//! - `sched_fifo`: round robin
+//! - `sched_mlfq`: multilevel feedback queue
+//! - `sched_cfs`: fair scheduling ordered by weighted virtual runtime
+//!
+//! Exactly one of them must be enabled, so another policy is built with
+//! `--no-default-features --features sched_mlfq`, as `make SCHED=mlfq` does.
+
+use super::TaskControlBlock;
+use alloc::sync::Arc;
+
+#[cfg(not(any(
+    feature = "sched_stride",
+    feature = "sched_fifo",
+    feature = "sched_mlfq",
+    feature = "sched_cfs"
+)))]
+compile_error!("enable one of the features sched_stride, sched_fifo, sched_mlfq and sched_cfs");
+
+#[cfg(any(
+    all(feature = "sched_stride", feature = "sched_fifo"),
+    all(feature = "sched_stride", feature = "sched_mlfq"),
+    all(feature = "sched_stride", feature = "sched_cfs"),
+    all(feature = "sched_fifo", feature = "sched_mlfq"),
+    all(feature = "sched_fifo", feature = "sched_cfs"),
+    all(feature = "sched_mlfq", feature = "sched_cfs")
+))]
+compile_error!("enable only one of the features sched_stride, sched_fifo, sched_mlfq and sched_cfs, with --no-default-features for the latter three");
+
+#[cfg(feature = "sched_stride")]
+mod stride;
+#[cfg(feature = "sched_stride")]
+pub use stride::StrideScheduler as SelectedScheduler;
+
+#[cfg(feature = "sched_fifo")]
+mod fifo;
+#[cfg(feature = "sched_fifo")]
+pub use fifo::FifoScheduler as SelectedScheduler;
+
+#[cfg(feature = "sched_mlfq")]
+mod mlfq;
+#[cfg(feature = "sched_mlfq")]
+pub use mlfq::MlfqScheduler as SelectedScheduler;
+
+#[cfg(feature = "sched_cfs")]
+mod cfs;
+#[cfg(feature = "sched_cfs")]
+pub use cfs::CfsScheduler as SelectedScheduler;
+
+/// A scheduling policy over the ready tasks
+pub trait Scheduler {
+    /// create an empty ready queue
+    fn new() -> Self;
+    /// a task becomes ready
+    fn add(&mut self, task: Arc<TaskControlBlock>);
+    /// pick the next task to run and take it out of the ready queue
+    fn fetch(&mut self) -> Option<Arc<TaskControlBlock>>;
+    /// take `task` out of the ready queue, return whether it was there
+    fn remove(&mut self, task: &Arc<TaskControlBlock>) -> bool;
+    /// a timer tick hit the running task `current`, return whether to preempt it
+    fn on_tick(&mut self, current: &Arc<TaskControlBlock>) -> bool;
+}
@@ -0,0 +1,72 @@
+//! Stride scheduling: the ready task with the smallest pass runs next and
+//! advances its pass by `BIG_STRIDE / priority`
+
+use super::Scheduler;
+use crate::config::BIG_STRIDE;
+use crate::task::TaskControlBlock;
+use alloc::collections::BinaryHeap;
+use alloc::sync::Arc;
+use core::cmp::Ordering;
+
+/// A ready task together with its pass when it was queued
+struct StrideEntry {
+    pass: usize,
+    task: Arc<TaskControlBlock>,
+}
+
+impl PartialEq for StrideEntry {
+    fn eq(&self, other: &Self) -> bool {
+        self.pass == other.pass
+    }
+}
+impl Eq for StrideEntry {}
+impl PartialOrd for StrideEntry {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+impl Ord for StrideEntry {
+    /// BinaryHeap is a max-heap, so the smaller pass is the greater entry.
+    /// Comparing the wrapped difference keeps the order right after overflow.
+    fn cmp(&self, other: &Self) -> Ordering {
+        (other.pass.wrapping_sub(self.pass) as isize).cmp(&0)
+    }
+}
+
+/// A stride scheduler
+pub struct StrideScheduler {
+    ready_queue: BinaryHeap<StrideEntry>,
+}
+
+impl Scheduler for StrideScheduler {
+    fn new() -> Self {
+        Self {
+            ready_queue: BinaryHeap::new(),
+        }
+    }
+    fn add(&mut self, task: Arc<TaskControlBlock>) {
+        let pass = task.inner_exclusive_access().stride;
+        self.ready_queue.push(StrideEntry { pass, task });
+    }
+    fn fetch(&mut self) -> Option<Arc<TaskControlBlock>> {
+        let task = self.ready_queue.pop()?.task;
+        let mut task_inner = task.inner_exclusive_access();
+        task_inner.stride = task_inner
+            .stride
+            .wrapping_add(BIG_STRIDE / task_inner.priority);
+        drop(task_inner);
+        Some(task)
+    }
+    fn remove(&mut self, task: &Arc<TaskControlBlock>) -> bool {
+        let mut found = false;
+        self.ready_queue.retain(|entry| {
+            let same = Arc::as_ptr(&entry.task) == Arc::as_ptr(task);
+            found |= same;
+            !same
+        });
+        found
+    }
+    fn on_tick(&mut self, _current: &Arc<TaskControlBlock>) -> bool {
+        true
+    }
+}
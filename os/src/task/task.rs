@@ -2,6 +2,8 @@
 
 use super::id::TaskUserRes;
 use super::{kstack_alloc, KernelStack, ProcessControlBlock, TaskContext};
+use crate::config::DEFAULT_PRIORITY;
+use crate::timer::get_time_us;
 use crate::trap::TrapContext;
 use crate::{mm::PhysPageNum, sync::UPSafeCell};
 use alloc::sync::{Arc, Weak};
@@ -22,12 +24,33 @@ impl TaskControlBlock {
     pub fn inner_exclusive_access(&self) -> RefMut<'_, TaskControlBlockInner> {
         self.inner.exclusive_access()
     }
+    /// Get the mutable reference of the inner TCB, or `None` if it is borrowed already
+    pub fn try_inner_exclusive_access(&self) -> Option<RefMut<'_, TaskControlBlockInner>> {
+        self.inner.try_exclusive_access()
+    }
     /// Get the address of app's page table
     pub fn get_user_token(&self) -> usize {
         let process = self.process.upgrade().unwrap();
         let inner = process.inner_exclusive_access();
         inner.memory_set.token()
     }
+    /// Charge the time since `last_time` to the process as user or kernel time
+    pub fn account_time(&self, user: bool) {
+        let now = get_time_us();
+        let mut inner = self.inner_exclusive_access();
+        let elapsed = now - inner.last_time;
+        inner.last_time = now;
+        drop(inner);
+        // the process is gone if this was the last thread of an exited one
+        if let Some(process) = self.process.upgrade() {
+            let mut process_inner = process.inner_exclusive_access();
+            if user {
+                process_inner.user_time += elapsed;
+            } else {
+                process_inner.kernel_time += elapsed;
+            }
+        }
+    }
 }
 
 pub struct TaskControlBlockInner {
@@ -41,6 +64,20 @@ pub struct TaskControlBlockInner {
     pub task_status: TaskStatus,
     /// It is set when active exit or execution error occurs
     pub exit_code: Option<i32>,
+    /// Stride scheduling priority, at least 2
+    pub priority: usize,
+    /// Stride scheduling pass, advanced by `BIG_STRIDE / priority` every time the task is scheduled
+    pub stride: usize,
+    /// Weighted virtual runtime of the fair scheduler
+    pub vruntime: usize,
+    /// Queue level of the multilevel feedback queue, 0 is the highest
+    pub level: usize,
+    /// Ticks used of the time slice of `level`
+    pub ticks: usize,
+    /// When the task last switched between user and kernel mode or was scheduled, in microseconds
+    pub last_time: usize,
+    /// The task is inside a syscall, which may hold pointers to user frames
+    pub in_syscall: bool,
 }
 
 impl TaskControlBlockInner {
@@ -55,17 +92,18 @@ impl TaskControlBlockInner {
 }
 
 impl TaskControlBlock {
-    /// Create a new task
+    /// Create a new task, `None` if no frame is left for its user stack and
+    /// trap context
     pub fn new(
         process: Arc<ProcessControlBlock>,
         ustack_base: usize,
         alloc_user_res: bool,
-    ) -> Self {
-        let res = TaskUserRes::new(Arc::clone(&process), ustack_base, alloc_user_res);
+    ) -> Option<Self> {
+        let res = TaskUserRes::new(Arc::clone(&process), ustack_base, alloc_user_res)?;
         let trap_cx_ppn = res.trap_cx_ppn();
         let kstack = kstack_alloc();
         let kstack_top = kstack.get_top();
-        Self {
+        Some(Self {
             process: Arc::downgrade(&process),
             kstack,
             inner: unsafe {
@@ -75,19 +113,29 @@ impl TaskControlBlock {
                     task_cx: TaskContext::goto_trap_return(kstack_top),
                     task_status: TaskStatus::Ready,
                     exit_code: None,
+                    priority: DEFAULT_PRIORITY,
+                    stride: 0,
+                    vruntime: 0,
+                    level: 0,
+                    ticks: 0,
+                    last_time: 0,
+                    in_syscall: false,
                 })
             },
-        }
+        })
     }
 }
 
+#[repr(C)]
 #[derive(Copy, Clone, PartialEq)]
 /// The execution status of the current process
+///
+/// The values match `TaskStatus` of user_lib, which is reported by `sys_task_info`
 pub enum TaskStatus {
     /// ready to run
-    Ready,
+    Ready = 1,
     /// running
-    Running,
+    Running = 2,
     /// blocked
-    Blocked,
+    Blocked = 4,
 }
@@ -8,6 +8,9 @@ use crate::sync::UPSafeCell;
 use crate::task::{current_task, wakeup_task, TaskControlBlock};
 use alloc::collections::BinaryHeap;
 use alloc::sync::Arc;
+use core::future::Future;
+use core::pin::Pin;
+use core::task::{Context, Poll, Waker};
 use lazy_static::*;
 use riscv::register::time;
 /// The number of ticks per second
@@ -38,12 +41,20 @@ pub fn set_next_trigger() {
     set_timer(get_time() + CLOCK_FREQ / TICKS_PER_SEC);
 }
 
+/// What a timer wakes up when it expires
+pub enum TimerWaiter {
+    /// a blocked user task
+    Task(Arc<TaskControlBlock>),
+    /// a kernel task waiting on a [`Sleep`] future
+    Future(Waker),
+}
+
 /// condvar for timer
 pub struct TimerCondVar {
     /// The time when the timer expires, in milliseconds
     pub expire_ms: usize,
     /// The task to be woken up when the timer expires
-    pub task: Arc<TaskControlBlock>,
+    pub waiter: TimerWaiter,
 }
 
 impl PartialEq for TimerCondVar {
@@ -79,7 +90,10 @@ pub fn add_timer(expire_ms: usize, task: Arc<TaskControlBlock>) {
         current_task().unwrap().process.upgrade().unwrap().getpid()
     );
     let mut timers = TIMERS.exclusive_access();
-    timers.push(TimerCondVar { expire_ms, task });
+    timers.push(TimerCondVar {
+        expire_ms,
+        waiter: TimerWaiter::Task(task),
+    });
 }
 
 /// Remove a timer
@@ -89,8 +103,9 @@ pub fn remove_timer(task: Arc<TaskControlBlock>) {
     let mut timers = TIMERS.exclusive_access();
     let mut temp = BinaryHeap::<TimerCondVar>::new();
     for condvar in timers.drain() {
-        if Arc::as_ptr(&task) != Arc::as_ptr(&condvar.task) {
-            temp.push(condvar);
+        match &condvar.waiter {
+            TimerWaiter::Task(t) if Arc::as_ptr(&task) == Arc::as_ptr(t) => {}
+            _ => temp.push(condvar),
         }
     }
     timers.clear();
@@ -100,18 +115,50 @@ pub fn remove_timer(task: Arc<TaskControlBlock>) {
 
 /// Check if the timer has expired
 pub fn check_timer() {
-    trace!(
-        "kernel:pid[{}] check_timer",
-        current_task().unwrap().process.upgrade().unwrap().getpid()
-    );
+    // also called on the idle control flow, where there is no current task
+    trace!("kernel: check_timer");
     let current_ms = get_time_ms();
     let mut timers = TIMERS.exclusive_access();
     while let Some(timer) = timers.peek() {
         if timer.expire_ms <= current_ms {
-            wakeup_task(Arc::clone(&timer.task));
-            timers.pop();
+            match timers.pop().unwrap().waiter {
+                TimerWaiter::Task(task) => wakeup_task(task),
+                TimerWaiter::Future(waker) => waker.wake(),
+            }
         } else {
             break;
         }
     }
 }
+
+/// Future returned by [`sleep_ms`]
+pub struct Sleep {
+    expire_ms: usize,
+    registered: bool,
+}
+
+impl Future for Sleep {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        if get_time_ms() >= self.expire_ms {
+            return Poll::Ready(());
+        }
+        if !self.registered {
+            TIMERS.exclusive_access().push(TimerCondVar {
+                expire_ms: self.expire_ms,
+                waiter: TimerWaiter::Future(cx.waker().clone()),
+            });
+            self.registered = true;
+        }
+        Poll::Pending
+    }
+}
+
+/// Let a kernel task wait for `ms` milliseconds without blocking anyone else
+pub fn sleep_ms(ms: usize) -> Sleep {
+    Sleep {
+        expire_ms: get_time_ms() + ms,
+        registered: false,
+    }
+}
@@ -15,10 +15,12 @@
 mod context;
 
 use crate::config::TRAMPOLINE;
-use crate::syscall::syscall;
+use crate::mm::MapPermission;
+use crate::syscall::{aio_poll_current, syscall};
 use crate::task::{
-    check_signals_of_current, current_add_signal, current_trap_cx, current_trap_cx_user_va,
-    current_user_token, exit_current_and_run_next, suspend_current_and_run_next, SignalFlags,
+    check_signals_of_current, current_add_signal, current_handle_page_fault, current_task,
+    current_trap_cx, current_trap_cx_user_va, current_user_token, exit_current_and_run_next,
+    reclaim_frames, scheduler_tick, suspend_current_and_run_next, SignalFlags,
 };
 use crate::timer::{check_timer, set_next_trigger};
 use core::arch::{asm, global_asm};
@@ -61,6 +63,8 @@ pub fn enable_timer_interrupt() {
 #[no_mangle]
 pub fn trap_handler() -> ! {
     set_kernel_trap_entry();
+    current_task().unwrap().account_time(true);
+    reclaim_frames();
     let scause = scause::read();
     let stval = stval::read();
     // trace!("into {:?}", scause.cause());
@@ -69,12 +73,23 @@ pub fn trap_handler() -> ! {
             // jump to next instruction anyway
             let mut cx = current_trap_cx();
             cx.sepc += 4;
+            // keep the frames of this process resident while the syscall may
+            // hold pointers to them
+            current_task().unwrap().inner_exclusive_access().in_syscall = true;
             // get system call return value
-            let result = syscall(cx.x[17], [cx.x[10], cx.x[11], cx.x[12], cx.x[13]]);
+            let args = [cx.x[10], cx.x[11], cx.x[12], cx.x[13], cx.x[14], cx.x[15]];
+            let result = syscall(cx.x[17], args);
+            current_task().unwrap().inner_exclusive_access().in_syscall = false;
             // cx is changed during sys_exec, so we have to call it again
             cx = current_trap_cx();
             cx.x[10] = result as usize;
         }
+        Trap::Exception(Exception::LoadPageFault)
+            if current_handle_page_fault(current_user_token(), stval.into(), MapPermission::R) => {}
+        Trap::Exception(Exception::StorePageFault)
+            if current_handle_page_fault(current_user_token(), stval.into(), MapPermission::W) => {}
+        Trap::Exception(Exception::InstructionPageFault)
+            if current_handle_page_fault(current_user_token(), stval.into(), MapPermission::X) => {}
         Trap::Exception(Exception::StoreFault)
         | Trap::Exception(Exception::StorePageFault)
         | Trap::Exception(Exception::InstructionFault)
@@ -95,7 +110,10 @@ pub fn trap_handler() -> ! {
         Trap::Interrupt(Interrupt::SupervisorTimer) => {
             set_next_trigger();
             check_timer();
-            suspend_current_and_run_next();
+            aio_poll_current();
+            if scheduler_tick() {
+                suspend_current_and_run_next();
+            }
         }
         _ => {
             panic!(
@@ -118,6 +136,7 @@ pub fn trap_handler() -> ! {
 pub fn trap_return() -> ! {
     //disable_supervisor_interrupt();
     set_user_trap_entry();
+    current_task().unwrap().account_time(false);
     let trap_cx_user_va = current_trap_cx_user_va();
     let user_satp = current_user_token();
     extern "C" {
@@ -0,0 +1,326 @@
+//! Async syscalls on top of the kernel's submission/completion ring.
+//!
+//! An operation is queued in the ring the first time its future is polled and
+//! handed to the kernel by the next `sys_aio_enter`, which [`crate::rt`] issues
+//! every round and whenever it parks. Completions are reaped by the runtime
+//! and wake the matching future. Dropping an unfinished future cancels the
+//! operation synchronously, so the kernel never touches its buffer afterwards.
+
+use crate::{sys_aio_enter, sys_aio_setup};
+use alloc::{collections::BTreeMap, vec::Vec};
+use core::{
+    future::Future,
+    marker::PhantomData,
+    pin::Pin,
+    ptr::{addr_of, addr_of_mut},
+    task::{Context, Poll, Waker},
+};
+use lazy_static::*;
+use spin::Mutex;
+
+const SQ_ENTRIES: usize = 32;
+const CQ_ENTRIES: usize = 64;
+
+const OP_READ: u32 = 1;
+const OP_WRITE: u32 = 2;
+const OP_SLEEP: u32 = 3;
+const OP_WAITPID: u32 = 4;
+const OP_CANCEL: u32 = 5;
+
+/// Result of an operation cancelled before it completed
+pub const ECANCELED: isize = -125;
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct Sqe {
+    opcode: u32,
+    flags: u32,
+    fd: isize,
+    addr: usize,
+    len: usize,
+    user_data: u64,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct Cqe {
+    user_data: u64,
+    result: isize,
+}
+
+#[repr(C, align(4096))]
+struct Ring {
+    sq_head: u32,
+    sq_tail: u32,
+    cq_head: u32,
+    cq_tail: u32,
+    sqes: [Sqe; SQ_ENTRIES],
+    cqes: [Cqe; CQ_ENTRIES],
+}
+
+const EMPTY_SQE: Sqe = Sqe {
+    opcode: 0,
+    flags: 0,
+    fd: 0,
+    addr: 0,
+    len: 0,
+    user_data: 0,
+};
+
+const EMPTY_CQE: Cqe = Cqe {
+    user_data: 0,
+    result: 0,
+};
+
+/// The kernel writes completions into this page behind our back
+static mut RING: Ring = Ring {
+    sq_head: 0,
+    sq_tail: 0,
+    cq_head: 0,
+    cq_tail: 0,
+    sqes: [EMPTY_SQE; SQ_ENTRIES],
+    cqes: [EMPTY_CQE; CQ_ENTRIES],
+};
+
+fn ring() -> *mut Ring {
+    unsafe { addr_of_mut!(RING) }
+}
+
+fn unsubmitted() -> usize {
+    unsafe {
+        let ring = ring();
+        let head = addr_of!((*ring).sq_head).read_volatile();
+        let tail = addr_of!((*ring).sq_tail).read_volatile();
+        tail.wrapping_sub(head) as usize
+    }
+}
+
+enum OpState {
+    Waiting(Option<Waker>),
+    Done(isize),
+}
+
+struct Driver {
+    registered: bool,
+    next_id: u64,
+    ops: BTreeMap<u64, OpState>,
+}
+
+lazy_static! {
+    static ref DRIVER: Mutex<Driver> = Mutex::new(Driver {
+        registered: false,
+        // user_data 0 marks completions nobody waits for
+        next_id: 1,
+        ops: BTreeMap::new(),
+    });
+}
+
+impl Driver {
+    /// queue `sqe` in the submission queue, false if the kernel has no ring
+    fn push(&mut self, sqe: Sqe) -> bool {
+        if !self.registered {
+            if sys_aio_setup(ring() as usize) != 0 {
+                return false;
+            }
+            self.registered = true;
+        }
+        if unsubmitted() == SQ_ENTRIES && sys_aio_enter(SQ_ENTRIES, 0, 0) < 0 {
+            return false;
+        }
+        unsafe {
+            let ring = ring();
+            let tail = addr_of!((*ring).sq_tail).read_volatile();
+            addr_of_mut!((*ring).sqes[tail as usize % SQ_ENTRIES]).write_volatile(sqe);
+            addr_of_mut!((*ring).sq_tail).write_volatile(tail.wrapping_add(1));
+        }
+        true
+    }
+
+    /// move completions out of the completion queue, return the wakers to wake
+    fn reap(&mut self) -> Vec<Waker> {
+        let mut wakers = Vec::new();
+        unsafe {
+            let ring = ring();
+            let mut head = addr_of!((*ring).cq_head).read_volatile();
+            while head != addr_of!((*ring).cq_tail).read_volatile() {
+                let cqe = addr_of!((*ring).cqes[head as usize % CQ_ENTRIES]).read_volatile();
+                head = head.wrapping_add(1);
+                addr_of_mut!((*ring).cq_head).write_volatile(head);
+                if let Some(state) = self.ops.get_mut(&cqe.user_data) {
+                    if let OpState::Waiting(waker) = state {
+                        wakers.extend(waker.take());
+                    }
+                    *state = OpState::Done(cqe.result);
+                }
+            }
+        }
+        wakers
+    }
+
+    /// the kernel lost our ring (e.g. we are a forked child), fail everything
+    fn fail_all(&mut self) -> Vec<Waker> {
+        self.registered = false;
+        let mut wakers = Vec::new();
+        for state in self.ops.values_mut() {
+            if let OpState::Waiting(waker) = state {
+                wakers.extend(waker.take());
+                *state = OpState::Done(-1);
+            }
+        }
+        wakers
+    }
+
+    fn enter(&mut self, min_complete: usize, timeout_ms: isize) -> Vec<Waker> {
+        if sys_aio_enter(unsubmitted(), min_complete, timeout_ms) < 0 {
+            self.fail_all()
+        } else {
+            self.reap()
+        }
+    }
+}
+
+/// Whether some operation is still waiting for the kernel
+pub(crate) fn in_flight() -> bool {
+    DRIVER
+        .lock()
+        .ops
+        .values()
+        .any(|state| matches!(state, OpState::Waiting(_)))
+}
+
+/// Submit queued operations and reap completions without waiting
+pub(crate) fn poll() {
+    let mut driver = DRIVER.lock();
+    if !driver.registered {
+        return;
+    }
+    let wakers = if unsubmitted() > 0 {
+        driver.enter(0, 0)
+    } else {
+        driver.reap()
+    };
+    drop(driver);
+    wakers.into_iter().for_each(Waker::wake);
+}
+
+/// Wait in the kernel for a completion, at most `timeout_ms` (negative waits forever)
+pub(crate) fn wait(timeout_ms: isize) {
+    let wakers = DRIVER.lock().enter(1, timeout_ms);
+    wakers.into_iter().for_each(Waker::wake);
+}
+
+/// Future of one async syscall, resolving to what the synchronous syscall returns
+pub struct Op<'a> {
+    sqe: Sqe,
+    id: Option<u64>,
+    done: bool,
+    _buf: PhantomData<&'a ()>,
+}
+
+impl Op<'_> {
+    fn new(opcode: u32, fd: isize, addr: usize, len: usize) -> Self {
+        Self {
+            sqe: Sqe {
+                opcode,
+                flags: 0,
+                fd,
+                addr,
+                len,
+                user_data: 0,
+            },
+            id: None,
+            done: false,
+            _buf: PhantomData,
+        }
+    }
+}
+
+impl Future for Op<'_> {
+    type Output = isize;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<isize> {
+        if self.done {
+            return Poll::Ready(-1);
+        }
+        let mut driver = DRIVER.lock();
+        let id = match self.id {
+            Some(id) => id,
+            None => {
+                let id = driver.next_id;
+                driver.next_id += 1;
+                let mut sqe = self.sqe;
+                sqe.user_data = id;
+                if !driver.push(sqe) {
+                    self.done = true;
+                    return Poll::Ready(-1);
+                }
+                driver
+                    .ops
+                    .insert(id, OpState::Waiting(Some(cx.waker().clone())));
+                self.id = Some(id);
+                return Poll::Pending;
+            }
+        };
+        match driver.ops.get_mut(&id) {
+            Some(OpState::Waiting(waker)) => {
+                *waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+            Some(OpState::Done(result)) => {
+                let result = *result;
+                driver.ops.remove(&id);
+                drop(driver);
+                self.id = None;
+                self.done = true;
+                Poll::Ready(result)
+            }
+            None => {
+                drop(driver);
+                self.id = None;
+                self.done = true;
+                Poll::Ready(-1)
+            }
+        }
+    }
+}
+
+impl Drop for Op<'_> {
+    fn drop(&mut self) {
+        let id = match self.id.take() {
+            Some(id) => id,
+            None => return,
+        };
+        let mut driver = DRIVER.lock();
+        if let Some(OpState::Waiting(_)) = driver.ops.remove(&id) {
+            let mut cancel = EMPTY_SQE;
+            cancel.opcode = OP_CANCEL;
+            cancel.addr = id as usize;
+            if driver.push(cancel) {
+                // completions of the cancelled op are ignored when reaped
+                let wakers = driver.enter(0, 0);
+                drop(driver);
+                wakers.into_iter().for_each(Waker::wake);
+            }
+        }
+    }
+}
+
+/// Read from `fd` into `buf`
+pub fn read(fd: usize, buf: &mut [u8]) -> Op<'_> {
+    Op::new(OP_READ, fd as isize, buf.as_mut_ptr() as usize, buf.len())
+}
+
+/// Write `buf` to `fd`
+pub fn write(fd: usize, buf: &[u8]) -> Op<'_> {
+    Op::new(OP_WRITE, fd as isize, buf.as_ptr() as usize, buf.len())
+}
+
+/// Sleep `ms` milliseconds in the kernel
+pub fn sleep(ms: usize) -> Op<'static> {
+    Op::new(OP_SLEEP, 0, 0, ms)
+}
+
+/// Wait for child `pid` (-1 for any child) to exit
+pub fn waitpid(pid: isize, exit_code: &mut i32) -> Op<'_> {
+    Op::new(OP_WAITPID, pid, exit_code as *mut i32 as usize, 0)
+}
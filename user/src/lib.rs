@@ -3,9 +3,11 @@
 #![feature(panic_info_message)]
 #![feature(alloc_error_handler)]
 
+pub mod aio;
 #[macro_use]
 pub mod console;
 mod lang_items;
+pub mod rt;
 mod syscall;
 
 extern crate alloc;
@@ -13,17 +15,59 @@ extern crate core;
 #[macro_use]
 extern crate bitflags;
 
+use alloc::string::String;
 use alloc::vec::Vec;
 use buddy_system_allocator::LockedHeap;
 pub use console::{flush, STDIN, STDOUT};
+use core::alloc::{GlobalAlloc, Layout};
+use core::sync::atomic::{AtomicUsize, Ordering};
 pub use syscall::*;
 
 const USER_HEAP_SIZE: usize = 16384;
+/// once `HEAP_SPACE` is used up, the heap grows by `mmap`ing from here upwards
+const HEAP_GROW_BASE: usize = 0x1_0000_0000;
+/// the least the heap grows by at a time
+const HEAP_GROW_SIZE: usize = 0x1_0000;
 
 static mut HEAP_SPACE: [u8; USER_HEAP_SIZE] = [0; USER_HEAP_SIZE];
 
 #[global_allocator]
-static HEAP: LockedHeap = LockedHeap::empty();
+static HEAP: GrowingHeap = GrowingHeap {
+    heap: LockedHeap::empty(),
+    brk: AtomicUsize::new(HEAP_GROW_BASE),
+};
+
+/// A `LockedHeap` that maps more memory when it runs out
+struct GrowingHeap {
+    heap: LockedHeap,
+    /// end of the memory mapped for the heap so far
+    brk: AtomicUsize,
+}
+
+unsafe impl GlobalAlloc for GrowingHeap {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = self.heap.alloc(layout);
+        if !ptr.is_null() {
+            return ptr;
+        }
+        // twice the block size always holds one suitably aligned block
+        let len = (layout.size().max(layout.align()).next_power_of_two() * 2).max(HEAP_GROW_SIZE);
+        let start = self.brk.fetch_add(len, Ordering::Relaxed);
+        if mmap(start, len, 0b011) != 0 {
+            // give the range back, unless another thread took memory after it
+            let _ =
+                self.brk
+                    .compare_exchange(start + len, start, Ordering::Relaxed, Ordering::Relaxed);
+            return core::ptr::null_mut();
+        }
+        self.heap.lock().add_to_heap(start, start + len);
+        self.heap.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        self.heap.dealloc(ptr, layout)
+    }
+}
 
 #[alloc_error_handler]
 pub fn handle_alloc_error(layout: core::alloc::Layout) -> ! {
@@ -49,7 +93,8 @@ fn clear_bss() {
 pub extern "C" fn _start(argc: usize, argv: usize) -> ! {
     clear_bss();
     unsafe {
-        HEAP.lock()
+        HEAP.heap
+            .lock()
             .init(HEAP_SPACE.as_ptr() as usize, USER_HEAP_SIZE);
     }
     let mut v: Vec<&'static str> = Vec::new();
@@ -82,6 +127,9 @@ bitflags! {
         const RDWR = 1 << 1;
         const CREATE = 1 << 9;
         const TRUNC = 1 << 10;
+        const EXCL = 1 << 7;
+        const APPEND = 1 << 11;
+        const DIRECTORY = 1 << 16;
     }
 }
 
@@ -98,12 +146,14 @@ impl TimeVal {
     }
 }
 
+#[repr(C)]
 #[derive(Copy, Clone, PartialEq, Debug)]
 pub enum TaskStatus {
     UnInit,
     Ready,
     Running,
     Exited,
+    Blocked,
 }
 
 #[derive(Copy, Clone, Debug)]
@@ -114,11 +164,17 @@ pub struct SyscallInfo {
 
 const MAX_SYSCALL_NUM: usize = 500;
 
+#[repr(C)]
 #[derive(Debug)]
 pub struct TaskInfo {
     pub status: TaskStatus,
     pub syscall_times: [u32; MAX_SYSCALL_NUM],
+    /// milliseconds since the process was first scheduled
     pub time: usize,
+    /// time spent in user mode, in microseconds
+    pub user_time: usize,
+    /// time spent in kernel mode, in microseconds
+    pub kernel_time: usize,
 }
 
 impl TaskInfo {
@@ -127,6 +183,32 @@ impl TaskInfo {
             status: TaskStatus::UnInit,
             syscall_times: [0; MAX_SYSCALL_NUM],
             time: 0,
+            user_time: 0,
+            kernel_time: 0,
+        }
+    }
+}
+
+/// A file action run in the child by [`spawn_with`], like posix_spawn's
+#[repr(C)]
+#[derive(Copy, Clone, Debug)]
+pub struct SpawnAction {
+    op: usize,
+    fd: usize,
+    new_fd: usize,
+}
+
+impl SpawnAction {
+    /// Make `new_fd` of the child a copy of `fd`
+    pub fn dup2(fd: usize, new_fd: usize) -> Self {
+        Self { op: 1, fd, new_fd }
+    }
+    /// Close `fd` in the child
+    pub fn close(fd: usize) -> Self {
+        Self {
+            op: 2,
+            fd,
+            new_fd: 0,
         }
     }
 }
@@ -142,8 +224,18 @@ pub struct Stat {
     pub mode: StatMode,
     /// number of hard links
     pub nlink: u32,
+    /// user id of the owner
+    pub uid: u32,
+    /// group id of the owner
+    pub gid: u32,
+    /// last access time, in seconds since boot
+    pub atime: u64,
+    /// last modification time, in seconds since boot
+    pub mtime: u64,
+    /// last status change time, in seconds since boot
+    pub ctime: u64,
     /// unused pad
-    pad: [u64; 7],
+    pad: [u64; 3],
 }
 
 impl Stat {
@@ -153,7 +245,12 @@ impl Stat {
             ino: 0,
             mode: StatMode::NULL,
             nlink: 0,
-            pad: [0; 7],
+            uid: 0,
+            gid: 0,
+            atime: 0,
+            mtime: 0,
+            ctime: 0,
+            pad: [0; 3],
         }
     }
 }
@@ -171,6 +268,8 @@ bitflags! {
         const DIR   = 0o040000;
         /// ordinary regular file
         const FILE  = 0o100000;
+        /// permission bits of owner, group and others
+        const PERM  = 0o777;
     }
 }
 
@@ -195,18 +294,177 @@ pub fn write(fd: usize, buf: &[u8]) -> isize {
     sys_write(fd, buf)
 }
 
+/// `whence` of [`lseek`], the offset is from the start of the file
+pub const SEEK_SET: usize = 0;
+/// `whence` of [`lseek`], the offset is from the current offset
+pub const SEEK_CUR: usize = 1;
+/// `whence` of [`lseek`], the offset is from the end of the file
+pub const SEEK_END: usize = 2;
+
+/// Move the offset of the file of `fd`, return the new offset
+pub fn lseek(fd: usize, offset: isize, whence: usize) -> isize {
+    sys_lseek(fd, offset, whence)
+}
+
+/// Read from `offset` of the file of `fd` without moving its offset
+pub fn pread(fd: usize, buf: &mut [u8], offset: usize) -> isize {
+    sys_pread(fd, buf, offset)
+}
+
+/// Write to `offset` of the file of `fd` without moving its offset
+pub fn pwrite(fd: usize, buf: &[u8], offset: usize) -> isize {
+    sys_pwrite(fd, buf, offset)
+}
+
+/// Cut the file of `fd` down to `len` bytes, or extend it with zeros
+pub fn ftruncate(fd: usize, len: usize) -> isize {
+    sys_ftruncate(fd, len)
+}
+
+/// `d_type` of a directory in a `linux_dirent64` record
+const DT_DIR: u8 = 4;
+/// size of the fields before the name in a `linux_dirent64` record
+const DIRENT64_HEADER_SZ: usize = 19;
+
+/// An entry of a directory read by [`read_dir`]
+#[derive(Debug)]
+pub struct DirEntry {
+    /// inode number
+    pub ino: u64,
+    /// is the entry a directory?
+    pub is_dir: bool,
+    /// name of the entry
+    pub name: String,
+}
+
+/// The entries of an open directory, read with `getdents64` a buffer at a time
+pub struct ReadDir {
+    fd: usize,
+    buffer: [u8; 512],
+    /// the next record in `buffer`
+    pos: usize,
+    /// the bytes of records in `buffer`
+    len: usize,
+}
+
+impl Iterator for ReadDir {
+    type Item = DirEntry;
+    fn next(&mut self) -> Option<DirEntry> {
+        if self.pos == self.len {
+            let len = sys_getdents64(self.fd, &mut self.buffer);
+            if len <= 0 {
+                return None;
+            }
+            self.pos = 0;
+            self.len = len as usize;
+        }
+        let record = &self.buffer[self.pos..];
+        let reclen = u16::from_le_bytes([record[16], record[17]]) as usize;
+        let name = &record[DIRENT64_HEADER_SZ..reclen];
+        let name_len = name.iter().position(|&byte| byte == 0).unwrap();
+        let mut ino = [0u8; 8];
+        ino.copy_from_slice(&record[..8]);
+        self.pos += reclen;
+        Some(DirEntry {
+            ino: u64::from_le_bytes(ino),
+            is_dir: record[18] == DT_DIR,
+            name: String::from(core::str::from_utf8(&name[..name_len]).unwrap()),
+        })
+    }
+}
+
+impl Drop for ReadDir {
+    fn drop(&mut self) {
+        close(self.fd);
+    }
+}
+
+/// Open the directory at `path` to iterate over its entries, "." and ".." included
+pub fn read_dir(path: &str) -> Option<ReadDir> {
+    let fd = open(path, OpenFlags::RDONLY | OpenFlags::DIRECTORY);
+    if fd < 0 {
+        return None;
+    }
+    Some(ReadDir {
+        fd: fd as usize,
+        buffer: [0; 512],
+        pos: 0,
+        len: 0,
+    })
+}
+
 pub fn link(old_path: &str, new_path: &str) -> isize {
     sys_linkat(AT_FDCWD as usize, old_path, AT_FDCWD as usize, new_path, 0)
 }
 
+/// `flags` of [`rename_noreplace`] failing if the new path exists
+const RENAME_NOREPLACE: usize = 1;
+
+/// Move a file or directory to `new_path`, replacing a file or an empty
+/// directory there in one step
+pub fn rename(old_path: &str, new_path: &str) -> isize {
+    sys_renameat2(AT_FDCWD as usize, old_path, AT_FDCWD as usize, new_path, 0)
+}
+
+/// Move a file or directory to `new_path`, fail if something is there
+pub fn rename_noreplace(old_path: &str, new_path: &str) -> isize {
+    sys_renameat2(
+        AT_FDCWD as usize,
+        old_path,
+        AT_FDCWD as usize,
+        new_path,
+        RENAME_NOREPLACE,
+    )
+}
+
+/// Mount a new file system of type `fstype` on the directory `target`,
+/// only "ramfs" is known and `source` is ignored
+pub fn mount(source: &str, target: &str, fstype: &str) -> isize {
+    sys_mount(source, target, fstype, 0)
+}
+
+/// Unmount the file system mounted on `target`
+pub fn umount(target: &str) -> isize {
+    sys_umount2(target, 0)
+}
+
 pub fn unlink(path: &str) -> isize {
     sys_unlinkat(AT_FDCWD as usize, path, 0)
 }
 
+const AT_REMOVEDIR: usize = 0x200;
+
+pub fn mkdir(path: &str) -> isize {
+    sys_mkdirat(AT_FDCWD as usize, path, 0)
+}
+
+pub fn rmdir(path: &str) -> isize {
+    sys_unlinkat(AT_FDCWD as usize, path, AT_REMOVEDIR)
+}
+
+pub fn chdir(path: &str) -> isize {
+    sys_chdir(path)
+}
+
+/// Write the null-terminated cwd into `buf`, return its length with the terminator
+pub fn getcwd(buf: &mut [u8]) -> isize {
+    sys_getcwd(buf)
+}
+
 pub fn fstat(fd: usize, st: &mut Stat) -> isize {
     sys_fstat(fd, st)
 }
 
+/// Write all modified file system blocks back to the disk
+pub fn sync() -> isize {
+    sys_sync()
+}
+
+/// Write the file of `fd` back to the disk, fail if it is not backed by one
+pub fn fsync(fd: usize) -> isize {
+    sys_fsync(fd)
+}
+
 pub fn mail_read(buf: &mut [u8]) -> isize {
     sys_mail_read(buf)
 }
@@ -236,6 +494,24 @@ pub fn getpid() -> isize {
     sys_getpid()
 }
 
+pub fn getuid() -> isize {
+    sys_getuid()
+}
+
+pub fn getgid() -> isize {
+    sys_getgid()
+}
+
+/// Become user `uid`, only the superuser may change users
+pub fn setuid(uid: u32) -> isize {
+    sys_setuid(uid)
+}
+
+/// Join group `gid`, only the superuser may change groups
+pub fn setgid(gid: u32) -> isize {
+    sys_setgid(gid)
+}
+
 pub fn fork() -> isize {
     sys_fork()
 }
@@ -297,7 +573,18 @@ pub fn sbrk(size: i32) -> isize {
 }
 
 pub fn spawn(path: &str) -> isize {
-    sys_spawn(path)
+    sys_spawn(path, core::ptr::null(), &[])
+}
+
+/// Start the program at `path` with `args` (null-terminated like `exec`'s) in
+/// a new child, running `actions` on the child's copy of our fd table first
+pub fn spawn_with(path: &str, args: &[*const u8], actions: &[SpawnAction]) -> isize {
+    let args = if args.is_empty() {
+        core::ptr::null()
+    } else {
+        args.as_ptr()
+    };
+    sys_spawn(path, args, actions)
 }
 
 pub fn dup(fd: usize) -> isize {
@@ -0,0 +1,410 @@
+//! A small single-threaded async runtime for user programs.
+//!
+//! Tasks are only re-polled after their `Waker` fires. When nothing is ready,
+//! the timer reactor puts the whole thread to sleep with `sys_sleep` until the
+//! earliest deadline, or waits in `sys_aio_enter` while [`crate::aio`]
+//! operations are in flight.
+
+use crate::{aio, get_time, sleep_blocking};
+use alloc::{
+    boxed::Box,
+    collections::{BTreeMap, BinaryHeap, VecDeque},
+    sync::Arc,
+    task::Wake,
+    vec::Vec,
+};
+use core::{
+    cmp::Ordering,
+    future::Future,
+    pin::Pin,
+    sync::atomic::{AtomicBool, Ordering as AtomicOrdering},
+    task::{Context, Poll, Waker},
+};
+use lazy_static::*;
+use spin::Mutex;
+
+type BoxFuture = Pin<Box<dyn Future<Output = ()> + Send>>;
+
+/// Spawned tasks that are not currently being polled
+struct Executor {
+    tasks: BTreeMap<usize, BoxFuture>,
+    wakers: BTreeMap<usize, Arc<TaskWaker>>,
+    next_id: usize,
+}
+
+lazy_static! {
+    static ref EXECUTOR: Mutex<Executor> = Mutex::new(Executor {
+        tasks: BTreeMap::new(),
+        wakers: BTreeMap::new(),
+        next_id: 0,
+    });
+    static ref READY_QUEUE: Mutex<VecDeque<usize>> = Mutex::new(VecDeque::new());
+    static ref REACTOR: Mutex<Reactor> = Mutex::new(Reactor::new());
+}
+
+struct TaskWaker {
+    id: usize,
+    /// set while the task sits in `READY_QUEUE`, so repeated wakes enqueue it once
+    queued: AtomicBool,
+}
+
+impl Wake for TaskWaker {
+    fn wake(self: Arc<Self>) {
+        self.wake_by_ref();
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        if !self.queued.swap(true, AtomicOrdering::AcqRel) {
+            READY_QUEUE.lock().push_back(self.id);
+        }
+    }
+}
+
+/// Waker of the future driven by `block_on`
+struct MainWaker {
+    woken: AtomicBool,
+}
+
+impl Wake for MainWaker {
+    fn wake(self: Arc<Self>) {
+        self.wake_by_ref();
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        self.woken.store(true, AtomicOrdering::Release);
+    }
+}
+
+struct JoinState<T> {
+    output: Option<T>,
+    waker: Option<Waker>,
+}
+
+/// An owned handle to a spawned task, resolving to the task's output.
+///
+/// Dropping the handle detaches the task; it keeps running.
+pub struct JoinHandle<T> {
+    state: Arc<Mutex<JoinState<T>>>,
+}
+
+impl<T> JoinHandle<T> {
+    /// Whether the task has already produced its output
+    pub fn is_finished(&self) -> bool {
+        self.state.lock().output.is_some()
+    }
+}
+
+impl<T> Future for JoinHandle<T> {
+    type Output = T;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<T> {
+        let mut state = self.state.lock();
+        match state.output.take() {
+            Some(output) => Poll::Ready(output),
+            None => {
+                state.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+/// Spawn a task onto the runtime. It runs while some `block_on` drives the runtime.
+pub fn spawn<F>(future: F) -> JoinHandle<F::Output>
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    let state = Arc::new(Mutex::new(JoinState {
+        output: None,
+        waker: None,
+    }));
+    let task_state = Arc::clone(&state);
+    let task = async move {
+        let output = future.await;
+        let waker = {
+            let mut state = task_state.lock();
+            state.output = Some(output);
+            state.waker.take()
+        };
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+    };
+    let mut executor = EXECUTOR.lock();
+    let id = executor.next_id;
+    executor.next_id += 1;
+    let task_waker = Arc::new(TaskWaker {
+        id,
+        queued: AtomicBool::new(false),
+    });
+    executor.tasks.insert(id, Box::pin(task));
+    executor.wakers.insert(id, Arc::clone(&task_waker));
+    drop(executor);
+    task_waker.wake_by_ref();
+    JoinHandle { state }
+}
+
+/// Poll every ready task once. The executor lock is released while a task
+/// is polled, so tasks may spawn or wake other tasks.
+fn run_ready() {
+    loop {
+        let id = match READY_QUEUE.lock().pop_front() {
+            Some(id) => id,
+            None => break,
+        };
+        let (mut future, task_waker) = {
+            let mut executor = EXECUTOR.lock();
+            match executor.tasks.remove(&id) {
+                Some(future) => (future, Arc::clone(&executor.wakers[&id])),
+                None => continue,
+            }
+        };
+        task_waker.queued.store(false, AtomicOrdering::Release);
+        let waker = Waker::from(task_waker);
+        let mut cx = Context::from_waker(&waker);
+        match future.as_mut().poll(&mut cx) {
+            Poll::Ready(()) => {
+                EXECUTOR.lock().wakers.remove(&id);
+            }
+            Poll::Pending => {
+                EXECUTOR.lock().tasks.insert(id, future);
+            }
+        }
+    }
+}
+
+/// Run `future` to completion on the current thread, driving spawned tasks
+/// and timers meanwhile.
+///
+/// Panics if neither the future nor any task can ever be woken again.
+pub fn block_on<F: Future>(future: F) -> F::Output {
+    let mut future = Box::pin(future);
+    let main_waker = Arc::new(MainWaker {
+        woken: AtomicBool::new(true),
+    });
+    let waker = Waker::from(Arc::clone(&main_waker));
+    let mut cx = Context::from_waker(&waker);
+    loop {
+        if main_waker.woken.swap(false, AtomicOrdering::AcqRel) {
+            if let Poll::Ready(output) = future.as_mut().poll(&mut cx) {
+                return output;
+            }
+        }
+        run_ready();
+        aio::poll();
+        if main_waker.woken.load(AtomicOrdering::Acquire) || !READY_QUEUE.lock().is_empty() {
+            continue;
+        }
+        if !park() {
+            panic!("block_on: nothing can wake the runtime any more");
+        }
+    }
+}
+
+/// Sleep until the earliest timer deadline or async syscall completion, then
+/// wake all expired timers. Returns false if nothing can wake us.
+fn park() -> bool {
+    let deadline = REACTOR.lock().next_deadline();
+    let now = get_time() as usize;
+    if aio::in_flight() {
+        let timeout = match deadline {
+            Some(deadline) => deadline.saturating_sub(now) as isize,
+            None => -1,
+        };
+        aio::wait(timeout);
+    } else {
+        match deadline {
+            Some(deadline) if deadline > now => sleep_blocking(deadline - now),
+            Some(_) => {}
+            None => return false,
+        }
+    }
+    let expired = REACTOR.lock().take_expired(get_time() as usize);
+    for waker in expired {
+        waker.wake();
+    }
+    true
+}
+
+struct Timer {
+    deadline: usize,
+    id: usize,
+}
+
+impl PartialEq for Timer {
+    fn eq(&self, other: &Self) -> bool {
+        self.deadline == other.deadline
+    }
+}
+impl Eq for Timer {}
+impl PartialOrd for Timer {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+impl Ord for Timer {
+    // BinaryHeap is a max-heap, reverse it so the earliest deadline is on top
+    fn cmp(&self, other: &Self) -> Ordering {
+        other.deadline.cmp(&self.deadline)
+    }
+}
+
+/// Timer reactor. Cancelled timers stay in the heap and are skipped lazily.
+struct Reactor {
+    timers: BinaryHeap<Timer>,
+    wakers: BTreeMap<usize, Waker>,
+    next_id: usize,
+}
+
+impl Reactor {
+    fn new() -> Self {
+        Self {
+            timers: BinaryHeap::new(),
+            wakers: BTreeMap::new(),
+            next_id: 0,
+        }
+    }
+
+    /// Register or refresh a timer, returns its id
+    fn register(&mut self, timer: Option<usize>, deadline: usize, waker: &Waker) -> usize {
+        if let Some(id) = timer {
+            if let Some(old) = self.wakers.get_mut(&id) {
+                if !old.will_wake(waker) {
+                    *old = waker.clone();
+                }
+                return id;
+            }
+        }
+        let id = self.next_id;
+        self.next_id += 1;
+        self.timers.push(Timer { deadline, id });
+        self.wakers.insert(id, waker.clone());
+        id
+    }
+
+    fn cancel(&mut self, id: usize) {
+        self.wakers.remove(&id);
+    }
+
+    fn next_deadline(&mut self) -> Option<usize> {
+        while let Some(timer) = self.timers.peek() {
+            if self.wakers.contains_key(&timer.id) {
+                return Some(timer.deadline);
+            }
+            self.timers.pop();
+        }
+        None
+    }
+
+    fn take_expired(&mut self, now: usize) -> Vec<Waker> {
+        let mut expired = Vec::new();
+        while let Some(timer) = self.timers.peek() {
+            if timer.deadline > now {
+                break;
+            }
+            let id = self.timers.pop().unwrap().id;
+            if let Some(waker) = self.wakers.remove(&id) {
+                expired.push(waker);
+            }
+        }
+        expired
+    }
+}
+
+/// Future returned by [`sleep`]
+pub struct Sleep {
+    deadline: usize,
+    timer: Option<usize>,
+}
+
+/// Wait until `ms` milliseconds have elapsed
+pub fn sleep(ms: usize) -> Sleep {
+    Sleep {
+        deadline: get_time() as usize + ms,
+        timer: None,
+    }
+}
+
+impl Future for Sleep {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        if get_time() as usize >= self.deadline {
+            if let Some(id) = self.timer.take() {
+                REACTOR.lock().cancel(id);
+            }
+            Poll::Ready(())
+        } else {
+            let id = REACTOR
+                .lock()
+                .register(self.timer, self.deadline, cx.waker());
+            self.timer = Some(id);
+            Poll::Pending
+        }
+    }
+}
+
+impl Drop for Sleep {
+    fn drop(&mut self) {
+        if let Some(id) = self.timer.take() {
+            REACTOR.lock().cancel(id);
+        }
+    }
+}
+
+/// Error returned by [`timeout`] when the deadline passes first
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Elapsed;
+
+/// Future returned by [`timeout`]
+pub struct Timeout<F: Future> {
+    future: Pin<Box<F>>,
+    delay: Sleep,
+}
+
+/// Run `future` for at most `ms` milliseconds
+pub fn timeout<F: Future>(ms: usize, future: F) -> Timeout<F> {
+    Timeout {
+        future: Box::pin(future),
+        delay: sleep(ms),
+    }
+}
+
+impl<F: Future> Future for Timeout<F> {
+    type Output = Result<F::Output, Elapsed>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        if let Poll::Ready(output) = self.future.as_mut().poll(cx) {
+            return Poll::Ready(Ok(output));
+        }
+        match Pin::new(&mut self.delay).poll(cx) {
+            Poll::Ready(()) => Poll::Ready(Err(Elapsed)),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+/// Future returned by [`yield_now`]
+pub struct YieldNow {
+    yielded: bool,
+}
+
+/// Give other ready tasks a chance to run
+pub fn yield_now() -> YieldNow {
+    YieldNow { yielded: false }
+}
+
+impl Future for YieldNow {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        if self.yielded {
+            Poll::Ready(())
+        } else {
+            self.yielded = true;
+            cx.waker().wake_by_ref();
+            Poll::Pending
+        }
+    }
+}
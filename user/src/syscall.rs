@@ -1,14 +1,27 @@
-use crate::{TaskInfo, SignalAction};
+use crate::{SignalAction, SpawnAction, TaskInfo};
 
 use super::{Stat, TimeVal};
 
+pub const SYSCALL_GETCWD: usize = 17;
+pub const SYSCALL_MKDIRAT: usize = 34;
+pub const SYSCALL_CHDIR: usize = 49;
 pub const SYSCALL_OPENAT: usize = 56;
 pub const SYSCALL_CLOSE: usize = 57;
 pub const SYSCALL_READ: usize = 63;
 pub const SYSCALL_WRITE: usize = 64;
+pub const SYSCALL_LSEEK: usize = 62;
+pub const SYSCALL_GETDENTS64: usize = 61;
+pub const SYSCALL_PREAD: usize = 67;
+pub const SYSCALL_PWRITE: usize = 68;
+pub const SYSCALL_FTRUNCATE: usize = 46;
 pub const SYSCALL_UNLINKAT: usize = 35;
 pub const SYSCALL_LINKAT: usize = 37;
+pub const SYSCALL_RENAMEAT2: usize = 276;
+pub const SYSCALL_UMOUNT2: usize = 39;
+pub const SYSCALL_MOUNT: usize = 40;
 pub const SYSCALL_FSTAT: usize = 80;
+pub const SYSCALL_SYNC: usize = 81;
+pub const SYSCALL_FSYNC: usize = 82;
 pub const SYSCALL_EXIT: usize = 93;
 pub const SYSCALL_SLEEP: usize = 101;
 pub const SYSCALL_YIELD: usize = 124;
@@ -17,7 +30,11 @@ pub const SYSCALL_SIGACTION: usize = 134;
 pub const SYSCALL_SIGPROCMASK: usize = 135;
 pub const SYSCALL_SIGRETURN: usize = 139;
 pub const SYSCALL_GETTIMEOFDAY: usize = 169;
+pub const SYSCALL_SETGID: usize = 144;
+pub const SYSCALL_SETUID: usize = 146;
 pub const SYSCALL_GETPID: usize = 172;
+pub const SYSCALL_GETUID: usize = 174;
+pub const SYSCALL_GETGID: usize = 176;
 pub const SYSCALL_GETTID: usize = 178;
 pub const SYSCALL_FORK: usize = 220;
 pub const SYSCALL_EXEC: usize = 221;
@@ -44,6 +61,8 @@ pub const SYSCALL_SEMAPHORE_DOWN: usize = 470;
 pub const SYSCALL_CONDVAR_CREATE: usize = 471;
 pub const SYSCALL_CONDVAR_SIGNAL: usize = 472;
 pub const SYSCALL_CONDVAR_WAIT: usize = 473;
+pub const SYSCALL_AIO_SETUP: usize = 490;
+pub const SYSCALL_AIO_ENTER: usize = 491;
 
 pub fn syscall(id: usize, args: [usize; 3]) -> isize {
     let mut ret: isize;
@@ -104,6 +123,35 @@ pub fn sys_write(fd: usize, buffer: &[u8]) -> isize {
     syscall(SYSCALL_WRITE, [fd, buffer.as_ptr() as usize, buffer.len()])
 }
 
+pub fn sys_getdents64(fd: usize, buffer: &mut [u8]) -> isize {
+    syscall(
+        SYSCALL_GETDENTS64,
+        [fd, buffer.as_mut_ptr() as usize, buffer.len()],
+    )
+}
+
+pub fn sys_lseek(fd: usize, offset: isize, whence: usize) -> isize {
+    syscall(SYSCALL_LSEEK, [fd, offset as usize, whence])
+}
+
+pub fn sys_pread(fd: usize, buffer: &mut [u8], offset: usize) -> isize {
+    syscall6(
+        SYSCALL_PREAD,
+        [fd, buffer.as_mut_ptr() as usize, buffer.len(), offset, 0, 0],
+    )
+}
+
+pub fn sys_pwrite(fd: usize, buffer: &[u8], offset: usize) -> isize {
+    syscall6(
+        SYSCALL_PWRITE,
+        [fd, buffer.as_ptr() as usize, buffer.len(), offset, 0, 0],
+    )
+}
+
+pub fn sys_ftruncate(fd: usize, len: usize) -> isize {
+    syscall(SYSCALL_FTRUNCATE, [fd, len, 0])
+}
+
 pub fn sys_linkat(
     old_dirfd: usize,
     old_path: &str,
@@ -124,14 +172,72 @@ pub fn sys_linkat(
     )
 }
 
+pub fn sys_renameat2(
+    old_dirfd: usize,
+    old_path: &str,
+    new_dirfd: usize,
+    new_path: &str,
+    flags: usize,
+) -> isize {
+    syscall6(
+        SYSCALL_RENAMEAT2,
+        [
+            old_dirfd,
+            old_path.as_ptr() as usize,
+            new_dirfd,
+            new_path.as_ptr() as usize,
+            flags,
+            0,
+        ],
+    )
+}
+
+pub fn sys_mount(source: &str, target: &str, fstype: &str, flags: usize) -> isize {
+    syscall6(
+        SYSCALL_MOUNT,
+        [
+            source.as_ptr() as usize,
+            target.as_ptr() as usize,
+            fstype.as_ptr() as usize,
+            flags,
+            0,
+            0,
+        ],
+    )
+}
+
+pub fn sys_umount2(target: &str, flags: usize) -> isize {
+    syscall(SYSCALL_UMOUNT2, [target.as_ptr() as usize, flags, 0])
+}
+
 pub fn sys_unlinkat(dirfd: usize, path: &str, flags: usize) -> isize {
     syscall(SYSCALL_UNLINKAT, [dirfd, path.as_ptr() as usize, flags])
 }
 
+pub fn sys_mkdirat(dirfd: usize, path: &str, mode: u32) -> isize {
+    syscall(SYSCALL_MKDIRAT, [dirfd, path.as_ptr() as usize, mode as usize])
+}
+
+pub fn sys_chdir(path: &str) -> isize {
+    syscall(SYSCALL_CHDIR, [path.as_ptr() as usize, 0, 0])
+}
+
+pub fn sys_getcwd(buf: &mut [u8]) -> isize {
+    syscall(SYSCALL_GETCWD, [buf.as_mut_ptr() as usize, buf.len(), 0])
+}
+
 pub fn sys_fstat(fd: usize, st: &mut Stat) -> isize {
     syscall(SYSCALL_FSTAT, [fd, st as *const _ as usize, 0])
 }
 
+pub fn sys_sync() -> isize {
+    syscall(SYSCALL_SYNC, [0, 0, 0])
+}
+
+pub fn sys_fsync(fd: usize) -> isize {
+    syscall(SYSCALL_FSYNC, [fd, 0, 0])
+}
+
 pub fn sys_mail_read(buffer: &mut [u8]) -> isize {
     syscall(
         SYSCALL_MAIL_READ,
@@ -167,6 +273,22 @@ pub fn sys_getpid() -> isize {
     syscall(SYSCALL_GETPID, [0, 0, 0])
 }
 
+pub fn sys_getuid() -> isize {
+    syscall(SYSCALL_GETUID, [0, 0, 0])
+}
+
+pub fn sys_getgid() -> isize {
+    syscall(SYSCALL_GETGID, [0, 0, 0])
+}
+
+pub fn sys_setuid(uid: u32) -> isize {
+    syscall(SYSCALL_SETUID, [uid as usize, 0, 0])
+}
+
+pub fn sys_setgid(gid: u32) -> isize {
+    syscall(SYSCALL_SETGID, [gid as usize, 0, 0])
+}
+
 pub fn sys_fork() -> isize {
     syscall(SYSCALL_FORK, [0, 0, 0])
 }
@@ -198,8 +320,23 @@ pub fn sys_munmap(start: usize, len: usize) -> isize {
     syscall(SYSCALL_MUNMAP, [start, len, 0])
 }
 
-pub fn sys_spawn(path: &str) -> isize {
-    syscall(SYSCALL_SPAWN, [path.as_ptr() as usize, 0, 0])
+pub fn sys_spawn(path: &str, args: *const *const u8, actions: &[SpawnAction]) -> isize {
+    syscall6(
+        SYSCALL_SPAWN,
+        [
+            path.as_ptr() as usize,
+            args as usize,
+            // an empty slice points nowhere in particular
+            if actions.is_empty() {
+                0
+            } else {
+                actions.as_ptr() as usize
+            },
+            actions.len(),
+            0,
+            0,
+        ],
+    )
 }
 
 pub fn sys_dup(fd: usize) -> isize {
@@ -292,3 +429,14 @@ pub fn sys_sigreturn() -> isize {
 pub fn sys_kill(pid: usize, signal: i32) -> isize {
     syscall(SYSCALL_KILL, [pid, signal as usize, 0])
 }
+
+pub fn sys_aio_setup(ring: usize) -> isize {
+    syscall(SYSCALL_AIO_SETUP, [ring, 0, 0])
+}
+
+pub fn sys_aio_enter(to_submit: usize, min_complete: usize, timeout_ms: isize) -> isize {
+    syscall(
+        SYSCALL_AIO_ENTER,
+        [to_submit, min_complete, timeout_ms as usize],
+    )
+}